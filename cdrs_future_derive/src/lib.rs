@@ -0,0 +1,188 @@
+//! `#[derive(TryFromRow)]`, `#[derive(IntoQueryValues)]` and `#[derive(Columns)]`
+//! for `cdrs-future`.
+//!
+//! Fields are mapped to columns with the same names. Supported attributes:
+//!
+//! * `#[cdrs(rename = "column")]` maps a field to a column with another name;
+//! * `#[cdrs(skip)]` ignores a field. It's built with `Default::default()`
+//!   by `TryFromRow`, is not bound by `IntoQueryValues` and is not listed by `Columns`.
+//!
+//! `Option<T>` fields map nullable columns.
+
+// field names are explicit as in `cdrs-future`
+#![allow(clippy::redundant_field_names)]
+
+extern crate proc_macro;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use syn::{Body, DeriveInput, Field, Ident, Lit, MetaItem, NestedMetaItem, Ty, VariantData};
+
+#[proc_macro_derive(TryFromRow, attributes(cdrs))]
+pub fn derive_try_from_row(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let fields = mapped_fields(&ast, "TryFromRow").into_iter().map(|field| {
+        let ident = &field.ident;
+        if field.skip {
+            return quote! { #ident: ::std::default::Default::default() };
+        }
+
+        let column = &field.column;
+        let field_name = ident.as_ref();
+        if field.nullable {
+            quote! {
+                #ident: try!(::cdrs_future::rows::nullable_column(&row, #column, #field_name))
+            }
+        } else {
+            quote! {
+                #ident: try!(::cdrs_future::rows::column(&row, #column, #field_name))
+            }
+        }
+    });
+
+    let expanded = quote! {
+        impl #impl_generics ::cdrs_future::rows::TryFromRow for #name #ty_generics #where_clause {
+            fn try_from_row(row: ::cdrs::types::rows::Row)
+                            -> ::cdrs_future::error::Result<Self> {
+                Ok(#name { #(#fields),* })
+            }
+        }
+    };
+
+    expanded.parse().unwrap()
+}
+
+#[proc_macro_derive(IntoQueryValues, attributes(cdrs))]
+pub fn derive_into_query_values(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let bound: Vec<_> = mapped_fields(&ast, "IntoQueryValues")
+        .into_iter()
+        .filter(|field| !field.skip)
+        .collect();
+    let arity = bound.len();
+    let values = bound.iter().map(|field| {
+        let ident = &field.ident;
+        if field.nullable {
+            quote! { ::cdrs_future::values::nullable_value(self.#ident) }
+        } else {
+            quote! { ::cdrs_future::values::value(self.#ident) }
+        }
+    });
+
+    let expanded = quote! {
+        impl #impl_generics ::cdrs_future::values::IntoQueryValues for #name #ty_generics
+            #where_clause
+        {
+            fn arity() -> Option<usize> {
+                Some(#arity)
+            }
+
+            fn into_query_values(self) -> Vec<::cdrs::types::value::Value> {
+                vec![#(#values),*]
+            }
+        }
+    };
+
+    expanded.parse().unwrap()
+}
+
+#[proc_macro_derive(Columns, attributes(cdrs))]
+pub fn derive_columns(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let columns: Vec<_> = mapped_fields(&ast, "Columns")
+        .into_iter()
+        .filter(|field| !field.skip)
+        .map(|field| field.column)
+        .collect();
+
+    let expanded = quote! {
+        impl #impl_generics ::cdrs_future::values::Columns for #name #ty_generics #where_clause {
+            fn columns() -> &'static [&'static str] {
+                &[#(#columns),*]
+            }
+        }
+    };
+
+    expanded.parse().unwrap()
+}
+
+struct MappedField {
+    ident: Ident,
+    column: String,
+    nullable: bool,
+    skip: bool,
+}
+
+fn mapped_fields(ast: &DeriveInput, derive: &str) -> Vec<MappedField> {
+    match ast.body {
+        Body::Struct(VariantData::Struct(ref fields)) => fields.iter().map(mapped_field).collect(),
+        _ => panic!("#[derive({})] is only supported for structs with named fields", derive),
+    }
+}
+
+fn mapped_field(field: &Field) -> MappedField {
+    let ident = field.ident.clone().unwrap();
+    let mut mapped = MappedField {
+        column: ident.as_ref().to_string(),
+        ident: ident,
+        nullable: is_option(&field.ty),
+        skip: false,
+    };
+
+    for attr in &field.attrs {
+        let items = match attr.value {
+            MetaItem::List(ref name, ref items) if name == "cdrs" => items,
+            MetaItem::Word(ref name) |
+            MetaItem::NameValue(ref name, _) if name == "cdrs" => {
+                panic!("`cdrs` attribute of field `{}` expects a list: #[cdrs(...)]",
+                       mapped.ident)
+            }
+            _ => continue,
+        };
+
+        for item in items {
+            match *item {
+                NestedMetaItem::MetaItem(MetaItem::NameValue(ref name, Lit::Str(ref column, _)))
+                    if name == "rename" => mapped.column = column.clone(),
+                NestedMetaItem::MetaItem(MetaItem::Word(ref name)) if name == "skip" => {
+                    mapped.skip = true
+                }
+                NestedMetaItem::MetaItem(MetaItem::Word(ref name)) if name == "rename" => {
+                    panic!("`rename` of field `{}` expects a column name: \
+                            #[cdrs(rename = \"column\")]",
+                           mapped.ident)
+                }
+                _ => {
+                    panic!("unknown `cdrs` attribute of field `{}`, \
+                            `rename = \"...\"` and `skip` are supported",
+                           mapped.ident)
+                }
+            }
+        }
+    }
+
+    mapped
+}
+
+fn is_option(ty: &Ty) -> bool {
+    match *ty {
+        Ty::Path(None, ref path) => {
+            path.segments
+                .last()
+                .map(|segment| segment.ident == "Option")
+                .unwrap_or(false)
+        }
+        _ => false,
+    }
+}
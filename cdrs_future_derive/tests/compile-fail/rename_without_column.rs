@@ -0,0 +1,12 @@
+#[macro_use]
+extern crate cdrs_future_derive;
+extern crate cdrs;
+extern crate cdrs_future;
+
+#[derive(TryFromRow)]
+struct User {
+    #[cdrs(rename)]
+    id: i32,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+#[macro_use]
+extern crate cdrs_future_derive;
+extern crate cdrs;
+extern crate cdrs_future;
+
+#[derive(TryFromRow)]
+struct User(i32, String);
+
+fn main() {}
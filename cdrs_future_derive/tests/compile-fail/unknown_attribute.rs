@@ -0,0 +1,12 @@
+#[macro_use]
+extern crate cdrs_future_derive;
+extern crate cdrs;
+extern crate cdrs_future;
+
+#[derive(IntoQueryValues)]
+struct User {
+    #[cdrs(column = "user_id")]
+    id: i32,
+}
+
+fn main() {}
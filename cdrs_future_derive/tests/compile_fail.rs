@@ -0,0 +1,6 @@
+extern crate trybuild;
+
+#[test]
+fn compile_fail() {
+    trybuild::TestCases::new().compile_fail("tests/compile-fail/*.rs");
+}
@@ -0,0 +1,150 @@
+#[macro_use]
+extern crate cdrs_future_derive;
+extern crate cdrs;
+extern crate cdrs_future;
+
+use std::io::Cursor;
+
+use cdrs::IntoBytes;
+use cdrs::compression::Compression;
+use cdrs::frame::frame_response::ResponseBody;
+use cdrs::frame::frame_result::ResResultBody;
+use cdrs::frame::parser::parse_frame;
+use cdrs::types::rows::Row;
+use cdrs::types::value::Value;
+use cdrs_future::error::Error;
+use cdrs_future::rows::TryFromRow;
+use cdrs_future::values::{Columns, IntoQueryValues};
+
+#[derive(Debug, PartialEq, TryFromRow, IntoQueryValues, Columns)]
+struct User {
+    id: i32,
+    #[cdrs(rename = "user_name")]
+    name: String,
+    email: Option<String>,
+    #[cdrs(skip)]
+    visits: u64,
+}
+
+const INT: u16 = 0x0009;
+const VARCHAR: u16 = 0x000D;
+
+fn push_string(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(&[(s.len() >> 8) as u8, s.len() as u8]);
+    body.extend_from_slice(s.as_bytes());
+}
+
+fn push_int(body: &mut Vec<u8>, i: i32) {
+    body.extend_from_slice(&[(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+}
+
+/// Decodes rows of a RESULT frame with given columns. `None` cells are nulls.
+fn rows(columns: &[(&str, u16)], rows: &[Vec<Option<Vec<u8>>>]) -> Vec<Row> {
+    let mut body = vec![];
+    push_int(&mut body, 0x0002);
+    push_int(&mut body, 0x0001);
+    push_int(&mut body, columns.len() as i32);
+    push_string(&mut body, "ks");
+    push_string(&mut body, "users");
+    for &(name, type_id) in columns {
+        push_string(&mut body, name);
+        body.extend_from_slice(&[(type_id >> 8) as u8, type_id as u8]);
+    }
+    push_int(&mut body, rows.len() as i32);
+    for row in rows {
+        for cell in row {
+            match *cell {
+                Some(ref bytes) => {
+                    push_int(&mut body, bytes.len() as i32);
+                    body.extend_from_slice(bytes);
+                }
+                None => push_int(&mut body, -1),
+            }
+        }
+    }
+
+    let mut frame = vec![0x84, 0, 0, 0, 0x08];
+    push_int(&mut frame, body.len() as i32);
+    frame.extend(body);
+
+    let frame = parse_frame(&mut Cursor::new(frame), &Compression::None).unwrap();
+    match frame.get_body().unwrap() {
+        ResponseBody::Result(ResResultBody::Rows(rows_body)) => Row::from_frame_body(rows_body),
+        _ => panic!("rows body expected"),
+    }
+}
+
+fn encode(values: Vec<Value>) -> Vec<Vec<u8>> {
+    values.iter().map(|value| value.into_cbytes()).collect()
+}
+
+fn int(i: i32) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    push_int(&mut bytes, i);
+    Some(bytes)
+}
+
+fn text(s: &str) -> Option<Vec<u8>> {
+    Some(s.as_bytes().to_vec())
+}
+
+const COLUMNS: &[(&str, u16)] = &[("id", INT), ("user_name", VARCHAR), ("email", VARCHAR)];
+
+#[test]
+fn maps_row_into_struct() {
+    let mut rows = rows(COLUMNS,
+                        &[vec![int(1), text("alice"), text("alice@example.com")],
+                          vec![int(2), text("bob"), None]]);
+    let bob = User::try_from_row(rows.pop().unwrap()).unwrap();
+    let alice = User::try_from_row(rows.pop().unwrap()).unwrap();
+
+    assert_eq!(alice,
+               User {
+                   id: 1,
+                   name: "alice".to_string(),
+                   email: Some("alice@example.com".to_string()),
+                   visits: 0,
+               });
+    assert_eq!(bob,
+               User {
+                   id: 2,
+                   name: "bob".to_string(),
+                   email: None,
+                   visits: 0,
+               });
+}
+
+#[test]
+fn names_field_and_column_on_failure() {
+    let mut rows = rows(COLUMNS, &[vec![int(1), None, None]]);
+
+    match User::try_from_row(rows.pop().unwrap()) {
+        Err(Error::Conversion { column, field, .. }) => {
+            assert_eq!(column, "user_name");
+            assert_eq!(field, "name");
+        }
+        other => panic!("Conversion error expected, got {:?}", other),
+    }
+}
+
+#[test]
+fn binds_fields_in_order() {
+    assert_eq!(User::arity(), Some(3));
+
+    let values = User {
+            id: 1,
+            name: "alice".to_string(),
+            email: None,
+            visits: 10,
+        }
+        .into_query_values();
+
+    // cdrs values aren't PartialEq, so they are compared as bytes
+    let expected = vec![Value::from(1), Value::from("alice".to_string()), Value::new_null()];
+    assert_eq!(encode(values), encode(expected));
+}
+
+#[test]
+fn lists_bound_columns() {
+    assert_eq!(User::columns(), &["id", "user_name", "email"]);
+}
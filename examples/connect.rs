@@ -0,0 +1,40 @@
+//! Connects to a node with `TransportTcp::new`, starts a session with `CDRS::new`
+//! and asks the node for its version.
+//!
+//! cargo run --example connect [address]
+
+extern crate cdrs;
+extern crate cdrs_future;
+extern crate futures;
+extern crate tokio_core;
+
+use std::env;
+
+use cdrs::authenticators::NoneAuthenticator;
+use cdrs::compression::Compression;
+use cdrs::query::QueryBuilder;
+use cdrs_future::client::CDRS;
+use cdrs_future::error;
+use cdrs_future::transport::TransportTcp;
+use futures::Future;
+use tokio_core::reactor::Core;
+
+fn main() {
+    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:9042".to_string());
+    let mut core = Core::new().unwrap();
+
+    let version = TransportTcp::new(addr, &core.handle())
+        .from_err::<error::Error>()
+        .and_then(|transport| CDRS::new(transport, NoneAuthenticator).start(Compression::None))
+        .and_then(|session| {
+                      let query = QueryBuilder::new("SELECT release_version FROM system.local")
+                          .finalize();
+                      session.query_value::<String, _>(query)
+                  })
+        .map(|(_, version)| version);
+
+    match core.run(version) {
+        Ok(version) => println!("Connected to {}", version.unwrap_or_default()),
+        Err(err) => println!("Error: {}", err),
+    }
+}
@@ -0,0 +1,46 @@
+//! Connects to the first of contact points which accepts a connection with
+//! `SessionBuilder`, authenticates, negotiates compression and uses a keyspace.
+//!
+//! cargo run --example session_builder [contact point]...
+
+extern crate cdrs;
+extern crate cdrs_future;
+extern crate futures;
+extern crate tokio_core;
+
+use std::env;
+
+use cdrs::authenticators::PasswordAuthenticator;
+use cdrs::compression::Compression;
+use cdrs::query::QueryBuilder;
+use cdrs_future::builder::SessionBuilder;
+use futures::Future;
+use tokio_core::reactor::Core;
+
+fn main() {
+    let mut contact_points: Vec<_> = env::args().skip(1).collect();
+    if contact_points.is_empty() {
+        contact_points.push("127.0.0.1:9042".to_string());
+    }
+    let mut core = Core::new().unwrap();
+
+    let mut builder = SessionBuilder::new(&core.handle());
+    for contact_point in contact_points {
+        builder = builder.contact_point(contact_point);
+    }
+    let tables = builder
+        .authenticator(PasswordAuthenticator::new("cassandra", "cassandra"))
+        .compression(Compression::Lz4)
+        .keyspace("system_schema")
+        .connect()
+        .and_then(|session| {
+                      let query = QueryBuilder::new("SELECT count(*) FROM tables").finalize();
+                      session.query_value::<i64, _>(query)
+                  })
+        .map(|(_, tables)| tables);
+
+    match core.run(tables) {
+        Ok(tables) => println!("There are {} tables", tables.unwrap_or_default()),
+        Err(err) => println!("Error: {}", err),
+    }
+}
@@ -0,0 +1,182 @@
+//! Authentication of connections.
+//!
+//! A server which requires authentication answers STARTUP with AUTHENTICATE.
+//! A client sends an initial token of its authenticator and the server either
+//! accepts it with AUTH_SUCCESS, or sends AUTH_CHALLENGE which is answered by
+//! `SaslAuthenticator::evaluate_challenge` until the server is satisfied, as SASL
+//! mechanisms of DSE or Kerberos need.
+
+use std::fmt;
+
+use cdrs::authenticators::{Authenticator, NoneAuthenticator, PasswordAuthenticator};
+use cdrs::types::CBytes;
+use zeroize::Zeroize;
+
+use error;
+
+/// Name of the authenticator of Cassandra which checks a username and a password.
+pub const PASSWORD_AUTHENTICATOR: &'static str = "org.apache.cassandra.auth.PasswordAuthenticator";
+
+/// Authenticator which can answer challenges of multi-round SASL exchanges.
+pub trait SaslAuthenticator: Authenticator {
+    /// Returns a token in response to a `challenge` of a server, `None` if
+    /// a server sent a null token. By default challenges are not supported,
+    /// which suits authenticators that send a single token, e.g. a password.
+    fn evaluate_challenge(&mut self, _challenge: Option<&[u8]>) -> error::Result<Vec<u8>> {
+        Err(error::Error::General(format!("Authenticator {:?} doesn't answer challenges",
+                                          self.get_cassandra_name())))
+    }
+}
+
+impl SaslAuthenticator for NoneAuthenticator {}
+
+impl<'a> SaslAuthenticator for PasswordAuthenticator<'a> {}
+
+/// Credentials which own their strings, e.g. ones read from a connection URL,
+/// unlike `PasswordAuthenticator`. A password is wiped from memory on drop.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    None,
+    Password { username: String, password: String },
+}
+
+impl Authenticator for Credentials {
+    fn get_auth_token(&self) -> CBytes {
+        let mut token = vec![0];
+        if let Credentials::Password { ref username, ref password } = *self {
+            token.extend_from_slice(username.as_bytes());
+            token.push(0);
+            token.extend_from_slice(password.as_bytes());
+        }
+        CBytes::new(token)
+    }
+
+    fn get_cassandra_name(&self) -> Option<&str> {
+        match *self {
+            Credentials::None => None,
+            Credentials::Password { .. } => Some(PASSWORD_AUTHENTICATOR),
+        }
+    }
+}
+
+impl SaslAuthenticator for Credentials {}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Credentials::None => write!(f, "None"),
+            Credentials::Password { ref username, .. } => {
+                write!(f, "Password {{ username: {:?}, password: \"***\" }}", username)
+            }
+        }
+    }
+}
+
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        if let Credentials::Password { ref mut password, .. } = *self {
+            password.zeroize();
+        }
+    }
+}
+
+/// Checks that `client` is the authenticator a `server` requires. A client
+/// without a name, e.g. `NoneAuthenticator`, has no credentials at all.
+pub fn verify_authenticator<A>(server: &str, client: &A) -> error::Result<()>
+    where A: Authenticator
+{
+    match client.get_cassandra_name() {
+        Some(name) if name == server => Ok(()),
+        Some(name) => {
+            Err(error::Error::AuthenticatorMismatch {
+                    server: server.to_string(),
+                    client: name.to_string(),
+                })
+        }
+        None => Err(error::Error::AuthenticationRequired { server: server.to_string() }),
+    }
+}
+
+/// Reads a token of an AUTH_CHALLENGE or AUTH_SUCCESS body, `None` if it's null.
+pub fn read_token(body: &[u8]) -> error::Result<Option<Vec<u8>>> {
+    if body.len() < 4 {
+        return Err("Authentication token is truncated".into());
+    }
+    let len = ((body[0] as i32) << 24) | ((body[1] as i32) << 16) | ((body[2] as i32) << 8) |
+              body[3] as i32;
+    if len < 0 {
+        return Ok(None);
+    }
+    match body.get(4..4 + len as usize) {
+        Some(token) => Ok(Some(token.to_vec())),
+        None => Err("Authentication token is truncated".into()),
+    }
+}
+
+/// Serializes a token as a body of an AUTH_RESPONSE frame.
+pub fn token_bytes(token: &[u8]) -> Vec<u8> {
+    let len = token.len() as i32;
+    let mut bytes = vec![(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    bytes.extend_from_slice(token);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use cdrs::IntoBytes;
+
+    use super::*;
+
+    const PASSWORD: &'static str = "org.apache.cassandra.auth.PasswordAuthenticator";
+    const KERBEROS: &'static str = "com.datastax.bdp.cassandra.auth.KerberosAuthenticator";
+
+    #[test]
+    fn verifies_authenticator() {
+        let password = PasswordAuthenticator::new("user", "secret");
+        assert!(verify_authenticator(PASSWORD, &password).is_ok());
+
+        let err = verify_authenticator(KERBEROS, &password).unwrap_err();
+        match err {
+            error::Error::AuthenticatorMismatch { ref server, ref client } => {
+                assert_eq!(server, KERBEROS);
+                assert_eq!(client, PASSWORD);
+            }
+            ref other => panic!("mismatch expected, got {:?}", other),
+        }
+        assert_eq!(err.to_string(),
+                   format!("Server requires authenticator {}, but the client provided {}",
+                           KERBEROS,
+                           PASSWORD));
+
+        let err = verify_authenticator(PASSWORD, &NoneAuthenticator).unwrap_err();
+        assert_eq!(err.to_string(),
+                   format!("Server requires authentication scheme {}, but no credentials were \
+                            configured",
+                           PASSWORD));
+    }
+
+    #[test]
+    fn credentials_send_password_tokens() {
+        let credentials = Credentials::Password {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        };
+        let password = PasswordAuthenticator::new("user", "secret");
+        assert_eq!(credentials.get_auth_token().into_cbytes(),
+                   password.get_auth_token().into_cbytes());
+        assert!(verify_authenticator(PASSWORD, &credentials).is_ok());
+        assert_eq!(format!("{:?}", credentials),
+                   "Password { username: \"user\", password: \"***\" }");
+
+        let err = verify_authenticator(PASSWORD, &Credentials::None).unwrap_err();
+        assert!(err.to_string().contains("no credentials were configured"));
+    }
+
+    #[test]
+    fn reads_tokens() {
+        let body = token_bytes(b"nonce");
+        assert_eq!(read_token(&body).unwrap(), Some(b"nonce".to_vec()));
+        assert_eq!(read_token(&[0xFF, 0xFF, 0xFF, 0xFF]).unwrap(), None);
+        assert!(read_token(&body[..6]).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! Backoff from hosts which report they are overloaded.
+//!
+//! A host which answers with `Overloaded`, or with a series of write timeouts,
+//! is deprioritized for an interval which doubles with every next report
+//! and is reset by a successful response. Optionally requests are shed locally
+//! with `Error::Backpressure` when every candidate host is backing off.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use load_balancing::{Annotation, LoadBalancingPolicy, QueryPlanExplanation};
+use error;
+
+/// Error code of `Overloaded` server error.
+pub const OVERLOADED: i32 = 0x1001;
+/// Error code of `Write_timeout` server error.
+pub const WRITE_TIMEOUT: i32 = 0x1100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BackoffOptions {
+    /// Deprioritize hosts which report they are overloaded.
+    pub enabled: bool,
+    /// Fail requests fast if every candidate host is backing off.
+    pub shed_load: bool,
+    /// Interval of the first backoff.
+    pub initial: Duration,
+    /// The longest interval.
+    pub max: Duration,
+    /// Number of write timeouts in a row which is treated as `Overloaded`.
+    pub write_timeouts: usize,
+}
+
+impl Default for BackoffOptions {
+    fn default() -> BackoffOptions {
+        BackoffOptions {
+            enabled: false,
+            shed_load: false,
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            write_timeouts: 5,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct HostState {
+    interval: Option<Duration>,
+    until: Option<Instant>,
+    write_timeouts: usize,
+}
+
+/// Backoff state of hosts.
+#[derive(Debug, Default)]
+pub struct HostBackoff {
+    options: BackoffOptions,
+    hosts: HashMap<SocketAddr, HostState>,
+    shed_requests: u64,
+}
+
+impl HostBackoff {
+    pub fn new(options: BackoffOptions) -> HostBackoff {
+        HostBackoff {
+            options: options,
+            hosts: HashMap::new(),
+            shed_requests: 0,
+        }
+    }
+
+    pub fn options(&self) -> &BackoffOptions {
+        &self.options
+    }
+
+    /// Records a result of a request sent to `host`.
+    pub fn record(&mut self, host: SocketAddr, result: &error::Result<()>) {
+        if !self.options.enabled {
+            return;
+        }
+
+        let options = self.options;
+        let state = self.hosts.entry(host).or_default();
+        let overloaded = match *result {
+            Ok(()) => {
+                *state = HostState::default();
+                return;
+            }
+            Err(ref err) => {
+                match server_error_code(err) {
+                    Some(OVERLOADED) => true,
+                    Some(WRITE_TIMEOUT) => {
+                        state.write_timeouts += 1;
+                        state.write_timeouts >= options.write_timeouts
+                    }
+                    _ => false,
+                }
+            }
+        };
+
+        if overloaded {
+            let interval = match state.interval {
+                Some(interval) => cmp::min(interval * 2, options.max),
+                None => options.initial,
+            };
+            state.interval = Some(interval);
+            state.until = Some(Instant::now() + interval);
+            state.write_timeouts = 0;
+        }
+    }
+
+    /// Current backoff interval of a host, if it's backing off.
+    pub fn interval(&self, host: &SocketAddr) -> Option<Duration> {
+        if self.is_backing_off(host) {
+            self.hosts.get(host).and_then(|state| state.interval)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_backing_off(&self, host: &SocketAddr) -> bool {
+        match self.hosts.get(host).and_then(|state| state.until) {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    /// Checks if a request with a given plan may be sent. Fails with
+    /// `Error::Backpressure` if load shedding is on and every host is backing off.
+    pub fn admit(&mut self, plan: &[SocketAddr]) -> error::Result<()> {
+        let shed = self.options.enabled && self.options.shed_load && !plan.is_empty() &&
+                   plan.iter().all(|host| self.is_backing_off(host));
+
+        if shed {
+            self.shed_requests += 1;
+            Err(error::Error::Backpressure)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Number of requests which were shed.
+    pub fn shed_requests(&self) -> u64 {
+        self.shed_requests
+    }
+}
+
+fn server_error_code(err: &error::Error) -> Option<i32> {
+    match *err {
+        error::Error::Server { ref error, .. } => Some(error.code()),
+        _ => None,
+    }
+}
+
+/// Moves hosts which are backing off to the end of plans of another policy.
+#[derive(Debug)]
+pub struct BackoffPolicy<P> {
+    inner: P,
+    backoff: Arc<Mutex<HostBackoff>>,
+}
+
+impl<P: LoadBalancingPolicy> BackoffPolicy<P> {
+    pub fn new(inner: P, backoff: Arc<Mutex<HostBackoff>>) -> BackoffPolicy<P> {
+        BackoffPolicy {
+            inner: inner,
+            backoff: backoff,
+        }
+    }
+
+    pub fn backoff(&self) -> Arc<Mutex<HostBackoff>> {
+        self.backoff.clone()
+    }
+}
+
+impl<P: LoadBalancingPolicy> LoadBalancingPolicy for BackoffPolicy<P> {
+    fn plan(&self, hosts: &[SocketAddr]) -> Vec<SocketAddr> {
+        self.explain(hosts).plan()
+    }
+
+    fn explain(&self, hosts: &[SocketAddr]) -> QueryPlanExplanation {
+        let backoff = self.backoff.lock().unwrap();
+        let (mut ready, mut backing_off): (Vec<_>, Vec<_>) = self.inner
+            .explain(hosts)
+            .hosts
+            .into_iter()
+            .partition(|explanation| !backoff.is_backing_off(&explanation.host));
+
+        for explanation in &mut backing_off {
+            if let Some(interval) = backoff.interval(&explanation.host) {
+                explanation.annotations.push(Annotation::BackingOff(interval));
+            }
+        }
+        ready.extend(backing_off);
+        QueryPlanExplanation { hosts: ready }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+    use codec;
+    use load_balancing::{LoadBalancingPolicy, RoundRobinPolicy, ScoreAwarePolicy};
+    use mock::{self, ERROR};
+    use scoring::{HostScores, Outcome, ScoringOptions};
+    use paging::Page;
+    use error;
+
+    fn server_error(code: i32) -> error::Result<()> {
+        let body = match code {
+            WRITE_TIMEOUT => mock::write_timeout_body(0),
+            code => mock::error_body(code, "busy"),
+        };
+        let bytes = mock::response(ERROR, 0, &body);
+        let frame = codec::parse_response(bytes).unwrap();
+        Page::from_frame(frame).map(|_| ())
+    }
+
+    fn options() -> BackoffOptions {
+        BackoffOptions {
+            enabled: true,
+            shed_load: true,
+            initial: Duration::from_secs(10),
+            max: Duration::from_secs(60),
+            write_timeouts: 3,
+        }
+    }
+
+    #[test]
+    fn overloaded_grows_backoff() {
+        let host: SocketAddr = "10.0.0.1:9042".parse().unwrap();
+        let mut backoff = HostBackoff::new(options());
+
+        backoff.record(host, &server_error(OVERLOADED));
+        assert_eq!(backoff.interval(&host), Some(Duration::from_secs(10)));
+        backoff.record(host, &server_error(OVERLOADED));
+        assert_eq!(backoff.interval(&host), Some(Duration::from_secs(20)));
+        for _ in 0..5 {
+            backoff.record(host, &server_error(OVERLOADED));
+        }
+        assert_eq!(backoff.interval(&host), Some(Duration::from_secs(60)));
+
+        backoff.record(host, &Ok(()));
+        assert!(!backoff.is_backing_off(&host));
+    }
+
+    #[test]
+    fn write_timeout_storm_backs_off() {
+        let host: SocketAddr = "10.0.0.1:9042".parse().unwrap();
+        let mut backoff = HostBackoff::new(options());
+
+        backoff.record(host, &server_error(WRITE_TIMEOUT));
+        backoff.record(host, &server_error(WRITE_TIMEOUT));
+        assert!(!backoff.is_backing_off(&host));
+        backoff.record(host, &server_error(WRITE_TIMEOUT));
+        assert!(backoff.is_backing_off(&host));
+    }
+
+    #[test]
+    fn sheds_load_when_every_host_backs_off() {
+        let hosts: Vec<SocketAddr> = vec!["10.0.0.1:9042".parse().unwrap(),
+                                          "10.0.0.2:9042".parse().unwrap()];
+        let backoff = Arc::new(Mutex::new(HostBackoff::new(options())));
+        let policy = BackoffPolicy::new(RoundRobinPolicy::new(), backoff.clone());
+
+        backoff.lock().unwrap().record(hosts[0], &server_error(OVERLOADED));
+        assert_eq!(policy.plan(&hosts), vec![hosts[1], hosts[0]]);
+        assert!(backoff.lock().unwrap().admit(&hosts).is_ok());
+
+        backoff.lock().unwrap().record(hosts[1], &server_error(OVERLOADED));
+        // the policy locks the backoff itself, so the plan is made first
+        let plan = policy.plan(&hosts);
+        match backoff.lock().unwrap().admit(&plan) {
+            Err(error::Error::Backpressure) => (),
+            other => panic!("Backpressure expected, got {:?}", other),
+        }
+        assert_eq!(backoff.lock().unwrap().shed_requests(), 1);
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let host: SocketAddr = "10.0.0.1:9042".parse().unwrap();
+        let mut backoff = HostBackoff::new(BackoffOptions::default());
+        backoff.record(host, &server_error(OVERLOADED));
+        assert!(!backoff.is_backing_off(&host));
+        assert!(backoff.admit(&[host]).is_ok());
+    }
+
+    #[test]
+    fn explains_plan() {
+        let hosts: Vec<SocketAddr> = vec!["10.0.0.1:9042".parse().unwrap(),
+                                          "10.0.0.2:9042".parse().unwrap()];
+        let scores = Arc::new(Mutex::new(HostScores::new(ScoringOptions::default())));
+        let backoff = Arc::new(Mutex::new(HostBackoff::new(options())));
+        let policy = BackoffPolicy::new(ScoreAwarePolicy::new(RoundRobinPolicy::new(),
+                                                              scores.clone()),
+                                        backoff.clone());
+
+        scores.lock().unwrap().record(hosts[1], Outcome::Error);
+        scores.lock().unwrap().record(hosts[1], Outcome::Success);
+        backoff.lock().unwrap().record(hosts[0], &server_error(OVERLOADED));
+
+        let explanation = policy.explain(&hosts);
+        assert_eq!(explanation.plan(), vec![hosts[1], hosts[0]]);
+        assert_eq!(explanation.hosts[1].annotations,
+                   vec![Annotation::Weight(1.0),
+                        Annotation::BackingOff(Duration::from_secs(10))]);
+        assert_eq!(explanation.to_string(),
+                   "10.0.0.2:9042 (weight 0.50), \
+                    10.0.0.1:9042 (weight 1.00, backing off for 10s)");
+    }
+}
@@ -0,0 +1,441 @@
+//! Building of batches which mix simple and prepared statements.
+//!
+//! A server refuses batches which mix counter updates with other statements,
+//! so a batch of a given type takes only statements of its kind: a `COUNTER`
+//! batch takes counter updates, `LOGGED` and `UNLOGGED` batches take the rest.
+//! Kinds of simple statements are told by their text where it's certain, e.g.
+//! `views = views + 1` updates a counter, while `tags = tags + ?` may append
+//! to a collection as well and is taken in any batch. Prepared statements are
+//! taken as they are since their text is not known. The check can be turned off
+//! with `check_counters(false)`; a server rejects a mixed batch then, see
+//! `check_response`.
+
+use cdrs::consistency::Consistency;
+use cdrs::frame::Frame;
+use cdrs::frame::frame_batch::BatchType;
+use cdrs::frame::frame_query::QueryFlags;
+use cdrs::query::{BatchQueryBuilder, BatchValue, QueryBatch};
+use cdrs::types::CBytesShort;
+use cdrs::types::value::Value;
+
+use script;
+use values::IntoQueryValues;
+use error::{self, ServerError};
+
+/// Beginnings of `Invalid` errors with which Cassandra, old and new versions,
+/// rejects batches mixing counter updates with other statements.
+const COUNTER_BATCH_REJECTIONS: &'static [&'static str] =
+    &["Cannot include non-counter statement in a counter batch",
+      "Cannot include a counter statement in a logged batch",
+      "Cannot include counter statement in a non-counter batch",
+      "Counter mutations are only allowed in COUNTER batches",
+      "Only counter mutations are allowed in COUNTER batches"];
+
+/// A statement of a batch.
+#[derive(Debug, Clone)]
+enum Statement {
+    Simple(String, Vec<Value>),
+    Prepared(CBytesShort, Vec<Value>),
+}
+
+/// Builder of a `QueryBatch` which is sent with `Session::batch`.
+#[derive(Debug, Clone)]
+pub struct BatchBuilder {
+    batch_type: BatchType,
+    statements: Vec<Statement>,
+    consistency: Consistency,
+    serial_consistency: Option<Consistency>,
+    timestamp: Option<i64>,
+    check_counters: bool,
+}
+
+impl BatchBuilder {
+    fn new(batch_type: BatchType) -> BatchBuilder {
+        BatchBuilder {
+            batch_type: batch_type,
+            statements: vec![],
+            consistency: Consistency::One,
+            serial_consistency: None,
+            timestamp: None,
+            check_counters: true,
+        }
+    }
+
+    /// A batch which is applied as a whole, even if a coordinator fails midway.
+    pub fn logged() -> BatchBuilder {
+        BatchBuilder::new(BatchType::Logged)
+    }
+
+    /// A batch which skips the batch log, it may be applied partially.
+    pub fn unlogged() -> BatchBuilder {
+        BatchBuilder::new(BatchType::Unlogged)
+    }
+
+    /// A batch of counter updates.
+    pub fn counter() -> BatchBuilder {
+        BatchBuilder::new(BatchType::Counter)
+    }
+
+    /// Adds a simple statement with values bound to its markers.
+    pub fn add_query<Q, V>(mut self, query: Q, values: V) -> BatchBuilder
+        where Q: Into<String>,
+              V: IntoQueryValues
+    {
+        self.statements.push(Statement::Simple(query.into(), values.into_query_values()));
+        self
+    }
+
+    /// Adds a prepared statement with values bound to its markers.
+    pub fn add_prepared<V: IntoQueryValues>(mut self, id: &CBytesShort, values: V) -> BatchBuilder {
+        self.statements.push(Statement::Prepared(id.clone(), values.into_query_values()));
+        self
+    }
+
+    pub fn consistency(mut self, consistency: Consistency) -> BatchBuilder {
+        self.consistency = consistency;
+        self
+    }
+
+    /// Consistency of the paxos phase of conditional statements.
+    pub fn serial_consistency(mut self, serial_consistency: Consistency) -> BatchBuilder {
+        self.serial_consistency = Some(serial_consistency);
+        self
+    }
+
+    /// Write time of every statement of the batch in microseconds since the epoch.
+    pub fn timestamp(mut self, timestamp: i64) -> BatchBuilder {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Whether `finalize` checks that statements match the type of the batch.
+    /// It does by default.
+    pub fn check_counters(mut self, check_counters: bool) -> BatchBuilder {
+        self.check_counters = check_counters;
+        self
+    }
+
+    /// Builds the batch. Fails if a batch has no statements, or with
+    /// `Error::CounterBatch` if a statement doesn't match the type of the batch.
+    pub fn finalize(self) -> error::Result<QueryBatch> {
+        if self.statements.is_empty() {
+            return Err("Batch has no statements".into());
+        }
+        let counter_batch = self.batch_type == BatchType::Counter;
+        if self.check_counters {
+            for statement in &self.statements {
+                if let Statement::Simple(ref query, _) = *statement {
+                    let mixed = if counter_batch {
+                        !may_be_counter_update(query)
+                    } else {
+                        is_counter_update(query)
+                    };
+                    if mixed {
+                        return Err(mixed_counter_error(counter_batch, query));
+                    }
+                }
+            }
+        }
+
+        let mut builder = BatchQueryBuilder::new()
+            .batch_type(self.batch_type)
+            .consistency(self.consistency)
+            .serial_consistency(self.serial_consistency)
+            .timestamp(self.timestamp);
+        for statement in self.statements {
+            builder = match statement {
+                Statement::Simple(query, values) => builder.add_query(query, unnamed(values)),
+                Statement::Prepared(id, values) => {
+                    builder.add_query_prepared(id, unnamed(values))
+                }
+            };
+        }
+        let mut batch = try!(builder.finalize());
+        // cdrs flags values as named if there are none at all
+        batch.query_flags.retain(|flag| match *flag {
+                                     QueryFlags::WithNamesForValues => false,
+                                     _ => true,
+                                 });
+        Ok(batch)
+    }
+}
+
+/// Values of a batched statement are bound by position, not by name.
+fn unnamed(values: Vec<Value>) -> Vec<BatchValue> {
+    values.into_iter().map(|value| (None, value)).collect()
+}
+
+fn mixed_counter_error(counter_batch: bool, query: &str) -> error::Error {
+    let reason = if counter_batch {
+        "Counter batch takes only counter updates"
+    } else {
+        "Counter updates are allowed only in a counter batch"
+    };
+    error::Error::CounterBatch(format!("{}: {}", reason, query))
+}
+
+/// Turns an error response to a batch into an error. A batch rejected because
+/// it mixes counter updates with other statements fails with `Error::CounterBatch`.
+pub fn check_response(frame: Frame) -> error::Result<Frame> {
+    match script::check_response(frame) {
+        Err(error::Error::Server { error: ServerError::Invalid, message }) => {
+            if COUNTER_BATCH_REJECTIONS.iter().any(|rejection| message.starts_with(rejection)) {
+                Err(error::Error::CounterBatch(message))
+            } else {
+                Err(error::Error::Server {
+                        error: ServerError::Invalid,
+                        message: message,
+                    })
+            }
+        }
+        result => result,
+    }
+}
+
+/// Returns `true` if a statement is an `UPDATE` which adds integer literals to
+/// or subtracts them from columns, e.g. `UPDATE t SET views = views + 1 WHERE id = ?`.
+/// Collections are appended the same way, so bound values, e.g. `views + ?`,
+/// don't make an update a counter update, see `may_be_counter_update`.
+pub fn is_counter_update(query: &str) -> bool {
+    match counter_operands(query) {
+        Some(operands) => operands.iter().all(|operand| operand.parse::<i64>().is_ok()),
+        None => false,
+    }
+}
+
+/// Returns `true` unless a statement is certainly not a counter update, i.e. it
+/// adds to or subtracts from columns integer literals or bound values only.
+fn may_be_counter_update(query: &str) -> bool {
+    match counter_operands(query) {
+        Some(operands) => {
+            operands.iter().all(|operand| operand.parse::<i64>().is_ok() || is_marker(operand))
+        }
+        None => false,
+    }
+}
+
+fn is_marker(operand: &str) -> bool {
+    operand == "?" ||
+    operand.starts_with(':') && operand.len() > 1 &&
+    operand[1..].chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Operands of an `UPDATE` whose every assignment adds to or subtracts from
+/// the column itself, e.g. `1` and `?` of `SET a = a + 1, b = b - ?`.
+/// It's `None` for other statements.
+fn counter_operands(query: &str) -> Option<Vec<String>> {
+    let query = query.trim().to_lowercase();
+    if !query.starts_with("update ") {
+        return None;
+    }
+    let assignments = match query.find(" set ") {
+        Some(set) => &query[set + " set ".len()..],
+        None => return None,
+    };
+    let assignments = match assignments.find(" where ") {
+        Some(filter) => &assignments[..filter],
+        None => assignments,
+    };
+
+    let mut operands = vec![];
+    for assignment in assignments.split(',') {
+        let mut sides = assignment.splitn(2, '=');
+        let column = sides.next().unwrap_or("").trim();
+        let value = sides.next().unwrap_or("").trim();
+        if column.is_empty() || !value.starts_with(column) {
+            return None;
+        }
+        let operation = value[column.len()..].trim_left();
+        if !operation.starts_with('+') && !operation.starts_with('-') {
+            return None;
+        }
+        operands.push(operation[1..].trim().to_string());
+    }
+    Some(operands)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+
+    use super::*;
+    use codec::HEADER_LEN;
+    use mock::{self, MockTransport, ERROR, RESULT, BATCH};
+
+    const INVALID: i32 = 0x2200;
+
+    fn push_short(bytes: &mut Vec<u8>, i: usize) {
+        bytes.extend_from_slice(&[(i >> 8) as u8, i as u8]);
+    }
+
+    fn push_int(bytes: &mut Vec<u8>, i: usize) {
+        bytes.extend_from_slice(&[(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+    }
+
+    fn batch_body(batch: QueryBatch) -> Vec<u8> {
+        Frame::new_req_batch(batch, vec![]).body
+    }
+
+    #[test]
+    fn mixes_simple_and_prepared_statements() {
+        let insert = "INSERT INTO users (id, name) VALUES (?, ?)";
+        let id = CBytesShort::new(vec![0xca, 0xfe]);
+        let batch = BatchBuilder::logged()
+            .add_query(insert, vec![Value::from(1), Value::from("alice".to_string())])
+            .add_prepared(&id, vec![Value::from(2)])
+            .consistency(Consistency::Quorum)
+            .timestamp(42)
+            .finalize()
+            .unwrap();
+
+        let mut expected = vec![0x00];
+        push_short(&mut expected, 2);
+        expected.push(0x00);
+        push_int(&mut expected, insert.len());
+        expected.extend_from_slice(insert.as_bytes());
+        push_short(&mut expected, 2);
+        push_int(&mut expected, 4);
+        expected.extend_from_slice(&[0, 0, 0, 1]);
+        push_int(&mut expected, 5);
+        expected.extend_from_slice(b"alice");
+        expected.push(0x01);
+        push_short(&mut expected, 2);
+        expected.extend_from_slice(&[0xca, 0xfe]);
+        push_short(&mut expected, 1);
+        push_int(&mut expected, 4);
+        expected.extend_from_slice(&[0, 0, 0, 2]);
+        // QUORUM, then a flag of the default timestamp and the timestamp
+        push_short(&mut expected, 0x0004);
+        expected.push(0x20);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 42]);
+
+        assert_eq!(batch_body(batch), expected);
+    }
+
+    #[test]
+    fn sets_batch_type_and_serial_consistency() {
+        let update = "UPDATE stats SET views = views + 1 WHERE id = 1";
+        let batch = BatchBuilder::counter()
+            .add_query(update, ())
+            .serial_consistency(Consistency::LocalSerial)
+            .finalize()
+            .unwrap();
+
+        let mut expected = vec![0x02];
+        push_short(&mut expected, 1);
+        expected.push(0x00);
+        push_int(&mut expected, update.len());
+        expected.extend_from_slice(update.as_bytes());
+        push_short(&mut expected, 0);
+        // ONE, then a flag of the serial consistency and LOCAL_SERIAL
+        push_short(&mut expected, 0x0001);
+        expected.push(0x10);
+        push_short(&mut expected, 0x0009);
+
+        assert_eq!(batch_body(batch), expected);
+
+        let unlogged = BatchBuilder::unlogged()
+            .add_query("DELETE FROM users WHERE id = 1", ())
+            .finalize()
+            .unwrap();
+        assert_eq!(batch_body(unlogged)[0], 0x01);
+    }
+
+    #[test]
+    fn refuses_to_mix_counter_updates() {
+        let counter = "UPDATE stats SET views = views + 1 WHERE id = 1";
+        let insert = "INSERT INTO users (id) VALUES (1)";
+        let is_counter_error = |result: error::Result<QueryBatch>| match result {
+            Err(error::Error::CounterBatch(_)) => true,
+            _ => false,
+        };
+
+        assert!(is_counter_error(BatchBuilder::logged().add_query(counter, ()).finalize()));
+        assert!(is_counter_error(BatchBuilder::counter().add_query(insert, ()).finalize()));
+        assert!(is_counter_error(BatchBuilder::counter()
+                                     .add_query(counter, ())
+                                     .add_query(insert, ())
+                                     .finalize()));
+        assert!(BatchBuilder::logged().finalize().is_err());
+
+        // collection updates are taken by logged batches, not by counter ones
+        for update in &["UPDATE users SET tags = tags + ['new'] WHERE id = 1",
+                        "UPDATE users SET tags = tags - {'old'} WHERE id = 1",
+                        "UPDATE users SET names = names + {1: 'x'} WHERE id = 1",
+                        "UPDATE users SET name = name + 'x' WHERE id = 1"] {
+            assert!(BatchBuilder::logged().add_query(*update, ()).finalize().is_ok());
+            assert!(is_counter_error(BatchBuilder::counter().add_query(*update, ()).finalize()));
+        }
+        // bound values may be counters or collections, so any batch takes them
+        let bound = "UPDATE users SET tags = tags + ? WHERE id = 1";
+        assert!(BatchBuilder::logged().add_query(bound, ()).finalize().is_ok());
+        assert!(BatchBuilder::counter().add_query(bound, ()).finalize().is_ok());
+        assert!(BatchBuilder::counter()
+                    .add_query(insert, ())
+                    .check_counters(false)
+                    .finalize()
+                    .is_ok());
+    }
+
+    #[test]
+    fn applies_counter_batch() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let batch = BatchBuilder::counter()
+            .add_query("UPDATE stats SET views = views + 1 WHERE id = 1", ())
+            .add_query("UPDATE stats SET likes = likes - ? WHERE id = 1", vec![Value::from(1i64)])
+            .finalize()
+            .unwrap();
+        mock::session(transport.clone()).apply_batch(batch).wait().unwrap();
+
+        let written = transport.written();
+        assert_eq!(mock::opcodes(&written), vec![BATCH]);
+        assert_eq!(written[HEADER_LEN], 0x02);
+    }
+
+    #[test]
+    fn server_rejection_of_mixed_batch_is_typed() {
+        let transport = MockTransport::new();
+        let rejection = "Cannot include non-counter statement in a counter batch";
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(INVALID, rejection)));
+        // other messages mentioning counters are not about mixing
+        let unknown_column = "Unknown identifier counter_total";
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(INVALID, unknown_column)));
+
+        // the check is off, so the batch reaches a server
+        let batch = || {
+            BatchBuilder::counter()
+                .add_query("UPDATE stats SET views = views + 1 WHERE id = 1", ())
+                .add_query("INSERT INTO users (id) VALUES (1)", ())
+                .check_counters(false)
+                .finalize()
+                .unwrap()
+        };
+        match mock::session(transport.clone()).apply_batch(batch()).wait() {
+            Err(error::Error::CounterBatch(reason)) => assert_eq!(reason, rejection),
+            other => panic!("CounterBatch expected, got {:?}", other.map(|_| ())),
+        }
+
+        match mock::session(transport.clone()).apply_batch(batch()).wait() {
+            Err(error::Error::Server { error: ServerError::Invalid, message }) => {
+                assert_eq!(message, unknown_column)
+            }
+            other => panic!("server error expected, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn tells_counter_updates() {
+        assert!(is_counter_update("UPDATE stats SET views = views + 1 WHERE id = ?"));
+        assert!(is_counter_update("update stats set a = a - 1, b = b + -2 where id = ?"));
+        assert!(!is_counter_update("UPDATE stats SET a = a - ?, b = b + :b WHERE id = ?"));
+        assert!(may_be_counter_update("UPDATE stats SET a = a - ?, b = b + :b WHERE id = ?"));
+        assert!(!is_counter_update("UPDATE users SET tags = tags + ['a', 'b'] WHERE id = ?"));
+        assert!(!may_be_counter_update("UPDATE users SET tags = tags + ['a'] WHERE id = ?"));
+        assert!(!is_counter_update("UPDATE users SET name = ? WHERE id = ?"));
+        assert!(!is_counter_update("UPDATE stats SET a = a + 1, name = 'x' WHERE id = ?"));
+        assert!(!is_counter_update("INSERT INTO stats (id, views) VALUES (1, 1)"));
+        assert!(!is_counter_update("DELETE FROM stats WHERE id = 1"));
+    }
+}
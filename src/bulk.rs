@@ -0,0 +1,367 @@
+//! Bulk executions of a prepared statement with bounded concurrency.
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use cdrs::authenticators::Authenticator;
+use cdrs::frame::Frame;
+use cdrs::query::QueryParams;
+use cdrs::transport::CDRSTransport;
+use cdrs::types::CBytesShort;
+use futures::future::{self, Future, Loop};
+use futures::stream::Stream;
+
+use client::{CDRSFuture, Session};
+use codec;
+use paging::Page;
+use prepared::TypedPrepared;
+use rows::TryFromRow;
+use values::IntoQueryValues;
+use error;
+
+/// What a writer does when an item fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OnFailure {
+    /// Keep executing other items.
+    Continue,
+    /// Stop pulling items. Executions which are in flight are completed and
+    /// counted in a summary.
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BulkOptions {
+    /// Max number of executions in flight. It's also limited by a number of sessions.
+    pub concurrency: usize,
+    pub on_failure: OnFailure,
+    /// How many times a failed item is executed again before it's reported.
+    pub retries: usize,
+    /// Max number of failed items kept in a summary along with their errors.
+    pub max_reported_failures: usize,
+}
+
+impl Default for BulkOptions {
+    fn default() -> BulkOptions {
+        BulkOptions {
+            concurrency: 16,
+            on_failure: OnFailure::Continue,
+            retries: 0,
+            max_reported_failures: 100,
+        }
+    }
+}
+
+/// An item which failed after all retries.
+#[derive(Debug)]
+pub struct FailedItem {
+    /// Position of the item in the input stream.
+    pub index: usize,
+    pub error: error::Error,
+}
+
+#[derive(Debug)]
+pub struct BulkSummary {
+    pub succeeded: usize,
+    /// Number of failed items, it may be bigger than `failures.len()`.
+    pub failed: usize,
+    /// The first failed items, up to `BulkOptions::max_reported_failures`.
+    pub failures: Vec<FailedItem>,
+    /// `true` if execution was stopped because of a failure.
+    pub aborted: bool,
+    /// The highest number of executions which were in flight at once.
+    pub max_in_flight: usize,
+    pub elapsed: Duration,
+}
+
+impl BulkSummary {
+    /// Number of completed items, either succeeded or failed, per second.
+    pub fn items_per_second(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 * 1e-9;
+        if secs == 0.0 {
+            0.0
+        } else {
+            (self.succeeded + self.failed) as f64 / secs
+        }
+    }
+}
+
+/// Executes a prepared statement for every item of a stream.
+///
+/// A session handles one request at a time, so executions are spread across
+/// given sessions and concurrency never exceeds their number.
+pub struct Writer<T: Authenticator + 'static, X: CDRSTransport + 'static> {
+    sessions: Vec<Session<T, X>>,
+    options: BulkOptions,
+}
+
+impl<T, X> Writer<T, X>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    pub fn new(sessions: Vec<Session<T, X>>) -> Writer<T, X> {
+        Writer {
+            sessions: sessions,
+            options: BulkOptions::default(),
+        }
+    }
+
+    /// The method overrides options of the writer.
+    pub fn options(&mut self, options: BulkOptions) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// Executes `prepared` for each item of `items` and resolves into a summary once
+    /// all of them are done, or once executions in flight are done after an abort.
+    /// The future fails only if `items` fails.
+    pub fn execute<P, R, S>(self,
+                            prepared: TypedPrepared<P, R>,
+                            items: S)
+                            -> CDRSFuture<BulkSummary>
+        where P: IntoQueryValues + Send + 'static,
+              R: TryFromRow + 'static,
+              S: Stream<Item = P, Error = error::Error> + Send + 'static
+    {
+        let options = self.options;
+        let concurrency = cmp::max(cmp::min(options.concurrency, self.sessions.len()), 1);
+        let sessions = Arc::new(Mutex::new(self.sessions));
+        let prepared = Arc::new(prepared);
+        let in_flight = Arc::new(Mutex::new(InFlight::default()));
+        let started = Instant::now();
+
+        let summary = BulkSummary {
+            succeeded: 0,
+            failed: 0,
+            failures: vec![],
+            aborted: false,
+            max_in_flight: 0,
+            elapsed: Duration::from_secs(0),
+        };
+
+        let mut next_index = 0;
+        let tracker = in_flight.clone();
+        let aborted = Arc::new(AtomicBool::new(false));
+        let abort = aborted.clone();
+        items.take_while(move |_| Ok(!aborted.load(Ordering::SeqCst)))
+            .map(move |params| {
+                let index = next_index;
+                next_index += 1;
+                tracker.lock().unwrap().start();
+
+                let query_parameters = prepared.query_parameters(params);
+                let item = execute_item(sessions.clone(),
+                                        prepared.id().clone(),
+                                        query_parameters,
+                                        options.retries);
+                let tracker = tracker.clone();
+                item.then(move |result| {
+                    tracker.lock().unwrap().finish();
+                    Ok((index, result.and_then(|result| result)))
+                })
+            })
+            .buffer_unordered(concurrency)
+            .fold(summary, move |mut summary, (index, result)| {
+                match result {
+                    Ok(()) => summary.succeeded += 1,
+                    Err(err) => {
+                        summary.failed += 1;
+                        if summary.failures.len() < options.max_reported_failures {
+                            summary.failures.push(FailedItem {
+                                                      index: index,
+                                                      error: err,
+                                                  });
+                        }
+                        // no more items are pulled, executions in flight still
+                        // come through
+                        if options.on_failure == OnFailure::Abort {
+                            summary.aborted = true;
+                            abort.store(true, Ordering::SeqCst);
+                        }
+                    }
+                }
+                Ok::<_, error::Error>(summary)
+            })
+            .map(move |mut summary| {
+                summary.max_in_flight = in_flight.lock().unwrap().max;
+                summary.elapsed = started.elapsed();
+                summary
+            })
+            .boxed()
+    }
+}
+
+#[derive(Debug, Default)]
+struct InFlight {
+    current: usize,
+    max: usize,
+}
+
+impl InFlight {
+    fn start(&mut self) {
+        self.current += 1;
+        self.max = cmp::max(self.max, self.current);
+    }
+
+    fn finish(&mut self) {
+        self.current -= 1;
+    }
+}
+
+/// Executes the statement `id` on an idle session retrying it up to `retries` times.
+/// Defaults of the session are applied once, so every attempt of the item is sent
+/// with the same timestamp. The session is returned to the idle ones whatever
+/// the result is.
+fn execute_item<T, X>(sessions: Arc<Mutex<Vec<Session<T, X>>>>,
+                      id: CBytesShort,
+                      query_parameters: error::Result<QueryParams>,
+                      retries: usize)
+                      -> CDRSFuture<error::Result<()>>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    let query_parameters = match query_parameters {
+        Ok(query_parameters) => query_parameters,
+        Err(err) => return future::ok(Err(err)).boxed(),
+    };
+    let session = sessions.lock()
+        .unwrap()
+        .pop()
+        .expect("there is an idle session for every execution in flight");
+    let query_parameters = session.with_defaults(query_parameters.into());
+    let frame = Frame::new_req_execute(&id, query_parameters, vec![]);
+
+    future::loop_fn((session, 0), move |(session, attempt)| {
+        let sessions = sessions.clone();
+        session.try_request(codec::clone_frame(&frame)).map(move |(session, result)| {
+            let result = result.and_then(|frame| Page::from_frame(frame).map(|_| ()));
+            match result {
+                Err(_) if attempt < retries => Loop::Continue((session, attempt + 1)),
+                result => {
+                    sessions.lock().unwrap().push(session);
+                    Loop::Break(result)
+                }
+            }
+        })
+    })
+            .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, Future};
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::types::rows::Row;
+
+    use super::*;
+    use client::{CDRS, Session};
+    use mock::{self, MockTransport, ERROR, RESULT};
+
+    /// Sessions which share one transport, so scripted responses are consumed
+    /// in order of requests whichever session sends them.
+    fn sessions(transport: &MockTransport,
+                n: usize)
+                -> Vec<Session<NoneAuthenticator, MockTransport>> {
+        (0..n)
+            .map(|_| {
+                     let cdrs = CDRS::new(transport.clone(), NoneAuthenticator);
+                     Session::start(cdrs)
+                 })
+            .collect()
+    }
+
+    fn prepared(transport: &MockTransport) -> TypedPrepared<(i32,), Row> {
+        let columns: [(&str, u16); 0] = [];
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::prepared_body(b"insert",
+                                                                &[("id", mock::INT)],
+                                                                &columns)));
+        sessions(transport, 1)
+            .pop()
+            .unwrap()
+            .prepare_typed_as("INSERT INTO t (id) VALUES (?)".to_string())
+            .wait()
+            .unwrap()
+            .1
+    }
+
+    fn script(transport: &MockTransport, n: usize, failing: &[usize]) {
+        for i in 0..n {
+            if failing.contains(&i) {
+                transport.push_read(mock::response(ERROR, 0, &mock::error_body(0x2200, "nope")));
+            } else {
+                transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+            }
+        }
+    }
+
+    fn items(n: i32) -> stream::BoxStream<(i32,), error::Error> {
+        stream::iter((0..n).map(|i| Ok((i,)))).boxed()
+    }
+
+    #[test]
+    fn reports_failures_and_bounds_concurrency() {
+        let transport = MockTransport::new();
+        let prepared = prepared(&transport);
+        script(&transport, 1000, &[10, 200, 201, 500, 750, 998, 999]);
+
+        let mut writer = Writer::new(sessions(&transport, 4));
+        writer.options(BulkOptions {
+                           concurrency: 3,
+                           on_failure: OnFailure::Continue,
+                           retries: 0,
+                           max_reported_failures: 5,
+                       });
+        let summary = writer.execute(prepared, items(1000)).wait().unwrap();
+
+        assert_eq!(summary.succeeded, 993);
+        assert_eq!(summary.failed, 7);
+        assert_eq!(summary.failures.len(), 5);
+        assert!(!summary.aborted);
+        assert!(summary.max_in_flight > 1 && summary.max_in_flight <= 3);
+    }
+
+    #[test]
+    fn aborts_on_first_failure() {
+        let transport = MockTransport::new();
+        let prepared = prepared(&transport);
+        script(&transport, 3, &[2]);
+
+        let mut writer = Writer::new(sessions(&transport, 1));
+        writer.options(BulkOptions {
+                           on_failure: OnFailure::Abort,
+                           ..BulkOptions::default()
+                       });
+        let summary = writer.execute(prepared, items(10)).wait().unwrap();
+
+        assert!(summary.aborted);
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.failures[0].index, 2);
+    }
+
+    #[test]
+    fn abort_completes_executions_in_flight() {
+        let transport = MockTransport::new();
+        let prepared = prepared(&transport);
+        let prepare_len = transport.written().len();
+        script(&transport, 10, &[1]);
+
+        let mut writer = Writer::new(sessions(&transport, 3));
+        writer.options(BulkOptions {
+                           concurrency: 3,
+                           on_failure: OnFailure::Abort,
+                           ..BulkOptions::default()
+                       });
+        let summary = writer.execute(prepared, items(10)).wait().unwrap();
+
+        assert!(summary.aborted);
+        assert_eq!(summary.failed, 1);
+        // every execution which was sent is counted
+        let executed = mock::opcodes(&transport.written()[prepare_len..]).len();
+        assert_eq!(summary.succeeded + summary.failed, executed);
+        assert!(executed < 10);
+    }
+}
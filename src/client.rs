@@ -1,33 +1,83 @@
+use std::cmp;
+use std::fmt;
 use std::io;
 use std::net;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures::{Async, Poll};
 use futures::future;
-use futures::future::Future;
+use futures::future::{Future, Loop};
+use futures::stream::{self, Stream};
 
 use cdrs::IntoBytes;
-use cdrs::types::CBytesShort;
-use cdrs::frame::{Frame, Opcode, Flag};
-use cdrs::query::{Query, QueryParams, QueryBatch};
+use cdrs::types::{CBytesShort, CStringLong, to_bigint, to_int, to_short};
+use cdrs::types::IntoRustByName;
+use cdrs::types::rows::Row;
+use cdrs::types::value::Value;
+use cdrs::frame::{Frame, Opcode, Flag, Version};
+use cdrs::frame::frame_query::QueryFlags;
+use cdrs::query::{Query, QueryBuilder, QueryParams, QueryParamsBuilder, QueryBatch};
 use cdrs::frame::frame_response::ResponseBody;
-use cdrs::frame::events::SimpleServerEvent;
+use cdrs::frame::frame_result::ResResultBody;
+use cdrs::frame::events::{ServerEvent, SimpleServerEvent};
 use cdrs::authenticators::Authenticator;
 use cdrs::compression::Compression;
-use cdrs::frame::parser::parse_frame;
-use cdrs::error;
+use cdrs::consistency::Consistency;
 use cdrs::events::{Listener, EventStream, new_listener};
 use cdrs::transport::CDRSTransport;
+use tokio_timer::Delay;
+use uuid::Uuid;
+use zeroize::Zeroize;
+
+use auth::{self, SaslAuthenticator};
+use batch;
+use codec::{self, CompressionStats, CustomPayload, EVENT_STREAM_ID, Expectation, FrameDecoder,
+            FrameEncoder};
+use csv::{self, CsvOptions};
+use decode::{self, DecodeExecutor};
+use frame_io::{FrameWriter, WriteOptions};
+use handshake;
+use insert::{self, BatchLwtResult, CasResult, InsertOptions};
+use metrics::{RequestToken, SharedObserver};
+use multiplex::{self, Dispatcher, Multiplexer};
+use paging::{Page, PageSizing, PagingState};
+use prepared::{self, PreparedCache, PreparedCaches, PreparedStatement, StatementId,
+               TypedPrepared};
+use request::{Consistent, DebugQuery, Override, RequestOptions, Statement};
+use response::{QueryResponse, WarningsHandler};
+use retry::{self, DefaultRetryPolicy, RetryDecision, RetryPolicy};
+use rows::{self, FromRow, TryFromRow};
+use scan::{self, ScanQuery, TokenRange};
+use script::{self, OnError, ScriptOptions, StatementOutcome};
+use schema::{self, SchemaColumn, TableMetadata};
+use scylla::ShardInfo;
+use supported::SupportedOptions;
+use timestamp::{self, NoTimestampGenerator, TimestampGenerator, Timestamped};
+use tracing::{self, TracingInfo};
+use values::{self, Columns, IntoQueryValues, QueryValues};
+use error;
 
 pub type CassandraOptions = HashMap<String, Vec<String>>;
+
+/// Max number of rows `Session::query_all` keeps in memory by default.
+pub const DEFAULT_MAX_ROWS: usize = 100000;
+/// How long `Session::await_schema_agreement_within` waits between checks by default.
+pub const DEFAULT_SCHEMA_AGREEMENT_INTERVAL_MS: u64 = 200;
 pub type CDRSFuture<T> = future::BoxFuture<T, error::Error>;
+pub type CDRSStream<T> = stream::BoxStream<T, error::Error>;
 
 #[derive(Eq,PartialEq,Ord,PartialOrd)]
 pub struct CDRS<T: Authenticator, X> {
     compressor: Compression,
     authenticator: T,
     transport: X,
+    encoder: FrameEncoder,
+    writer: FrameWriter,
+    decoder: FrameDecoder,
 }
 
-impl<'a, T: Authenticator + 'a, X: CDRSTransport + 'a> CDRS<T, X> {
+impl<T: Authenticator, X: CDRSTransport> CDRS<T, X> {
     pub fn new(transport: X, authenticator: T) -> CDRS<T, X>
         where T: Send
     {
@@ -35,137 +85,571 @@ impl<'a, T: Authenticator + 'a, X: CDRSTransport + 'a> CDRS<T, X> {
             compressor: Compression::None,
             authenticator: authenticator,
             transport: transport,
+            encoder: FrameEncoder::default(),
+            writer: FrameWriter::new(),
+            decoder: FrameDecoder::new(),
         }
     }
 
-    pub fn get_options(&'static mut self) -> CDRSFuture<CassandraOptions>
-        where T: Send
+    /// Overrides how request frames are written: chunk size, flush policy
+    /// and a timeout of a single frame write.
+    pub fn write_options(&mut self, options: WriteOptions) -> &mut Self {
+        self.writer.set_options(options);
+        self
+    }
+
+    /// Sets an address of the server, it's reported by errors about corrupted frames.
+    pub fn peer_addr(&mut self, addr: net::SocketAddr) -> &mut Self {
+        self.decoder.set_peer(addr);
+        self
+    }
+
+    /// Sets a minimal size of a request frame body which gets compressed.
+    /// Smaller frames are sent uncompressed even if compression was negotiated.
+    pub fn compression_min_size(&mut self, min_size: usize) -> &mut Self {
+        self.encoder.set_min_size(min_size);
+        self
+    }
+
+    /// Sets a max length of a request frame body, it should match the server's
+    /// `native_transport_max_frame_size`. Longer frames fail with `FrameTooLarge`
+    /// before a single byte of them is written. It's `codec::MAX_BODY_LEN` by default.
+    pub fn max_body_len(&mut self, max_body_len: usize) -> &mut Self {
+        self.encoder.set_max_body_len(max_body_len);
+        self
+    }
+
+    /// Works as `supported` on an instance with a compression already chosen.
+    pub fn get_options(self) -> CDRSFuture<(CDRS<T, X>, SupportedOptions)>
+        where T: Send + 'static,
+              X: 'static
     {
-        let options_frame = Frame::new_req_options().into_cbytes();
+        let options_frame = Frame::new_req_options();
+        let compressor = self.compressor;
+        let expectation = Expectation::response_to(&options_frame, &compressor);
+        let mut cdrs = self;
 
-        future::result(self.transport.write(options_frame.as_slice()))
-            .map_err(Into::into)
-            .and_then(move |_| {
-                          parse_frame(&mut self.transport, &self.compressor)
-                              .and_then(resolve_supported_ops)
-                      })
+        if let Err(err) = cdrs.queue_frame(options_frame, &compressor) {
+            return future::err(err).boxed();
+        }
+
+        cdrs.read_response(compressor, expectation)
+            .and_then(|(cdrs, frame)| resolve_supported_ops(frame).map(|options| (cdrs, options)))
             .boxed()
     }
 
-    pub fn start(mut self, compressor: Compression) -> CDRSFuture<Session<T, X>>
+    /// Asks a server which options it supports, e.g. compression algorithms,
+    /// before a connection is started.
+    pub fn supported(self) -> CDRSFuture<(CDRS<T, X>, SupportedOptions)>
         where T: Send + 'static,
               X: 'static
+    {
+        self.get_options()
+    }
+
+    /// Performs a handshake: STARTUP and authentication if a server requires it.
+    /// It waits for a server as long as it takes, see `handshake::start`
+    /// for a bounded one.
+    pub fn start(mut self, compressor: Compression) -> CDRSFuture<Session<T, X>>
+        where T: SaslAuthenticator + Send + 'static,
+              X: 'static
     {
         self.compressor = compressor;
-        let startup_frame = Frame::new_req_startup(compressor.as_str()).into_cbytes();
+        let startup_frame = Frame::new_req_startup(compressor.as_str());
+        let expectation = Expectation::response_to(&startup_frame, &compressor);
 
-        future::result(self.transport.write(startup_frame.as_slice()))
-            .map_err(Into::into)
-            .and_then(move |_| {
-                let start_response = try!(parse_frame(&mut self.transport, &compressor));
+        self.queue_request(startup_frame.into_cbytes());
 
+        self.read_response(compressor, expectation)
+            .and_then(move |(cdrs, start_response)| {
                 if start_response.opcode == Opcode::Ready {
-                    return Ok(Session::start(self));
+                    return future::ok(Session::start(cdrs)).boxed();
                 }
 
                 if start_response.opcode == Opcode::Authenticate {
-                    let body = start_response.get_body()?;
-                    let authenticator =
-                        body.get_authenticator()
-                            .expect("Cassandra Server did communicate that it needed password
-                        authentication but the  auth schema was missing in the body response");
-
-                    // This creates a new scope; avoiding a clone
-                    // and we check whether
-                    // 1. any authenticators has been passed in by client and if not send error back
-                    // 2. authenticator is provided by the client and `auth_scheme` presented by
-                    //      the server and client are same if not send error back
-                    // 3. if it falls through it means the preliminary conditions are true
-
-                    let auth_check = self.authenticator
-                        .get_cassandra_name()
-                        .ok_or(error::Error::General("No authenticator was provided".to_string()))
-                        .map(|auth| {
-                            if authenticator != auth {
-                                let io_err =
-                                    io::Error::new(io::ErrorKind::NotFound,
-                                                   format!("Unsupported type of authenticator. {:?} got,
-                                     but {} is supported.",
-                                                           authenticator,
-                                                           authenticator));
-                                return Err(error::Error::Io(io_err));
-                            }
-                            Ok(())
-                        });
+                    let body = match start_response.get_body() {
+                        Ok(body) => body,
+                        Err(err) => return future::err(err.into()).boxed(),
+                    };
+                    let authenticator = match body.get_authenticator() {
+                        Some(authenticator) => authenticator,
+                        None => {
+                            let reason = "AUTHENTICATE response names no authenticator";
+                            return future::err(error::Error::General(reason.to_string())).boxed();
+                        }
+                    };
 
-                    if let Err(err) = auth_check {
-                        return Err(err);
+                    if let Err(err) = auth::verify_authenticator(authenticator,
+                                                                 &cdrs.authenticator) {
+                        return future::err(err).boxed();
                     }
 
-                    let auth_token_bytes = self.authenticator.get_auth_token().into_cbytes();
-                    try!(self.transport
-                             .write(Frame::new_req_auth_response(auth_token_bytes)
-                                        .into_cbytes()
-                                        .as_slice()));
-                    try!(parse_frame(&mut self.transport, &compressor));
-
-                    return Ok(Session::start(self));
+                    // the serialized token is wiped by `authenticate`, the token itself here
+                    let token = cdrs.authenticator.get_auth_token();
+                    let auth_token_bytes = token.into_cbytes();
+                    token.into_plain().zeroize();
+                    return cdrs.authenticate(auth_token_bytes, compressor);
                 }
 
-                unimplemented!();
-            }).boxed()
+                // e.g. an ERROR about a compression or a protocol version the server
+                // doesn't support
+                future::err(unexpected_response("STARTUP", start_response)).boxed()
+            })
+            .boxed()
+    }
+
+    /// Works as `start` and makes `keyspace` the default one of the session,
+    /// see `Session::use_keyspace`. The session fails to start if it can't be used.
+    pub fn start_with_keyspace(self,
+                               compressor: Compression,
+                               keyspace: &str)
+                               -> CDRSFuture<Session<T, X>>
+        where T: SaslAuthenticator + Send + 'static,
+              X: 'static
+    {
+        if let Err(err) = schema::validate_keyspace_name(keyspace) {
+            return future::err(err).boxed();
+        }
+
+        let keyspace = keyspace.to_string();
+        self.start(compressor)
+            .and_then(move |session| session.use_keyspace(&keyspace))
+            .boxed()
+    }
+
+    /// Performs a handshake with the best compression a server supports:
+    /// lz4, then snappy, then none. It costs an OPTIONS round trip, which
+    /// detects a shard of a Scylla node as well, see `Session::detect_shard`.
+    pub fn start_negotiated(self) -> CDRSFuture<Session<T, X>>
+        where T: SaslAuthenticator + Send + 'static,
+              X: 'static
+    {
+        self.supported()
+            .and_then(|(cdrs, supported)| {
+                          let compressor = try!(handshake::choose_compression(Compression::Lz4,
+                                                                              &supported,
+                                                                              true));
+                          Ok((cdrs, compressor, ShardInfo::from_options(supported.raw())))
+                      })
+            .and_then(|(cdrs, compressor, shard_info)| {
+                          cdrs.start(compressor).map(move |mut session| {
+                                                         session.shard_info = shard_info;
+                                                         session
+                                                     })
+                      })
+            .boxed()
+    }
+
+    /// Sends AUTH_RESPONSE with a serialized token and answers challenges
+    /// of a server until it accepts the client with AUTH_SUCCESS.
+    fn authenticate(self,
+                    token_bytes: Vec<u8>,
+                    compressor: Compression)
+                    -> CDRSFuture<Session<T, X>>
+        where T: SaslAuthenticator + Send + 'static,
+              X: 'static
+    {
+        future::loop_fn((self, token_bytes), move |(mut cdrs, token_bytes)| {
+            // the token is wiped from the frame right away and from the write
+            // buffer once it's sent, credentials kept by the authenticator are
+            // out of reach
+            let mut auth_frame = Frame::new_req_auth_response(token_bytes);
+            let expectation = Expectation::response_to(&auth_frame, &compressor);
+            cdrs.writer.push_sensitive(auth_frame.into_cbytes());
+            auth_frame.body.zeroize();
+
+            cdrs.read_response(compressor, expectation)
+                .and_then(|(mut cdrs, response)| match response.opcode {
+                              Opcode::AuthSuccess => Ok(Loop::Break(Session::start(cdrs))),
+                              Opcode::AuthChallenge => {
+                                  let challenge = try!(auth::read_token(&response.body));
+                                  let mut token = try!(cdrs.authenticator
+                                      .evaluate_challenge(challenge.as_ref().map(|c| &c[..])));
+                                  let token_bytes = auth::token_bytes(&token);
+                                  token.zeroize();
+                                  Ok(Loop::Continue((cdrs, token_bytes)))
+                              }
+                              // e.g. an ERROR about wrong credentials
+                              _ => Err(unexpected_response("AUTH_RESPONSE", response)),
+                          })
+                .boxed()
+        })
+                .boxed()
+    }
+
+    /// Turns the instance into a future which resolves into a next response frame
+    /// along with the instance itself, so several round trips could be chained.
+    fn read_response(self,
+                     compressor: Compression,
+                     expectation: Expectation)
+                     -> CDRSFuture<(CDRS<T, X>, Frame)>
+        where T: Send + 'static,
+              X: 'static
+    {
+        let mut cdrs = Some(self);
+
+        future::poll_fn(move || {
+                let frame = {
+                    let cdrs = cdrs.as_mut().expect("response frame has been read already");
+                    try_ready!(cdrs.poll_response(&compressor, &expectation))
+                };
+
+                Ok(Async::Ready((cdrs.take().unwrap(), frame)))
+            })
+            .boxed()
+    }
+
+    /// Queues request bytes to be written by a next `poll_response`.
+    fn queue_request(&mut self, bytes: Vec<u8>) {
+        self.writer.push(bytes);
+    }
+
+    /// Encodes a request frame, compressing it if needed, and queues it.
+    fn queue_frame(&mut self, frame: Frame, compressor: &Compression) -> error::Result<()> {
+        self.queue_frame_with(frame, compressor, Override::Auto)
+    }
+
+    /// Works as `queue_frame` overriding whether the frame is compressed.
+    pub fn queue_frame_with(&mut self,
+                            frame: Frame,
+                            compressor: &Compression,
+                            compression: Override)
+                            -> error::Result<()> {
+        let bytes = try!(self.encoder.encode_with(frame, compressor, compression));
+        self.queue_request(bytes);
+        Ok(())
+    }
+
+    /// Writes queued requests and polls a response frame. Partially written request
+    /// and partially read frame are kept between polls.
+    /// A connection is closed if the frame breaks the protocol or is corrupted
+    /// as it's impossible to find where the next frame starts.
+    pub fn poll_response(&mut self,
+                         compressor: &Compression,
+                         expectation: &Expectation)
+                         -> Poll<Frame, error::Error> {
+        try_ready!(self.writer.poll_write(&mut self.transport));
+
+        let result = self.decoder.poll_frame(&mut self.transport, compressor, expectation);
+
+        if let Err(ref err) = result {
+            if err.breaks_connection() {
+                let _ = self.drop_connection();
+            }
+        }
+
+        result
+    }
+
+    /// Number of bytes written to and read from the transport.
+    pub fn bytes_transferred(&self) -> (u64, u64) {
+        (self.writer.bytes_written(), self.decoder.bytes_read())
     }
 
-    fn drop_connection(&mut self) -> error::Result<()> {
+    /// Takes a custom payload of a response which was read last, see
+    /// `FrameDecoder::take_custom_payload`.
+    pub fn take_custom_payload(&mut self) -> CustomPayload {
+        self.decoder.take_custom_payload()
+    }
+
+    /// Closes the connection, it's left to a server to finish requests in flight.
+    pub fn drop_connection(&mut self) -> error::Result<()> {
         self.transport
             .close(net::Shutdown::Both)
-            .map_err(|err| error::Error::Io(err))
+            .map_err(error::Error::Io)
+    }
+}
+
+impl<T: Authenticator, X> fmt::Debug for CDRS<T, X> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // an authenticator holds credentials, so only its scheme is shown
+        f.debug_struct("CDRS")
+            .field("compressor", &self.compressor)
+            .field("authenticator", &self.authenticator.get_cassandra_name())
+            .field("encoder", &self.encoder)
+            .field("writer", &self.writer)
+            .finish()
     }
 }
 
-pub struct Session<T: Authenticator, X> {
+/// A connection which serves one request at a time. Requests take a session by
+/// value and give it back along with their results, so a request future dropped
+/// before its response, e.g. one which lost a race against a timeout, drops
+/// the session too and closes its connection. A late response is never read
+/// as a response to another request.
+pub struct Session<T: Authenticator, X: CDRSTransport> {
     started: bool,
-    cdrs: CDRS<T, X>,
+    /// It's taken only by `listen_for` which turns a connection into a listener.
+    cdrs: Option<CDRS<T, X>>,
     compressor: Compression,
+    page_sizing: PageSizing,
+    max_rows: usize,
+    prepared_cache: Arc<Mutex<PreparedCache>>,
+    redact_statements: bool,
+    next_stream: i16,
+    decode_executor: Option<DecodeExecutor>,
+    retry_policy: Arc<RetryPolicy + Send + Sync>,
+    timestamp_generator: Arc<TimestampGenerator + Send + Sync>,
+    request_timeout: Option<Duration>,
+    schema_agreement_interval: Duration,
+    keyspace: Option<String>,
+    warnings_handler: Option<WarningsHandler>,
+    default_consistency: Option<Consistency>,
+    default_serial_consistency: Option<Consistency>,
+    metrics_observer: Option<SharedObserver>,
+    shard_info: Option<ShardInfo>,
+}
+
+impl<T: Authenticator, X: CDRSTransport> fmt::Debug for Session<T, X> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prepared = self.prepared_cache.try_lock().map(|cache| cache.len()).ok();
+        f.debug_struct("Session")
+            .field("started", &self.started)
+            .field("cdrs", &self.cdrs)
+            .field("compressor", &self.compressor)
+            .field("keyspace", &self.keyspace)
+            .field("page_sizing", &self.page_sizing)
+            .field("max_rows", &self.max_rows)
+            .field("prepared_statements", &prepared)
+            .field("redact_statements", &self.redact_statements)
+            .field("offloads_decoding", &self.decode_executor.is_some())
+            .field("handles_warnings", &self.warnings_handler.is_some())
+            .field("observed", &self.metrics_observer.is_some())
+            .field("shard_info", &self.shard_info)
+            .finish()
+    }
 }
 
 impl<T: Authenticator + 'static, X: CDRSTransport + 'static> Session<T, X> {
     /// Creates new session basing on CDRS instance.
     pub fn start(cdrs: CDRS<T, X>) -> Session<T, X> {
-        let compressor = cdrs.compressor.clone();
+        let compressor = cdrs.compressor;
         Session {
-            cdrs: cdrs,
+            cdrs: Some(cdrs),
             started: true,
             compressor: compressor,
+            page_sizing: PageSizing::default(),
+            max_rows: DEFAULT_MAX_ROWS,
+            prepared_cache: Arc::new(Mutex::new(PreparedCache::new())),
+            redact_statements: true,
+            next_stream: 0,
+            decode_executor: None,
+            retry_policy: Arc::new(DefaultRetryPolicy::default()),
+            timestamp_generator: Arc::new(NoTimestampGenerator),
+            request_timeout: None,
+            schema_agreement_interval: Duration::from_millis(DEFAULT_SCHEMA_AGREEMENT_INTERVAL_MS),
+            keyspace: None,
+            warnings_handler: None,
+            default_consistency: None,
+            default_serial_consistency: None,
+            metrics_observer: None,
+            shard_info: None,
         }
     }
 
+    /// The method sets how long requests wait for a response before they fail
+    /// with `Error::RequestTimeout`, which closes the connection. Requests wait
+    /// as long as it takes by default. `RequestOptions::timeout` overrides it.
+    pub fn request_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// The method sets how long `await_schema_agreement_within` waits between
+    /// checks of schema versions.
+    pub fn schema_agreement_interval(&mut self, interval: Duration) -> &mut Self {
+        self.schema_agreement_interval = interval;
+        self
+    }
+
+    /// The method sets a handler which is called with warnings of every response
+    /// which has them, responses with server errors included. Warnings come only
+    /// if a request asks for them, see `RequestOptions::warnings`.
+    pub fn on_warnings<F>(&mut self, handler: F) -> &mut Self
+        where F: Fn(&[String]) + Send + Sync + 'static
+    {
+        self.warnings_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// The method sets consistency of queries, executions and batches which leave
+    /// it to the session, see `Statement::new`. Requests keep their own
+    /// consistency otherwise.
+    pub fn set_default_consistency(&mut self, consistency: Consistency) -> &mut Self {
+        self.default_consistency = Some(consistency);
+        self
+    }
+
+    /// The method sets serial consistency of queries, executions and batches
+    /// which don't set one themselves.
+    pub fn set_default_serial_consistency(&mut self, serial_consistency: Consistency) -> &mut Self {
+        self.default_serial_consistency = Some(serial_consistency);
+        self
+    }
+
+    /// The method sets a generator of timestamps of queries, executions and batches
+    /// which don't set one themselves. It's `NoTimestampGenerator` by default,
+    /// which leaves timestamps to servers.
+    pub fn timestamp_generator<G>(&mut self, generator: G) -> &mut Self
+        where G: TimestampGenerator + Send + Sync + 'static
+    {
+        self.timestamp_generator = Arc::new(generator);
+        self
+    }
+
+    /// Applies default consistency levels and the timestamp generator of the
+    /// session to `statement`.
+    pub fn with_defaults<R: Consistent + Timestamped>(&self, statement: Statement<R>) -> R {
+        let mut request = statement.resolve(self.default_consistency.as_ref(),
+                                            self.default_serial_consistency.as_ref());
+        timestamp::apply(&*self.timestamp_generator, &mut request);
+        request
+    }
+
+    /// The method sets an observer of every request of the session, which may be
+    /// shared with other sessions. Requests are not measured without an observer.
+    pub fn metrics_observer(&mut self, observer: SharedObserver) -> &mut Self {
+        self.metrics_observer = Some(observer);
+        self
+    }
+
+    /// The method sets a policy which decides whether queries, executions and
+    /// batches failed with transient server errors are sent again.
+    /// It's `DefaultRetryPolicy` by default.
+    pub fn retry_policy<P>(&mut self, policy: P) -> &mut Self
+        where P: RetryPolicy + Send + Sync + 'static
+    {
+        self.retry_policy = Arc::new(policy);
+        self
+    }
+
+    /// The method sets whether bound values of statements are hidden when they're
+    /// formatted for logs and error context by `debug_query`. It's on by default.
+    pub fn redact_statements(&mut self, redact_statements: bool) -> &mut Self {
+        self.redact_statements = redact_statements;
+        self
+    }
+
+    /// Formats `query` for logs according to the session's redaction setting.
+    pub fn debug_query<'q>(&self, query: &'q Query) -> DebugQuery<'q> {
+        DebugQuery::new(query).redact_values(self.redact_statements)
+    }
+
+    /// Keyspace unqualified table names refer to, as set by `use_keyspace`.
+    pub fn current_keyspace(&self) -> Option<&str> {
+        self.keyspace.as_deref()
+    }
+
+    /// Numbers of request frames sent with and without compression.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.cdrs.as_ref().map(|cdrs| cdrs.encoder.stats()).unwrap_or_default()
+    }
+
     /// The method overrides a compression method of current session
     pub fn compressor(&mut self, compressor: Compression) -> &mut Self {
         self.compressor = compressor;
         self
     }
 
-    /// Manually ends current session.
-    /// Apart of that session will be ended automatically when the instance is dropped.
-    pub fn end(&mut self) {
-        if self.started {
-            self.started = false;
-            match self.cdrs.drop_connection() {
-                Ok(_) => (),
-                Err(err) => {
-                    println!("Error occured during dropping CDRS {:?}", err);
-                }
-            }
+    /// The method overrides how many rows are requested per page by paged queries.
+    /// `PageSizing::Adaptive` adjusts the page size after every page to fit
+    /// a bytes budget.
+    pub fn page_sizing(&mut self, page_sizing: PageSizing) -> &mut Self {
+        self.page_sizing = page_sizing;
+        self
+    }
+
+    /// Page size which will be requested by a next page of a paged query.
+    pub fn page_size(&self) -> i32 {
+        self.page_sizing.page_size()
+    }
+
+    /// Copy of a paged query which requests its next page. The page has
+    /// the session's page size unless the query has a page size of its own.
+    fn page_query(&self, query: &Query) -> Query {
+        let mut page_query = clone_query(query);
+        if page_query.page_size.is_none() {
+            page_query.page_size = Some(self.page_sizing.page_size());
+        }
+        page_query
+    }
+
+    /// The method overrides max number of rows `query_all` is allowed to collect.
+    pub fn max_rows(&mut self, max_rows: usize) -> &mut Self {
+        self.max_rows = max_rows;
+        self
+    }
+
+    /// The method makes typed results convert rows on `executor` rather than on
+    /// a reactor thread. An executor can be shared by many sessions.
+    pub fn set_decode_executor(&mut self, executor: DecodeExecutor) -> &mut Self {
+        self.decode_executor = Some(executor);
+        self
+    }
+
+    pub fn decode_executor(&self) -> Option<&DecodeExecutor> {
+        self.decode_executor.as_ref()
+    }
+
+    /// Statements prepared by `prepare_typed_as`. Schema change events should be
+    /// passed to it to keep result metadata of the statements up to date.
+    pub fn prepared_cache(&self) -> Arc<Mutex<PreparedCache>> {
+        self.prepared_cache.clone()
+    }
+
+    /// The method turns on or off skipping of result metadata in responses to
+    /// executions of cached statements, see `PreparedCache::set_skip_metadata`.
+    /// It's off by default.
+    pub fn skip_metadata(&mut self, skip_metadata: bool) -> &mut Self {
+        self.prepared_cache.lock().unwrap().set_skip_metadata(skip_metadata);
+        self
+    }
+
+    /// The method limits the number of statements kept in the prepared cache,
+    /// the least recently used ones are evicted. It's `DEFAULT_CACHE_CAPACITY` by default.
+    pub fn prepared_cache_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.prepared_cache.lock().unwrap().set_capacity(capacity);
+        self
+    }
+
+    /// Makes `keyspace` the default one, so unqualified table names refer to it.
+    /// The name is validated and quoted, see `schema::validate_keyspace_name`.
+    /// The session is dropped if it fails, see `try_use_keyspace`.
+    pub fn use_keyspace(self, keyspace: &str) -> CDRSFuture<Self>
+        where T: Send
+    {
+        self.try_use_keyspace(keyspace)
+            .and_then(|(session, result)| result.map(|_| session))
+            .boxed()
+    }
+
+    /// Works as `use_keyspace` but gives the session back when it fails as well.
+    /// The current keyspace is kept then.
+    pub fn try_use_keyspace(self, keyspace: &str) -> CDRSFuture<(Self, error::Result<()>)>
+        where T: Send
+    {
+        if let Err(err) = schema::validate_keyspace_name(keyspace) {
+            return future::ok((self, Err(err))).boxed();
         }
+
+        let keyspace = keyspace.to_string();
+        let cql = format!("USE {}", insert::quote_identifier(&keyspace));
+        self.try_request(query_frame(QueryBuilder::new(cql).finalize(), vec![]))
+            .map(move |(mut session, result)| {
+                     let result = result.and_then(script::check_response).map(|_| {
+                         session.keyspace = Some(keyspace);
+                     });
+                     (session, result)
+                 })
+            .boxed()
     }
 
     /// The method makes a request to DB Server to prepare provided query.
-    pub fn prepare(&'static mut self,
+    pub fn prepare(self,
                    query: String,
                    with_tracing: bool,
                    with_warnings: bool)
-                   -> CDRSFuture<Frame>
+                   -> CDRSFuture<(Self, Frame)>
         where T: Send
     {
         let mut flags = vec![];
@@ -176,131 +660,3397 @@ impl<T: Authenticator + 'static, X: CDRSTransport + 'static> Session<T, X> {
             flags.push(Flag::Warning);
         }
 
-        let options_frame = Frame::new_req_prepare(query, flags).into_cbytes();
+        self.request(Frame::new_req_prepare(query, flags))
+    }
+
+    /// Prepares `query` and resolves into the statement along with metadata of its
+    /// bind markers and result columns. It can be passed to `execute` as it is.
+    ///
+    /// The statement is put into the prepared cache, so its executions ask a server
+    /// to skip result metadata and the cached one is put back into responses.
+    pub fn prepare_statement(self, query: String) -> CDRSFuture<(Self, PreparedStatement)>
+        where T: Send
+    {
+        let prepare_frame = Frame::new_req_prepare(query.clone(), vec![]);
 
-        future::result(self.cdrs.transport.write(options_frame.as_slice()))
-            .map_err(Into::into)
-            .and_then(move |_| parse_frame(&mut self.cdrs.transport, &self.compressor))
+        self.request(prepare_frame)
+            .and_then(move |(session, frame)| {
+                          PreparedStatement::from_frame_cached(query,
+                                                               frame,
+                                                               &session.prepared_cache)
+                              .map(|prepared| (session, prepared))
+                      })
             .boxed()
     }
 
-    /// The method makes a request to DB Server to execute a query with provided id
-    /// using provided query parameters. `id` is an ID of a query which Server
-    /// returns back to a driver as a response to `prepare` request.
-    pub fn execute(&'static mut self,
-                   id: &CBytesShort,
-                   query_parameters: QueryParams,
-                   with_tracing: bool,
-                   with_warnings: bool)
-                   -> CDRSFuture<Frame>
-        where T: Send
+    /// The method prepares `query` as a statement which binds values of type `P`
+    /// and maps result rows into `R`. It fails if `P` doesn't provide as many values
+    /// as the statement has bind markers.
+    pub fn prepare_typed_as<P, R>(self, query: String) -> CDRSFuture<(Self, TypedPrepared<P, R>)>
+        where T: Send,
+              P: IntoQueryValues + 'static,
+              R: TryFromRow + 'static
     {
+        let prepare_frame = Frame::new_req_prepare(query.clone(), vec![]);
 
-        let mut flags = vec![];
-        if with_tracing {
-            flags.push(Flag::Tracing);
-        }
-        if with_warnings {
-            flags.push(Flag::Warning);
+        self.request(prepare_frame)
+            .and_then(move |(session, frame)| {
+                          let cache = session.prepared_cache();
+                          TypedPrepared::from_frame(query, frame, cache)
+                              .map(|prepared| (session, prepared))
+                      })
+            .boxed()
+    }
+
+    /// Returns an id of `query` from the prepared cache, or prepares it
+    /// and puts it into the cache.
+    pub fn prepare_cached(self, query: String) -> CDRSFuture<(Self, CBytesShort)>
+        where T: Send
+    {
+        let cached = self.prepared_cache.lock().unwrap().id(&query);
+        if let Some(id) = cached {
+            return future::ok((self, id)).boxed();
         }
-        let options_frame = Frame::new_req_execute(id, query_parameters, flags).into_cbytes();
 
-        future::result(self.cdrs.transport.write(options_frame.as_slice()))
-            .map_err(Into::into)
-            .and_then(move |_| parse_frame(&mut self.cdrs.transport, &self.compressor))
+        self.request(Frame::new_req_prepare(query.clone(), vec![]))
+            .and_then(move |(session, frame)| {
+                let cache = session.prepared_cache();
+                let prepared = try!(TypedPrepared::<Vec<Value>, Row>::from_frame(query,
+                                                                                  frame,
+                                                                                  cache));
+                Ok((session, prepared.id().clone()))
+            })
             .boxed()
     }
 
-    /// The method makes a request to DB Server to execute a query provided in `query` argument.
-    /// you can build the query with QueryBuilder
-    /// ```
-    /// use cdrs::query::QueryBuilder;
-    /// use cdrs::compression::Compression;
-    /// use cdrs::consistency::Consistency;
+    /// Executes `query` with `values` preparing it first unless it's in the prepared
+    /// cache. If a server doesn't know the statement anymore, e.g. it was restarted,
+    /// the statement is prepared again and executed once more.
+    pub fn execute_cached(self,
+                          query: String,
+                          values: Vec<Value>,
+                          consistency: Consistency)
+                          -> CDRSFuture<(Self, Frame)>
+        where T: Send
+    {
+        self.execute_cached_with(query, values, Some(consistency))
+    }
+
+    // consistency which is `None` is left to the session
+    fn execute_cached_with(self,
+                           query: String,
+                           values: Vec<Value>,
+                           consistency: Option<Consistency>)
+                           -> CDRSFuture<(Self, Frame)>
+        where T: Send
+    {
+        let retry = (query.clone(), values.clone(), consistency.clone());
+        self.prepare_cached(query)
+            .and_then(move |(session, id)| {
+                let query_parameters = session.execute_params(values, consistency);
+                session.request(Frame::new_req_execute(&id, query_parameters, vec![]))
+            })
+            .and_then(move |(session, frame)| {
+                if !prepared::is_unprepared(&frame) {
+                    return future::ok((session, frame)).boxed();
+                }
+
+                let (query, values, consistency) = retry;
+                session.prepared_cache.lock().unwrap().remove(&query);
+                session.prepare_cached(query)
+                    .and_then(move |(session, id)| {
+                        let query_parameters = session.execute_params(values, consistency);
+                        session.request(Frame::new_req_execute(&id, query_parameters, vec![]))
+                    })
+                    .boxed()
+            })
+            .boxed()
+    }
+
+    /// Parameters of an execution with `values` and defaults of the session.
+    /// Consistency which is `None` is left to the session, `One` without a default.
+    fn execute_params(&self, values: Vec<Value>, consistency: Option<Consistency>) -> QueryParams {
+        let query_parameters = QueryParamsBuilder::new(Consistency::One)
+            .values(values)
+            .finalize();
+        let statement = Statement::new(query_parameters);
+        self.with_defaults(match consistency {
+                               Some(consistency) => statement.consistency(consistency),
+                               None => statement,
+                           })
+    }
+
+    /// Prepares `query` unless it's in the prepared cache and executes it with `values`
+    /// and consistency of the session, `One` without a default. Server errors fail
+    /// the future. An error says which stage failed with `Error::Stage`.
     ///
-    ///   let select_query = QueryBuilder::new("select * from emp").finalize();
+    /// ```no_run
+    /// # extern crate cdrs;
+    /// # extern crate cdrs_future;
+    /// # extern crate futures;
+    /// # use cdrs::authenticators::NoneAuthenticator;
+    /// # use cdrs::transport::CDRSTransport;
+    /// # use cdrs_future::client::Session;
+    /// use cdrs::types::value::Value;
+    /// use futures::Future;
+    ///
+    /// # fn run<X: CDRSTransport + 'static>(session: Session<NoneAuthenticator, X>) {
+    /// let insert = "INSERT INTO ks.users (id, name) VALUES (?, ?)";
+    /// let select = "SELECT name FROM ks.users WHERE id = ?";
+    /// let user = vec![Value::from(1), Value::from("alice".to_string())];
+    /// let (_, names) = session.exec_with_values(insert, user)
+    ///     .and_then(move |(session, _)| session.exec_with_values(select, vec![Value::from(1)]))
+    ///     .wait()
+    ///     .unwrap();
+    /// # }
+    /// # fn main() {}
     /// ```
-    pub fn query(&'static mut self,
-                 query: Query,
-                 with_tracing: bool,
-                 with_warnings: bool)
-                 -> CDRSFuture<Frame>
+    pub fn exec_with_values<V>(self, query: &str, values: V) -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              V: IntoQueryValues + Send + 'static
+    {
+        self.exec_bound(query.to_string(), move |_| Ok(values.into_query_values()))
+    }
+
+    /// Works as `exec_with_values` binding values to markers with the same names,
+    /// e.g. `id` to `:id`, or to columns for `?` markers.
+    pub fn exec_with_values_with_names(self,
+                                       query: &str,
+                                       values: Vec<(String, Value)>)
+                                       -> CDRSFuture<(Self, Frame)>
         where T: Send
     {
-        let mut flags = vec![];
+        self.exec_bound(query.to_string(),
+                        move |markers| values::bind_by_name(markers, values))
+    }
 
-        if with_tracing {
-            flags.push(Flag::Tracing);
-        }
+    /// Prepares `query` unless it's in the prepared cache and executes it with values
+    /// bound by name, e.g. `id` to `:id`. Values are sent with their names in order
+    /// of markers of the statement. A marker without a value or a value without
+    /// a marker fails the future before the statement is executed.
+    pub fn execute_named(self,
+                         query: &str,
+                         values: HashMap<String, Value>,
+                         consistency: Consistency)
+                         -> CDRSFuture<(Self, Frame)>
+        where T: Send
+    {
+        let query = query.to_string();
+        self.prepare_cached(query.clone())
+            .and_then(move |(session, id)| {
+                let markers = session.prepared_cache
+                    .lock()
+                    .unwrap()
+                    .get(&query)
+                    .map(|statement| statement.markers.clone())
+                    .unwrap_or_default();
+                let values = match values::bind_by_name(&markers, values.into_iter().collect()) {
+                    Ok(values) => values,
+                    Err(err) => return future::err(err).boxed(),
+                };
 
-        if with_warnings {
-            flags.push(Flag::Warning);
-        }
+                let query_parameters = session.execute_params(values, Some(consistency));
+                let mut execute_frame = Frame::new_req_execute(&id, query_parameters, vec![]);
+                if let Err(err) = values::set_value_names(&mut execute_frame, &markers) {
+                    return future::err(err).boxed();
+                }
+                session.request(execute_frame)
+            })
+            .boxed()
+    }
 
-        let query_frame = Frame::new_req_query(query.query,
-                                               query.consistency,
-                                               query.values,
-                                               query.with_names,
-                                               query.page_size,
-                                               query.paging_state,
-                                               query.serial_consistency,
-                                               query.timestamp,
-                                               flags)
-                .into_cbytes();
+    fn exec_bound<F>(self, query: String, bind: F) -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              F: FnOnce(&[String]) -> error::Result<Vec<Value>> + Send + 'static
+    {
+        self.prepare_cached(query.clone())
+            .map_err(|err| error::Error::in_stage("prepare", err))
+            .and_then(move |(session, _)| {
+                let markers = session.prepared_cache
+                    .lock()
+                    .unwrap()
+                    .get(&query)
+                    .map(|statement| statement.markers.clone())
+                    .unwrap_or_default();
+                let values = match bind(&markers) {
+                    Ok(values) => values,
+                    Err(err) => return future::err(error::Error::in_stage("bind", err)).boxed(),
+                };
 
-        future::result(self.cdrs.transport.write(query_frame.as_slice()))
-            .map_err(Into::into)
-            .and_then(move |_| parse_frame(&mut self.cdrs.transport, &self.compressor))
+                session.execute_cached_with(query, values, None)
+                    .and_then(|(session, frame)| {
+                                  script::check_response(frame).map(|frame| (session, frame))
+                              })
+                    .map_err(|err| error::Error::in_stage("execute", err))
+                    .boxed()
+            })
             .boxed()
     }
 
-    pub fn batch(&'static mut self,
-                 batch_query: QueryBatch,
-                 with_tracing: bool,
-                 with_warnings: bool)
-                 -> CDRSFuture<Frame>
-        where T: Send
+    /// Inserts `value` into `table` with a statement generated from its columns,
+    /// see `insert::insert_cql`. The statement is prepared once per session and
+    /// reused from the prepared cache afterwards.
+    ///
+    /// Resolves into `false` if the insert is `IF NOT EXISTS` and the row existed,
+    /// and into `true` otherwise. The session is given back for next requests.
+    /// Options which can't go together, e.g. `IF NOT EXISTS` with a timestamp,
+    /// fail the future before anything is sent.
+    pub fn insert_into<V>(self,
+                          table: &str,
+                          value: V,
+                          options: InsertOptions)
+                          -> CDRSFuture<(Self, bool)>
+        where T: Send,
+              V: Columns + IntoQueryValues
     {
-        let mut flags = vec![];
-
-        if with_tracing {
-            flags.push(Flag::Tracing);
-        }
+        let cql = match insert::insert_cql(table, V::columns(), &options) {
+            Ok(cql) => cql,
+            Err(err) => return future::err(err).boxed(),
+        };
+        let mut values = value.into_query_values();
+        values.extend(options.values());
 
-        if with_warnings {
-            flags.push(Flag::Warning);
-        }
+        self.prepare_cached(cql)
+            .and_then(move |(session, id)| {
+                let query_parameters = session.execute_params(values, options.get_consistency());
+                session.request(Frame::new_req_execute(&id, query_parameters, vec![]))
+            })
+            .and_then(|(session, frame)| {
+                          let applied = try!(insert::is_applied(frame));
+                          Ok((session, applied))
+                      })
+            .boxed()
+    }
 
-        let query_frame = Frame::new_req_batch(batch_query, flags).into_cbytes();
+    /// The method makes a request to DB Server to execute a query with provided id
+    /// using provided query parameters. `id` is an ID of a query which Server
+    /// returns back to a driver as a response to `prepare` request, or a statement
+    /// of `prepare_statement`.
+    ///
+    /// A server error doesn't fail the future, it comes as an ERROR frame. See
+    /// `script::check_response` which turns it into `Error::Server`.
+    pub fn execute<I, P>(self,
+                         id: &I,
+                         query_parameters: P,
+                         with_tracing: bool,
+                         with_warnings: bool)
+                         -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              I: StatementId + ?Sized,
+              P: Into<Statement<QueryParams>>
+    {
+        let options = RequestOptions::new()
+            .tracing(with_tracing)
+            .warnings(with_warnings);
+        self.execute_with(id, query_parameters, options)
+    }
 
-        future::result(self.cdrs.transport.write(query_frame.as_slice()))
-            .map_err(Into::into)
-            .and_then(move |_| parse_frame(&mut self.cdrs.transport, &self.compressor))
+    /// Works as `execute` taking options of the request.
+    pub fn execute_with<I, P>(self,
+                              id: &I,
+                              query_parameters: P,
+                              options: RequestOptions)
+                              -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              I: StatementId + ?Sized,
+              P: Into<Statement<QueryParams>>
+    {
+        let mut query_parameters = self.with_defaults(query_parameters.into());
+        let cached = self.skip_result_metadata(id, &mut query_parameters);
+        let execute_frame = Frame::new_req_execute(id.statement_id(),
+                                                   query_parameters,
+                                                   options.flags());
+        self.send_with(execute_frame, options)
+            .and_then(move |(session, mut frame)| {
+                          if let Some(query) = cached {
+                              try!(session.prepared_cache
+                                       .lock()
+                                       .unwrap()
+                                       .restore_metadata(&query, &mut frame));
+                          }
+                          Ok((session, frame))
+                      })
             .boxed()
     }
 
-    /// It consumes CDRS
-    pub fn listen_for<'b>(mut self,
-                          events: Vec<SimpleServerEvent>)
-                          -> CDRSFuture<(Listener<X>, EventStream)>
-        where T: Send
+    /// Works as `execute_with` resolving into the response with its warnings,
+    /// the id of its trace and its custom payload.
+    pub fn execute_with_info<I, P>(self,
+                                   id: &I,
+                                   query_parameters: P,
+                                   options: RequestOptions)
+                                   -> CDRSFuture<(Self, QueryResponse)>
+        where T: Send,
+              I: StatementId + ?Sized,
+              P: Into<Statement<QueryParams>>
     {
-        let query_frame = Frame::new_req_register(events).into_cbytes();
+        self.execute_with_payload(id, query_parameters, options, &CustomPayload::new())
+    }
 
-        future::result(self.cdrs.transport.write(query_frame.as_slice()))
-            .map_err(Into::into)
-            .and_then(move |_| {
-                          parse_frame(&mut self.cdrs.transport, &self.compressor)
-                              .and_then(move |_| Ok(new_listener(self.cdrs.transport)))
+    /// Works as `execute_with_info` sending a custom payload along with the request.
+    pub fn execute_with_payload<I, P>(self,
+                                      id: &I,
+                                      query_parameters: P,
+                                      options: RequestOptions,
+                                      payload: &CustomPayload)
+                                      -> CDRSFuture<(Self, QueryResponse)>
+        where T: Send,
+              I: StatementId + ?Sized,
+              P: Into<Statement<QueryParams>>
+    {
+        let mut query_parameters = self.with_defaults(query_parameters.into());
+        let cached = self.skip_result_metadata(id, &mut query_parameters);
+        let mut execute_frame = Frame::new_req_execute(id.statement_id(),
+                                                       query_parameters,
+                                                       options.flags());
+        codec::set_custom_payload(&mut execute_frame, payload);
+        self.send_with_info(execute_frame, options)
+            .and_then(move |(session, mut response)| {
+                          if let Some(query) = cached {
+                              try!(session.prepared_cache
+                                       .lock()
+                                       .unwrap()
+                                       .restore_metadata(&query, &mut response.frame));
+                          }
+                          Ok((session, response))
                       })
             .boxed()
+    }
 
+    /// Sets `SkipMetadata` flag if result metadata of `id` is in the prepared cache.
+    /// Returns a query of the statement if a response may need its metadata back.
+    fn skip_result_metadata<I>(&self, id: &I, query_parameters: &mut QueryParams) -> Option<String>
+        where I: StatementId + ?Sized
+    {
+        let query = match id.query() {
+            Some(query) => query,
+            None => return None,
+        };
+        let skipped = query_parameters.flags.iter().any(|flag| match *flag {
+                                                             QueryFlags::SkipMetadata => true,
+                                                             _ => false,
+                                                         });
+        if !skipped && self.prepared_cache.lock().unwrap().can_restore_metadata(query) {
+            query_parameters.flags.push(QueryFlags::SkipMetadata);
+        }
+        Some(query.to_string())
     }
-}
 
-fn resolve_supported_ops(frame: Frame) -> Result<CassandraOptions, error::Error> {
-    match frame.get_body() {
-        Ok(ResponseBody::Supported(ref supported_body)) => Ok(supported_body.data.clone()),
-        _ => Err("Unexpected type of frame. Supported frame is supported".into()),
+    /// Works as `execute` returning rows of a single result page, see `query_rows`.
+    pub fn execute_rows<I>(self,
+                           id: &I,
+                           query_parameters: QueryParams)
+                           -> CDRSFuture<(Self, Vec<Row>)>
+        where T: Send,
+              I: StatementId + ?Sized
+    {
+        self.execute(id, query_parameters, false, false)
+            .and_then(|(session, frame)| Page::from_frame(frame).map(|page| (session, page.rows)))
+            .boxed()
+    }
+
+    /// Works as `query_cas` executing a prepared conditional statement.
+    pub fn execute_cas<I>(self,
+                          id: &I,
+                          query_parameters: QueryParams)
+                          -> CDRSFuture<(Self, CasResult)>
+        where T: Send,
+              I: StatementId + ?Sized
+    {
+        self.execute_rows(id, query_parameters)
+            .and_then(|(session, rows)| CasResult::from_rows(rows).map(|result| (session, result)))
+            .boxed()
+    }
+
+    /// The method makes a request to DB Server to execute a query provided in `query` argument.
+    /// you can build the query with QueryBuilder
+    /// ```
+    /// extern crate cdrs;
+    ///
+    /// use cdrs::query::QueryBuilder;
+    /// use cdrs::compression::Compression;
+    /// use cdrs::consistency::Consistency;
+    ///
+    ///   let select_query = QueryBuilder::new("select * from emp").finalize();
+    /// ```
+    ///
+    /// A server error doesn't fail the future, it comes as an ERROR frame. See
+    /// `script::check_response` which turns it into `Error::Server`.
+    pub fn query<Q>(self,
+                    query: Q,
+                    with_tracing: bool,
+                    with_warnings: bool)
+                    -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        let options = RequestOptions::new()
+            .tracing(with_tracing)
+            .warnings(with_warnings);
+        self.query_with(query, options)
+    }
+
+    /// Works as `query` taking options of the request.
+    pub fn query_with<Q>(self, query: Q, options: RequestOptions) -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        let query = self.with_defaults(query.into());
+        let query_frame = query_frame(query, options.flags());
+        self.send_with(query_frame, options)
+    }
+
+    /// Works as `query` marking the query idempotent, so a retry policy may send
+    /// it again after a write timeout, see `RequestOptions::idempotent`.
+    pub fn query_idempotent<Q>(self, query: Q) -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        self.query_with(query, RequestOptions::new().idempotent(true))
+    }
+
+    /// Works as `query_with` resolving into the response with its warnings,
+    /// the id of its trace and its custom payload.
+    pub fn query_with_info<Q>(self,
+                              query: Q,
+                              options: RequestOptions)
+                              -> CDRSFuture<(Self, QueryResponse)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        self.query_with_payload(query, options, &CustomPayload::new())
+    }
+
+    /// Works as `query_with_info` sending a custom payload along with the query.
+    /// An empty payload is not sent at all.
+    pub fn query_with_payload<Q>(self,
+                                 query: Q,
+                                 options: RequestOptions,
+                                 payload: &CustomPayload)
+                                 -> CDRSFuture<(Self, QueryResponse)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        let query = self.with_defaults(query.into());
+        let mut query_frame = query_frame(query, options.flags());
+        codec::set_custom_payload(&mut query_frame, payload);
+        self.send_with_info(query_frame, options)
+    }
+
+    /// Works as `query` binding `values` to markers of the query in place of its
+    /// values, see `query_values!`. Named values are sent along with their names.
+    pub fn query_with_values<Q>(self, query: Q, values: QueryValues) -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        let mut query = self.with_defaults(query.into());
+        let (values, names) = values.into_parts();
+        query.values = Some(values);
+        query.with_names = Some(names.is_some());
+        let mut query_frame = query_frame(query, vec![]);
+        if let Some(names) = names {
+            if let Err(err) = values::set_value_names(&mut query_frame, &names) {
+                return future::err(err).boxed();
+            }
+        }
+        self.request(query_frame)
+    }
+
+    /// Runs `query` with values bound to its markers by name, e.g. `id` to `:id`.
+    /// A server checks names of the values as a query is not prepared.
+    pub fn query_named(self,
+                       query: &str,
+                       values: HashMap<String, Value>,
+                       consistency: Consistency)
+                       -> CDRSFuture<(Self, Frame)>
+        where T: Send
+    {
+        let mut values: Vec<_> = values.into_iter().collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut query = QueryBuilder::new(query).finalize();
+        query.consistency = consistency;
+        self.query_with_values(query, QueryValues::Named(values))
+    }
+
+    /// Works as `query_with` with tracing enabled, resolves into the response and
+    /// the id of its trace, see `get_tracing_info`. The id is `None` if a server
+    /// didn't trace the request.
+    pub fn query_traced<Q>(self,
+                           query: Q,
+                           options: RequestOptions)
+                           -> CDRSFuture<(Self, (Frame, Option<Uuid>))>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        self.query_with(query, options.tracing(true))
+            .map(|(session, response)| {
+                     let tracing_id = response.tracing_id;
+                     (session, (response, tracing_id))
+                 })
+            .boxed()
+    }
+
+    /// Works as `query` returning rows of the response. Results without rows,
+    /// e.g. of an `INSERT`, give no rows and a server error fails the future.
+    /// Only the first page is returned, see `query_all` for all of them.
+    pub fn query_rows<Q>(self, query: Q) -> CDRSFuture<(Self, Vec<Row>)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        self.query(query, false, false)
+            .and_then(|(session, frame)| Page::from_frame(frame).map(|page| (session, page.rows)))
+            .boxed()
+    }
+
+    /// Runs a conditional statement, e.g. `INSERT ... IF NOT EXISTS`, and reports
+    /// whether it was applied along with the existing row if it wasn't. Serial
+    /// consistency of the query is sent as it is. See `batch_lwt` for batches,
+    /// which return a row per conditional statement.
+    pub fn query_cas<Q>(self, query: Q) -> CDRSFuture<(Self, CasResult)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        self.query_rows(query)
+            .and_then(|(session, rows)| CasResult::from_rows(rows).map(|result| (session, result)))
+            .boxed()
+    }
+
+    /// Sends a batch. A server error doesn't fail the future, it comes as an ERROR
+    /// frame, see `apply_batch` which fails instead.
+    pub fn batch<B>(self,
+                    batch_query: B,
+                    with_tracing: bool,
+                    with_warnings: bool)
+                    -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              B: Into<Statement<QueryBatch>>
+    {
+        let options = RequestOptions::new()
+            .tracing(with_tracing)
+            .warnings(with_warnings);
+        let batch_query = self.with_defaults(batch_query.into());
+        self.send_with(Frame::new_req_batch(batch_query, options.flags()), options)
+    }
+
+    /// Works as `batch` sending a custom payload along with the batch, see
+    /// `query_with_payload`.
+    pub fn batch_with_payload<B>(self,
+                                 batch_query: B,
+                                 options: RequestOptions,
+                                 payload: &CustomPayload)
+                                 -> CDRSFuture<(Self, QueryResponse)>
+        where T: Send,
+              B: Into<Statement<QueryBatch>>
+    {
+        let batch_query = self.with_defaults(batch_query.into());
+        let mut batch_frame = Frame::new_req_batch(batch_query, options.flags());
+        codec::set_custom_payload(&mut batch_frame, payload);
+        self.send_with_info(batch_frame, options)
+    }
+
+    /// Works as `batch`, but a server error fails the future. A batch which mixes
+    /// counter updates with other statements fails with `Error::CounterBatch`.
+    pub fn apply_batch<B>(self, batch_query: B) -> CDRSFuture<(Self, Frame)>
+        where T: Send,
+              B: Into<Statement<QueryBatch>>
+    {
+        self.batch(batch_query, false, false)
+            .and_then(|(session, response)| {
+                          batch::check_response(response).map(|response| (session, response))
+                      })
+            .boxed()
+    }
+
+    /// Works as `batch` and decodes whether a conditional batch was applied.
+    pub fn batch_lwt<B>(self, batch_query: B) -> CDRSFuture<(Self, BatchLwtResult)>
+        where T: Send,
+              B: Into<Statement<QueryBatch>>
+    {
+        self.batch(batch_query, false, false)
+            .and_then(|(session, frame)| {
+                          BatchLwtResult::from_frame(frame).map(|result| (session, result))
+                      })
+            .boxed()
+    }
+
+    /// The method pages through all results of a query and collects all rows.
+    /// Pages are requested with the session's page size. It fails with
+    /// `TooManyRows` error as soon as a page takes the number of rows over
+    /// the session's `max_rows` limit.
+    pub fn query_all<Q>(self, query: Q) -> CDRSFuture<(Self, Vec<Row>)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        self.query_all_into(query)
+    }
+
+    /// Works as `query_all` converting each row into `R`.
+    pub fn query_all_into<R, Q>(self, query: Q) -> CDRSFuture<(Self, Vec<R>)>
+        where T: Send,
+              Q: Into<Statement<Query>>,
+              R: TryFromRow + Send + 'static
+    {
+        let query = self.with_defaults(query.into());
+        future::loop_fn((self, query, vec![]), |(session, mut query, rows)| {
+            let page_frame = query_frame(session.page_query(&query), vec![]);
+
+            session
+                .request(page_frame)
+                .and_then(move |(mut session, frame)| {
+                    let page_bytes = frame.body.len();
+                    let page = try!(Page::from_frame(frame));
+                    session.page_sizing.observe(page_bytes, page.rows.len());
+
+                    if rows.len() + page.rows.len() > session.max_rows {
+                        return Err(error::Error::TooManyRows { max_rows: session.max_rows });
+                    }
+                    Ok((session, page, rows))
+                })
+                .and_then(move |(session, page, mut rows)| {
+                    let paging_state = page.paging_state;
+                    decode::decode_rows(session.decode_executor.as_ref(), page.rows)
+                        .map(move |decoded| {
+                            rows.extend(decoded);
+                            match paging_state {
+                                Some(paging_state) => {
+                                    query.paging_state = Some(paging_state.into());
+                                    Loop::Continue((session, query, rows))
+                                }
+                                None => Loop::Break((session, rows)),
+                            }
+                        })
+                })
+        })
+                .boxed()
+    }
+
+    /// Requests a single page of `page_size` rows of a query. It starts at `paging_state`
+    /// of a previous page, which may come from another session, or at the first row
+    /// if it's `None`. A server fails the query if the state doesn't belong to it.
+    pub fn query_page<Q>(self,
+                         query: Q,
+                         page_size: i32,
+                         paging_state: Option<PagingState>)
+                         -> CDRSFuture<(Self, Page)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        let mut query = self.with_defaults(query.into());
+        query.page_size = Some(page_size);
+        query.paging_state = paging_state.map(|paging_state| paging_state.into());
+
+        self.request(query_frame(query, vec![]))
+            .and_then(|(session, frame)| Page::from_frame(frame).map(|page| (session, page)))
+            .boxed()
+    }
+
+    /// Yields rows of a query one by one, requesting a next page once rows
+    /// of the previous one are taken until the server reports the last page.
+    /// Pages have `page_size` of the query, or the session's page size if it
+    /// has none. The stream takes the session and closes it once dropped.
+    pub fn query_stream<Q>(self, query: Q) -> CDRSStream<Row>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        self.query_stream_into(query)
+    }
+
+    /// Works as `query_stream` converting each row into `R`. Rows of a page are
+    /// converted on the decode executor of the session if it has one.
+    pub fn query_stream_into<R, Q>(self, query: Q) -> CDRSStream<R>
+        where T: Send,
+              Q: Into<Statement<Query>>,
+              R: TryFromRow + Send + 'static
+    {
+        let query = self.with_defaults(query.into());
+        stream::unfold((self, Some(query)), |(session, query)| {
+            let mut query = match query {
+                Some(query) => query,
+                None => return None,
+            };
+            let page_frame = query_frame(session.page_query(&query), vec![]);
+
+            let page = session.request(page_frame).and_then(|(mut session, frame)| {
+                let page_bytes = frame.body.len();
+                let page = try!(Page::from_frame(frame));
+                session.page_sizing.observe(page_bytes, page.rows.len());
+                Ok((session, page))
+            });
+            Some(page.and_then(move |(session, page)| {
+                let next = page.paging_state.map(|paging_state| {
+                                                      query.paging_state =
+                                                          Some(paging_state.into());
+                                                      query
+                                                  });
+                decode::decode_rows(session.decode_executor.as_ref(), page.rows)
+                    .map(move |rows| {
+                             let rows = rows.into_iter().map(Ok::<R, error::Error>);
+                             (stream::iter(rows), (session, next))
+                         })
+            }))
+        })
+                .flatten()
+                .boxed()
+    }
+
+    /// Pages through results of a query writing them into `writer` as CSV,
+    /// so only one page is kept in memory. Resolves into the writer along with
+    /// the number of written rows. See `csv` for formatting of values.
+    pub fn query_to_csv<W, Q>(self,
+                              query: Q,
+                              writer: W,
+                              options: CsvOptions)
+                              -> CDRSFuture<(Self, (W, usize))>
+        where T: Send,
+              Q: Into<Statement<Query>>,
+              W: io::Write + Send + 'static
+    {
+        let query = self.with_defaults(query.into());
+        let state = (self, query, writer, options.header, 0);
+        future::loop_fn(state, move |(session, mut query, mut writer, header, count)| {
+            let page_frame = query_frame(session.page_query(&query), vec![]);
+
+            session
+                .request(page_frame)
+                .and_then(move |(mut session, frame)| {
+                    let page_bytes = frame.body.len();
+                    let mut rows_body = match try!(frame.get_body()) {
+                        ResponseBody::Result(ResResultBody::Rows(rows_body)) => rows_body,
+                        body => {
+                            try!(Page::from_response(&frame, body));
+                            return Ok(Loop::Break((session, (writer, count))));
+                        }
+                    };
+                    session.page_sizing.observe(page_bytes, rows_body.rows_content.len());
+
+                    if header {
+                        try!(csv::write_header(&mut writer, &rows_body.metadata.col_specs));
+                    }
+                    let paging_state = rows_body.metadata.paging_state.take();
+                    let count = count + try!(csv::write_rows(&mut writer, rows_body, &options));
+
+                    match paging_state {
+                        Some(paging_state) => {
+                            query.paging_state = Some(paging_state);
+                            Ok(Loop::Continue((session, query, writer, false, count)))
+                        }
+                        None => Ok(Loop::Break((session, (writer, count)))),
+                    }
+                })
+        })
+                .boxed()
+    }
+
+    /// Returns the first row of a query result, or `None` if it's empty or
+    /// the query doesn't return rows at all.
+    ///
+    /// The query is not rewritten with `LIMIT`, instead a page size of 1 is requested.
+    /// If `strict` is set a page size of 2 is requested and the query fails with
+    /// `UnexpectedRows` as soon as a second row comes. It carries the number of rows
+    /// received by then, which is a lower bound as the rest aren't requested.
+    pub fn query_one<Q>(self, query: Q, strict: bool) -> CDRSFuture<(Self, Option<Row>)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        self.query_one_into(query, strict)
+    }
+
+    /// Works as `query_rows` converting rows into `R` by positions of columns,
+    /// e.g. into tuples. Only the first page is returned.
+    pub fn query_as<R, Q>(self, query: Q) -> CDRSFuture<(Self, Vec<R>)>
+        where T: Send,
+              Q: Into<Statement<Query>>,
+              R: FromRow + Send + 'static
+    {
+        self.query(query, false, false)
+            .and_then(|(session, frame)| rows::rows_as(frame).map(|rows| (session, rows)))
+            .boxed()
+    }
+
+    /// Works as `query_as` returning the first row only, it's `None` if there
+    /// are no rows.
+    pub fn query_one_as<R, Q>(self, query: Q) -> CDRSFuture<(Self, Option<R>)>
+        where T: Send,
+              Q: Into<Statement<Query>>,
+              R: FromRow + Send + 'static
+    {
+        let mut query = self.with_defaults(query.into());
+        query.page_size = Some(1);
+        self.query_as(query)
+            .map(|(session, rows)| (session, rows.into_iter().next()))
+            .boxed()
+    }
+
+    /// Works as `query_one` converting the row into `R`.
+    pub fn query_one_into<R, Q>(self, query: Q, strict: bool) -> CDRSFuture<(Self, Option<R>)>
+        where T: Send,
+              Q: Into<Statement<Query>>,
+              R: TryFromRow + Send + 'static
+    {
+        let mut query = self.with_defaults(query.into());
+        query.page_size = Some(if strict { 2 } else { 1 });
+
+        future::loop_fn((self, query, None, 0), move |(session, mut query, first, count)| {
+            let page_frame = query_frame(clone_query(&query), vec![]);
+
+            session
+                .request(page_frame)
+                .and_then(move |(session, frame)| {
+                    let page = try!(Page::from_frame(frame));
+                    let count = count + page.rows.len();
+                    let first = first.or(page.rows.into_iter().next());
+
+                    // an empty page doesn't mean there are no rows in next ones
+                    match page.paging_state {
+                        _ if strict && count > 1 => Ok(Loop::Break((session, first, count))),
+                        Some(paging_state) if strict || first.is_none() => {
+                            query.paging_state = Some(paging_state.into());
+                            Ok(Loop::Continue((session, query, first, count)))
+                        }
+                        _ => Ok(Loop::Break((session, first, count))),
+                    }
+                })
+        })
+                .and_then(move |(session, first, count)| {
+                    if strict && count > 1 {
+                        return Err(error::Error::UnexpectedRows { rows: count });
+                    }
+
+                    match first {
+                        Some(row) => R::try_from_row(row).map(|row| (session, Some(row))),
+                        None => Ok((session, None)),
+                    }
+                })
+                .boxed()
+    }
+
+    /// Returns the single value of a query which selects one column, e.g. `count(*)`.
+    /// It's `None` if there are no rows or the value is null, rows after the first
+    /// one are ignored. Fails with `UnexpectedColumns` if the query returns any
+    /// other number of columns.
+    pub fn query_value<V, Q>(self, query: Q) -> CDRSFuture<(Self, Option<V>)>
+        where T: Send,
+              Q: Into<Statement<Query>>,
+              V: Send + 'static,
+              Row: IntoRustByName<V>
+    {
+        let mut query = self.with_defaults(query.into());
+        query.page_size = Some(1);
+
+        future::loop_fn((self, query), |(session, mut query)| {
+            let page_frame = query_frame(clone_query(&query), vec![]);
+
+            session
+                .request(page_frame)
+                .and_then(move |(session, frame)| {
+                    let mut rows_body = match try!(frame.get_body()) {
+                        ResponseBody::Result(ResResultBody::Rows(rows_body)) => rows_body,
+                        body => {
+                            try!(Page::from_response(&frame, body));
+                            return Ok(Loop::Break((session, None)));
+                        }
+                    };
+                    let columns: Vec<String> = rows_body.metadata
+                        .col_specs
+                        .iter()
+                        .map(|spec| spec.name.as_plain())
+                        .collect();
+                    if columns.len() != 1 {
+                        return Err(error::Error::UnexpectedColumns { columns: columns });
+                    }
+
+                    // an empty page doesn't mean there are no rows in next ones
+                    let paging_state = rows_body.metadata.paging_state.take();
+                    match (Row::from_frame_body(rows_body).into_iter().next(), paging_state) {
+                        (Some(row), _) => {
+                            let value = try!(rows::nullable_column(&row, &columns[0], "value"));
+                            Ok(Loop::Break((session, value)))
+                        }
+                        (None, Some(paging_state)) => {
+                            query.paging_state = Some(paging_state);
+                            Ok(Loop::Continue((session, query)))
+                        }
+                        (None, None) => Ok(Loop::Break((session, None))),
+                    }
+                })
+        })
+                .boxed()
+    }
+
+    /// Sends a request and sends it again while the retry policy asks to.
+    fn send_with(self, frame: Frame, options: RequestOptions) -> CDRSFuture<(Self, Frame)>
+        where T: Send
+    {
+        future::loop_fn((self, 0), move |(session, retries)| {
+            session.try_request_with(codec::clone_frame(&frame),
+                                     options.get_compression(),
+                                     options.get_timeout())
+                .and_then(move |(session, result)| {
+                    let response = try!(result);
+                    let decision = match retry::server_error(&response) {
+                        Some(error) => {
+                            session.retry_policy
+                                .on_server_error(&error, retries, options.is_idempotent())
+                        }
+                        None => RetryDecision::Rethrow,
+                    };
+                    match decision {
+                        RetryDecision::Retry => Ok(Loop::Continue((session, retries + 1))),
+                        // there is no other node behind a session
+                        RetryDecision::RetryNextNode |
+                        RetryDecision::Rethrow => Ok(Loop::Break((session, response))),
+                    }
+                })
+        })
+                .boxed()
+    }
+
+    /// Works as `send_with` resolving into a `QueryResponse`.
+    fn send_with_info(self,
+                      frame: Frame,
+                      options: RequestOptions)
+                      -> CDRSFuture<(Self, QueryResponse)>
+        where T: Send
+    {
+        self.send_with(frame, options)
+            .map(|(mut session, frame)| {
+                     let mut response = QueryResponse::from(frame);
+                     response.custom_payload = session.cdrs_mut().take_custom_payload();
+                     (session, response)
+                 })
+            .boxed()
+    }
+
+    /// Executes statements of a CQL script one by one, see `script::split_statements`.
+    /// Every executed statement gets an outcome. If `options.on_error` is `Stop`
+    /// statements after a failed one are not executed. Nodes are given time to agree
+    /// on schema after a statement which changed it, see `await_schema_agreement_within`.
+    pub fn execute_script(self,
+                          cql: &str,
+                          options: ScriptOptions)
+                          -> CDRSFuture<(Self, Vec<StatementOutcome>)>
+        where T: Send
+    {
+        let statements: VecDeque<_> = script::split_statements(cql)
+            .into_iter()
+            .enumerate()
+            .collect();
+
+        future::loop_fn((self, statements, vec![]),
+                        move |(session, mut statements, mut outcomes)| {
+            let (index, statement) = match statements.pop_front() {
+                Some(next) => next,
+                None => return future::ok(Loop::Break((session, outcomes))).boxed(),
+            };
+            let frame = query_frame(QueryBuilder::new(statement.clone()).finalize(), vec![]);
+
+            session
+                .try_request(frame)
+                .and_then(move |(session, result)| {
+                    let result = result.and_then(script::check_response);
+                    let schema_changed = result.as_ref()
+                        .map(script::is_schema_change)
+                        .unwrap_or(false);
+                    let failed = result.is_err();
+                    outcomes.push(StatementOutcome::new(index, &statement, result));
+
+                    if failed && options.on_error == OnError::Stop {
+                        return future::ok(Loop::Break((session, outcomes))).boxed();
+                    }
+                    if schema_changed && options.schema_agreement {
+                        let timeout = options.schema_agreement_timeout;
+                        let agreement = session.await_schema_agreement_within(timeout);
+                        let step = agreement.map(move |(session, agreed)| {
+                            if !agreed {
+                                // the statement was applied, but nodes may not know it yet
+                                let failed = error::Error::SchemaDisagreement { waited: timeout };
+                                if let Some(outcome) = outcomes.last_mut() {
+                                    outcome.result = Err(failed);
+                                }
+                                if options.on_error == OnError::Stop {
+                                    return Loop::Break((session, outcomes));
+                                }
+                            }
+                            Loop::Continue((session, statements, outcomes))
+                        });
+                        return step.boxed();
+                    }
+                    future::ok(Loop::Continue((session, statements, outcomes))).boxed()
+                })
+                .boxed()
+        })
+                .boxed()
+    }
+
+    /// Compares schema versions of nodes until all of them are the same, at most
+    /// `attempts` times, every `schema_agreement_interval`. Resolves into `false`
+    /// if nodes didn't agree, a failed check counts as a disagreement.
+    pub fn await_schema_agreement(self, attempts: usize) -> CDRSFuture<(Self, bool)>
+        where T: Send
+    {
+        future::loop_fn((self, 0), move |(session, attempt)| {
+            if attempt >= attempts {
+                return future::ok(Loop::Break((session, false))).boxed();
+            }
+
+            let check = if attempt == 0 {
+                session.check_schema_agreement()
+            } else {
+                Delay::new(Instant::now() + session.schema_agreement_interval)
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err).into())
+                    .and_then(move |_| session.check_schema_agreement())
+                    .boxed()
+            };
+            check.map(move |(session, agreed)| if agreed {
+                          Loop::Break((session, true))
+                      } else {
+                          Loop::Continue((session, attempt + 1))
+                      })
+                .boxed()
+        })
+                .boxed()
+    }
+
+    /// Compares schema versions of nodes every `schema_agreement_interval` until
+    /// all of them are the same or `timeout` elapses, the reactor is free between
+    /// checks. Nodes which report no version are not reachable and not compared.
+    /// Resolves into `false` if nodes didn't agree in time.
+    pub fn await_schema_agreement_within(self, timeout: Duration) -> CDRSFuture<(Self, bool)>
+        where T: Send
+    {
+        let deadline = Instant::now() + timeout;
+        future::loop_fn(self, move |session| {
+            session.check_schema_agreement()
+                .and_then(move |(session, agreed)| {
+                    let now = Instant::now();
+                    if agreed || now >= deadline {
+                        return future::ok(Loop::Break((session, agreed))).boxed();
+                    }
+
+                    let next_check = cmp::min(now + session.schema_agreement_interval, deadline);
+                    Delay::new(next_check)
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err).into())
+                        .map(move |_| Loop::Continue(session))
+                        .boxed()
+                })
+        })
+                .boxed()
+    }
+
+    /// Runs a statement which changes schema, e.g. `CREATE TABLE`, and waits up to
+    /// `timeout` until nodes agree on schema, see `await_schema_agreement_within`.
+    /// Resolves into whether they agreed, a server error fails the future.
+    pub fn query_ddl<Q>(self, query: Q, timeout: Duration) -> CDRSFuture<(Self, bool)>
+        where T: Send,
+              Q: Into<Statement<Query>>
+    {
+        self.query_with(query, RequestOptions::new())
+            .and_then(|(session, response)| {
+                          script::check_response(response).map(|response| (session, response))
+                      })
+            .and_then(move |(session, response)| if script::is_schema_change(&response) {
+                          session.await_schema_agreement_within(timeout)
+                      } else {
+                          future::ok((session, true)).boxed()
+                      })
+            .boxed()
+    }
+
+    /// Checks once whether every node reports the same schema version, a failed
+    /// check counts as a disagreement.
+    fn check_schema_agreement(self) -> CDRSFuture<(Self, bool)>
+        where T: Send
+    {
+        let local = QueryBuilder::new(script::SELECT_LOCAL_SCHEMA).finalize();
+        self.try_request(query_frame(local, vec![]))
+            .and_then(|(session, local)| {
+                let peers = QueryBuilder::new(script::SELECT_PEERS_SCHEMA).finalize();
+                session.try_request(query_frame(peers, vec![]))
+                    .map(move |(session, peers)| {
+                        let versions = (local.and_then(script::schema_versions),
+                                        peers.and_then(script::schema_versions));
+                        let agreed = match versions {
+                            (Ok(mut versions), Ok(peers)) => {
+                                versions.extend(peers);
+                                versions.windows(2).all(|pair| pair[0] == pair[1])
+                            }
+                            _ => false,
+                        };
+                        (session, agreed)
+                    })
+            })
+            .boxed()
+    }
+
+    /// Reads a trace of a request from `system_traces`. A trace is written after
+    /// the response, so an incomplete one is read again every `TRACE_INTERVAL_MS`,
+    /// at most `TRACE_ATTEMPTS` times, then the future fails with `Error::NotFound`.
+    pub fn get_tracing_info(self, tracing_id: Uuid) -> CDRSFuture<(Self, TracingInfo)>
+        where T: Send
+    {
+        future::loop_fn((self, 1), move |(session, attempt)| {
+            let select = QueryBuilder::new(tracing::SELECT_TRACE_SESSION)
+                .values(vec![tracing::session_id(&tracing_id)])
+                .finalize();
+            session.query_with(select, RequestOptions::new())
+                .and_then(move |(session, response)| match tracing::trace_session(response) {
+                    Ok(Some(info)) => future::ok(Loop::Break((session, info))).boxed(),
+                    Ok(None) if attempt < tracing::TRACE_ATTEMPTS => {
+                        let interval = Duration::from_millis(tracing::TRACE_INTERVAL_MS);
+                        Delay::new(Instant::now() + interval)
+                            .map_err(|err| io::Error::new(io::ErrorKind::Other, err).into())
+                            .map(move |_| Loop::Continue((session, attempt + 1)))
+                            .boxed()
+                    }
+                    Ok(None) => {
+                        let missing = format!("Trace {}", tracing_id);
+                        future::err(error::Error::NotFound(missing)).boxed()
+                    }
+                    Err(err) => future::err(err).boxed(),
+                })
+        })
+                .and_then(move |(session, mut info)| {
+                    let select = QueryBuilder::new(tracing::SELECT_TRACE_EVENTS)
+                        .values(vec![tracing::session_id(&tracing_id)])
+                        .finalize();
+                    session.query_with(select, RequestOptions::new())
+                        .and_then(move |(session, response)| {
+                                      info.events = try!(tracing::trace_events(response));
+                                      Ok((session, info))
+                                  })
+                })
+                .boxed()
+    }
+
+    /// Reads structure of a table from `system_schema`. Fails with `Error::NotFound`
+    /// if there is no such table.
+    pub fn describe_table(self, keyspace: &str, table: &str) -> CDRSFuture<(Self, TableMetadata)>
+        where T: Send
+    {
+        let query = QueryBuilder::new(schema::SELECT_COLUMNS)
+            .values(vec![Value::from(keyspace.to_string()), Value::from(table.to_string())])
+            .finalize();
+        let keyspace = keyspace.to_string();
+        let table = table.to_string();
+
+        self.query_all_into::<SchemaColumn, _>(query)
+            .and_then(move |(session, columns)| {
+                          TableMetadata::from_columns(&keyspace, &table, columns)
+                              .map(|metadata| (session, metadata))
+                      })
+            .boxed()
+    }
+
+    /// Lists names of keyspaces in alphabetical order. Keyspaces managed by a server
+    /// itself are listed only if `include_system` is `true`.
+    pub fn keyspaces(self, include_system: bool) -> CDRSFuture<(Self, Vec<String>)>
+        where T: Send
+    {
+        let query = QueryBuilder::new(schema::SELECT_KEYSPACES).finalize();
+
+        self.query_all(query)
+            .and_then(move |(session, rows)| {
+                let mut keyspaces = try!(schema::sorted_names(rows, "keyspace_name"));
+                if !include_system {
+                    keyspaces.retain(|keyspace| !schema::is_system_keyspace(keyspace));
+                }
+                Ok((session, keyspaces))
+            })
+            .boxed()
+    }
+
+    /// Lists names of tables of a keyspace in alphabetical order.
+    /// Nonexistent keyspace has no tables.
+    pub fn tables(self, keyspace: &str) -> CDRSFuture<(Self, Vec<String>)>
+        where T: Send
+    {
+        let query = QueryBuilder::new(schema::SELECT_TABLES)
+            .values(vec![Value::from(keyspace.to_string())])
+            .finalize();
+
+        self.query_all(query)
+            .and_then(|(session, rows)| {
+                          schema::sorted_names(rows, "table_name").map(|tables| (session, tables))
+                      })
+            .boxed()
+    }
+
+    /// Reads a whole table splitting it into `splits` token ranges of nearly equal size.
+    /// See `scan_ranges`.
+    pub fn scan(self, query: ScanQuery, splits: usize) -> CDRSStream<Row>
+        where T: Send
+    {
+        self.scan_ranges(query, scan::split_even(splits))
+    }
+
+    /// Reads a whole table with a query per token range. Each range is read
+    /// page by page and rows of all ranges are yielded as a single stream.
+    ///
+    /// Queries of a session share one connection, so ranges are read one
+    /// after another, `Cluster::scan` reads several of them at once.
+    /// The stream takes the session and closes it once dropped.
+    pub fn scan_ranges(self,
+                       query: ScanQuery,
+                       ranges: Vec<TokenRange>)
+                       -> CDRSStream<Row>
+        where T: Send
+    {
+        // scans leave consistency to the session
+        let pending: VecDeque<Query> = ranges.iter()
+            .map(|range| self.with_defaults(Statement::new(query.query_for(range))))
+            .collect();
+
+        stream::unfold((self, pending), |(session, mut pending)| {
+            let mut query = match pending.pop_front() {
+                Some(query) => query,
+                None => return None,
+            };
+            let page_frame = query_frame(session.page_query(&query), vec![]);
+
+            Some(session.request(page_frame).and_then(move |(mut session, frame)| {
+                let page_bytes = frame.body.len();
+                let page = try!(Page::from_frame(frame));
+                session.page_sizing.observe(page_bytes, page.rows.len());
+
+                if let Some(paging_state) = page.paging_state {
+                    query.paging_state = Some(paging_state.into());
+                    pending.push_front(query);
+                }
+                let rows = page.rows.into_iter().map(Ok::<Row, error::Error>);
+                Ok((stream::iter(rows), (session, pending)))
+            }))
+        })
+                .flatten()
+                .boxed()
+    }
+
+    /// Sends a request frame and resolves into a response along with the session
+    /// itself, so requests which take several round trips could be chained.
+    /// The session is dropped if the request fails, see `try_request`.
+    pub fn request(self, frame: Frame) -> CDRSFuture<(Self, Frame)>
+        where T: Send
+    {
+        self.try_request(frame)
+            .and_then(|(session, result)| result.map(|frame| (session, frame)))
+            .boxed()
+    }
+
+    /// Sends a hand-built frame and resolves into a response as it is. Server errors
+    /// are not turned into `Err`, a caller has to check the opcode. The frame gets
+    /// a stream id of the session, which overrides its own one, and is compressed
+    /// according to the session's settings. A frame longer than the connection
+    /// accepts fails with `FrameTooLarge` before it's written, see `CDRS::max_body_len`.
+    pub fn send_frame(mut self, mut frame: Frame) -> CDRSFuture<(Self, Frame)>
+        where T: Send
+    {
+        frame.stream = self.next_stream as _;
+        // ids are non-negative, negative ones are reserved for events
+        self.next_stream = self.next_stream.checked_add(1).unwrap_or(0);
+
+        self.request(frame)
+    }
+
+    /// Sends OPTIONS and reads SUPPORTED to check that the connection is alive,
+    /// e.g. an idle one behind a firewall which drops idle connections. No response
+    /// in `timeout` fails the check and closes the connection. The session is given
+    /// back whatever the result is and the returned future itself never fails.
+    pub fn heartbeat(self, timeout: Duration) -> CDRSFuture<(Self, error::Result<()>)>
+        where T: Send
+    {
+        self.try_request_with(Frame::new_req_options(), Override::Auto, Some(timeout))
+            .map(|(session, result)| {
+                     let result = result.and_then(resolve_supported_ops).map(|_| ());
+                     (session, result)
+                 })
+            .boxed()
+    }
+
+    /// Asks the server which options it supports, e.g. CQL and protocol versions.
+    pub fn options(self) -> CDRSFuture<(Self, SupportedOptions)>
+        where T: Send
+    {
+        self.request(Frame::new_req_options())
+            .and_then(|(session, frame)| {
+                          resolve_supported_ops(frame).map(|options| (session, options))
+                      })
+            .boxed()
+    }
+
+    /// Learns sharding parameters of a Scylla node and the shard the connection
+    /// is served by from its SUPPORTED options, see `shard_info`.
+    pub fn detect_shard(self) -> CDRSFuture<Self>
+        where T: Send
+    {
+        self.options()
+            .map(|(mut session, options)| {
+                     session.shard_info = ShardInfo::from_options(options.raw());
+                     session
+                 })
+            .boxed()
+    }
+
+    /// Sharding parameters of a Scylla node along with the shard of the session.
+    /// It's `None` until they are detected or if the node is not a Scylla one.
+    pub fn shard_info(&self) -> Option<ShardInfo> {
+        self.shard_info
+    }
+
+    /// Works as `request` but gives the session back when the request fails as well.
+    /// The returned future itself never fails.
+    pub fn try_request(self, frame: Frame) -> CDRSFuture<(Self, error::Result<Frame>)>
+        where T: Send
+    {
+        self.try_request_with(frame, Override::Auto, None)
+    }
+
+    fn try_request_with(mut self,
+                        frame: Frame,
+                        compression: Override,
+                        timeout: Option<Duration>)
+                        -> CDRSFuture<(Self, error::Result<Frame>)>
+        where T: Send
+    {
+        let timeout = timeout.or(self.request_timeout);
+        let mut deadline = timeout.map(|timeout| Delay::new(Instant::now() + timeout));
+        let expectation = Expectation::response_to(&frame, &self.compressor);
+        let mut observed = self.metrics_observer.clone().map(|observer| {
+            Observed::start(observer, &frame.opcode, self.cdrs_mut().bytes_transferred())
+        });
+        let compressor = self.compressor;
+        if let Err(err) = self.cdrs_mut().queue_frame_with(frame, &compressor, compression) {
+            if let Some(observed) = observed {
+                observed.finish(self.cdrs_mut().bytes_transferred(), Err(&err));
+            }
+            return future::ok((self, Err(err))).boxed();
+        }
+
+        let mut session = Some(self);
+        future::poll_fn(move || {
+                let result = {
+                    let session = session.as_mut().expect("response frame has been read already");
+                    let cdrs = session.cdrs.as_mut().expect("session is a listener");
+                    let result = match cdrs.poll_response(&session.compressor, &expectation) {
+                        Ok(Async::Ready(frame)) => {
+                            if let Some(ref handler) = session.warnings_handler {
+                                if !frame.warnings.is_empty() {
+                                    handler(&frame.warnings);
+                                }
+                            }
+                            Ok(frame)
+                        }
+                        Ok(Async::NotReady) => {
+                            let expired = match deadline.as_mut().map(|deadline| deadline.poll()) {
+                                None |
+                                Some(Ok(Async::NotReady)) => return Ok(Async::NotReady),
+                                Some(Ok(Async::Ready(()))) => {
+                                    Err(error::Error::RequestTimeout(timeout.unwrap()))
+                                }
+                                Some(Err(err)) => {
+                                    Err(io::Error::new(io::ErrorKind::Other, err).into())
+                                }
+                            };
+                            // a late response would be read as a response to the next request
+                            let _ = cdrs.drop_connection();
+                            expired
+                        }
+                        Err(err) => Err(err),
+                    };
+                    if let Some(observed) = observed.take() {
+                        observed.finish(cdrs.bytes_transferred(), result.as_ref());
+                    }
+                    result
+                };
+
+                Ok(Async::Ready((session.take().unwrap(), result)))
+            })
+            .boxed()
+    }
+
+    /// Turns the session into a multiplexer which sends requests concurrently on
+    /// its connection. The returned dispatcher reads responses, it has to be
+    /// spawned on a reactor, see `multiplex`.
+    pub fn multiplex(mut self) -> (Multiplexer<T, X>, Dispatcher<T, X>) {
+        let cdrs = self.cdrs.take().expect("session is a listener");
+        self.started = false;
+        let (mut multiplexer, dispatcher) = multiplex::new(cdrs, self.compressor, self.next_stream);
+        if let Some(observer) = self.metrics_observer.clone() {
+            multiplexer.metrics_observer(observer);
+        }
+        (multiplexer, dispatcher)
+    }
+
+    /// It consumes CDRS
+    pub fn listen_for(mut self,
+                      events: Vec<SimpleServerEvent>)
+                      -> CDRSFuture<(Listener<X>, EventStream)>
+        where T: Send
+    {
+        let query_frame = Frame::new_req_register(events);
+        let expectation = Expectation::response_to(&query_frame, &self.compressor);
+
+        let compressor = self.compressor;
+        let mut cdrs = self.cdrs.take().expect("session is a listener");
+        self.started = false;
+        if let Err(err) = cdrs.queue_frame(query_frame, &compressor) {
+            return future::err(err).boxed();
+        }
+
+        cdrs.read_response(compressor, expectation)
+            .map(|(cdrs, _)| new_listener(cdrs.transport))
+            .boxed()
+
+    }
+
+    /// Registers for `events` and turns the connection into a stream of them which
+    /// is read on a reactor, no thread is blocked waiting for events. The stream
+    /// fails once the connection is lost.
+    pub fn listen_for_async(mut self,
+                            events: Vec<SimpleServerEvent>)
+                            -> CDRSFuture<CDRSStream<ServerEvent>>
+        where T: Send
+    {
+        let register_frame = Frame::new_req_register(events);
+        let expectation = Expectation::response_to(&register_frame, &self.compressor);
+
+        let compressor = self.compressor;
+        let mut cdrs = self.cdrs.take().expect("session is a listener");
+        self.started = false;
+        if let Err(err) = cdrs.queue_frame(register_frame, &compressor) {
+            return future::err(err).boxed();
+        }
+
+        cdrs.read_response(compressor, expectation)
+            .and_then(move |(cdrs, response)| if response.opcode == Opcode::Ready {
+                          Ok(event_stream(cdrs, compressor))
+                      } else {
+                          Err(unexpected_response("REGISTER", response))
+                      })
+            .boxed()
+    }
+
+    /// Registers `listener`, another connection to the same cluster, for schema
+    /// changes and passes them to the prepared cache of the session, so statements
+    /// of changed tables get fresh result metadata. It has to be spawned on a reactor
+    /// and runs until the connection of the listener ends.
+    pub fn follow_schema_changes(&self, listener: Session<T, X>) -> CDRSFuture<()>
+        where T: Send
+    {
+        let caches = PreparedCaches::new();
+        caches.add(&self.prepared_cache);
+        listener.listen_for_async(vec![SimpleServerEvent::SchemaChange])
+            .and_then(move |events| prepared::follow_schema_events(vec![caches], events))
+            .boxed()
+    }
+
+    fn cdrs_mut(&mut self) -> &mut CDRS<T, X> {
+        self.cdrs.as_mut().expect("session is a listener")
+    }
+}
+
+/// A request reported to a metrics observer, see `Session::metrics_observer`.
+struct Observed {
+    observer: SharedObserver,
+    token: RequestToken,
+    started: Instant,
+    transferred: (u64, u64),
+}
+
+impl Observed {
+    fn start(observer: SharedObserver, opcode: &Opcode, transferred: (u64, u64)) -> Observed {
+        let token = observer.on_request_start(opcode);
+        Observed {
+            observer: observer,
+            token: token,
+            started: Instant::now(),
+            transferred: transferred,
+        }
+    }
+
+    fn finish(self, transferred: (u64, u64), result: Result<&Frame, &error::Error>) {
+        self.observer.on_bytes((transferred.0 - self.transferred.0) as usize,
+                               (transferred.1 - self.transferred.1) as usize);
+        self.observer.on_request_complete(self.token, result, self.started.elapsed());
+    }
+}
+
+// `Drop` can't have the `'static` bounds of other methods of a session
+impl<T: Authenticator, X: CDRSTransport> Session<T, X> {
+    /// Manually ends current session.
+    /// Apart of that session will be ended automatically when the instance is dropped.
+    pub fn end(&mut self) {
+        if self.started {
+            self.started = false;
+            if let Some(cdrs) = self.cdrs.as_mut() {
+                if let Err(err) = cdrs.drop_connection() {
+                    warn!("Error occured during dropping CDRS {:?}", err);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Authenticator, X: CDRSTransport> Drop for Session<T, X> {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+/// Stream of events a connection registered for receives.
+fn event_stream<T, X>(mut cdrs: CDRS<T, X>, compressor: Compression) -> CDRSStream<ServerEvent>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    let expectation = Expectation {
+        stream: EVENT_STREAM_ID,
+        compression: compressor != Compression::None,
+        tracing: false,
+        multiplexed: false,
+    };
+
+    stream::poll_fn(move || {
+            let frame = try_ready!(cdrs.poll_response(&compressor, &expectation));
+            match try!(frame.get_body()) {
+                ResponseBody::Event(event) => Ok(Async::Ready(Some(event.event))),
+                _ => Err(unexpected_response("EVENT stream", frame)),
+            }
+        })
+        .boxed()
+}
+
+/// Builds a QUERY frame of `query` with given flags.
+pub fn query_frame(query: Query, flags: Vec<Flag>) -> Frame {
+    // cdrs neither writes a page size nor flags a paging state, so parameters
+    // of the query are serialized here
+    let mut query_flags = 0;
+    if query.values.is_some() {
+        query_flags = QueryFlags::set_value(query_flags);
+    }
+    if query.with_names.unwrap_or(false) {
+        query_flags = QueryFlags::set_with_names_for_values(query_flags);
+    }
+    if query.page_size.is_some() {
+        query_flags = QueryFlags::set_page_size(query_flags);
+    }
+    if query.paging_state.is_some() {
+        query_flags = QueryFlags::set_with_paging_state(query_flags);
+    }
+    if query.serial_consistency.is_some() {
+        query_flags = QueryFlags::set_with_serial_consistency(query_flags);
+    }
+    if query.timestamp.is_some() {
+        query_flags = QueryFlags::set_with_default_timestamp(query_flags);
+    }
+
+    let mut body = CStringLong::new(query.query).into_cbytes();
+    body.extend(query.consistency.into_cbytes());
+    body.push(query_flags);
+    if let Some(values) = query.values {
+        body.extend(to_short(values.len() as i16));
+        for value in values {
+            body.extend(value.into_cbytes());
+        }
+    }
+    if let Some(page_size) = query.page_size {
+        body.extend(to_int(page_size));
+    }
+    if let Some(paging_state) = query.paging_state {
+        body.extend(paging_state.into_cbytes());
+    }
+    if let Some(serial_consistency) = query.serial_consistency {
+        body.extend(serial_consistency.into_cbytes());
+    }
+    if let Some(timestamp) = query.timestamp {
+        body.extend(to_bigint(timestamp));
+    }
+
+    Frame {
+        version: Version::Request,
+        flags: flags,
+        stream: 0,
+        opcode: Opcode::Query,
+        body: body,
+        tracing_id: None,
+        warnings: vec![],
+    }
+}
+
+/// Copies `query`, which cdrs doesn't make `Clone`, to send it once more.
+pub fn clone_query(query: &Query) -> Query {
+    Query {
+        query: query.query.clone(),
+        consistency: query.consistency.clone(),
+        values: query.values.clone(),
+        with_names: query.with_names,
+        page_size: query.page_size,
+        paging_state: query.paging_state.clone(),
+        serial_consistency: query.serial_consistency.clone(),
+        timestamp: query.timestamp,
+    }
+}
+
+/// Turns a response a handshake doesn't expect into an error. An ERROR response
+/// becomes the server error it carries.
+pub fn unexpected_response(request: &str, response: Frame) -> error::Error {
+    let opcode = format!("{:?}", response.opcode);
+    match script::check_response(response) {
+        Err(err) => err,
+        Ok(_) => error::Error::General(format!("Unexpected response to {}: {}", request, opcode)),
+    }
+}
+
+fn resolve_supported_ops(frame: Frame) -> Result<SupportedOptions, error::Error> {
+    match frame.get_body() {
+        Ok(ResponseBody::Supported(supported_body)) => Ok(supported_body.data.into()),
+        _ => Err(unexpected_response("OPTIONS", frame)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use futures::Future;
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::compression::Compression;
+    use cdrs::types::CBytes;
+
+    use super::*;
+    use error::ProtocolViolation;
+    use mock::{self, MockTransport, WriteStep, ERROR, STARTUP, READY, AUTHENTICATE, OPTIONS,
+               SUPPORTED, QUERY, RESULT, PREPARE, EXECUTE, EVENT, AUTH_CHALLENGE, AUTH_RESPONSE,
+               AUTH_SUCCESS};
+
+    #[test]
+    fn start_survives_spurious_io_errors() {
+        let transport = MockTransport::new();
+        transport.push_write(WriteStep::Error(io::ErrorKind::Interrupted));
+        transport.push_write(WriteStep::Accept(0));
+        transport.push_write(WriteStep::Accept(4));
+        transport.push_read_error(io::ErrorKind::Interrupted);
+        transport.push_read(mock::response(READY, 0, &[]));
+
+        let session = CDRS::new(transport.clone(), NoneAuthenticator)
+            .start(Compression::None)
+            .wait()
+            .unwrap();
+
+        assert!(session.started);
+        let startup = Frame::new_req_startup(None).into_cbytes();
+        assert_eq!(transport.written(), startup);
+    }
+
+    #[test]
+    fn start_fails_on_genuine_errors() {
+        let transport = MockTransport::new();
+        transport.push_read_error(io::ErrorKind::ConnectionReset);
+
+        let result = CDRS::new(transport, NoneAuthenticator)
+            .start(Compression::None)
+            .wait();
+
+        match result {
+            Err(error::Error::Io(ref err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionReset)
+            }
+            _ => panic!("Connection reset expected"),
+        }
+    }
+
+    #[test]
+    fn start_fails_on_server_errors() {
+        let transport = MockTransport::new();
+        let unsupported = mock::error_body(0x000A, "Unsupported compression: snappy");
+        transport.push_read(mock::response(ERROR, 0, &unsupported));
+        match CDRS::new(transport, NoneAuthenticator).start(Compression::Snappy).wait() {
+            Err(error::Error::Server { ref error, .. }) => {
+                assert_eq!(*error, error::ServerError::Protocol)
+            }
+            other => panic!("server error expected, got {:?}", other.map(|_| ())),
+        }
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(SUPPORTED, 0, &mock::supported_body(&[])));
+        match CDRS::new(transport, NoneAuthenticator).start(Compression::None).wait() {
+            Err(error::Error::General(ref message)) => assert!(message.contains("Supported")),
+            other => panic!("general error expected, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    const PASSWORD_AUTHENTICATOR: &'static str = "org.apache.cassandra.auth.PasswordAuthenticator";
+
+    /// Body of an AUTHENTICATE frame which asks for authenticator `class`.
+    fn authenticate_body(class: &str) -> Vec<u8> {
+        let mut body = vec![0, class.len() as u8];
+        body.extend_from_slice(class.as_bytes());
+        body
+    }
+
+    #[test]
+    fn start_wipes_credentials() {
+        use cdrs::authenticators::PasswordAuthenticator;
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(AUTHENTICATE,
+                                           0,
+                                           &authenticate_body(PASSWORD_AUTHENTICATOR)));
+        transport.push_read(mock::response(AUTH_SUCCESS, 0, &[0, 0, 0, 0]));
+
+        let session = CDRS::new(transport.clone(), PasswordAuthenticator::new("user", "secret"))
+            .start(Compression::None)
+            .wait()
+            .unwrap();
+
+        assert!(transport.written().windows(6).any(|w| w == b"secret"));
+        assert!(session.cdrs.as_ref().unwrap().writer.allocated().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn start_authenticates_with_password() {
+        use cdrs::authenticators::PasswordAuthenticator;
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(AUTHENTICATE,
+                                           0,
+                                           &authenticate_body(PASSWORD_AUTHENTICATOR)));
+        transport.push_read(mock::response(AUTH_SUCCESS, 0, &[0xFF, 0xFF, 0xFF, 0xFF]));
+        CDRS::new(transport.clone(), PasswordAuthenticator::new("user", "secret"))
+            .start(Compression::None)
+            .wait()
+            .unwrap();
+        assert_eq!(mock::opcodes(&transport.written()), vec![STARTUP, AUTH_RESPONSE]);
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(AUTHENTICATE,
+                                           0,
+                                           &authenticate_body(PASSWORD_AUTHENTICATOR)));
+        let bad_credentials = mock::error_body(0x0100, "Provided username and/or password are \
+                                                        incorrect");
+        transport.push_read(mock::response(ERROR, 0, &bad_credentials));
+        match CDRS::new(transport, PasswordAuthenticator::new("user", "wrong"))
+                  .start(Compression::None)
+                  .wait() {
+            Err(error::Error::Server { ref error, .. }) => {
+                assert_eq!(*error, error::ServerError::BadCredentials)
+            }
+            other => panic!("authentication error expected, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn start_fails_on_authenticator_mismatch() {
+        use cdrs::authenticators::PasswordAuthenticator;
+
+        const KERBEROS: &'static str = "com.datastax.bdp.cassandra.auth.KerberosAuthenticator";
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(AUTHENTICATE, 0, &authenticate_body(KERBEROS)));
+        match CDRS::new(transport.clone(), PasswordAuthenticator::new("user", "secret"))
+                  .start(Compression::None)
+                  .wait() {
+            Err(error::Error::AuthenticatorMismatch { ref server, ref client }) => {
+                assert_eq!(server, KERBEROS);
+                assert_eq!(client, PASSWORD_AUTHENTICATOR);
+            }
+            other => panic!("authenticator mismatch expected, got {:?}", other.map(|_| ())),
+        }
+        // credentials are never sent to a server which expects another mechanism
+        assert_eq!(mock::opcodes(&transport.written()), vec![STARTUP]);
+    }
+
+    #[test]
+    fn start_without_credentials_fails_on_required_authentication() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(AUTHENTICATE,
+                                           0,
+                                           &authenticate_body(PASSWORD_AUTHENTICATOR)));
+        match CDRS::new(transport.clone(), NoneAuthenticator).start(Compression::None).wait() {
+            Err(error::Error::AuthenticationRequired { ref server }) => {
+                assert_eq!(server, PASSWORD_AUTHENTICATOR)
+            }
+            other => panic!("authentication required expected, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(mock::opcodes(&transport.written()), vec![STARTUP]);
+    }
+
+    #[test]
+    fn start_with_unneeded_credentials() {
+        use cdrs::authenticators::PasswordAuthenticator;
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(READY, 0, &[]));
+        CDRS::new(transport.clone(), PasswordAuthenticator::new("user", "secret"))
+            .start(Compression::None)
+            .wait()
+            .unwrap();
+        assert_eq!(mock::opcodes(&transport.written()), vec![STARTUP]);
+        assert!(!transport.written().windows(6).any(|w| w == b"secret"));
+    }
+
+    #[test]
+    fn start_negotiated_picks_best_compression() {
+        let negotiate = |algorithms: Option<&[&str]>| {
+            let transport = MockTransport::new();
+            let supported = match algorithms {
+                Some(algorithms) => mock::supported_body(&[(handshake::COMPRESSION, algorithms)]),
+                None => mock::supported_body(&[("CQL_VERSION", &["3.4.4"])]),
+            };
+            transport.push_read(mock::response(SUPPORTED, 0, &supported));
+            transport.push_read(mock::response(READY, 0, &[]));
+            let session = CDRS::new(transport, NoneAuthenticator)
+                .start_negotiated()
+                .wait()
+                .unwrap();
+            session.compressor
+        };
+
+        assert_eq!(negotiate(Some(&["snappy", "lz4"])), Compression::Lz4);
+        assert_eq!(negotiate(Some(&["snappy"])), Compression::Snappy);
+        assert_eq!(negotiate(Some(&[])), Compression::None);
+        assert_eq!(negotiate(None), Compression::None);
+    }
+
+    #[test]
+    fn session_reports_supported_options() {
+        use supported::{COMPRESSION, CQL_VERSION, PROTOCOL_VERSIONS};
+
+        let transport = MockTransport::new();
+        let supported = mock::supported_body(&[(CQL_VERSION, &["3.4.4"]),
+                                               (COMPRESSION, &["snappy", "lz4", "zstd"]),
+                                               (PROTOCOL_VERSIONS, &["3/v3", "4/v4"]),
+                                               ("SCYLLA_SHARD", &["1"])]);
+        transport.push_read(mock::response(SUPPORTED, 0, &supported));
+
+        let (_, options) = mock::session(transport.clone()).options().wait().unwrap();
+        assert_eq!(options.cql_versions(), &["3.4.4"]);
+        assert_eq!(options.compressions, vec![Compression::Snappy, Compression::Lz4]);
+        assert_eq!(options.protocol_versions(), &["3/v3", "4/v4"]);
+        assert_eq!(options.values("SCYLLA_SHARD"), &["1"]);
+        assert_eq!(mock::opcodes(&transport.written()), vec![OPTIONS]);
+    }
+
+    #[test]
+    fn supported_rejects_unexpected_response() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(READY, 0, &[]));
+
+        match CDRS::new(transport, NoneAuthenticator).supported().wait() {
+            Err(error::Error::General(message)) => {
+                assert_eq!(message, "Unexpected response to OPTIONS: Ready")
+            }
+            other => panic!("unexpected result: {:?}", other.map(|(_, options)| options)),
+        }
+    }
+
+    #[test]
+    fn session_detects_shard_of_scylla_node() {
+        use scylla::{SCYLLA_NR_SHARDS, SCYLLA_SHARD, SCYLLA_SHARD_AWARE_PORT};
+
+        let transport = MockTransport::new();
+        let supported = mock::supported_body(&[(SCYLLA_SHARD, &["2"]),
+                                               (SCYLLA_NR_SHARDS, &["4"]),
+                                               (SCYLLA_SHARD_AWARE_PORT, &["19042"])]);
+        transport.push_read(mock::response(SUPPORTED, 0, &supported));
+        transport.push_read(mock::response(SUPPORTED, 0, &mock::supported_body(&[])));
+
+        let session = mock::session(transport.clone());
+        assert_eq!(session.shard_info(), None);
+        let session = session.detect_shard().wait().unwrap();
+        let info = session.shard_info().unwrap();
+        assert_eq!((info.shard, info.nr_shards, info.shard_aware_port),
+                   (2, 4, Some(19042)));
+
+        // a node which is not a Scylla one
+        let session = session.detect_shard().wait().unwrap();
+        assert_eq!(session.shard_info(), None);
+    }
+
+    const TWO_ROUNDS: &'static str = "com.example.TwoRoundsAuthenticator";
+
+    /// Authenticator of a mechanism which takes two rounds.
+    #[derive(Clone)]
+    struct TwoRounds;
+
+    impl Authenticator for TwoRounds {
+        fn get_auth_token(&self) -> CBytes {
+            CBytes::new(b"client-first".to_vec())
+        }
+
+        fn get_cassandra_name(&self) -> Option<&str> {
+            Some(TWO_ROUNDS)
+        }
+    }
+
+    impl SaslAuthenticator for TwoRounds {
+        fn evaluate_challenge(&mut self, challenge: Option<&[u8]>) -> error::Result<Vec<u8>> {
+            assert_eq!(challenge, Some(&b"server-nonce"[..]));
+            Ok(b"client-proof".to_vec())
+        }
+    }
+
+    #[test]
+    fn start_answers_challenges() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(AUTHENTICATE, 0, &authenticate_body(TWO_ROUNDS)));
+        let challenge = auth::token_bytes(b"server-nonce");
+        transport.push_read(mock::response(AUTH_CHALLENGE, 0, &challenge));
+        transport.push_read(mock::response(AUTH_SUCCESS, 0, &auth::token_bytes(b"done")));
+
+        CDRS::new(transport.clone(), TwoRounds)
+            .start(Compression::None)
+            .wait()
+            .unwrap();
+
+        let written = transport.written();
+        assert_eq!(mock::opcodes(&written), vec![STARTUP, AUTH_RESPONSE, AUTH_RESPONSE]);
+        assert!(written.ends_with(&auth::token_bytes(b"client-proof")));
+
+        // a password authenticator doesn't answer challenges
+        use cdrs::authenticators::PasswordAuthenticator;
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(AUTHENTICATE,
+                                           0,
+                                           &authenticate_body(PASSWORD_AUTHENTICATOR)));
+        transport.push_read(mock::response(AUTH_CHALLENGE, 0, &auth::token_bytes(b"nonce")));
+        assert!(CDRS::new(transport, PasswordAuthenticator::new("user", "secret"))
+                    .start(Compression::None)
+                    .wait()
+                    .is_err());
+    }
+
+    #[test]
+    fn debug_hides_credentials() {
+        use cdrs::authenticators::PasswordAuthenticator;
+
+        let cdrs = CDRS::new(MockTransport::new(), PasswordAuthenticator::new("user", "hunter2"));
+        let session = Session::start(cdrs);
+
+        let debug = format!("{:?}", session);
+        assert!(debug.contains("PasswordAuthenticator"), "{}", debug);
+        assert!(!debug.contains("hunter2") && !debug.contains("user"), "{}", debug);
+    }
+
+    fn ids_page(ids: &[i32], paging_state: Option<&[u8]>) -> Vec<u8> {
+        let rows: Vec<_> = ids.iter().map(|id| vec![mock::int(*id)]).collect();
+        mock::response(RESULT,
+                       0,
+                       &mock::rows_body(&[("id", mock::INT)], &rows, paging_state))
+    }
+
+    #[test]
+    fn query_rows_unwraps_results() {
+        use cdrs::query::QueryBuilder;
+        use cdrs::types::IntoRustByName;
+
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[1, 2], None));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(0x2200, "no table t")));
+
+        let (session, rows) = mock::session(transport)
+            .query_rows(QueryBuilder::new("SELECT id FROM t").finalize())
+            .wait()
+            .unwrap();
+        let ids: Vec<i32> = rows.iter()
+            .map(|row| row.get_by_name("id").unwrap().unwrap())
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+
+        let (session, rows) = session.query_rows(QueryBuilder::new("INSERT INTO t (id) VALUES (3)")
+                                                     .finalize())
+            .wait()
+            .unwrap();
+        assert!(rows.is_empty());
+
+        match session.query_rows(QueryBuilder::new("SELECT id FROM t").finalize()).wait() {
+            Err(error::Error::Server { ref error, .. }) => {
+                assert_eq!(*error, error::ServerError::Invalid)
+            }
+            other => panic!("server error expected, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn execute_rows_unwraps_results() {
+        use cdrs::query::QueryParamsBuilder;
+        use cdrs::consistency::Consistency;
+
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[7], None));
+
+        let id = CBytesShort::new(b"stmt".to_vec());
+        let params = QueryParamsBuilder::new(Consistency::One).finalize();
+        let (_, rows) = mock::session(transport).execute_rows(&id, params).wait().unwrap();
+        assert_eq!(rows.len(), 1);
+    }
+
+    #[test]
+    fn execute_cached_prepares_again_on_unprepared() {
+        use cdrs::consistency::Consistency;
+
+        const SELECT: &'static str = "SELECT id FROM t";
+
+        let transport = MockTransport::new();
+        let prepared = mock::prepared_body(b"old", &[], &[("id", mock::INT)]);
+        transport.push_read(mock::response(RESULT, 0, &prepared));
+        transport.push_read(ids_page(&[1], None));
+
+        let (session, _) = mock::session(transport.clone())
+            .execute_cached(SELECT.to_string(), vec![], Consistency::One)
+            .wait()
+            .unwrap();
+        // the statement is taken from the cache
+        transport.push_read(ids_page(&[1], None));
+        let (session, _) = session.execute_cached(SELECT.to_string(), vec![], Consistency::One)
+            .wait()
+            .unwrap();
+        assert_eq!(mock::opcodes(&transport.written()), vec![PREPARE, EXECUTE, EXECUTE]);
+
+        // the server forgot the statement
+        let mut unprepared = mock::error_body(0x2500, "Prepared query with ID 6f6c64 not found");
+        unprepared.extend_from_slice(&[0, 3]);
+        unprepared.extend_from_slice(b"old");
+        transport.push_read(mock::response(ERROR, 0, &unprepared));
+        let prepared = mock::prepared_body(b"new", &[], &[("id", mock::INT)]);
+        transport.push_read(mock::response(RESULT, 0, &prepared));
+        transport.push_read(ids_page(&[2], None));
+        let (session, frame) = session.execute_cached(SELECT.to_string(), vec![], Consistency::One)
+            .wait()
+            .unwrap();
+
+        assert_eq!(Page::from_frame(frame).unwrap().rows.len(), 1);
+        assert_eq!(mock::opcodes(&transport.written()),
+                   vec![PREPARE, EXECUTE, EXECUTE, EXECUTE, PREPARE, EXECUTE]);
+        let cache = session.prepared_cache();
+        let id = cache.lock().unwrap().id(SELECT).unwrap();
+        assert_eq!(id.into_plain(), b"new".to_vec());
+    }
+
+    #[test]
+    fn exec_with_values_binds_by_name() {
+        use cdrs::types::value::Value;
+
+        const INSERT: &'static str = "INSERT INTO t (id, name) VALUES (?, ?)";
+
+        let markers = [("id", mock::INT), ("name", mock::VARCHAR)];
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::prepared_body(b"ins", &markers, &[])));
+        for _ in 0..3 {
+            transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        }
+
+        let named = vec![("name".to_string(), Value::from("bob".to_string())),
+                         ("id".to_string(), Value::from(2))];
+        let (session, _) = mock::session(transport.clone())
+            .exec_with_values_with_names(INSERT, named)
+            .wait()
+            .unwrap();
+        let (session, _) = session.exec_with_values(INSERT,
+                                                    vec![Value::from(2),
+                                                         Value::from("bob".to_string())])
+            .wait()
+            .unwrap();
+        let (session, _) = session.exec_with_values(INSERT, (2, "bob")).wait().unwrap();
+        let written = transport.written();
+        assert_eq!(mock::opcodes(&written)[1..].to_vec(), vec![EXECUTE, EXECUTE, EXECUTE]);
+        // named values are bound in order of markers, tuples are bound as they are
+        let prepare_len = Frame::new_req_prepare(INSERT.to_string(), vec![]).into_cbytes().len();
+        let executions: Vec<_> = written[prepare_len..].chunks((written.len() - prepare_len) / 3)
+            .collect();
+        assert_eq!(executions[0], executions[1]);
+        assert_eq!(executions[1], executions[2]);
+
+        let unknown = vec![("id".to_string(), Value::from(2)),
+                           ("nick".to_string(), Value::from("bob".to_string()))];
+        match session.exec_with_values_with_names(INSERT, unknown).wait() {
+            Err(error::Error::Stage { stage, ref error }) => {
+                assert_eq!(stage, "bind");
+                assert_eq!(error.to_string(),
+                           "General error: No value is bound to marker `name`")
+            }
+            other => panic!("bind error expected, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn executes_with_named_values() {
+        use cdrs::types::value::Value;
+
+        const SELECT: &'static str = "SELECT name FROM users \
+                                      WHERE org = :org AND id = :id AND age > :age";
+
+        let markers = [("org", mock::VARCHAR), ("id", mock::INT), ("age", mock::INT)];
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::prepared_body(b"sel",
+                                                                &markers,
+                                                                &[("name", mock::VARCHAR)])));
+        let columns = [("name", mock::VARCHAR)];
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::rows_body(&columns,
+                                                            &[vec![mock::text("alice")]],
+                                                            None)));
+
+        let mut named = HashMap::new();
+        named.insert("age".to_string(), Value::new_null());
+        named.insert("id".to_string(), Value::from(7));
+        named.insert("org".to_string(), Value::from("acme".to_string()));
+        let (session, frame) = mock::session(transport.clone())
+            .execute_named(SELECT, named, Consistency::Quorum)
+            .wait()
+            .unwrap();
+        let page = Page::from_frame(frame).unwrap();
+        assert_eq!(page.rows.len(), 1);
+
+        let written = transport.written();
+        assert_eq!(mock::opcodes(&written), vec![PREPARE, EXECUTE]);
+        let prepare_len = Frame::new_req_prepare(SELECT.to_string(), vec![]).into_cbytes().len();
+        // id of the statement, QUORUM, flags of values with names and three values
+        let mut expected = vec![0, 3, b's', b'e', b'l', 0, 4, 0x41, 0, 3];
+        expected.extend_from_slice(&[0, 3, b'o', b'r', b'g', 0, 0, 0, 4, b'a', b'c', b'm', b'e']);
+        expected.extend_from_slice(&[0, 2, b'i', b'd', 0, 0, 0, 4, 0, 0, 0, 7]);
+        expected.extend_from_slice(&[0, 3, b'a', b'g', b'e', 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(&written[prepare_len + codec::HEADER_LEN..], &expected[..]);
+
+        // the statement is cached, a wrong name fails it before anything is sent
+        let mut wrong = HashMap::new();
+        wrong.insert("org".to_string(), Value::from("acme".to_string()));
+        wrong.insert("id".to_string(), Value::from(7));
+        wrong.insert("agee".to_string(), Value::from(30));
+        match session.execute_named(SELECT, wrong, Consistency::One).wait() {
+            Err(err) => {
+                assert_eq!(err.to_string(), "General error: No value is bound to marker `age`")
+            }
+            Ok(_) => panic!("unknown name has to fail"),
+        }
+        assert_eq!(transport.written().len(), written.len());
+    }
+
+    #[test]
+    fn queries_with_named_values() {
+        use cdrs::types::value::Value;
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let mut named = HashMap::new();
+        named.insert("name".to_string(), Value::from("bob".to_string()));
+        named.insert("id".to_string(), Value::from(2));
+        let insert = "INSERT INTO users (id, name) VALUES (:id, :name)";
+        mock::session(transport.clone())
+            .query_named(insert, named, Consistency::One)
+            .wait()
+            .unwrap();
+
+        let mut expected = vec![0, 0, 0, insert.len() as u8];
+        expected.extend_from_slice(insert.as_bytes());
+        expected.extend_from_slice(&[0, 1, 0x41, 0, 2]);
+        expected.extend_from_slice(&[0, 2, b'i', b'd', 0, 0, 0, 4, 0, 0, 0, 2]);
+        expected.extend_from_slice(&[0, 4, b'n', b'a', b'm', b'e', 0, 0, 0, 3, b'b', b'o', b'b']);
+        assert_eq!(&transport.written()[codec::HEADER_LEN..], &expected[..]);
+    }
+
+    #[test]
+    fn writes_whole_frames_on_short_writes() {
+        let insert = "INSERT INTO users (id, name) VALUES (2, 'bob')";
+        let sent = |accepted: Option<usize>| {
+            let transport = MockTransport::new();
+            if let Some(accepted) = accepted {
+                for _ in 0..200 {
+                    transport.push_write(WriteStep::Accept(accepted));
+                }
+            }
+            transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+            mock::session(transport.clone()).query_named(insert, HashMap::new(), Consistency::One)
+                .wait()
+                .unwrap();
+            transport.written()
+        };
+
+        let frame = sent(None);
+        assert!(frame.len() > codec::HEADER_LEN + insert.len());
+        assert_eq!(sent(Some(1)), frame);
+        assert_eq!(sent(Some(3)), frame);
+    }
+
+    fn sent_with_defaults<F, R>(defaults: bool, send: F) -> Vec<u8>
+        where F: FnOnce(Session<NoneAuthenticator, MockTransport>)
+                        -> CDRSFuture<(Session<NoneAuthenticator, MockTransport>, R)>
+    {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        let mut session = mock::session(transport.clone());
+        if defaults {
+            session.set_default_consistency(Consistency::LocalQuorum)
+                .set_default_serial_consistency(Consistency::LocalSerial);
+        }
+        send(session).wait().unwrap();
+        transport.written()[codec::HEADER_LEN..].to_vec()
+    }
+
+    #[test]
+    fn applies_default_consistency() {
+        use cdrs::query::QueryParamsBuilder;
+
+        const SELECT: &'static str = "SELECT * FROM t";
+        let select = || QueryBuilder::new(SELECT).finalize();
+        let defaulted = || Statement::new(select());
+        // consistency, flags and serial consistency follow the query
+        let levels = |body: Vec<u8>| body[4 + SELECT.len()..].to_vec();
+
+        let body = sent_with_defaults(true, |session| session.query(defaulted(), false, false));
+        assert_eq!(levels(body), vec![0, 6, 0x10, 0, 9]);
+
+        let overridden = defaulted().consistency(Consistency::One);
+        let body = sent_with_defaults(true, |session| session.query(overridden, false, false));
+        assert_eq!(levels(body), vec![0, 1, 0x10, 0, 9]);
+
+        // a query passed as it is keeps its consistency and serial consistency
+        let mut explicit = select();
+        explicit.serial_consistency = Some(Consistency::Serial);
+        let body = sent_with_defaults(true, |session| session.query(explicit, false, false));
+        assert_eq!(levels(body), vec![0, 1, 0x10, 0, 8]);
+
+        let body = sent_with_defaults(false, |session| session.query(defaulted(), false, false));
+        assert_eq!(levels(body), vec![0, 1, 0x00]);
+
+        let params = QueryParamsBuilder::new(Consistency::One).finalize();
+        let id = CBytesShort::new(b"id".to_vec());
+        let body = sent_with_defaults(true, |session| {
+            session.execute(&id, Statement::new(params), false, false)
+        });
+        assert_eq!(body, vec![0, 2, b'i', b'd', 0, 6, 0x10, 0, 9]);
+
+        let update = batch::BatchBuilder::unlogged()
+            .add_query("UPDATE t SET v = 1 WHERE k = 1", ())
+            .finalize()
+            .unwrap();
+        let update = Statement::new(update);
+        let body = sent_with_defaults(true, |session| session.batch(update, false, false));
+        assert_eq!(body[body.len() - 5..].to_vec(), vec![0, 6, 0x10, 0, 9]);
+    }
+
+    #[test]
+    fn applies_default_consistency_to_paged_queries() {
+        const SELECT: &'static str = "SELECT * FROM t";
+        let defaulted = || Statement::new(QueryBuilder::new(SELECT).finalize());
+        // consistency follows the query
+        let consistency = |body: Vec<u8>| body[4 + SELECT.len()..6 + SELECT.len()].to_vec();
+
+        let body = sent_with_defaults(true, |session| session.query_all(defaulted()));
+        assert_eq!(consistency(body), vec![0, 6]);
+        let body = sent_with_defaults(true, |session| session.query_page(defaulted(), 10, None));
+        assert_eq!(consistency(body), vec![0, 6]);
+        let body = sent_with_defaults(true, |session| session.query_one(defaulted(), false));
+        assert_eq!(consistency(body), vec![0, 6]);
+        let body = sent_with_defaults(true, |session| session.query_value::<i32, _>(defaulted()));
+        assert_eq!(consistency(body), vec![0, 6]);
+
+        let mut explicit = QueryBuilder::new(SELECT).finalize();
+        explicit.consistency = Consistency::Two;
+        let body = sent_with_defaults(true, |session| session.query_all(explicit));
+        assert_eq!(consistency(body), vec![0, 2]);
+    }
+
+    #[test]
+    fn generates_timestamps_of_requests() {
+        use timestamp::MonotonicTimestampGenerator;
+
+        const SELECT: &'static str = "SELECT * FROM t";
+        let transport = MockTransport::new();
+        for _ in 0..3 {
+            transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        }
+        let mut session = mock::session(transport.clone());
+        session.timestamp_generator(MonotonicTimestampGenerator::new());
+
+        let mut explicit = QueryBuilder::new(SELECT).finalize();
+        explicit.timestamp = Some(42);
+        let (session, _) = session.query(QueryBuilder::new(SELECT).finalize(), false, false)
+            .wait()
+            .unwrap();
+        let (session, _) = session.query(QueryBuilder::new(SELECT).finalize(), false, false)
+            .wait()
+            .unwrap();
+        session.query(explicit, false, false).wait().unwrap();
+
+        // ONE, a flag of the default timestamp and the timestamp
+        let query_len = codec::HEADER_LEN + 4 + SELECT.len() + 3 + 8;
+        let written = transport.written();
+        let timestamps: Vec<_> = written.chunks(query_len)
+            .map(|query| {
+                     assert_eq!(query[query_len - 9], 0x20);
+                     query[query_len - 8..].iter().fold(0, |t, &byte| (t << 8) | byte as i64)
+                 })
+            .collect();
+        assert_eq!(timestamps.len(), 3);
+        assert!(timestamps[0] < timestamps[1]);
+        assert_eq!(timestamps[2], 42);
+    }
+
+    #[test]
+    fn applies_defaults_to_cached_executions() {
+        use timestamp::MonotonicTimestampGenerator;
+
+        const SELECT: &'static str = "SELECT id FROM t";
+        let transport = MockTransport::new();
+        let prepared = mock::prepared_body(b"sel", &[], &[("id", mock::INT)]);
+        transport.push_read(mock::response(RESULT, 0, &prepared));
+        transport.push_read(ids_page(&[1], None));
+        transport.push_read(ids_page(&[1], None));
+        let mut session = mock::session(transport.clone());
+        session.set_default_consistency(Consistency::LocalQuorum)
+            .timestamp_generator(MonotonicTimestampGenerator::new());
+
+        let (session, _) = session.exec_with_values(SELECT, Vec::<Value>::new()).wait().unwrap();
+        session.execute_cached(SELECT.to_string(), vec![], Consistency::One).wait().unwrap();
+
+        let prepare_len = Frame::new_req_prepare(SELECT.to_string(), vec![]).into_cbytes().len();
+        let written = transport.written()[prepare_len..].to_vec();
+        let executions: Vec<_> = written.chunks(written.len() / 2).collect();
+        // consistency and flags follow the id, the timestamp is the last
+        let offset = codec::HEADER_LEN + 2 + 3;
+        let timestamp = |execution: &[u8]| {
+            execution[execution.len() - 8..].iter().fold(0, |t, &byte| (t << 8) | byte as i64)
+        };
+        assert_eq!(&executions[0][offset..offset + 2], &[0, 6]);
+        assert_eq!(&executions[1][offset..offset + 2], &[0, 1]);
+        assert!(executions.iter().all(|execution| execution[offset + 2] & 0x20 != 0));
+        assert!(timestamp(executions[0]) < timestamp(executions[1]));
+    }
+
+    /// Records starts and completions of requests.
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<(&'static str, u64)>>,
+    }
+
+    impl ::metrics::SessionMetricsObserver for RecordingObserver {
+        fn on_request_start(&self, _opcode: &Opcode) -> RequestToken {
+            let mut events = self.events.lock().unwrap();
+            let token = events.len() as u64;
+            events.push(("start", token));
+            RequestToken(token)
+        }
+
+        fn on_request_complete(&self,
+                               token: RequestToken,
+                               result: Result<&Frame, &error::Error>,
+                               _latency: Duration) {
+            let outcome = match result {
+                Ok(frame) if frame.opcode == Opcode::Error => "server error",
+                Ok(_) => "response",
+                Err(_) => "error",
+            };
+            self.events.lock().unwrap().push((outcome, token.0));
+        }
+
+        fn on_bytes(&self, _written: usize, _read: usize) {}
+    }
+
+    #[test]
+    fn observes_every_request_once() {
+        use metrics::CountingObserver;
+        use request::Override;
+
+        let select = || QueryBuilder::new("SELECT * FROM t").finalize();
+        let responses = vec![mock::response(RESULT, 0, &mock::void_body()),
+                             mock::response(ERROR, 0, &mock::error_body(0x2200, "bad query"))];
+        let transport = MockTransport::new();
+        for response in &responses {
+            transport.push_read(response.clone());
+        }
+        let recording = Arc::new(RecordingObserver::default());
+        let counting = Arc::new(CountingObserver::new());
+        let mut session = mock::session(transport.clone());
+        session.metrics_observer(recording.clone());
+
+        let (mut session, _) = session.query(select(), false, false).wait().unwrap();
+        session.metrics_observer(counting.clone());
+        let (session, _) = session.query(select(), false, false).wait().unwrap();
+        let forced = RequestOptions::new().compression(Override::ForceOn);
+        assert!(session.query_with(select(), forced).wait().is_err());
+
+        assert_eq!(*recording.events.lock().unwrap(), vec![("start", 0), ("response", 0)]);
+        assert_eq!((counting.started(), counting.completed(), counting.failed()), (2, 1, 1));
+        assert_eq!(counting.server_errors(), 1);
+        assert_eq!(counting.bytes_written(), transport.written().len() / 2);
+        assert_eq!(counting.bytes_read(), responses[1].len());
+    }
+
+    #[test]
+    fn exec_with_values_reports_failed_stage() {
+        use cdrs::types::value::Value;
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(0x2000, "line 1:0 no")));
+        match mock::session(transport).exec_with_values("SELEC 1", vec![]).wait() {
+            Err(error::Error::Stage { stage, .. }) => assert_eq!(stage, "prepare"),
+            other => panic!("prepare error expected, got {:?}", other.map(|_| ())),
+        }
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::prepared_body(b"s", &[("id", mock::INT)], &[])));
+        let overloaded = mock::error_body(0x1001, "overloaded");
+        transport.push_read(mock::response(ERROR, 0, &overloaded));
+        match mock::session(transport)
+                  .exec_with_values("SELECT id FROM t WHERE id = ?", vec![Value::from(1)])
+                  .wait() {
+            Err(err @ error::Error::Stage { stage: "execute", .. }) => {
+                assert!(err.to_string().starts_with("execute failed: "), "{}", err)
+            }
+            other => panic!("execute error expected, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn request_timeout_closes_connection() {
+        use tokio_core::reactor::Core;
+        use cdrs::query::QueryBuilder;
+
+        let mut core = Core::new().unwrap();
+        let transport = MockTransport::new();
+        let mut silent = mock::session(transport.clone());
+        silent.request_timeout(Some(Duration::from_millis(50)));
+
+        let started = Instant::now();
+        let select = QueryBuilder::new("SELECT * FROM t").finalize();
+        match core.run(silent.query(clone_query(&select), false, false)) {
+            Err(error::Error::RequestTimeout(timeout)) => {
+                assert_eq!(timeout, Duration::from_millis(50))
+            }
+            other => panic!("RequestTimeout expected, got {:?}", other.map(|_| ())),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(transport.is_closed());
+
+        // a timeout of a request overrides the one of a session
+        let transport = MockTransport::new();
+        let options = RequestOptions::new().timeout(Duration::from_millis(20));
+        match core.run(mock::session(transport.clone()).query_with(select, options)) {
+            Err(err @ error::Error::RequestTimeout(_)) => assert!(err.breaks_connection()),
+            other => panic!("RequestTimeout expected, got {:?}", other.map(|_| ())),
+        }
+        assert!(transport.is_closed());
+    }
+
+    #[test]
+    fn dropped_session_closes_connection() {
+        let transport = MockTransport::new();
+        drop(mock::session(transport.clone()));
+        assert!(transport.is_closed());
+
+        // ended sessions are not closed once more
+        let transport = MockTransport::new();
+        let mut ended = mock::session(transport.clone());
+        ended.end();
+        drop(ended);
+        assert_eq!(transport.closes(), 1);
+    }
+
+    #[test]
+    fn query_all_concatenates_pages() {
+        use cdrs::query::QueryBuilder;
+        use cdrs::types::IntoRustByName;
+
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[1, 2], Some(b"p1")));
+        transport.push_read(ids_page(&[3, 4], Some(b"p2")));
+        transport.push_read(ids_page(&[5], None));
+
+        let mut session = mock::session(transport.clone());
+        session.page_sizing(PageSizing::Fixed(2));
+        let (_, rows) = session
+            .query_all(QueryBuilder::new("SELECT id FROM t").finalize())
+            .wait()
+            .unwrap();
+
+        let ids: Vec<i32> = rows.iter()
+            .map(|row| row.get_by_name("id").unwrap().unwrap())
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+        assert_eq!(mock::page_sizes(&transport.written()), vec![Some(2); 3]);
+    }
+
+    #[test]
+    fn query_all_keeps_page_size_of_query() {
+        use cdrs::query::QueryBuilder;
+
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[1, 2, 3], Some(b"p1")));
+        transport.push_read(ids_page(&[4], None));
+
+        let mut session = mock::session(transport.clone());
+        session.page_sizing(PageSizing::Fixed(2));
+        let mut query = QueryBuilder::new("SELECT id FROM t").finalize();
+        query.page_size = Some(3);
+        let (_, rows) = session.query_all(query).wait().unwrap();
+
+        assert_eq!(rows.len(), 4);
+        assert_eq!(mock::page_sizes(&transport.written()), vec![Some(3), Some(3)]);
+    }
+
+    #[test]
+    fn query_stream_follows_paging_state() {
+        use futures::Stream;
+        use cdrs::query::QueryBuilder;
+        use cdrs::types::IntoRustByName;
+
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[1, 2], Some(b"p1")));
+        transport.push_read(ids_page(&[3, 4], Some(b"p2")));
+        transport.push_read(ids_page(&[], None));
+
+        let mut query = QueryBuilder::new("SELECT id FROM t").finalize();
+        query.page_size = Some(2);
+        let ids: Vec<i32> = mock::session(transport.clone())
+            .query_stream(query)
+            .map(|row| row.get_by_name("id").unwrap().unwrap())
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+
+        let written = transport.written();
+        assert_eq!(mock::opcodes(&written), vec![QUERY, QUERY, QUERY]);
+        assert!(written.windows(2).any(|w| w == b"p1"));
+        assert!(written.windows(2).any(|w| w == b"p2"));
+    }
+
+    #[test]
+    fn query_stream_adapts_page_size() {
+        use futures::Stream;
+        use cdrs::query::QueryBuilder;
+        use paging::AdaptivePageSize;
+
+        let first = ids_page(&[1, 2], Some(b"p1"));
+        let mut expected = AdaptivePageSize::new(100, 1000, 1, 1000);
+        expected.observe(first.len() - 9, 2);
+
+        let transport = MockTransport::new();
+        transport.push_read(first);
+        transport.push_read(ids_page(&[3], None));
+
+        let mut session = mock::session(transport.clone());
+        session.page_sizing(PageSizing::Adaptive(AdaptivePageSize::new(100, 1000, 1, 1000)));
+        let rows = session
+            .query_stream(QueryBuilder::new("SELECT id FROM t").finalize())
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(rows.len(), 3);
+        assert_ne!(expected.page_size(), 100);
+        assert_eq!(mock::page_sizes(&transport.written()),
+                   vec![Some(100), Some(expected.page_size())]);
+    }
+
+    #[test]
+    fn query_page_resumes_from_serialized_state() {
+        use cdrs::query::QueryBuilder;
+        use cdrs::types::IntoRustByName;
+
+        let query = || QueryBuilder::new("SELECT id FROM t").finalize();
+        let ids = |page: &Page| -> Vec<i32> {
+            page.rows.iter().map(|row| row.get_by_name("id").unwrap().unwrap()).collect()
+        };
+
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[1, 2], Some(b"p1")));
+        let (_, page) = mock::session(transport.clone())
+            .query_page(query(), 2, None)
+            .wait()
+            .unwrap();
+        assert_eq!(ids(&page), vec![1, 2]);
+        assert!(page.more_pages);
+        let cursor = page.paging_state.unwrap().to_string();
+        assert!(!transport.written().windows(2).any(|w| w == b"p1"));
+
+        // the next pages are requested by other sessions
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[3, 4], Some(b"p2")));
+        transport.push_read(ids_page(&[5], None));
+        let paging_state = cursor.parse::<PagingState>().unwrap();
+        assert_eq!(paging_state.as_bytes(), b"p1");
+        let (session, page) = mock::session(transport.clone())
+            .query_page(query(), 2, Some(paging_state))
+            .wait()
+            .unwrap();
+        assert_eq!(ids(&page), vec![3, 4]);
+        assert!(transport.written().windows(2).any(|w| w == b"p1"));
+
+        let (_, page) = session.query_page(query(), 2, page.paging_state).wait().unwrap();
+        assert_eq!(ids(&page), vec![5]);
+        assert!(!page.more_pages);
+        assert_eq!(page.paging_state, None);
+        assert!(transport.written().windows(2).any(|w| w == b"p2"));
+    }
+
+    #[test]
+    fn query_page_fails_with_stale_state() {
+        use cdrs::query::QueryBuilder;
+
+        let transport = MockTransport::new();
+        let invalid = mock::error_body(0x000A, "Invalid value for the paging state");
+        transport.push_read(mock::response(ERROR, 0, &invalid));
+        let paging_state = "c3RhbGU".parse().unwrap();
+        let result = mock::session(transport)
+            .query_page(QueryBuilder::new("SELECT id FROM t").finalize(), 2, Some(paging_state))
+            .wait();
+        match result {
+            Err(error::Error::Server { ref error, ref message }) => {
+                assert_eq!(*error, error::ServerError::Protocol);
+                assert!(message.contains("paging state"));
+            }
+            other => panic!("server error expected, got {:?}", other.map(|(_, page)| page.rows)),
+        }
+    }
+
+    #[test]
+    fn query_all_enforces_max_rows() {
+        use cdrs::query::QueryBuilder;
+
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[1, 2], Some(b"p1")));
+        transport.push_read(ids_page(&[3, 4], Some(b"p2")));
+        transport.push_read(ids_page(&[5], None));
+
+        let mut session = mock::session(transport);
+        session.page_sizing(PageSizing::Fixed(2)).max_rows(3);
+        let result = session
+            .query_all(QueryBuilder::new("SELECT id FROM t").finalize())
+            .wait();
+
+        match result {
+            Err(error::Error::TooManyRows { max_rows: 3 }) => (),
+            other => panic!("TooManyRows expected, got {:?}", other.map(|(_, rows)| rows.len())),
+        }
+    }
+
+    fn query_one(transport: MockTransport, strict: bool) -> error::Result<Option<i32>> {
+        use cdrs::query::QueryBuilder;
+        use cdrs::types::IntoRustByName;
+
+        mock::session(transport)
+            .query_one(QueryBuilder::new("SELECT id FROM t").finalize(), strict)
+            .wait()
+            .map(|(_, row)| row.map(|row| row.get_by_name("id").unwrap().unwrap()))
+    }
+
+    #[test]
+    fn query_one_returns_first_row() {
+        for &strict in &[false, true] {
+            let transport = MockTransport::new();
+            transport.push_read(ids_page(&[], None));
+            assert_eq!(query_one(transport, strict).unwrap(), None);
+
+            let transport = MockTransport::new();
+            transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+            assert_eq!(query_one(transport, strict).unwrap(), None);
+
+            let transport = MockTransport::new();
+            transport.push_read(ids_page(&[1], None));
+            assert_eq!(query_one(transport, strict).unwrap(), Some(1));
+        }
+
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[], Some(b"p1")));
+        transport.push_read(ids_page(&[2], Some(b"p2")));
+        assert_eq!(query_one(transport, false).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn query_one_counts_rows_when_strict() {
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[1, 2], Some(b"p1")));
+        assert_eq!(query_one(transport, false).unwrap(), Some(1));
+
+        // the second row fails the query without paging further
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[1, 2], Some(b"p1")));
+        transport.push_read(ids_page(&[3], None));
+        match query_one(transport.clone(), true) {
+            Err(error::Error::UnexpectedRows { rows: 2 }) => (),
+            other => panic!("UnexpectedRows expected, got {:?}", other),
+        }
+        assert_eq!(mock::opcodes(&transport.written()), vec![QUERY]);
+
+        let transport = MockTransport::new();
+        transport.push_read(ids_page(&[1], Some(b"p1")));
+        transport.push_read(ids_page(&[2], Some(b"p2")));
+        transport.push_read(ids_page(&[3], None));
+        match query_one(transport.clone(), true) {
+            Err(error::Error::UnexpectedRows { rows: 2 }) => (),
+            other => panic!("UnexpectedRows expected, got {:?}", other),
+        }
+        assert_eq!(mock::opcodes(&transport.written()), vec![QUERY, QUERY]);
+    }
+
+    fn query_value<V>(body: Vec<u8>) -> error::Result<Option<V>>
+        where V: Send + 'static,
+              Row: IntoRustByName<V>
+    {
+        use cdrs::query::QueryBuilder;
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &body));
+        mock::session(transport)
+            .query_value(QueryBuilder::new("SELECT count(*) FROM t").finalize())
+            .wait()
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn query_value_extracts_scalar() {
+        let count = mock::rows_body(&[("count", mock::BIGINT)], &[vec![mock::bigint(42)]], None);
+        assert_eq!(query_value::<i64>(count).unwrap(), Some(42));
+
+        let version = mock::rows_body(&[("release_version", mock::VARCHAR)],
+                                      &[vec![mock::text("3.11.2")]],
+                                      None);
+        assert_eq!(query_value::<String>(version).unwrap(),
+                   Some("3.11.2".to_string()));
+
+        let null = mock::rows_body(&[("count", mock::BIGINT)], &[vec![None]], None);
+        assert_eq!(query_value::<i64>(null).unwrap(), None);
+
+        let empty = mock::rows_body(&[("count", mock::BIGINT)], &[], None);
+        assert_eq!(query_value::<i64>(empty).unwrap(), None);
+
+        let many = mock::rows_body(&[("id", mock::INT)],
+                                   &[vec![mock::int(1)], vec![mock::int(2)]],
+                                   None);
+        assert_eq!(query_value::<i32>(many).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn query_value_follows_empty_pages() {
+        use cdrs::query::QueryBuilder;
+
+        let columns = [("count", mock::BIGINT)];
+        let transport = MockTransport::new();
+        let first = mock::rows_body(&columns, &[], Some(b"p1"));
+        let second = mock::rows_body(&columns, &[vec![mock::bigint(42)]], Some(b"p2"));
+        transport.push_read(mock::response(RESULT, 0, &first));
+        transport.push_read(mock::response(RESULT, 0, &second));
+
+        let (_, value) = mock::session(transport.clone())
+            .query_value::<i64, _>(QueryBuilder::new("SELECT count(*) FROM t").finalize())
+            .wait()
+            .unwrap();
+        assert_eq!(value, Some(42));
+
+        let written = transport.written();
+        assert_eq!(mock::opcodes(&written), vec![QUERY, QUERY]);
+        assert!(written.windows(2).any(|w| w == b"p1"));
+    }
+
+    #[test]
+    fn query_value_requires_single_column() {
+        let body = mock::rows_body(&[("id", mock::INT), ("name", mock::VARCHAR)],
+                                   &[vec![mock::int(1), mock::text("a")]],
+                                   None);
+        let err = query_value::<i32>(body).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "Query returned 2 columns [id, name], but a single one was expected");
+    }
+
+    #[test]
+    fn query_value_reports_conversion_failure() {
+        let body = mock::rows_body(&[("name", mock::VARCHAR)], &[vec![mock::text("a")]], None);
+        match query_value::<i64>(body) {
+            Err(error::Error::Conversion { ref column, .. }) => assert_eq!(column, "name"),
+            other => panic!("Conversion error expected, got {:?}", other),
+        }
+    }
+
+    fn users_session() -> Session<NoneAuthenticator, MockTransport> {
+        let columns = [("id", mock::INT), ("name", mock::VARCHAR), ("age", mock::INT)];
+        let rows = [vec![mock::int(1), mock::text("alice"), mock::int(30)],
+                    vec![mock::int(2), mock::text("bob"), None]];
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::rows_body(&columns, &rows, None)));
+        mock::session(transport)
+    }
+
+    #[test]
+    fn query_as_maps_rows_into_tuples() {
+        use cdrs::query::QueryBuilder;
+
+        let select = || QueryBuilder::new("SELECT id, name, age FROM users").finalize();
+        let (_, users) = users_session()
+            .query_as::<(i32, String, Option<i32>), _>(select())
+            .wait()
+            .unwrap();
+        assert_eq!(users,
+                   vec![(1, "alice".to_string(), Some(30)), (2, "bob".to_string(), None)]);
+
+        let (_, user) = users_session()
+            .query_one_as::<(i32, String, Option<i32>), _>(select())
+            .wait()
+            .unwrap();
+        assert_eq!(user, Some((1, "alice".to_string(), Some(30))));
+
+        // null of a column which is not read into an `Option`
+        match users_session().query_as::<(i32, String, i32), _>(select()).wait() {
+            Err(error::Error::Conversion { ref column, ref reason, .. }) => {
+                assert_eq!((column.as_str(), reason.as_str()), ("age", "null"))
+            }
+            other => panic!("Conversion error expected, got {:?}", other.map(|(_, rows)| rows)),
+        }
+
+        let err = users_session()
+            .query_as::<(i32, i64, Option<i32>), _>(select())
+            .wait()
+            .unwrap_err();
+        assert_eq!(err.to_string(),
+                   "Cannot convert column `name` into field `1`: expected i64, column is Varchar");
+
+        assert!(users_session().query_as::<(i32, String), _>(select()).wait().is_err());
+    }
+
+    #[test]
+    fn query_to_csv_writes_every_page() {
+        use cdrs::query::QueryBuilder;
+
+        let columns = [("id", mock::INT), ("name", mock::VARCHAR)];
+        let first = vec![vec![mock::int(1), mock::text("Smith, John")]];
+        let second = vec![vec![mock::int(2), mock::text("say \"hi\"\nbye")],
+                          vec![mock::int(3), None]];
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::rows_body(&columns, &first, Some(b"p1"))));
+        transport.push_read(mock::response(RESULT, 0, &mock::rows_body(&columns, &second, None)));
+
+        let (_, (csv, rows)) = mock::session(transport)
+            .query_to_csv(QueryBuilder::new("SELECT id, name FROM t").finalize(),
+                          vec![],
+                          CsvOptions::default())
+            .wait()
+            .unwrap();
+
+        assert_eq!(rows, 3);
+        assert_eq!(String::from_utf8(csv).unwrap(),
+                   "id,name\r\n1,\"Smith, John\"\r\n2,\"say \"\"hi\"\"\nbye\"\r\n3,\r\n");
+    }
+
+    fn schema_version(version: u8) -> Vec<u8> {
+        let rows = vec![vec![Some(vec![version; 16])]];
+        mock::response(RESULT,
+                       0,
+                       &mock::rows_body(&[("schema_version", mock::UUID)], &rows, None))
+    }
+
+    const SCRIPT: &'static str = "CREATE TABLE t (id int PRIMARY KEY);\n\
+                                  INSERT INTO t (id) VALUES ('one');\n\
+                                  INSERT INTO t (id) VALUES (2);";
+
+    #[test]
+    fn execute_script_waits_for_schema_and_stops_on_error() {
+        use tokio_core::reactor::Core;
+
+        let mut core = Core::new().unwrap();
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::schema_change_body("CREATED", "ks", "t")));
+        // peers lag behind once
+        transport.push_read(schema_version(1));
+        transport.push_read(schema_version(0));
+        transport.push_read(schema_version(1));
+        transport.push_read(schema_version(1));
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(0x2200, "Invalid")));
+
+        let mut session = mock::session(transport.clone());
+        session.schema_agreement_interval(Duration::from_millis(10));
+        let (_, outcomes) = core.run(session.execute_script(SCRIPT, ScriptOptions::default()))
+            .unwrap();
+
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes[0].is_ok());
+        assert_eq!(outcomes[1].index, 1);
+        assert_eq!(outcomes[1].preview, "INSERT INTO t (id) VALUES ('one')");
+        assert!(!outcomes[1].is_ok());
+        // CREATE, two rounds of schema checks, INSERT
+        assert_eq!(mock::opcodes(&transport.written()).len(), 6);
+    }
+
+    #[test]
+    fn execute_script_continues_after_error() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::schema_change_body("CREATED", "ks", "t")));
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(0x2200, "Invalid")));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let options = ScriptOptions {
+            on_error: OnError::Continue,
+            schema_agreement: false,
+            ..ScriptOptions::default()
+        };
+        let (_, outcomes) = mock::session(transport)
+            .execute_script(SCRIPT, options)
+            .wait()
+            .unwrap();
+
+        let ok: Vec<_> = outcomes.iter().map(|outcome| outcome.is_ok()).collect();
+        assert_eq!(ok, vec![true, false, true]);
+    }
+
+    #[test]
+    fn execute_script_fails_on_schema_disagreement() {
+        use tokio_core::reactor::Core;
+
+        let mut core = Core::new().unwrap();
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::schema_change_body("CREATED", "ks", "t")));
+        for _ in 0..10 {
+            transport.push_read(schema_version(1));
+            transport.push_read(schema_version(0));
+        }
+
+        let mut session = mock::session(transport.clone());
+        session.schema_agreement_interval(Duration::from_millis(10));
+        let options = ScriptOptions {
+            schema_agreement_timeout: Duration::from_millis(30),
+            ..ScriptOptions::default()
+        };
+        let (_, outcomes) = core.run(session.execute_script(SCRIPT, options)).unwrap();
+
+        assert_eq!(outcomes.len(), 1);
+        match outcomes[0].result {
+            Err(error::Error::SchemaDisagreement { waited }) => {
+                assert_eq!(waited, Duration::from_millis(30))
+            }
+            ref other => panic!("unexpected outcome {:?}", other),
+        }
+    }
+
+    #[test]
+    fn awaits_schema_agreement_between_attempts() {
+        use tokio_core::reactor::Core;
+
+        let mut core = Core::new().unwrap();
+        let transport = MockTransport::new();
+        for _ in 0..3 {
+            transport.push_read(schema_version(1));
+            transport.push_read(schema_version(0));
+        }
+
+        let mut session = mock::session(transport.clone());
+        session.schema_agreement_interval(Duration::from_millis(20));
+        let started = Instant::now();
+        let (_, agreed) = core.run(session.await_schema_agreement(3)).unwrap();
+        assert!(!agreed);
+        // an interval between every two of three checks
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(mock::opcodes(&transport.written()).len(), 6);
+    }
+
+    #[test]
+    fn query_ddl_polls_schema_agreement() {
+        use tokio_core::reactor::Core;
+        use cdrs::query::QueryBuilder;
+
+        let mut core = Core::new().unwrap();
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::schema_change_body("CREATED", "ks", "t")));
+        for &(local, peers) in &[(1, 0), (1, 1)] {
+            transport.push_read(schema_version(local));
+            transport.push_read(schema_version(peers));
+        }
+
+        let mut ddl = mock::session(transport.clone());
+        ddl.schema_agreement_interval(Duration::from_millis(30));
+        let started = Instant::now();
+        let create = QueryBuilder::new("CREATE TABLE t (id int PRIMARY KEY)").finalize();
+        let (ddl, agreed) = core.run(ddl.query_ddl(create, Duration::from_secs(5))).unwrap();
+        assert!(agreed);
+        // the second check waits for the interval rather than going right away
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert_eq!(mock::opcodes(&transport.written()).len(), 5);
+
+        // nodes which never agree are given up on after the timeout
+        for _ in 0..20 {
+            transport.push_read(schema_version(1));
+            transport.push_read(schema_version(0));
+        }
+        let started = Instant::now();
+        let (_, agreed) = core.run(ddl.await_schema_agreement_within(Duration::from_millis(100)))
+            .unwrap();
+        assert!(!agreed);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(1));
+        // a check every 30 ms and the last one at the deadline, no busy loop
+        let checks = (mock::opcodes(&transport.written()).len() - 5) / 2;
+        assert!((2..=5).contains(&checks), "{} checks", checks);
+    }
+
+    #[test]
+    fn decodes_warnings_of_responses() {
+        use cdrs::query::QueryBuilder;
+
+        let aggregation = "Aggregation query used without partition key";
+        let batch = "Batch for [ks.t] is of size 6.1KiB, exceeding specified threshold";
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::warned_response(RESULT,
+                                                  0,
+                                                  &[aggregation, batch],
+                                                  &mock::void_body()));
+        transport.push_read(mock::warned_response(ERROR,
+                                                  0,
+                                                  &[aggregation],
+                                                  &mock::error_body(0x2200, "bad query")));
+
+        let handled = Arc::new(Mutex::new(vec![]));
+        let mut session = mock::session(transport.clone());
+        let sink = handled.clone();
+        session.on_warnings(move |warnings| sink.lock().unwrap().extend_from_slice(warnings));
+
+        let select = || QueryBuilder::new("SELECT count(*) FROM t").finalize();
+        let options = RequestOptions::new().warnings(true);
+        let (session, response) = session.query_with_info(select(), options).wait().unwrap();
+        assert_eq!(response.warnings, vec![aggregation.to_string(), batch.to_string()]);
+        assert_eq!(response.tracing_id, None);
+        assert_eq!(response.into_frame().opcode, Opcode::Result);
+
+        let (_, response) = session.query_with_info(select(), options).wait().unwrap();
+        assert_eq!(response.warnings, vec![aggregation.to_string()]);
+        assert_eq!(response.frame.opcode, Opcode::Error);
+
+        assert_eq!(*handled.lock().unwrap(),
+                   vec![aggregation.to_string(), batch.to_string(), aggregation.to_string()]);
+    }
+
+    #[test]
+    fn custom_payload_round_trip() {
+        use std::io::{Read, Write};
+        use std::net;
+        use std::thread;
+        use tokio_core::reactor::Core;
+        use cdrs::query::QueryBuilder;
+        use transport::TransportTcp;
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // echoes a custom payload of every request back
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut flags = vec![];
+            for _ in 0..2 {
+                let mut header = [0; 9];
+                stream.read_exact(&mut header).unwrap();
+                let len = ((header[5] as usize) << 24) | ((header[6] as usize) << 16) |
+                          ((header[7] as usize) << 8) | header[8] as usize;
+                let mut body = vec![0; len];
+                stream.read_exact(&mut body).unwrap();
+                flags.push(header[1]);
+
+                let mut payload_len = 0;
+                if header[1] & 0x04 != 0 {
+                    let entries = ((body[0] as usize) << 8) | body[1] as usize;
+                    payload_len = 2;
+                    for _ in 0..entries {
+                        let key = &body[payload_len..];
+                        payload_len += 2 + (((key[0] as usize) << 8) | key[1] as usize);
+                        let value = &body[payload_len..];
+                        payload_len += 4 +
+                                       (((value[0] as usize) << 24) |
+                                        ((value[1] as usize) << 16) |
+                                        ((value[2] as usize) << 8) |
+                                        value[3] as usize);
+                    }
+                }
+                let mut response_body = body[..payload_len].to_vec();
+                response_body.extend(mock::void_body());
+                let mut response = mock::response(RESULT, 0, &response_body);
+                response[1] = header[1] & 0x04;
+                stream.write_all(&response).unwrap();
+            }
+            flags
+        });
+
+        let mut core = Core::new().unwrap();
+        let transport = core.run(TransportTcp::new(addr, &core.handle())).unwrap();
+        let session = Session::start(CDRS::new(transport, NoneAuthenticator));
+        let select = || QueryBuilder::new("SELECT * FROM t").finalize();
+        let mut payload = CustomPayload::new();
+        payload.insert("tenant".to_string(), b"acme".to_vec());
+        payload.insert("route".to_string(), vec![0, 1, 2]);
+
+        let (session, response) =
+            core.run(session.query_with_payload(select(), RequestOptions::new(), &payload))
+                .unwrap();
+        assert_eq!(response.custom_payload, payload);
+        assert_eq!(response.frame.opcode, Opcode::Result);
+
+        let (_, response) =
+            core.run(session.query_with_payload(select(), RequestOptions::new(), &HashMap::new()))
+                .unwrap();
+        assert!(response.custom_payload.is_empty());
+
+        // an empty payload doesn't set the flag
+        assert_eq!(server.join().unwrap(), vec![0x04, 0]);
+    }
+
+    #[test]
+    fn send_frame_returns_raw_response() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(SUPPORTED, 0, &mock::supported_body(&[])));
+        let mut options = Frame::new_req_options();
+        options.stream = 0x1234;
+        let (_, response) = mock::session(transport.clone()).send_frame(options).wait().unwrap();
+        assert_eq!(response.opcode, Opcode::Supported);
+        // the stream id is assigned by the session
+        assert_eq!(&transport.written()[2..4], &[0, 0]);
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(0x2200, "Invalid")));
+        let query = query_frame(QueryBuilder::new("SELECT nothing").finalize(), vec![]);
+        let (_, response) = mock::session(transport).send_frame(query).wait().unwrap();
+        assert_eq!(response.opcode, Opcode::Error);
+    }
+
+    #[test]
+    fn send_frame_rejects_too_large_frames() {
+        let transport = MockTransport::new();
+        let mut cdrs = CDRS::new(transport.clone(), NoneAuthenticator);
+        cdrs.max_body_len(16);
+        let query = query_frame(QueryBuilder::new("SELECT * FROM t").finalize(), vec![]);
+        match Session::start(cdrs).send_frame(query).wait() {
+            Err(error::Error::FrameTooLarge { max: 16, .. }) => (),
+            other => panic!("FrameTooLarge expected, got {:?}", other.map(|(_, frame)| frame)),
+        }
+        assert!(transport.written().is_empty());
+    }
+
+    #[test]
+    fn send_frame_rejects_response_to_another_stream() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 7, &mock::void_body()));
+        let result = mock::session(transport).send_frame(Frame::new_req_options()).wait();
+
+        match result {
+            Err(error::Error::ProtocolViolation(ProtocolViolation::StreamId { expected: 0,
+                                                                            actual: 7 })) => (),
+            other => panic!("StreamId violation expected, got {:?}", other.map(|(_, frame)| frame)),
+        }
+    }
+
+    #[test]
+    fn closes_connection_on_corrupted_body() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(READY, 0, &[]));
+        // lz4 body which announces 64 bytes and is cut right after its first token
+        let mut corrupted = mock::response(RESULT, 0, &[0, 0, 0, 64, 0xF0]);
+        corrupted[1] |= 0x01;
+        transport.push_read(corrupted);
+
+        let peer = "127.0.0.1:9042".parse().unwrap();
+        let mut cdrs = CDRS::new(transport.clone(), NoneAuthenticator);
+        cdrs.peer_addr(peer);
+        let session = cdrs.start(Compression::Lz4).wait().unwrap();
+        let result = session.send_frame(Frame::new_req_options()).wait().map(|(_, frame)| frame);
+
+        match result {
+            Err(error::Error::DecompressionFailed { ref codec,
+                                                    compressed_len,
+                                                    expected_len,
+                                                    context }) => {
+                assert_eq!((codec.as_str(), compressed_len, expected_len), ("lz4", 5, Some(64)));
+                assert_eq!((context.peer, context.opcode), (Some(peer), Some(RESULT)));
+            }
+            other => panic!("DecompressionFailed expected, got {:?}", other),
+        }
+        assert!(transport.is_closed());
+    }
+
+    #[test]
+    fn request_reaches_buffered_transport() {
+        use std::time::Duration;
+        use futures::future::Either;
+        use tokio_core::reactor::{Core, Timeout};
+        use cdrs::query::QueryBuilder;
+        use frame_io::FlushPolicy;
+
+        let transport = MockTransport::buffered();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let mut cdrs = CDRS::new(transport.clone(), NoneAuthenticator);
+        cdrs.write_options(WriteOptions {
+                               flush_policy: FlushPolicy::EveryBytes(1024 * 1024),
+                               ..WriteOptions::default()
+                           });
+        let session = Session::start(cdrs);
+
+        let mut core = Core::new().unwrap();
+        let timeout = Timeout::new(Duration::from_millis(500), &core.handle()).unwrap();
+        let query = session.query(QueryBuilder::new("INSERT INTO t (id) VALUES (1)").finalize(),
+                                  false,
+                                  false);
+
+        match core.run(query.select2(timeout)) {
+            Ok(Either::A(_)) => (),
+            Ok(Either::B(_)) => panic!("request was never flushed"),
+            Err(Either::A((err, _))) => panic!("{:?}", err),
+            Err(Either::B((err, _))) => panic!("{:?}", err),
+        }
+        assert_eq!(transport.flushes(), 1);
+    }
+
+    #[test]
+    fn chains_requests_of_a_session_on_a_reactor() {
+        use tokio_core::reactor::Core;
+        use cdrs::query::QueryBuilder;
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transport.push_read(ids_page(&[1, 2], None));
+
+        let mut core = Core::new().unwrap();
+        let session = mock::session(transport.clone());
+        let insert = QueryBuilder::new("INSERT INTO t (id) VALUES (1)").finalize();
+        let requests = session.query(insert, false, false)
+            .and_then(|(session, _)| {
+                          session.query_all(QueryBuilder::new("SELECT id FROM t").finalize())
+                      });
+        let (session, rows) = core.run(requests).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(mock::opcodes(&transport.written()).len(), 2);
+        // the session is still usable and owned by the caller
+        assert_eq!(session.compression_stats().uncompressed, 2);
+        drop(session);
+    }
+
+    #[test]
+    fn slow_response_does_not_block_reactor() {
+        use std::io::{Read, Write};
+        use std::net;
+        use std::thread;
+        use std::time::Duration;
+        use futures::future::Either;
+        use tokio_core::reactor::{Core, Timeout};
+        use cdrs::query::QueryBuilder;
+        use cdrs::types::IntoRustByName;
+        use transport::TransportTcp;
+
+        let value = "v".repeat(200 * 1024);
+        let response = mock::response(RESULT,
+                                      0,
+                                      &mock::rows_body(&[("v", mock::VARCHAR)],
+                                                       &[vec![mock::text(&value)]],
+                                                       None));
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0; 9];
+            stream.read_exact(&mut header).unwrap();
+            let len = ((header[5] as usize) << 24) | ((header[6] as usize) << 16) |
+                      ((header[7] as usize) << 8) | header[8] as usize;
+            stream.read_exact(&mut vec![0; len]).unwrap();
+
+            // the rest of the header and the body come much later
+            stream.write_all(&response[..5]).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            stream.write_all(&response[5..]).unwrap();
+        });
+
+        let mut core = Core::new().unwrap();
+        let transport = core.run(TransportTcp::new(addr, &core.handle())).unwrap();
+        let session = Session::start(CDRS::new(transport, NoneAuthenticator));
+        let query = session.query_rows(QueryBuilder::new("SELECT v FROM t").finalize());
+        let timeout = Timeout::new(Duration::from_millis(50), &core.handle()).unwrap();
+
+        // the reactor keeps running other futures while the frame is read
+        let query = match core.run(query.select2(timeout)) {
+            Ok(Either::B((_, query))) => query,
+            Ok(Either::A(_)) => panic!("response came before the rest of it was sent"),
+            Err(Either::A((err, _))) => panic!("{:?}", err),
+            Err(Either::B((err, _))) => panic!("{:?}", err),
+        };
+        let (_, rows) = core.run(query).unwrap();
+        let read: String = rows[0].get_by_name("v").unwrap().unwrap();
+        assert_eq!(read, value);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn listen_for_async_streams_events() {
+        use cdrs::frame::events::{StatusChange, StatusChangeType, TopologyChange,
+                                  TopologyChangeType};
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(READY, 0, &[]));
+        transport.push_read(mock::response(EVENT,
+                                           EVENT_STREAM_ID,
+                                           &mock::node_event_body("TOPOLOGY_CHANGE",
+                                                                  "NEW_NODE",
+                                                                  [10, 0, 0, 2],
+                                                                  9042)));
+        transport.push_read(mock::response(EVENT,
+                                           EVENT_STREAM_ID,
+                                           &mock::node_event_body("STATUS_CHANGE",
+                                                                  "DOWN",
+                                                                  [10, 0, 0, 3],
+                                                                  9042)));
+        transport.push_read_error(io::ErrorKind::ConnectionReset);
+
+        let events = mock::session(transport.clone())
+            .listen_for_async(vec![SimpleServerEvent::TopologyChange,
+                                   SimpleServerEvent::StatusChange])
+            .wait()
+            .unwrap();
+        let mut events = events.wait();
+
+        match events.next() {
+            Some(Ok(ServerEvent::TopologyChange(TopologyChange {
+                change_type: TopologyChangeType::NewNode,
+                ref addr,
+            }))) => assert_eq!(addr.addr, "10.0.0.2:9042".parse().unwrap()),
+            other => panic!("new node expected, got {:?}", other),
+        }
+        match events.next() {
+            Some(Ok(ServerEvent::StatusChange(StatusChange {
+                change_type: StatusChangeType::Down,
+                ref addr,
+            }))) => assert_eq!(addr.addr, "10.0.0.3:9042".parse().unwrap()),
+            other => panic!("node down expected, got {:?}", other),
+        }
+        // the stream ends with the error which broke the connection
+        match events.next() {
+            Some(Err(error::Error::Io(ref err))) => {
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionReset)
+            }
+            other => panic!("IO error expected, got {:?}", other),
+        }
+        assert_eq!(mock::opcodes(&transport.written()), vec![0x0B]);
+    }
+    #[test]
+    fn listen_for_async_rejects_other_frames() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(READY, 0, &[]));
+        transport.push_read(mock::response(RESULT, EVENT_STREAM_ID, &mock::void_body()));
+
+        let events = mock::session(transport)
+            .listen_for_async(vec![SimpleServerEvent::SchemaChange])
+            .wait()
+            .unwrap();
+        match events.wait().next() {
+            Some(Err(error::Error::General(ref message))) => {
+                assert_eq!(message, "Unexpected response to EVENT stream: Result")
+            }
+            other => panic!("unexpected response error expected, got {:?}", other),
+        }
     }
 }
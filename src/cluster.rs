@@ -0,0 +1,1331 @@
+//! Requests spread over pools of several hosts.
+//!
+//! A cluster keeps a pool per contact point. Pools connect lazily with a connector
+//! the cluster is given, e.g. one which runs `CDRS::start`. A request tries hosts
+//! in the order of a load balancing policy, round-robin by default, and moves
+//! on to the next host if a host cannot be reached. A host whose connection fails
+//! is marked down and goes after other hosts for a while. A request whose connection
+//! breaks after it was sent is not sent to another host, it may have been applied.
+//! Server errors are returned unless a retry policy asks to send a request again.
+//!
+//! Idempotent requests may be executed speculatively: if a host is slow to
+//! respond, or fails, the request is also sent to other hosts and the first
+//! response wins. The losing request is dropped if it doesn't complete in time.
+//!
+//! `refresh_topology` learns datacenters and tokens of hosts from `system.local`
+//! and `system.peers`. `DcAwarePolicy` uses datacenters to prefer hosts of a local
+//! datacenter, and requests with a routing key go to replicas of their partition.
+//! Pools of a `shard_aware` cluster also send them to the shard of a Scylla
+//! node which owns their token.
+//!
+//! Pools report requests and their sessions to metrics of the cluster, see
+//! `host_metrics`. Metrics of hosts which leave the topology are dropped after
+//! a grace period.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use cdrs::authenticators::Authenticator;
+use cdrs::consistency::Consistency;
+use cdrs::frame::Frame;
+use cdrs::query::{Query, QueryBatch, QueryBuilder, QueryParamsBuilder};
+use cdrs::transport::CDRSTransport;
+use cdrs::types::CBytesShort;
+use cdrs::types::rows::Row;
+use cdrs::types::value::Value;
+use futures::future::{self, Either, Future, Loop};
+use futures::stream::{self, Stream};
+use futures::sync::oneshot;
+use tokio_core::reactor::{Handle, Timeout};
+
+use client::{self, CDRSFuture, CDRSStream, Session};
+use decode::DecodeExecutor;
+use load_balancing::{Datacenters, DcAwarePolicy, LoadBalancingPolicy, RoundRobinPolicy};
+use metrics::{HostMetrics, HostMetricsRegistry};
+use paging::Page;
+use prepared;
+use pool::{Pool, PoolOptions};
+use request::{RequestOptions, Statement};
+use retry::{self, DefaultRetryPolicy, RetryDecision, RetryPolicy};
+use rows;
+use scan::{self, ScanQuery, TokenRange};
+use scylla::ShardInfo;
+use token::{self, TokenRing};
+use error;
+
+/// Datacenter, partitioner and tokens of a node a session is connected to.
+pub const SELECT_LOCAL_TOPOLOGY: &'static str = "SELECT data_center, partitioner, tokens \
+                                                 FROM system.local WHERE key = 'local'";
+/// Addresses, datacenters and tokens of other nodes as the connected node sees them.
+pub const SELECT_PEERS_TOPOLOGY: &'static str = "SELECT rpc_address, data_center, tokens \
+                                                 FROM system.peers";
+
+/// Seconds metrics of a host which left the topology are kept by default,
+/// see `Cluster::metrics_grace`.
+pub const HOST_METRICS_GRACE_SECS: u64 = 300;
+
+/// Pools of hosts of a cluster. Clones share the same pools.
+pub struct Cluster<T: Authenticator + 'static, X: CDRSTransport + 'static> {
+    hosts: Vec<SocketAddr>,
+    pools: Arc<HashMap<SocketAddr, Pool<T, X>>>,
+    policy: Arc<LoadBalancingPolicy + Send + Sync>,
+    datacenters: Datacenters,
+    ring: Arc<Mutex<TokenRing>>,
+    replication_factor: usize,
+    retry_policy: Arc<RetryPolicy + Send + Sync>,
+    /// Metrics of requests of every pool.
+    metrics: Arc<Mutex<HostMetricsRegistry>>,
+    /// Hosts whose connections failed along with the time they are tried again.
+    down: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+    down_interval: Duration,
+    losing_request_timeout: Duration,
+}
+
+impl<T: Authenticator, X: CDRSTransport> Clone for Cluster<T, X> {
+    fn clone(&self) -> Cluster<T, X> {
+        Cluster {
+            hosts: self.hosts.clone(),
+            pools: self.pools.clone(),
+            policy: self.policy.clone(),
+            datacenters: self.datacenters.clone(),
+            ring: self.ring.clone(),
+            replication_factor: self.replication_factor,
+            retry_policy: self.retry_policy.clone(),
+            metrics: self.metrics.clone(),
+            down: self.down.clone(),
+            down_interval: self.down_interval,
+            losing_request_timeout: self.losing_request_timeout,
+        }
+    }
+}
+
+impl<T, X> Cluster<T, X>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    /// Creates a pool per host which opens sessions with `connect` once they
+    /// are needed.
+    pub fn new<F>(hosts: Vec<SocketAddr>, connect: F) -> Cluster<T, X>
+        where F: Fn(SocketAddr) -> CDRSFuture<Session<T, X>> + Send + Sync + 'static
+    {
+        let connect = Arc::new(connect);
+        let grace = Duration::from_secs(HOST_METRICS_GRACE_SECS);
+        let metrics = Arc::new(Mutex::new(HostMetricsRegistry::new(grace)));
+        let pools = hosts.iter()
+            .map(|&host| {
+                     let connect = connect.clone();
+                     let mut pool = Pool::new(host, vec![]);
+                     pool.connector(move || connect(host)).metrics(metrics.clone());
+                     (host, pool)
+                 })
+            .collect();
+
+        Cluster {
+            hosts: hosts,
+            pools: Arc::new(pools),
+            policy: Arc::new(RoundRobinPolicy::new()),
+            datacenters: Arc::new(Mutex::new(HashMap::new())),
+            ring: Arc::new(Mutex::new(TokenRing::default())),
+            replication_factor: 1,
+            retry_policy: Arc::new(DefaultRetryPolicy::default()),
+            metrics: metrics,
+            down: Arc::new(Mutex::new(HashMap::new())),
+            down_interval: Duration::from_secs(1),
+            losing_request_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// The method sets a policy which orders hosts for every request.
+    /// It's `RoundRobinPolicy` by default.
+    pub fn policy<P>(&mut self, policy: P) -> &mut Self
+        where P: LoadBalancingPolicy + Send + Sync + 'static
+    {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// The method sets `DcAwarePolicy` which prefers hosts of `local_dc` and
+    /// uses at most `used_hosts_per_remote_dc` hosts of every other datacenter.
+    /// Datacenters are known after `refresh_topology`.
+    pub fn local_dc<S: Into<String>>(&mut self,
+                                     local_dc: S,
+                                     used_hosts_per_remote_dc: usize)
+                                     -> &mut Self {
+        let policy =
+            DcAwarePolicy::new(local_dc, used_hosts_per_remote_dc, self.datacenters.clone());
+        self.policy(policy)
+    }
+
+    /// The method sets a policy which decides whether requests failed with
+    /// transient server errors are sent again, to the same host or the next one.
+    /// Write timeouts are retried by `DefaultRetryPolicy`, which is the default,
+    /// only for requests which are marked idempotent, e.g. with `query_with`.
+    pub fn retry_policy<P>(&mut self, policy: P) -> &mut Self
+        where P: RetryPolicy + Send + Sync + 'static
+    {
+        self.retry_policy = Arc::new(policy);
+        self
+    }
+
+    /// The method sets how many replicas of a partition routed requests try
+    /// before other hosts. It's 1 by default, i.e. only the primary replica.
+    pub fn replication_factor(&mut self, replication_factor: usize) -> &mut Self {
+        self.replication_factor = replication_factor;
+        self
+    }
+
+    /// The method sets how long a host whose connection failed goes after other
+    /// hosts in query plans. It's a second by default. The host is tried first
+    /// again once the interval passes or it responds to a request.
+    pub fn down_interval(&mut self, interval: Duration) -> &mut Self {
+        self.down_interval = interval;
+        self
+    }
+
+    /// The method sets how long a speculative execution lets the request which
+    /// lost run before it's dropped, which closes its session. It's 10 seconds
+    /// by default.
+    pub fn losing_request_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.losing_request_timeout = timeout;
+        self
+    }
+
+    /// Returns `true` if a connection to `host` failed within the down interval.
+    pub fn is_down(&self, host: &SocketAddr) -> bool {
+        match self.down.lock().unwrap().get(host) {
+            Some(&until) => until > Instant::now(),
+            None => false,
+        }
+    }
+
+    /// The method makes every pool shard-aware, see `Pool::shard_aware`, with
+    /// `connect` opening a session to a given shard of a host for `Pool::fill_shards`.
+    /// Requests with a routing key go to a session of the shard which owns its token.
+    pub fn shard_aware<F>(&mut self, connect: F) -> &mut Self
+        where F: Fn(SocketAddr, ShardInfo, u32) -> CDRSFuture<Session<T, X>> + Send + Sync + 'static
+    {
+        let connect = Arc::new(connect);
+        let mut pools = (*self.pools).clone();
+        for (&host, pool) in pools.iter_mut() {
+            let connect = connect.clone();
+            pool.shard_aware(true).shard_connector(move |info, shard| connect(host, info, shard));
+        }
+        self.pools = Arc::new(pools);
+        self
+    }
+
+    /// The method sets options of every pool.
+    pub fn pool_options(&mut self, options: PoolOptions) -> &mut Self {
+        let mut pools = (*self.pools).clone();
+        for pool in pools.values_mut() {
+            pool.options(options);
+        }
+        self.pools = Arc::new(pools);
+        self
+    }
+
+    /// The method makes sessions of every pool convert rows of typed results
+    /// on `executor`, see `Session::set_decode_executor`.
+    pub fn decode_executor(&mut self, executor: DecodeExecutor) -> &mut Self {
+        let mut pools = (*self.pools).clone();
+        for pool in pools.values_mut() {
+            pool.decode_executor(executor.clone());
+        }
+        self.pools = Arc::new(pools);
+        self
+    }
+
+    pub fn hosts(&self) -> &[SocketAddr] {
+        &self.hosts
+    }
+
+    /// Metrics of requests of every host, e.g. latencies, errors and connections.
+    pub fn host_metrics(&self) -> HashMap<SocketAddr, HostMetrics> {
+        self.metrics.lock().unwrap().host_metrics()
+    }
+
+    /// Registry of metrics shared by pools of the cluster, e.g. to render them
+    /// with `HostMetricsRegistry::prometheus`.
+    pub fn metrics(&self) -> Arc<Mutex<HostMetricsRegistry>> {
+        self.metrics.clone()
+    }
+
+    /// The method sets how long metrics of a host which left the topology are
+    /// kept after `refresh_topology`. It's `HOST_METRICS_GRACE_SECS` by default.
+    pub fn metrics_grace(&mut self, grace: Duration) -> &mut Self {
+        self.metrics.lock().unwrap().set_grace(grace);
+        self
+    }
+
+    /// Pool of a host, if it's a host of the cluster.
+    pub fn pool(&self, host: &SocketAddr) -> Option<&Pool<T, X>> {
+        self.pools.get(host)
+    }
+
+    /// Datacenters of hosts learnt by `refresh_topology`.
+    pub fn datacenters(&self) -> Datacenters {
+        self.datacenters.clone()
+    }
+
+    /// Token ring learnt by `refresh_topology`. It's empty unless the cluster
+    /// uses Murmur3Partitioner.
+    pub fn ring(&self) -> TokenRing {
+        self.ring.lock().unwrap().clone()
+    }
+
+    /// Reads datacenters and tokens of the host which responds first and of its
+    /// peers. Peers are expected to listen on the port of that host. It can be run
+    /// again whenever the topology may have changed.
+    pub fn refresh_topology(&self) -> CDRSFuture<()> {
+        let datacenters = self.datacenters.clone();
+        let ring = self.ring.clone();
+        let metrics = self.metrics.clone();
+        let local = |_: &Session<T, X>| {
+            client::query_frame(QueryBuilder::new(SELECT_LOCAL_TOPOLOGY).finalize(), vec![])
+        };
+        self.request_on(self.plan(None), None, local, true)
+            .and_then(|(pool, local)| local_node(local).map(|local| (pool, local)))
+            .and_then(|(pool, (partitioner, local))| {
+                let host = pool.host();
+                pool.query(QueryBuilder::new(SELECT_PEERS_TOPOLOGY).finalize())
+                    .and_then(move |peers| {
+                        let mut nodes = try!(peer_nodes(peers, host.port()));
+                        nodes.insert(host, local);
+                        let topology: Vec<SocketAddr> = nodes.keys().cloned().collect();
+                        metrics.lock().unwrap().update_topology(&topology);
+
+                        let mut found = HashMap::new();
+                        let mut tokens = HashMap::new();
+                        for (host, node) in nodes {
+                            if let Some(dc) = node.datacenter {
+                                found.insert(host, dc);
+                            }
+                            tokens.insert(host, node.tokens);
+                        }
+                        *datacenters.lock().unwrap() = found;
+                        *ring.lock().unwrap() = match partitioner {
+                            Some(ref name) if name == token::MURMUR3_PARTITIONER => {
+                                TokenRing::new(tokens)
+                            }
+                            _ => TokenRing::default(),
+                        };
+                        Ok(())
+                    })
+            })
+            .boxed()
+    }
+
+    /// Hosts in the order of the policy. If there is a `routing_key`, replicas of
+    /// its partition which the policy allows come first.
+    fn plan(&self, routing_key: Option<&[u8]>) -> Vec<SocketAddr> {
+        self.plan_for_token(routing_key.map(token::murmur3_token))
+    }
+
+    /// Works as `plan` with replicas of a `token` first.
+    fn plan_for_token(&self, token: Option<i64>) -> Vec<SocketAddr> {
+        let plan = self.policy.plan(&self.hosts);
+        let mut replicas = match token {
+            Some(token) => self.ring.lock().unwrap().replicas(token, self.replication_factor),
+            None => vec![],
+        };
+        replicas.retain(|host| plan.contains(host));
+        for host in plan {
+            if !replicas.contains(&host) {
+                replicas.push(host);
+            }
+        }
+        // hosts which are down are tried only if every other one fails
+        let (mut up, down): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            replicas.into_iter().partition(|host| !self.is_down(host));
+        up.extend(down);
+        up
+    }
+
+    /// Listens for schema changes on a connection to the first host of the policy
+    /// which accepts one and passes them to prepared caches of sessions of every
+    /// pool, see `Pool::follow_schema_changes`. It has to be spawned on a reactor
+    /// and runs until the connection ends.
+    pub fn follow_schema_changes(&self) -> CDRSFuture<()> {
+        let caches: Vec<_> = self.pools.values().map(|pool| pool.prepared_caches()).collect();
+        let plan: VecDeque<Pool<T, X>> = self.plan(None)
+            .iter()
+            .filter_map(|host| self.pools.get(host).cloned())
+            .collect();
+
+        future::loop_fn((plan, None), |(mut plan, last)| {
+            let pool = match plan.pop_front() {
+                Some(pool) => pool,
+                None => {
+                    let last = last.unwrap_or_else(|| "Cluster has no hosts".into());
+                    return future::err(last).boxed();
+                }
+            };
+            pool.listen_for_schema_changes()
+                .then(move |result| match result {
+                          Ok(events) => Ok(Loop::Break(events)),
+                          Err(err) => Ok(Loop::Continue((plan, Some(err)))),
+                      })
+                .boxed()
+        })
+                .and_then(move |events| prepared::follow_schema_events(caches, events))
+                .boxed()
+    }
+
+    /// Sends a request built by `frame` to hosts in the order of the policy until
+    /// one of them responds. A frame is built for every host which is tried and
+    /// is sent as it is, `query`, `execute` and `batch` apply defaults of sessions.
+    /// If no host responds the error of the last one is returned. The request is
+    /// not known to be idempotent, see `request_with`.
+    pub fn request<F>(&self, frame: F) -> CDRSFuture<Frame>
+        where F: Fn() -> Frame + Send + 'static
+    {
+        self.request_with(frame, RequestOptions::new())
+    }
+
+    /// Works as `request`. If `options` mark the request idempotent, it's also
+    /// sent to the next host when a connection breaks after it was sent, and
+    /// a retry policy may retry its write timeouts.
+    pub fn request_with<F>(&self, frame: F, options: RequestOptions) -> CDRSFuture<Frame>
+        where F: Fn() -> Frame + Send + 'static
+    {
+        self.request_on(self.plan(None),
+                        None,
+                        move |_| frame(),
+                        options.is_idempotent())
+            .map(|(_, response)| response)
+            .boxed()
+    }
+
+    /// Same as `request`, but replicas of a partition with `routing_key` are tried
+    /// first, e.g. a serialized partition key or `token::composite_routing_key`.
+    /// Hosts are tried in the order of the policy if the token ring is unknown.
+    pub fn request_routed<F>(&self, routing_key: &[u8], frame: F) -> CDRSFuture<Frame>
+        where F: Fn() -> Frame + Send + 'static
+    {
+        self.request_routed_with(routing_key, frame, RequestOptions::new())
+    }
+
+    /// Works as `request_routed` with `options`, see `request_with`.
+    pub fn request_routed_with<F>(&self,
+                                  routing_key: &[u8],
+                                  frame: F,
+                                  options: RequestOptions)
+                                  -> CDRSFuture<Frame>
+        where F: Fn() -> Frame + Send + 'static
+    {
+        let token = token::murmur3_token(routing_key);
+        self.request_on(self.plan_for_token(Some(token)),
+                        Some(token),
+                        move |_| frame(),
+                        options.is_idempotent())
+            .map(|(_, response)| response)
+            .boxed()
+    }
+
+    /// Works as `request`, but the request is sent to the first host of the plan,
+    /// and if no response comes in `delay`, or the host fails, to other hosts in
+    /// turn. The first response wins, the other request is discarded, see
+    /// `losing_request_timeout`. Requests which are not `idempotent` are never
+    /// sent twice.
+    pub fn request_speculative<F>(&self,
+                                  frame: F,
+                                  idempotent: bool,
+                                  delay: Duration,
+                                  handle: &Handle)
+                                  -> Box<Future<Item = Frame, Error = error::Error>>
+        where F: Fn() -> Frame + Send + Sync + 'static
+    {
+        self.speculate(move |_| frame(), idempotent, delay, handle)
+    }
+
+    /// Works as `request_speculative` building frames on sessions, see `request_on`.
+    fn speculate<F>(&self,
+                    frame: F,
+                    idempotent: bool,
+                    delay: Duration,
+                    handle: &Handle)
+                    -> Box<Future<Item = Frame, Error = error::Error>>
+        where F: Fn(&Session<T, X>) -> Frame + Send + Sync + 'static
+    {
+        let mut plan = self.plan(None);
+        if !idempotent || plan.len() < 2 {
+            return Box::new(self.request_on(plan, None, frame, idempotent)
+                                .map(|(_, response)| response));
+        }
+        let timer = match Timeout::new(delay, handle) {
+            Ok(timer) => timer,
+            Err(err) => return Box::new(future::err(err.into())),
+        };
+
+        // hosts other than the first one are left to the speculative request
+        let next_plan = plan.split_off(1);
+        let frame = Arc::new(frame);
+        let primary_frame = frame.clone();
+        let (failed, primary_failed) = oneshot::channel();
+        let primary = self.request_on(plan, None, move |session| primary_frame(session), true)
+            .then(move |result| {
+                      if result.is_err() {
+                          let _ = failed.send(());
+                      }
+                      result
+                  });
+
+        let answered = Arc::new(AtomicBool::new(false));
+        let speculative_answered = answered.clone();
+        let cluster = self.clone();
+        // it starts after `delay` or once the primary request fails
+        let speculative = timer.map_err(error::Error::from)
+            .select2(primary_failed)
+            .then(move |_| {
+                if speculative_answered.load(Ordering::SeqCst) {
+                    return future::err("A response has come already".into()).boxed();
+                }
+                cluster.request_on(next_plan, None, move |session| frame(session), true)
+            });
+
+        let handle = handle.clone();
+        let timeout = self.losing_request_timeout;
+        Box::new(primary.select2(speculative).then(move |result| {
+            let response: Box<Future<Item = Frame, Error = error::Error>> = match result {
+                Ok(Either::A(((_, response), speculative))) => {
+                    answered.store(true, Ordering::SeqCst);
+                    spawn_losing(speculative, timeout, &handle);
+                    Box::new(future::ok(response))
+                }
+                Ok(Either::B(((_, response), primary))) => {
+                    answered.store(true, Ordering::SeqCst);
+                    spawn_losing(primary, timeout, &handle);
+                    Box::new(future::ok(response))
+                }
+                Err(Either::A((_, speculative))) => {
+                    Box::new(speculative.map(|(_, response)| response))
+                }
+                Err(Either::B((_, primary))) => Box::new(primary.map(|(_, response)| response)),
+            };
+            response
+        }))
+    }
+
+    /// Tries hosts of `plan` in turn and returns a pool of the host which responded.
+    /// A frame is built on a session of every host which is tried, so it may take
+    /// defaults of the session. A host whose connection fails is marked down.
+    /// A request whose connection broke after it was sent moves on to the next host
+    /// only if it's `idempotent`, as the failed host may have applied it.
+    /// Shard-aware pools send a request with a `token` to the shard which owns it.
+    fn request_on<F>(&self,
+                     plan: Vec<SocketAddr>,
+                     token: Option<i64>,
+                     frame: F,
+                     idempotent: bool)
+                     -> CDRSFuture<(Pool<T, X>, Frame)>
+        where F: Fn(&Session<T, X>) -> Frame + Send + 'static
+    {
+        let plan: VecDeque<Pool<T, X>> = plan.iter()
+            .filter_map(|host| self.pools.get(host).cloned())
+            .collect();
+
+        let frame = Arc::new(Mutex::new(frame));
+        let retry_policy = self.retry_policy.clone();
+        let down = self.down.clone();
+        let down_interval = self.down_interval;
+
+        future::loop_fn((plan, 0, None), move |(mut plan, retries, last)| {
+            let pool = match plan.pop_front() {
+                Some(pool) => pool,
+                None => {
+                    let last = last.unwrap_or_else(|| Err("Cluster has no hosts".into()));
+                    return future::result(last.map(Loop::Break)).boxed();
+                }
+            };
+
+            let retry_policy = retry_policy.clone();
+            let down = down.clone();
+            let frame = frame.clone();
+            let frame = move |session: &Session<T, X>| (*frame.lock().unwrap())(session);
+            pool.try_request_routed_with(frame, token, None)
+                .then(move |result| {
+                    let (sent, result) = match result {
+                        Ok(result) => (true, result),
+                        Err(err) => (false, Err(err)),
+                    };
+                    let response = match result {
+                        Ok(response) => response,
+                        Err(err) => {
+                            if is_connection_failure(&err) {
+                                let until = Instant::now() + down_interval;
+                                down.lock().unwrap().insert(pool.host(), until);
+                            }
+                            let failover = if sent {
+                                idempotent && is_connection_failure(&err) || is_not_sent(&err)
+                            } else {
+                                is_host_failure(&err)
+                            };
+                            if failover {
+                                return Ok(Loop::Continue((plan, retries, Some(Err(err)))));
+                            }
+                            return Err(err);
+                        }
+                    };
+                    down.lock().unwrap().remove(&pool.host());
+
+                    let decision = match retry::server_error(&response) {
+                        Some(error) => retry_policy.on_server_error(&error, retries, idempotent),
+                        None => RetryDecision::Rethrow,
+                    };
+                    match decision {
+                        RetryDecision::Retry => plan.push_front(pool.clone()),
+                        RetryDecision::RetryNextNode => {}
+                        RetryDecision::Rethrow => return Ok(Loop::Break((pool, response))),
+                    }
+                    Ok(Loop::Continue((plan, retries + 1, Some(Ok((pool, response))))))
+                })
+                .boxed()
+        })
+                .boxed()
+    }
+
+    /// Sends a query with defaults of sessions, e.g. their consistency, see `request`.
+    pub fn query<Q: Into<Statement<Query>>>(&self, query: Q) -> CDRSFuture<Frame> {
+        self.query_with(query, RequestOptions::new())
+    }
+
+    /// Sends a query with `options`, e.g. marking it idempotent, see `request_with`.
+    pub fn query_with<Q>(&self, query: Q, options: RequestOptions) -> CDRSFuture<Frame>
+        where Q: Into<Statement<Query>>
+    {
+        let query = query.into();
+        let idempotent = options.is_idempotent();
+        self.request_on(self.plan(None),
+                        None,
+                        move |session| query_frame(session, &query, &options),
+                        idempotent)
+            .map(|(_, response)| response)
+            .boxed()
+    }
+
+    /// Sends a query speculatively if `options` mark it idempotent,
+    /// see `request_speculative`.
+    pub fn query_speculative<Q>(&self,
+                                query: Q,
+                                options: RequestOptions,
+                                delay: Duration,
+                                handle: &Handle)
+                                -> Box<Future<Item = Frame, Error = error::Error>>
+        where Q: Into<Statement<Query>>
+    {
+        let query = query.into();
+        let idempotent = options.is_idempotent();
+        self.speculate(move |session| query_frame(session, &query, &options),
+                       idempotent,
+                       delay,
+                       handle)
+    }
+
+    /// Sends a query to replicas of a partition first, see `request_routed`.
+    pub fn query_routed<Q>(&self, query: Q, routing_key: &[u8]) -> CDRSFuture<Frame>
+        where Q: Into<Statement<Query>>
+    {
+        let query = query.into();
+        let options = RequestOptions::new();
+        let token = token::murmur3_token(routing_key);
+        self.request_on(self.plan_for_token(Some(token)),
+                        Some(token),
+                        move |session| query_frame(session, &query, &options),
+                        false)
+            .map(|(_, response)| response)
+            .boxed()
+    }
+
+    /// Executes a prepared statement with `values`, see `request`. Servers assign
+    /// the same id to the same statement, but it has to be prepared on every host.
+    /// Defaults of sessions other than consistency apply, e.g. their timestamps.
+    pub fn execute(&self,
+                   id: CBytesShort,
+                   values: Vec<Value>,
+                   consistency: Consistency)
+                   -> CDRSFuture<Frame> {
+        self.request_on(self.plan(None),
+                        None,
+                        move |session| execute_frame(session, &id, &values, &consistency),
+                        false)
+            .map(|(_, response)| response)
+            .boxed()
+    }
+
+    /// Executes a prepared statement on replicas of a partition first,
+    /// see `request_routed`.
+    pub fn execute_routed(&self,
+                          id: CBytesShort,
+                          values: Vec<Value>,
+                          consistency: Consistency,
+                          routing_key: &[u8])
+                          -> CDRSFuture<Frame> {
+        let token = token::murmur3_token(routing_key);
+        self.request_on(self.plan_for_token(Some(token)),
+                        Some(token),
+                        move |session| execute_frame(session, &id, &values, &consistency),
+                        false)
+            .map(|(_, response)| response)
+            .boxed()
+    }
+
+    /// Reads a whole table with a query per token range, see `scan`. Ranges follow
+    /// the token ring, which is known after `refresh_topology`, and every range is
+    /// read from its replicas first. Without the ring the whole ring is split into
+    /// `parallelism` ranges of nearly equal size.
+    ///
+    /// At most `parallelism` ranges are read at once, each of them page by page.
+    /// Rows of all ranges are yielded as a single stream in the order they come.
+    pub fn scan(&self, query: ScanQuery, parallelism: usize) -> CDRSStream<Row> {
+        let ring = self.ring();
+        let ranges = if ring.is_empty() {
+            scan::split_even(parallelism)
+        } else {
+            scan::ring_ranges(&ring.tokens())
+        };
+        self.scan_ranges(query, ranges, parallelism)
+    }
+
+    /// Works as `scan` with given token ranges.
+    pub fn scan_ranges(&self,
+                       query: ScanQuery,
+                       ranges: Vec<TokenRange>,
+                       parallelism: usize)
+                       -> CDRSStream<Row> {
+        let streams = ranges.iter()
+            .map(|range| self.range_stream(query.query_for(range), range.end))
+            .collect();
+        scan::merge_bounded(streams, parallelism).boxed()
+    }
+
+    /// Rows of a query of a single token range, a page is requested once rows
+    /// of the previous one are taken.
+    fn range_stream(&self, query: Query, token: i64) -> CDRSStream<Row> {
+        let cluster = self.clone();
+        stream::unfold(Some(query), move |query| {
+            let mut query = match query {
+                Some(query) => query,
+                None => return None,
+            };
+            // scans leave consistency to sessions
+            let page_query = Statement::new(client::clone_query(&query));
+            let options = RequestOptions::new();
+            let page_frame = move |session: &Session<T, X>| {
+                query_frame(session, &page_query, &options)
+            };
+
+            let plan = cluster.plan_for_token(Some(token));
+            let page = cluster.request_on(plan, Some(token), page_frame, true);
+            Some(page.and_then(move |(_, frame)| {
+                let page = try!(Page::from_frame(frame));
+                let next = page.paging_state.map(|paging_state| {
+                                                      query.paging_state =
+                                                          Some(paging_state.into());
+                                                      query
+                                                  });
+                let rows = page.rows.into_iter().map(Ok::<Row, error::Error>);
+                Ok((stream::iter(rows), next))
+            }))
+        })
+                .flatten()
+                .boxed()
+    }
+
+    /// Sends a batch built by `batch` for every host which is tried with defaults
+    /// of its session, see `request`.
+    pub fn batch<F, B>(&self, batch: F) -> CDRSFuture<Frame>
+        where F: Fn() -> B + Send + 'static,
+              B: Into<Statement<QueryBatch>>
+    {
+        self.request_on(self.plan(None),
+                        None,
+                        move |session| {
+                            Frame::new_req_batch(session.with_defaults(batch().into()), vec![])
+                        },
+                        false)
+            .map(|(_, response)| response)
+            .boxed()
+    }
+}
+
+/// A frame of `query` with defaults of `session` it's sent on.
+fn query_frame<T, X>(session: &Session<T, X>,
+                     query: &Statement<Query>,
+                     options: &RequestOptions)
+                     -> Frame
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    let query = query.clone_with(client::clone_query);
+    client::query_frame(session.with_defaults(query), options.flags())
+}
+
+/// A frame of an execution with defaults of `session` it's sent on other than consistency.
+fn execute_frame<T, X>(session: &Session<T, X>,
+                       id: &CBytesShort,
+                       values: &[Value],
+                       consistency: &Consistency)
+                       -> Frame
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    let query_parameters = QueryParamsBuilder::new(consistency.clone())
+        .values(values.to_vec())
+        .finalize();
+    let query_parameters = session.with_defaults(query_parameters.into());
+    Frame::new_req_execute(id, query_parameters, vec![])
+}
+
+/// Returns `true` if a request couldn't be sent because of a host rather than
+/// the request itself, so another host can be tried.
+fn is_host_failure(err: &error::Error) -> bool {
+    match *err {
+        error::Error::Backpressure |
+        error::Error::PoolTimeout { .. } |
+        error::Error::ShuttingDown => true,
+        _ => is_connection_failure(err) || is_not_sent(err),
+    }
+}
+
+/// Lets a request which lost a speculative execution run for up to `timeout`,
+/// so its session can go back to a pool, and drops it after that.
+fn spawn_losing<F>(request: F, timeout: Duration, handle: &Handle)
+    where F: Future + 'static
+{
+    match Timeout::new(timeout, handle) {
+        Ok(timer) => handle.spawn(request.select2(timer).then(|_| Ok(()))),
+        Err(_) => handle.spawn(request.then(|_| Ok(()))),
+    }
+}
+
+/// Returns `true` if a connection to a host couldn't be opened or broke.
+fn is_connection_failure(err: &error::Error) -> bool {
+    match *err {
+        error::Error::Io(_) |
+        error::Error::ConnectionReset |
+        error::Error::HandshakeTimeout(_) |
+        error::Error::Connect { .. } => true,
+        _ => err.breaks_connection(),
+    }
+}
+
+/// Returns `true` if a request which was handed to a session wasn't written.
+fn is_not_sent(err: &error::Error) -> bool {
+    match *err {
+        error::Error::StreamIdExhausted { .. } => true,
+        _ => false,
+    }
+}
+
+/// Tokens of a `set<text>` cell.
+fn tokens(cell: Option<&Option<Vec<u8>>>) -> error::Result<Vec<i64>> {
+    let bytes = match cell {
+        Some(&Some(ref bytes)) => bytes,
+        _ => return Ok(vec![]),
+    };
+    let read_int = |at: usize| -> error::Result<usize> {
+        match bytes.get(at..at + 4) {
+            Some(int) => {
+                Ok(((int[0] as usize) << 24) | ((int[1] as usize) << 16) |
+                   ((int[2] as usize) << 8) | int[3] as usize)
+            }
+            None => Err("Set of tokens is truncated".into()),
+        }
+    };
+
+    let count = try!(read_int(0));
+    let mut at = 4;
+    let mut tokens = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = try!(read_int(at));
+        let token = try!(bytes.get(at + 4..at + 4 + len)
+                             .and_then(|token| String::from_utf8(token.to_vec()).ok())
+                             .and_then(|token| token.parse().ok())
+                             .ok_or_else(|| error::Error::from("Token is not a number")));
+        tokens.push(token);
+        at += 4 + len;
+    }
+    Ok(tokens)
+}
+
+/// Datacenter and tokens of a node.
+struct Node {
+    datacenter: Option<String>,
+    tokens: Vec<i64>,
+}
+
+/// Partitioner and the node of a response to `SELECT_LOCAL_TOPOLOGY`.
+fn local_node(frame: Frame) -> error::Result<(Option<String>, Node)> {
+    let local = try!(rows::raw_rows(frame));
+    let row = match local.first() {
+        Some(row) => row,
+        None => return Err("system.local has no rows".into()),
+    };
+    let node = Node {
+        datacenter: try!(rows::text_cell(row.get(0))),
+        tokens: try!(tokens(row.get(2))),
+    };
+    Ok((try!(rows::text_cell(row.get(1))), node))
+}
+
+/// Nodes of a response to `SELECT_PEERS_TOPOLOGY` by addresses with `port`.
+/// Peers without an address are skipped.
+fn peer_nodes(frame: Frame, port: u16) -> error::Result<HashMap<SocketAddr, Node>> {
+    let mut nodes = HashMap::new();
+    for row in try!(rows::raw_rows(frame)) {
+        if let Some(address) = try!(rows::inet_cell(row.get(0))) {
+            let node = Node {
+                datacenter: try!(rows::text_cell(row.get(1))),
+                tokens: try!(tokens(row.get(2))),
+            };
+            nodes.insert(SocketAddr::new(address, port), node);
+        }
+    }
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net;
+    use std::thread;
+    use std::time::Instant;
+    use futures::{future, Future};
+    use tokio_core::reactor::Core;
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::query::QueryBuilder;
+
+    use super::*;
+    use client::CDRS;
+    use mock::{self, MockTransport, ERROR, QUERY, RESULT};
+
+    #[test]
+    fn skips_dead_hosts() {
+        let dead: SocketAddr = "10.0.0.1:9042".parse().unwrap();
+        let live: SocketAddr = "10.0.0.2:9042".parse().unwrap();
+        let transport = MockTransport::new();
+        for _ in 0..4 {
+            transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        }
+
+        let connected = transport.clone();
+        let cluster = Cluster::new(vec![dead, live], move |host| {
+            if host == dead {
+                let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+                return future::err(refused.into()).boxed();
+            }
+            let cdrs = CDRS::new(connected.clone(), NoneAuthenticator);
+            future::ok(Session::start(cdrs)).boxed()
+        });
+
+        for _ in 0..4 {
+            cluster.query(QueryBuilder::new("SELECT * FROM t").finalize()).wait().unwrap();
+        }
+        assert_eq!(mock::opcodes(&transport.written()), vec![QUERY; 4]);
+        assert_eq!(cluster.pool(&live).unwrap().size(), 1);
+        assert_eq!(cluster.pool(&dead).unwrap().size(), 0);
+    }
+
+    fn local_body(dc: &str, tokens: &[&str]) -> Vec<u8> {
+        mock::rows_body(&[("data_center", mock::VARCHAR),
+                          ("partitioner", mock::VARCHAR),
+                          ("tokens", mock::VARCHAR_SET)],
+                        &[vec![mock::text(dc),
+                               mock::text(token::MURMUR3_PARTITIONER),
+                               mock::text_set(tokens)]],
+                        None)
+    }
+
+    fn peers_body(peers: &[([u8; 4], Option<&str>, &[&str])]) -> Vec<u8> {
+        let rows: Vec<_> = peers.iter()
+            .map(|&(address, dc, tokens)| {
+                     vec![mock::inet(address), dc.and_then(mock::text), mock::text_set(tokens)]
+                 })
+            .collect();
+        mock::rows_body(&[("rpc_address", mock::INET),
+                          ("data_center", mock::VARCHAR),
+                          ("tokens", mock::VARCHAR_SET)],
+                        &rows,
+                        None)
+    }
+
+    #[test]
+    fn refreshes_topology_for_dc_aware_policy() {
+        let hosts: Vec<SocketAddr> = (1..5)
+            .map(|i| format!("10.0.0.{}:9042", i).parse().unwrap())
+            .collect();
+        let transport = MockTransport::new();
+        let peers = peers_body(&[([10, 0, 0, 2], Some("dc1"), &[]),
+                                 ([10, 0, 0, 3], Some("dc2"), &[]),
+                                 ([10, 0, 0, 4], Some("dc1"), &[]),
+                                 ([10, 0, 0, 9], None, &[])]);
+        transport.push_read(mock::response(RESULT, 0, &local_body("dc2", &[])));
+        transport.push_read(mock::response(RESULT, 0, &peers));
+
+        let connected = transport.clone();
+        let mut cluster = Cluster::new(hosts.clone(), move |_| {
+            let cdrs = CDRS::new(connected.clone(), NoneAuthenticator);
+            future::ok(Session::start(cdrs)).boxed()
+        });
+        cluster.local_dc("dc1", 1).metrics_grace(Duration::from_secs(0));
+        // a host which left the topology
+        let gone: SocketAddr = "10.0.0.7:9042".parse().unwrap();
+        cluster.metrics().lock().unwrap().connect_failed(gone);
+
+        // nothing is known yet, so every host is tried
+        assert_eq!(cluster.policy.plan(&hosts).len(), 4);
+        cluster.refresh_topology().wait().unwrap();
+        assert_eq!(cluster.datacenters().lock().unwrap().len(), 4);
+        let metrics = cluster.host_metrics();
+        assert!(!metrics.contains_key(&gone));
+        assert_eq!(metrics[&hosts[0]].requests, 2);
+        assert_eq!(cluster.policy.plan(&hosts), vec![hosts[1], hosts[3], hosts[0]]);
+        assert_eq!(cluster.policy.plan(&hosts), vec![hosts[3], hosts[1], hosts[0]]);
+
+        cluster.local_dc("dc2", 0);
+        assert_eq!(cluster.policy.plan(&hosts), vec![hosts[0], hosts[2]]);
+    }
+
+    /// Answers every request on a connection to `listener` with a void result
+    /// after `delay`, until the connection is closed.
+    fn serve_slowly(listener: net::TcpListener, delay: Duration) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0; 9];
+            while stream.read_exact(&mut header).is_ok() {
+                let len = ((header[5] as usize) << 24) | ((header[6] as usize) << 16) |
+                          ((header[7] as usize) << 8) | header[8] as usize;
+                let mut body = vec![0; len];
+                stream.read_exact(&mut body).unwrap();
+                thread::sleep(delay);
+                let id = ((header[2] as i16) << 8) | header[3] as i16;
+                stream.write_all(&mock::response(RESULT, id, &mock::void_body())).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn measures_hosts_separately() {
+        use transport::TransportTcp;
+
+        let listeners: Vec<_> = (0..2)
+            .map(|_| net::TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let hosts: Vec<SocketAddr> = listeners.iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let servers: Vec<_> = listeners.into_iter()
+            .zip(vec![1, 120])
+            .map(|(listener, millis)| serve_slowly(listener, Duration::from_millis(millis)))
+            .collect();
+
+        let mut core = Core::new().unwrap();
+        let remote = core.remote();
+        let cluster = Cluster::new(hosts.clone(), move |host| {
+            let remote = remote.clone();
+            future::lazy(move || TransportTcp::new(host, &remote.handle().unwrap()))
+                .map(|transport| Session::start(CDRS::new(transport, NoneAuthenticator)))
+                .map_err(error::Error::from)
+                .boxed()
+        });
+        for _ in 0..6 {
+            core.run(cluster.query(QueryBuilder::new("SELECT * FROM t").finalize())).unwrap();
+        }
+
+        let metrics = cluster.host_metrics();
+        let (fast, slow) = (&metrics[&hosts[0]], &metrics[&hosts[1]]);
+        assert_eq!((fast.requests, slow.requests), (3, 3));
+        assert_eq!((fast.connections, slow.connections), (1, 1));
+        assert!(fast.latency.quantile(0.9).unwrap() <= Duration::from_millis(50));
+        assert!(slow.latency.quantile(0.5).unwrap() >= Duration::from_millis(250));
+
+        drop(cluster);
+        for server in servers {
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn routes_requests_to_replicas() {
+        let hosts: Vec<SocketAddr> = (1..4)
+            .map(|i| format!("10.0.0.{}:9042", i).parse().unwrap())
+            .collect();
+        let transports: HashMap<SocketAddr, MockTransport> =
+            hosts.iter().map(|&host| (host, MockTransport::new())).collect();
+        let first = &transports[&hosts[0]];
+        let peers = peers_body(&[([10, 0, 0, 2], Some("dc1"), &["-7000000000000000000"]),
+                                 ([10, 0, 0, 3], Some("dc1"), &["8000000000000000000"])]);
+        first.push_read(mock::response(RESULT,
+                                       0,
+                                       &local_body("dc1", &["-9000000000000000000", "0"])));
+        first.push_read(mock::response(RESULT, 0, &peers));
+        for transport in transports.values() {
+            transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        }
+
+        let connected = transports.clone();
+        let cluster = Cluster::new(hosts.clone(), move |host| {
+            let cdrs = CDRS::new(connected[&host].clone(), NoneAuthenticator);
+            future::ok(Session::start(cdrs)).boxed()
+        });
+        cluster.refresh_topology().wait().unwrap();
+        assert_eq!(cluster.ring().replicas(0, 3), vec![hosts[0], hosts[2], hosts[1]]);
+
+        // the token of "123" is -7468325962851647638, it's owned by the second host
+        let select = QueryBuilder::new("SELECT * FROM t WHERE pk = '123'").finalize();
+        cluster.query_routed(client::clone_query(&select), b"123").wait().unwrap();
+        assert_eq!(mock::opcodes(&transports[&hosts[1]].written()), vec![QUERY]);
+
+        let key = token::composite_routing_key(&[b"9223372036854775807"]);
+        assert_eq!(key, b"9223372036854775807".to_vec());
+        cluster.query_routed(select, &key).wait().unwrap();
+        assert_eq!(mock::opcodes(&transports[&hosts[2]].written()), vec![QUERY]);
+        assert_eq!(mock::opcodes(&transports[&hosts[0]].written()), vec![QUERY; 2]);
+    }
+
+    #[test]
+    fn retries_unavailable_on_next_host() {
+        let hosts: Vec<SocketAddr> = (1..3)
+            .map(|i| format!("10.0.0.{}:9042", i).parse().unwrap())
+            .collect();
+        let transports: HashMap<SocketAddr, MockTransport> =
+            hosts.iter().map(|&host| (host, MockTransport::new())).collect();
+        let unavailable = mock::error_body(retry::UNAVAILABLE, "not enough replicas");
+        transports[&hosts[0]].push_read(mock::response(ERROR, 0, &unavailable));
+        transports[&hosts[1]].push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let connected = transports.clone();
+        let cluster = Cluster::new(hosts.clone(), move |host| {
+            let cdrs = CDRS::new(connected[&host].clone(), NoneAuthenticator);
+            future::ok(Session::start(cdrs)).boxed()
+        });
+
+        let response = cluster.query(QueryBuilder::new("SELECT * FROM t").finalize())
+            .wait()
+            .unwrap();
+        assert_eq!(retry::error_code(&response), None);
+        for host in &hosts {
+            assert_eq!(mock::opcodes(&transports[host].written()), vec![QUERY]);
+        }
+    }
+
+    #[test]
+    fn scans_ranges_of_ring_on_their_replicas() {
+        use futures::Stream;
+        use cdrs::types::IntoRustByName;
+
+        let hosts: Vec<SocketAddr> = (1..3)
+            .map(|i| format!("10.0.0.{}:9042", i).parse().unwrap())
+            .collect();
+        let transports: HashMap<SocketAddr, MockTransport> =
+            hosts.iter().map(|&host| (host, MockTransport::new())).collect();
+        let page = |ids: &[i32], paging_state: Option<&[u8]>| {
+            let rows: Vec<_> = ids.iter().map(|id| vec![mock::int(*id)]).collect();
+            mock::response(RESULT,
+                           0,
+                           &mock::rows_body(&[("id", mock::INT)], &rows, paging_state))
+        };
+        // ranges ending with -100, 100 and the wraparound one belong to the first host
+        for ids in &[[1], [3], [5]] {
+            transports[&hosts[0]].push_read(page(ids, None));
+        }
+        transports[&hosts[1]].push_read(page(&[2], None));
+        transports[&hosts[1]].push_read(page(&[4], Some(b"p")));
+        transports[&hosts[1]].push_read(page(&[6], None));
+
+        let connected = transports.clone();
+        let cluster = Cluster::new(hosts.clone(), move |host| {
+            let cdrs = CDRS::new(connected[&host].clone(), NoneAuthenticator);
+            future::ok(Session::start(cdrs)).boxed()
+        });
+        let mut tokens = HashMap::new();
+        tokens.insert(hosts[0], vec![-100, 100]);
+        tokens.insert(hosts[1], vec![0, 200]);
+        *cluster.ring.lock().unwrap() = TokenRing::new(tokens);
+
+        let rows = cluster.scan(ScanQuery::new("SELECT id FROM t", &["id"]), 1)
+            .collect()
+            .wait()
+            .unwrap();
+        let mut ids: Vec<i32> = rows.iter()
+            .map(|row| row.get_by_name("id").unwrap().unwrap())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5, 6]);
+        for host in &hosts {
+            assert_eq!(mock::opcodes(&transports[host].written()), vec![QUERY; 3]);
+        }
+    }
+
+    /// A cluster of two hosts, the first of which never responds.
+    fn cluster_with_stalled_host() -> (Cluster<NoneAuthenticator, MockTransport>,
+                                       Vec<MockTransport>) {
+        let hosts: Vec<SocketAddr> = (1..3)
+            .map(|i| format!("10.0.0.{}:9042", i).parse().unwrap())
+            .collect();
+        let transports: HashMap<SocketAddr, MockTransport> =
+            hosts.iter().map(|&host| (host, MockTransport::new())).collect();
+        transports[&hosts[1]].push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let connected = transports.clone();
+        let cluster = Cluster::new(hosts.clone(), move |host| {
+            let cdrs = CDRS::new(connected[&host].clone(), NoneAuthenticator);
+            future::ok(Session::start(cdrs)).boxed()
+        });
+        (cluster, hosts.iter().map(|host| transports[host].clone()).collect())
+    }
+
+    #[test]
+    fn speculative_execution_outruns_slow_host() {
+        let (mut cluster, transports) = cluster_with_stalled_host();
+        cluster.losing_request_timeout(Duration::from_millis(50));
+        let mut core = Core::new().unwrap();
+        let started = Instant::now();
+        let query = cluster.query_speculative(QueryBuilder::new("SELECT * FROM t").finalize(),
+                                              RequestOptions::new().idempotent(true),
+                                              Duration::from_millis(50),
+                                              &core.handle());
+
+        let response = core.run(query).unwrap();
+        assert_eq!(retry::error_code(&response), None);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        for transport in &transports {
+            assert_eq!(mock::opcodes(&transport.written()), vec![QUERY]);
+        }
+
+        // the stalled request is dropped after the timeout along with its session
+        let stalled = cluster.hosts()[0];
+        assert_eq!(cluster.pool(&stalled).unwrap().size(), 1);
+        core.run(Timeout::new(Duration::from_millis(100), &core.handle()).unwrap()).unwrap();
+        assert_eq!(cluster.pool(&stalled).unwrap().size(), 0);
+    }
+
+    #[test]
+    fn speculates_at_once_when_first_host_fails() {
+        let hosts: Vec<SocketAddr> = (1..3)
+            .map(|i| format!("10.0.0.{}:9042", i).parse().unwrap())
+            .collect();
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let connected = transport.clone();
+        let refused = hosts[0];
+        let cluster = Cluster::new(hosts.clone(), move |host| {
+            if host == refused {
+                return future::err("Connection refused".into()).boxed();
+            }
+            let cdrs = CDRS::new(connected.clone(), NoneAuthenticator);
+            future::ok(Session::start(cdrs)).boxed()
+        });
+
+        let mut core = Core::new().unwrap();
+        let started = Instant::now();
+        let query = cluster.query_speculative(QueryBuilder::new("SELECT * FROM t").finalize(),
+                                              RequestOptions::new().idempotent(true),
+                                              Duration::from_secs(10),
+                                              &core.handle());
+
+        let response = core.run(query).unwrap();
+        assert_eq!(retry::error_code(&response), None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(mock::opcodes(&transport.written()), vec![QUERY]);
+    }
+
+    #[test]
+    fn never_speculates_on_non_idempotent_requests() {
+        let (cluster, transports) = cluster_with_stalled_host();
+        let mut core = Core::new().unwrap();
+        let query = cluster.query_speculative(QueryBuilder::new("INSERT INTO t").finalize(),
+                                              RequestOptions::new(),
+                                              Duration::from_millis(10),
+                                              &core.handle());
+        let timeout = Timeout::new(Duration::from_millis(100), &core.handle()).unwrap();
+
+        match core.run(query.select2(timeout)) {
+            Ok(Either::B(_)) => {}
+            _ => panic!("the query should wait for the stalled host"),
+        }
+        assert_eq!(mock::opcodes(&transports[0].written()), vec![QUERY]);
+        assert!(transports[1].written().is_empty());
+    }
+
+    /// A cluster of two hosts, with a transport per host.
+    fn cluster_of_two() -> (Cluster<NoneAuthenticator, MockTransport>,
+                            Vec<SocketAddr>,
+                            Vec<MockTransport>) {
+        let hosts: Vec<SocketAddr> = (1..3)
+            .map(|i| format!("10.0.0.{}:9042", i).parse().unwrap())
+            .collect();
+        let transports: HashMap<SocketAddr, MockTransport> =
+            hosts.iter().map(|&host| (host, MockTransport::new())).collect();
+
+        let connected = transports.clone();
+        let cluster = Cluster::new(hosts.clone(), move |host| {
+            let cdrs = CDRS::new(connected[&host].clone(), NoneAuthenticator);
+            future::ok(Session::start(cdrs)).boxed()
+        });
+        let transports = hosts.iter().map(|host| transports[host].clone()).collect();
+        (cluster, hosts, transports)
+    }
+
+    #[test]
+    fn never_resends_requests_which_broke_a_connection() {
+        let (cluster, hosts, transports) = cluster_of_two();
+        transports[0].push_read_error(io::ErrorKind::ConnectionReset);
+        transports[1].push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transports[1].push_read(mock::response(RESULT, 0, &mock::void_body()));
+        let insert = || QueryBuilder::new("INSERT INTO t").finalize();
+
+        match cluster.query(insert()).wait() {
+            Err(error::Error::Io(ref err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionReset)
+            }
+            other => panic!("connection error expected, got {:?}", other.map(|_| ())),
+        }
+        assert!(transports[1].written().is_empty());
+        assert!(cluster.is_down(&hosts[0]));
+        assert!(!cluster.is_down(&hosts[1]));
+
+        // the host which is down goes last even when the policy puts it first
+        cluster.query(insert()).wait().unwrap();
+        cluster.query(insert()).wait().unwrap();
+        assert_eq!(mock::opcodes(&transports[0].written()), vec![QUERY]);
+        assert_eq!(mock::opcodes(&transports[1].written()), vec![QUERY; 2]);
+    }
+
+    #[test]
+    fn resends_idempotent_requests_which_broke_a_connection() {
+        let (cluster, hosts, transports) = cluster_of_two();
+        transports[0].push_read_error(io::ErrorKind::ConnectionReset);
+        transports[1].push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let options = RequestOptions::new().idempotent(true);
+        let response = cluster.query_with(QueryBuilder::new("SELECT * FROM t").finalize(), options)
+            .wait()
+            .unwrap();
+        assert_eq!(retry::error_code(&response), None);
+        for transport in &transports {
+            assert_eq!(mock::opcodes(&transport.written()), vec![QUERY]);
+        }
+        assert!(cluster.is_down(&hosts[0]));
+    }
+
+    #[test]
+    fn fails_if_every_host_is_dead() {
+        let hosts = vec!["10.0.0.1:9042".parse().unwrap(), "10.0.0.2:9042".parse().unwrap()];
+        let cluster: Cluster<NoneAuthenticator, MockTransport> = Cluster::new(hosts, |_| {
+            let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+            future::err(refused.into()).boxed()
+        });
+
+        match cluster.query(QueryBuilder::new("SELECT * FROM t").finalize()).wait() {
+            Err(error::Error::Io(ref err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused)
+            }
+            other => panic!("connection error expected, got {:?}", other.map(|_| ())),
+        }
+    }
+}
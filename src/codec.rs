@@ -0,0 +1,1052 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use futures::{Async, Poll};
+
+use cdrs::{AsByte, IntoBytes};
+use cdrs::compression::{Compression, CompressionError};
+use cdrs::frame::{Frame, Flag, Opcode, Version};
+use cdrs::frame::parser::parse_frame;
+use cdrs::types::to_int;
+
+use error;
+use error::{ChecksumKind, FrameContext, ProtocolViolation};
+use frame_io;
+use request::Override;
+
+/// Length of a frame header in protocol v3 and higher.
+pub const HEADER_LEN: usize = 9;
+/// Stream id used by a server for event frames.
+pub const EVENT_STREAM_ID: i16 = -1;
+/// Max length of a frame body a server accepts, longer frames make it close a connection.
+pub const MAX_BODY_LEN: usize = 256 * 1024 * 1024;
+/// Protocol version negotiated by the client.
+pub const PROTOCOL_VERSION: u8 = 0x04;
+
+const RESPONSE_DIRECTION: u8 = 0x80;
+const TRACING_ID_LEN: usize = 16;
+
+const FLAG_COMPRESSION: u8 = 0x01;
+const FLAG_TRACING: u8 = 0x02;
+const FLAG_CUSTOM_PAYLOAD: u8 = 0x04;
+const FLAG_WARNING: u8 = 0x08;
+
+const OPCODE_ERROR: u8 = 0x00;
+const OPCODE_READY: u8 = 0x02;
+const OPCODE_AUTHENTICATE: u8 = 0x03;
+const OPCODE_SUPPORTED: u8 = 0x06;
+const OPCODE_RESULT: u8 = 0x08;
+const OPCODE_EVENT: u8 = 0x0C;
+const OPCODE_AUTH_CHALLENGE: u8 = 0x0E;
+const OPCODE_AUTH_SUCCESS: u8 = 0x10;
+
+/// A custom payload of a frame, protocol v4 sends it along with requests and
+/// responses for server-side extensions, e.g. query handlers of DSE.
+pub type CustomPayload = HashMap<String, Vec<u8>>;
+
+/// Attaches a custom payload to a request frame. An empty payload doesn't set
+/// the flag, so the frame stays as it is.
+pub fn set_custom_payload(frame: &mut Frame, payload: &CustomPayload) {
+    if payload.is_empty() {
+        return;
+    }
+    let mut body = vec![(payload.len() >> 8) as u8, payload.len() as u8];
+    for (key, value) in payload {
+        body.extend_from_slice(&[(key.len() >> 8) as u8, key.len() as u8]);
+        body.extend_from_slice(key.as_bytes());
+        let len = value.len() as u32;
+        body.extend_from_slice(&[(len >> 24) as u8,
+                                 (len >> 16) as u8,
+                                 (len >> 8) as u8,
+                                 len as u8]);
+        body.extend_from_slice(value);
+    }
+    body.extend(mem::replace(&mut frame.body, vec![]));
+    frame.body = body;
+    frame.flags.push(Flag::CustomPayload);
+}
+
+/// Copies a frame, which `cdrs` doesn't make `Clone`, e.g. to send a request again.
+pub fn clone_frame(frame: &Frame) -> Frame {
+    Frame {
+        version: Version::from(vec![frame.version.as_byte()]),
+        flags: Flag::get_collection(Flag::many_to_cbytes(&frame.flags)),
+        opcode: Opcode::from(frame.opcode.as_byte()),
+        stream: frame.stream,
+        body: frame.body.clone(),
+        tracing_id: frame.tracing_id,
+        warnings: frame.warnings.clone(),
+    }
+}
+
+/// Raw header of a frame received from a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub flags: u8,
+    pub stream: i16,
+    pub opcode: u8,
+    pub length: i32,
+}
+
+impl FrameHeader {
+    pub fn parse(bytes: &[u8; HEADER_LEN]) -> FrameHeader {
+        FrameHeader {
+            version: bytes[0],
+            flags: bytes[1],
+            stream: ((bytes[2] as i16) << 8) | bytes[3] as i16,
+            opcode: bytes[4],
+            length: ((bytes[5] as i32) << 24) | ((bytes[6] as i32) << 16) |
+                    ((bytes[7] as i32) << 8) | bytes[8] as i32,
+        }
+    }
+
+    /// Checks the header against what the client expects to receive. Fields are checked
+    /// in the order they appear on the wire and the first broken one is reported.
+    pub fn validate(&self, expectation: &Expectation) -> Result<(), ProtocolViolation> {
+        if self.version & RESPONSE_DIRECTION == 0 {
+            return Err(ProtocolViolation::Direction { actual: self.version });
+        }
+
+        let expected_version = PROTOCOL_VERSION | RESPONSE_DIRECTION;
+        if self.version != expected_version {
+            return Err(ProtocolViolation::Version {
+                           expected: expected_version,
+                           actual: self.version,
+                       });
+        }
+
+        let unexpected_flags = self.flags & !expectation.allowed_flags();
+        if unexpected_flags != 0 {
+            return Err(ProtocolViolation::Flags { unexpected: unexpected_flags });
+        }
+
+        let is_event = self.stream == EVENT_STREAM_ID && self.opcode == OPCODE_EVENT;
+        if !is_event && !expectation.multiplexed && self.stream != expectation.stream {
+            return Err(ProtocolViolation::StreamId {
+                           expected: expectation.stream,
+                           actual: self.stream,
+                       });
+        }
+
+        if !is_response_opcode(self.opcode) {
+            return Err(ProtocolViolation::Opcode { actual: self.opcode });
+        }
+
+        if self.length < 0 {
+            return Err(ProtocolViolation::Length { actual: self.length });
+        }
+
+        Ok(())
+    }
+}
+
+/// What the client expects from a response to an outstanding request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expectation {
+    /// Stream id of the outstanding request.
+    pub stream: i16,
+    /// Whether compression was negotiated for the connection.
+    pub compression: bool,
+    /// Whether the outstanding request asked for tracing.
+    pub tracing: bool,
+    /// Whether several requests are outstanding, so a response may come
+    /// on any stream.
+    pub multiplexed: bool,
+}
+
+impl Expectation {
+    /// Builds an expectation of a response to a given request frame.
+    pub fn response_to(request: &Frame, compressor: &Compression) -> Expectation {
+        Expectation {
+            stream: request.stream as i16,
+            compression: *compressor != Compression::None,
+            tracing: request.flags.contains(&Flag::Tracing),
+            multiplexed: false,
+        }
+    }
+
+    /// Builds an expectation of a response to any of outstanding requests
+    /// of a multiplexed connection.
+    pub fn multiplexed(compressor: &Compression) -> Expectation {
+        Expectation {
+            stream: 0,
+            compression: *compressor != Compression::None,
+            tracing: true,
+            multiplexed: true,
+        }
+    }
+
+    fn allowed_flags(&self) -> u8 {
+        let mut flags = FLAG_CUSTOM_PAYLOAD | FLAG_WARNING;
+        if self.compression {
+            flags |= FLAG_COMPRESSION;
+        }
+        if self.tracing {
+            flags |= FLAG_TRACING;
+        }
+        flags
+    }
+}
+
+fn is_response_opcode(opcode: u8) -> bool {
+    match opcode {
+        OPCODE_ERROR |
+        OPCODE_READY |
+        OPCODE_AUTHENTICATE |
+        OPCODE_SUPPORTED |
+        OPCODE_RESULT |
+        OPCODE_EVENT |
+        OPCODE_AUTH_CHALLENGE |
+        OPCODE_AUTH_SUCCESS => true,
+        _ => false,
+    }
+}
+
+/// Default minimal size of a frame body which gets compressed.
+pub const DEFAULT_COMPRESSION_MIN_SIZE: usize = 512;
+
+/// Numbers of request frames sent with and without compression.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CompressionStats {
+    pub compressed: u64,
+    pub uncompressed: u64,
+}
+
+/// Serializes request frames, compressing bodies which are big enough.
+///
+/// Compression flag is set per frame, so small frames are sent uncompressed even
+/// when a session negotiated a codec: compressing a few dozens of bytes costs CPU
+/// and usually makes the payload bigger.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FrameEncoder {
+    min_size: usize,
+    max_body_len: usize,
+    stats: CompressionStats,
+}
+
+impl Default for FrameEncoder {
+    fn default() -> FrameEncoder {
+        FrameEncoder::new(DEFAULT_COMPRESSION_MIN_SIZE)
+    }
+}
+
+impl FrameEncoder {
+    pub fn new(min_size: usize) -> FrameEncoder {
+        FrameEncoder {
+            min_size: min_size,
+            max_body_len: MAX_BODY_LEN,
+            stats: CompressionStats::default(),
+        }
+    }
+
+    pub fn set_min_size(&mut self, min_size: usize) {
+        self.min_size = min_size;
+    }
+
+    /// Sets a max length of a frame body, longer frames are rejected with
+    /// `FrameTooLarge`. It's `MAX_BODY_LEN` by default.
+    pub fn set_max_body_len(&mut self, max_body_len: usize) {
+        self.max_body_len = max_body_len;
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    pub fn encode(&mut self, frame: Frame, compressor: &Compression) -> error::Result<Vec<u8>> {
+        self.encode_with(frame, compressor, Override::Auto)
+    }
+
+    /// Works as `encode` but lets a request force compression on or off.
+    pub fn encode_with(&mut self,
+                       mut frame: Frame,
+                       compressor: &Compression,
+                       compression: Override)
+                       -> error::Result<Vec<u8>> {
+        let negotiated = *compressor != Compression::None;
+        let compress = match compression {
+            Override::Auto => negotiated && frame.body.len() >= self.min_size,
+            Override::ForceOn if negotiated => true,
+            Override::ForceOn => {
+                return Err("Compression is forced for a request, but the session \
+                            has not negotiated any"
+                                   .into())
+            }
+            Override::ForceOff => false,
+        };
+
+        if compress {
+            let body = mem::replace(&mut frame.body, vec![]);
+            frame.body = try!(compress_body(body, compressor).map_err(|err| {
+                error::Error::General(format!("Cannot compress frame body: {:?}", err))
+            }));
+            frame.flags.push(Flag::Compression);
+        } else {
+            frame.flags.retain(|flag| *flag != Flag::Compression);
+        }
+
+        // it's rejected before a single byte is written, so the connection stays usable
+        if frame.body.len() > self.max_body_len {
+            return Err(error::Error::FrameTooLarge {
+                           length: frame.body.len(),
+                           max: self.max_body_len,
+                       });
+        }
+
+        if compress {
+            self.stats.compressed += 1;
+        } else {
+            self.stats.uncompressed += 1;
+        }
+        Ok(frame.into_cbytes())
+    }
+}
+
+/// Compresses a body of a request frame. cdrs doesn't prefix an lz4 body with
+/// the length of the uncompressed one, as the protocol expects.
+pub fn compress_body(body: Vec<u8>,
+                     compressor: &Compression)
+                     -> Result<Vec<u8>, CompressionError> {
+    let len = body.len();
+    let compressed = try!(compressor.encode(body));
+    match *compressor {
+        Compression::Lz4 => {
+            let mut prefixed = to_int(len as i32);
+            prefixed.extend(compressed);
+            Ok(prefixed)
+        }
+        _ => Ok(compressed),
+    }
+}
+
+/// Initial value of a CRC24 checksum of protocol v5 segment headers.
+const CRC24_INIT: u32 = 0x875060;
+const CRC24_POLY: u32 = 0x1974F0B;
+/// Bytes protocol v5 feeds into a payload CRC32 before a payload itself.
+const CRC32_INITIAL_BYTES: [u8; 4] = [0xFA, 0x2D, 0x55, 0xCA];
+
+/// CRC24 of a protocol v5 segment header.
+pub fn crc24(bytes: &[u8]) -> u32 {
+    let mut crc = CRC24_INIT;
+    for &byte in bytes {
+        crc ^= (byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+    crc & 0xFFFFFF
+}
+
+/// CRC32 of a protocol v5 segment payload.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in CRC32_INITIAL_BYTES.iter().chain(bytes) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Resumable reader of response frames.
+///
+/// Bytes are accumulated across reads: first the header, which is validated as soon as
+/// it is complete, then the body up to the declared length. When a reader returns
+/// `WouldBlock` everything read so far is kept and `NotReady` is returned, so the
+/// decoder can be polled again once the transport is readable without losing or
+/// re-reading a single byte.
+///
+/// Compressed bodies are decompressed by the decoder, so a corrupted body is reported
+/// as `DecompressionFailed` along with the peer and the opcode of its frame.
+///
+/// A custom payload is cut out of a body before it's parsed, it's kept by the decoder
+/// until the next frame, see `take_custom_payload`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    header: Option<FrameHeader>,
+    peer: Option<SocketAddr>,
+    last_opcode: Option<u8>,
+    custom_payload: Vec<(String, Vec<u8>)>,
+    read: u64,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::default()
+    }
+
+    /// Sets an address of a server frames are read from, it's reported
+    /// by errors about corrupted frames.
+    pub fn set_peer(&mut self, peer: SocketAddr) {
+        self.peer = Some(peer);
+    }
+
+    /// Number of bytes read since the decoder was created.
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    /// Where the decoder is in a stream: its peer and the opcode of the latest frame.
+    pub fn context(&self) -> FrameContext {
+        FrameContext {
+            peer: self.peer,
+            opcode: self.header.map(|header| header.opcode).or(self.last_opcode),
+        }
+    }
+
+    /// Checks that `bytes` have the `expected` checksum of a given kind.
+    pub fn verify_checksum(&self,
+                           kind: ChecksumKind,
+                           bytes: &[u8],
+                           expected: u32)
+                           -> error::Result<()> {
+        let actual = match kind {
+            ChecksumKind::Header => crc24(bytes),
+            ChecksumKind::Payload => crc32(bytes),
+        };
+        if actual != expected {
+            return Err(error::Error::ChecksumMismatch {
+                           kind: kind,
+                           expected: expected,
+                           actual: actual,
+                           context: self.context(),
+                       });
+        }
+        Ok(())
+    }
+
+    /// Takes a custom payload of a frame which was read last, it's empty
+    /// if the frame has none.
+    pub fn take_custom_payload(&mut self) -> CustomPayload {
+        mem::replace(&mut self.custom_payload, vec![]).into_iter().collect()
+    }
+
+    /// Returns `true` if a part of a frame has been read already.
+    pub fn is_in_progress(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    pub fn poll_frame<R: io::Read>(&mut self,
+                                   reader: &mut R,
+                                   compressor: &Compression,
+                                   expectation: &Expectation)
+                                   -> Poll<Frame, error::Error> {
+        loop {
+            let expected_len = self.expected_len();
+
+            if self.buffer.len() == expected_len {
+                if self.header.is_none() {
+                    let header = self.read_header();
+                    try!(header.validate(expectation));
+                    self.header = Some(header);
+                    continue;
+                }
+
+                let mut frame_bytes = mem::replace(&mut self.buffer, vec![]);
+                let header = self.header.take().expect("header is read before a body");
+                self.last_opcode = Some(header.opcode);
+                if header.flags & FLAG_COMPRESSION != 0 {
+                    let body = frame_bytes.split_off(HEADER_LEN);
+                    let body = try!(self.decompress(body, compressor));
+                    frame_bytes[1] &= !FLAG_COMPRESSION;
+                    frame_bytes.extend(body);
+                    set_body_len(&mut frame_bytes);
+                }
+                self.custom_payload = if header.flags & FLAG_CUSTOM_PAYLOAD != 0 {
+                    let payload = try!(split_custom_payload(&mut frame_bytes, header.flags));
+                    frame_bytes[1] &= !FLAG_CUSTOM_PAYLOAD;
+                    set_body_len(&mut frame_bytes);
+                    payload
+                } else {
+                    vec![]
+                };
+                return parse_response(frame_bytes).map(Async::Ready);
+            }
+
+            let read_from = self.buffer.len();
+            self.buffer.resize(expected_len, 0);
+            let read_result = frame_io::read(reader, &mut self.buffer[read_from..]);
+
+            match read_result {
+                Ok(Async::Ready(0)) => {
+                    self.buffer.truncate(read_from);
+                    let eof = io::Error::new(io::ErrorKind::UnexpectedEof,
+                                             "connection closed in the middle of a frame");
+                    return Err(eof.into());
+                }
+                Ok(Async::Ready(n)) => {
+                    self.buffer.truncate(read_from + n);
+                    self.read += n as u64;
+                }
+                Ok(Async::NotReady) => {
+                    self.buffer.truncate(read_from);
+                    return Ok(Async::NotReady);
+                }
+                Err(err) => {
+                    self.buffer.truncate(read_from);
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+
+    /// Decompresses a body of a frame which was read last. An lz4 body starts with
+    /// the length of the decompressed body, it's checked too.
+    fn decompress(&self, body: Vec<u8>, compressor: &Compression) -> error::Result<Vec<u8>> {
+        let compressed_len = body.len();
+        let expected_len = match *compressor {
+            Compression::Lz4 if compressed_len >= 4 => {
+                Some(((body[0] as usize) << 24) | ((body[1] as usize) << 16) |
+                     ((body[2] as usize) << 8) | body[3] as usize)
+            }
+            _ => None,
+        };
+
+        match compressor.decode(body) {
+            Ok(decoded) if expected_len.map_or(true, |len| len == decoded.len()) => Ok(decoded),
+            _ => {
+                Err(error::Error::DecompressionFailed {
+                        codec: compressor.as_str().unwrap_or("none").to_string(),
+                        compressed_len: compressed_len,
+                        expected_len: expected_len,
+                        context: self.context(),
+                    })
+            }
+        }
+    }
+
+    fn expected_len(&self) -> usize {
+        match self.header {
+            Some(ref header) => HEADER_LEN + header.length as usize,
+            None => HEADER_LEN,
+        }
+    }
+
+    fn read_header(&self) -> FrameHeader {
+        let mut header_bytes = [0; HEADER_LEN];
+        header_bytes.copy_from_slice(&self.buffer[..HEADER_LEN]);
+        FrameHeader::parse(&header_bytes)
+    }
+}
+
+/// Updates the length in a header of frame bytes after its body is changed.
+/// Parses bytes of an uncompressed frame. `parse_frame` of cdrs turns an ERROR
+/// frame into an error which loses its stream, so it's parsed as a RESULT and
+/// gets its opcode back. It's left to a request to tell an ERROR response.
+pub fn parse_response(mut frame_bytes: Vec<u8>) -> error::Result<Frame> {
+    let is_error = frame_bytes.len() > 4 && frame_bytes[4] == OPCODE_ERROR;
+    if is_error {
+        frame_bytes[4] = OPCODE_RESULT;
+    }
+    let mut frame = try!(parse_frame(&mut io::Cursor::new(frame_bytes), &Compression::None));
+    if is_error {
+        frame.opcode = Opcode::Error;
+    }
+    Ok(frame)
+}
+
+fn set_body_len(frame_bytes: &mut [u8]) {
+    let length = (frame_bytes.len() - HEADER_LEN) as u32;
+    frame_bytes[5..HEADER_LEN].copy_from_slice(&[(length >> 24) as u8,
+                                                 (length >> 16) as u8,
+                                                 (length >> 8) as u8,
+                                                 length as u8]);
+}
+
+/// Cuts a custom payload out of a body of frame bytes. It goes after a tracing id
+/// and warnings if a frame has them.
+fn split_custom_payload(frame_bytes: &mut Vec<u8>,
+                        flags: u8)
+                        -> error::Result<Vec<(String, Vec<u8>)>> {
+    let read_len = |at: usize, size: usize| -> error::Result<usize> {
+        match frame_bytes.get(at..at + size) {
+            Some(bytes) => Ok(bytes.iter().fold(0, |len, byte| (len << 8) | *byte as usize)),
+            None => Err("Custom payload of a frame is truncated".into()),
+        }
+    };
+
+    let mut start = HEADER_LEN;
+    if flags & FLAG_TRACING != 0 {
+        start += TRACING_ID_LEN;
+    }
+    if flags & FLAG_WARNING != 0 {
+        let count = try!(read_len(start, 2));
+        start += 2;
+        for _ in 0..count {
+            start += 2 + try!(read_len(start, 2));
+        }
+    }
+
+    let count = try!(read_len(start, 2));
+    let mut at = start + 2;
+    let mut payload = Vec::with_capacity(count);
+    for _ in 0..count {
+        let key_len = try!(read_len(at, 2));
+        let key = match frame_bytes.get(at + 2..at + 2 + key_len) {
+            Some(key) => String::from_utf8_lossy(key).into_owned(),
+            None => return Err("Custom payload of a frame is truncated".into()),
+        };
+        at += 2 + key_len;
+        let value_len = try!(read_len(at, 4));
+        // a negative length is a null value
+        let value = if value_len > i32::max_value() as usize {
+            at += 4;
+            vec![]
+        } else {
+            let value = match frame_bytes.get(at + 4..at + 4 + value_len) {
+                Some(value) => value.to_vec(),
+                None => return Err("Custom payload of a frame is truncated".into()),
+            };
+            at += 4 + value_len;
+            value
+        };
+        payload.push((key, value));
+    }
+
+    frame_bytes.drain(start..at);
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use futures::Async;
+
+    use super::*;
+    use error::{ChecksumKind, Error, ProtocolViolation};
+    use cdrs::compression::Compression;
+
+    fn expectation() -> Expectation {
+        Expectation {
+            stream: 3,
+            compression: false,
+            tracing: false,
+            multiplexed: false,
+        }
+    }
+
+    fn header(version: u8, flags: u8, stream: i16, opcode: u8, length: i32) -> [u8; HEADER_LEN] {
+        [version,
+         flags,
+         (stream >> 8) as u8,
+         stream as u8,
+         opcode,
+         (length >> 24) as u8,
+         (length >> 16) as u8,
+         (length >> 8) as u8,
+         length as u8]
+    }
+
+    fn violation(bytes: [u8; HEADER_LEN]) -> ProtocolViolation {
+        FrameHeader::parse(&bytes)
+            .validate(&expectation())
+            .unwrap_err()
+    }
+
+    #[test]
+    fn accepts_valid_response() {
+        let ready = FrameHeader::parse(&header(0x84, 0, 3, OPCODE_READY, 0));
+        assert_eq!(ready.validate(&expectation()), Ok(()));
+
+        let event = FrameHeader::parse(&header(0x84, 0, -1, OPCODE_EVENT, 10));
+        assert_eq!(event.length, 10);
+        assert_eq!(event.validate(&expectation()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_request_direction() {
+        assert_eq!(violation(header(0x04, 0, 3, OPCODE_READY, 0)),
+                   ProtocolViolation::Direction { actual: 0x04 });
+    }
+
+    #[test]
+    fn rejects_other_version() {
+        assert_eq!(violation(header(0x83, 0, 3, OPCODE_READY, 0)),
+                   ProtocolViolation::Version {
+                       expected: 0x84,
+                       actual: 0x83,
+                   });
+    }
+
+    #[test]
+    fn rejects_unknown_stream() {
+        assert_eq!(violation(header(0x84, 0, 7, OPCODE_RESULT, 0)),
+                   ProtocolViolation::StreamId {
+                       expected: 3,
+                       actual: 7,
+                   });
+        // only events may come with -1 stream id
+        assert_eq!(violation(header(0x84, 0, -1, OPCODE_RESULT, 0)).field(),
+                   "stream");
+        // a multiplexed connection reads responses on any stream
+        let multiplexed = Expectation::multiplexed(&Compression::None);
+        let response = FrameHeader::parse(&header(0x84, 0, 7, OPCODE_RESULT, 0));
+        assert_eq!(response.validate(&multiplexed), Ok(()));
+    }
+
+    #[test]
+    fn rejects_non_response_opcodes() {
+        // QUERY is a request opcode
+        assert_eq!(violation(header(0x84, 0, 3, 0x07, 0)),
+                   ProtocolViolation::Opcode { actual: 0x07 });
+        assert_eq!(violation(header(0x84, 0, 3, 0x42, 0)),
+                   ProtocolViolation::Opcode { actual: 0x42 });
+    }
+
+    #[test]
+    fn rejects_not_negotiated_flags() {
+        assert_eq!(violation(header(0x84, FLAG_COMPRESSION, 3, OPCODE_RESULT, 0)),
+                   ProtocolViolation::Flags { unexpected: FLAG_COMPRESSION });
+        assert_eq!(violation(header(0x84, FLAG_TRACING | FLAG_WARNING, 3, OPCODE_RESULT, 0)),
+                   ProtocolViolation::Flags { unexpected: FLAG_TRACING });
+    }
+
+    #[test]
+    fn rejects_negative_length() {
+        assert_eq!(violation(header(0x84, 0, 3, OPCODE_RESULT, -1)).field(),
+                   "length");
+    }
+
+    /// Reader which returns data in given chunks and `WouldBlock` between them.
+    struct ChunkedReader {
+        chunks: Vec<Vec<u8>>,
+        blocked: bool,
+    }
+
+    impl ChunkedReader {
+        fn new(bytes: &[u8], split_points: &[usize]) -> ChunkedReader {
+            let mut chunks = vec![];
+            let mut from = 0;
+            for &point in split_points.iter().chain(Some(bytes.len()).iter()) {
+                chunks.push(bytes[from..point].to_vec());
+                from = point;
+            }
+            chunks.reverse();
+
+            ChunkedReader {
+                chunks: chunks,
+                blocked: false,
+            }
+        }
+    }
+
+    impl io::Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.blocked {
+                self.blocked = true;
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"));
+            }
+            self.blocked = false;
+
+            let mut chunk = match self.chunks.pop() {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+            let n = ::std::cmp::min(buf.len(), chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            let rest = chunk.split_off(n);
+            if !rest.is_empty() {
+                self.chunks.push(rest);
+            }
+            Ok(n)
+        }
+    }
+
+    /// SUPPORTED frame with `COMPRESSION: [lz4, snappy]` body.
+    fn supported_frame() -> Vec<u8> {
+        let mut body = vec![0, 1, 0, 11];
+        body.extend_from_slice(b"COMPRESSION");
+        body.extend_from_slice(&[0, 2]);
+        for s in &["lz4", "snappy"] {
+            body.extend_from_slice(&[0, s.len() as u8]);
+            body.extend_from_slice(s.as_bytes());
+        }
+
+        let mut frame = header(0x84, 0, 3, OPCODE_SUPPORTED, body.len() as i32).to_vec();
+        frame.extend(body);
+        frame
+    }
+
+    fn decode(bytes: &[u8], split_points: &[usize]) -> Frame {
+        let mut reader = ChunkedReader::new(bytes, split_points);
+        let mut decoder = FrameDecoder::new();
+
+        loop {
+            match decoder.poll_frame(&mut reader, &Compression::None, &expectation()) {
+                Ok(Async::Ready(frame)) => {
+                    assert!(!decoder.is_in_progress());
+                    return frame;
+                }
+                Ok(Async::NotReady) => continue,
+                Err(err) => panic!("Unexpected error {:?} for split {:?}", err, split_points),
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_every_two_chunk_partition() {
+        let bytes = supported_frame();
+        let whole = decode(&bytes, &[]);
+
+        for point in 1..bytes.len() {
+            let frame = decode(&bytes, &[point]);
+            assert_eq!(frame.opcode, whole.opcode);
+            assert_eq!(frame.stream, whole.stream);
+            assert_eq!(frame.body, whole.body);
+        }
+    }
+
+    #[test]
+    fn decodes_multi_chunk_partitions() {
+        let bytes = supported_frame();
+        let whole = decode(&bytes, &[]);
+        // simple LCG keeps partitions reproducible
+        let mut seed: u32 = 7;
+
+        for _ in 0..20 {
+            let mut points = vec![];
+            for _ in 0..5 {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                points.push(1 + (seed >> 16) as usize % (bytes.len() - 1));
+            }
+            points.sort();
+            points.dedup();
+
+            let frame = decode(&bytes, &points);
+            assert_eq!(frame.body, whole.body);
+        }
+    }
+
+    #[test]
+    fn reports_violation_before_body() {
+        let bytes = header(0x84, FLAG_COMPRESSION, 3, OPCODE_READY, 0);
+        let mut cursor = io::Cursor::new(bytes.to_vec());
+        let mut decoder = FrameDecoder::new();
+
+        match decoder.poll_frame(&mut cursor, &Compression::None, &expectation()) {
+            Err(Error::ProtocolViolation(violation)) => assert_eq!(violation.field(), "flags"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    fn compressed_result(body: Vec<u8>) -> Vec<u8> {
+        let mut frame = header(0x84, FLAG_COMPRESSION, 3, OPCODE_RESULT, body.len() as i32)
+            .to_vec();
+        frame.extend(body);
+        frame
+    }
+
+    fn lz4_expectation() -> Expectation {
+        Expectation { compression: true, ..expectation() }
+    }
+
+    #[test]
+    fn decompresses_bodies() {
+        let body = vec![0, 0, 0, 1];
+        let bytes = compressed_result(compress_body(body.clone(), &Compression::Lz4).unwrap());
+        let mut cursor = io::Cursor::new(bytes);
+        let mut decoder = FrameDecoder::new();
+
+        match decoder.poll_frame(&mut cursor, &Compression::Lz4, &lz4_expectation()) {
+            Ok(Async::Ready(frame)) => assert_eq!(frame.body, body),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_truncated_compressed_body() {
+        let mut compressed = compress_body(vec![7; 100], &Compression::Lz4).unwrap();
+        let truncated_len = compressed.len() - 2;
+        compressed.truncate(truncated_len);
+        let mut decoder = FrameDecoder::new();
+        decoder.set_peer("127.0.0.1:9042".parse().unwrap());
+
+        let mut cursor = io::Cursor::new(compressed_result(compressed));
+        match decoder.poll_frame(&mut cursor, &Compression::Lz4, &lz4_expectation()) {
+            Err(err @ Error::DecompressionFailed { .. }) => {
+                assert!(err.breaks_connection());
+                assert_eq!(err.to_string(),
+                           format!("Cannot decompress lz4 body of {} bytes into 100 bytes \
+                                    (peer 127.0.0.1:9042, opcode 0x08)",
+                                   truncated_len));
+            }
+            other => panic!("DecompressionFailed expected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_checksum_mismatch() {
+        let mut decoder = FrameDecoder::new();
+        let bytes = supported_frame();
+        assert!(decoder.verify_checksum(ChecksumKind::Header, &bytes[..3], crc24(&bytes[..3]))
+                    .is_ok());
+        assert!(decoder.verify_checksum(ChecksumKind::Payload, &bytes, crc32(&bytes)).is_ok());
+
+        decoder.poll_frame(&mut io::Cursor::new(bytes.clone()), &Compression::None, &expectation())
+            .unwrap();
+        let payload_crc = crc32(&bytes);
+        let err = decoder.verify_checksum(ChecksumKind::Payload, &bytes, payload_crc ^ 1)
+            .unwrap_err();
+        assert!(err.breaks_connection());
+        match err {
+            Error::ChecksumMismatch { kind: ChecksumKind::Payload, expected, actual, context } => {
+                assert_eq!((expected, actual), (payload_crc ^ 1, payload_crc));
+                assert_eq!(context.opcode, Some(OPCODE_SUPPORTED));
+            }
+            other => panic!("ChecksumMismatch expected, got {:?}", other),
+        }
+
+        let header_crc = crc24(&bytes[..3]);
+        match decoder.verify_checksum(ChecksumKind::Header, &bytes[..3], header_crc ^ 0x10) {
+            Err(Error::ChecksumMismatch { kind: ChecksumKind::Header, actual, .. }) => {
+                assert_eq!(actual, header_crc)
+            }
+            other => panic!("ChecksumMismatch expected, got {:?}", other),
+        }
+    }
+
+    fn query_frame(len: usize) -> Frame {
+        let query = "a".repeat(len);
+        Frame::new_req_prepare(query, vec![])
+    }
+
+    #[test]
+    fn compresses_only_big_bodies() {
+        let mut encoder = FrameEncoder::new(512);
+
+        let small = query_frame(10);
+        let small_body = small.body.clone();
+        let bytes = encoder.encode(small, &Compression::Lz4).unwrap();
+        assert_eq!(bytes[1] & FLAG_COMPRESSION, 0);
+        assert_eq!(&bytes[HEADER_LEN..], small_body.as_slice());
+
+        let big = query_frame(2048);
+        let big_body = big.body.clone();
+        let bytes = encoder.encode(big, &Compression::Lz4).unwrap();
+        assert_eq!(bytes[1] & FLAG_COMPRESSION, FLAG_COMPRESSION);
+        assert_eq!(&bytes[HEADER_LEN..],
+                   compress_body(big_body, &Compression::Lz4).unwrap().as_slice());
+
+        assert_eq!(encoder.stats(),
+                   CompressionStats {
+                       compressed: 1,
+                       uncompressed: 1,
+                   });
+    }
+
+    #[test]
+    fn never_compresses_without_codec() {
+        let mut encoder = FrameEncoder::new(0);
+        let bytes = encoder.encode(query_frame(2048), &Compression::None).unwrap();
+        assert_eq!(bytes[1] & FLAG_COMPRESSION, 0);
+        assert_eq!(encoder.stats().uncompressed, 1);
+    }
+
+    #[test]
+    fn compression_override() {
+        let mut encoder = FrameEncoder::new(512);
+
+        let small = query_frame(10);
+        let small_body = small.body.clone();
+        let bytes = encoder.encode_with(small, &Compression::Lz4, Override::ForceOn).unwrap();
+        assert_eq!(bytes[1] & FLAG_COMPRESSION, FLAG_COMPRESSION);
+        assert_eq!(&bytes[HEADER_LEN..],
+                   compress_body(small_body, &Compression::Lz4).unwrap().as_slice());
+
+        let big = query_frame(2048);
+        let big_body = big.body.clone();
+        let bytes = encoder.encode_with(big, &Compression::Lz4, Override::ForceOff).unwrap();
+        assert_eq!(bytes[1] & FLAG_COMPRESSION, 0);
+        assert_eq!(&bytes[HEADER_LEN..], big_body.as_slice());
+
+        let big = query_frame(2048);
+        let big_body = big.body.clone();
+        let bytes = encoder.encode_with(big, &Compression::Lz4, Override::Auto).unwrap();
+        assert_eq!(bytes[1] & FLAG_COMPRESSION, FLAG_COMPRESSION);
+        assert_eq!(&bytes[HEADER_LEN..],
+                   compress_body(big_body, &Compression::Lz4).unwrap().as_slice());
+
+        assert_eq!(encoder.stats(),
+                   CompressionStats {
+                       compressed: 2,
+                       uncompressed: 1,
+                   });
+    }
+
+    #[test]
+    fn forced_compression_requires_codec() {
+        let mut encoder = FrameEncoder::new(512);
+        let result = encoder.encode_with(query_frame(10), &Compression::None, Override::ForceOn);
+        assert!(result.is_err());
+    }
+
+    /// A bytes map with a single entry.
+    fn payload_bytes(key: &str, value: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0, 1, 0, key.len() as u8];
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, value.len() as u8]);
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    #[test]
+    fn attaches_custom_payload() {
+        let mut frame = query_frame(10);
+        let body = frame.body.clone();
+        set_custom_payload(&mut frame, &CustomPayload::new());
+        assert_eq!(frame.body, body);
+        assert!(!frame.flags.contains(&Flag::CustomPayload));
+
+        let mut payload = CustomPayload::new();
+        payload.insert("proxy".to_string(), b"route-a".to_vec());
+        set_custom_payload(&mut frame, &payload);
+        let mut expected = payload_bytes("proxy", b"route-a");
+        expected.extend(body);
+        assert_eq!(frame.body, expected);
+
+        let bytes = FrameEncoder::default().encode(frame, &Compression::None).unwrap();
+        assert_eq!(bytes[1], FLAG_CUSTOM_PAYLOAD);
+    }
+
+    #[test]
+    fn cuts_custom_payload_out_of_responses() {
+        let void = [0, 0, 0, 1];
+        let mut body = payload_bytes("proxy", b"route-a");
+        body.extend_from_slice(&void);
+        let mut bytes = header(0x84, FLAG_CUSTOM_PAYLOAD, 3, OPCODE_RESULT, body.len() as i32)
+            .to_vec();
+        bytes.extend(body);
+
+        let mut decoder = FrameDecoder::new();
+        match decoder.poll_frame(&mut io::Cursor::new(bytes), &Compression::None, &expectation()) {
+            Ok(Async::Ready(frame)) => assert_eq!(frame.body, void),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        let payload = decoder.take_custom_payload();
+        assert_eq!(payload.get("proxy"), Some(&b"route-a".to_vec()));
+        assert!(decoder.take_custom_payload().is_empty());
+
+        // the payload goes after warnings
+        let warning = "Batch is too large";
+        let mut body = vec![0, 1, 0, warning.len() as u8];
+        body.extend_from_slice(warning.as_bytes());
+        body.extend(payload_bytes("empty", b""));
+        body.extend_from_slice(&void);
+        let flags = FLAG_WARNING | FLAG_CUSTOM_PAYLOAD;
+        let mut bytes = header(0x84, flags, 3, OPCODE_RESULT, body.len() as i32).to_vec();
+        bytes.extend(body);
+
+        match decoder.poll_frame(&mut io::Cursor::new(bytes), &Compression::None, &expectation()) {
+            Ok(Async::Ready(frame)) => assert_eq!(frame.warnings, vec![warning.to_string()]),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(decoder.take_custom_payload().get("empty"), Some(&vec![]));
+    }
+}
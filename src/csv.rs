@@ -0,0 +1,323 @@
+//! Export of query results as CSV (RFC 4180).
+//!
+//! Values are formatted from their serialized form according to column types:
+//! uuids are hyphenated, timestamps are ISO 8601 in UTC, blobs are hex or base64
+//! and collections look like JSON. Nulls are empty fields. Types without
+//! a readable form (varint, decimal, UDTs, tuples, custom) are written as hex.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use cdrs::frame::frame_result::{BodyResResultRows, ColSpec, ColType, ColTypeOption,
+                                ColTypeOptionValue};
+
+use base64::{self, Alphabet};
+use validation;
+
+/// How blobs are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BlobFormat {
+    /// `0x` followed by hex digits, the way cqlsh prints them.
+    Hex,
+    /// Standard base64 with padding.
+    Base64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CsvOptions {
+    /// Write a row of column names first.
+    pub header: bool,
+    pub blobs: BlobFormat,
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions {
+            header: true,
+            blobs: BlobFormat::Hex,
+        }
+    }
+}
+
+/// Writes rows of a single result, with a header if options say so.
+/// Returns the number of written rows.
+pub fn rows_to_csv<W: io::Write>(writer: &mut W,
+                                 rows: BodyResResultRows,
+                                 options: &CsvOptions)
+                                 -> io::Result<usize> {
+    if options.header {
+        try!(write_header(writer, &rows.metadata.col_specs));
+    }
+    write_rows(writer, rows, options)
+}
+
+/// Writes a row of column names.
+pub fn write_header<W: io::Write>(writer: &mut W, columns: &[ColSpec]) -> io::Result<()> {
+    let names: Vec<_> = columns.iter().map(|column| column.name.as_plain()).collect();
+    write_record(writer, &names)
+}
+
+/// Writes rows without a header, so pages of one result can be written one by one.
+/// Returns the number of written rows.
+pub fn write_rows<W: io::Write>(writer: &mut W,
+                                rows: BodyResResultRows,
+                                options: &CsvOptions)
+                                -> io::Result<usize> {
+    let columns = rows.metadata.col_specs;
+    let count = rows.rows_content.len();
+
+    for row in rows.rows_content {
+        let fields: Vec<_> = row.into_iter()
+            .zip(&columns)
+            .map(|(cell, column)| if cell.is_empty() {
+                     // cdrs reads null as empty bytes
+                     String::new()
+                 } else {
+                     format_value(&column.col_type, cell.as_slice(), options)
+                 })
+            .collect();
+        try!(write_record(writer, &fields));
+    }
+
+    Ok(count)
+}
+
+fn write_record<W: io::Write>(writer: &mut W, fields: &[String]) -> io::Result<()> {
+    let fields: Vec<_> = fields.iter().map(|field| quote(field)).collect();
+    try!(writer.write_all(fields.join(",").as_bytes()));
+    writer.write_all(b"\r\n")
+}
+
+/// Quotes a field if it has a comma, a quote or a line break.
+pub fn quote(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Formats a serialized value of type `col_type`.
+pub fn format_value(col_type: &ColTypeOption, bytes: &[u8], options: &CsvOptions) -> String {
+    match col_type.id {
+        ColType::Ascii | ColType::Varchar => String::from_utf8_lossy(bytes).into_owned(),
+        ColType::Boolean if bytes.len() == 1 => (bytes[0] != 0).to_string(),
+        ColType::Tinyint if bytes.len() == 1 => (bytes[0] as i8).to_string(),
+        ColType::Smallint if bytes.len() == 2 => (read_be(bytes) as i16).to_string(),
+        ColType::Int if bytes.len() == 4 => (read_be(bytes) as i32).to_string(),
+        ColType::Bigint | ColType::Counter | ColType::Time if bytes.len() == 8 => {
+            (read_be(bytes) as i64).to_string()
+        }
+        ColType::Float if bytes.len() == 4 => {
+            f32::from_bits(read_be(bytes) as u32).to_string()
+        }
+        ColType::Double if bytes.len() == 8 => f64::from_bits(read_be(bytes)).to_string(),
+        ColType::Timestamp if bytes.len() == 8 => format_timestamp(read_be(bytes) as i64),
+        ColType::Date if bytes.len() == 4 => {
+            // days are counted from 2^31 which is the epoch
+            let days = read_be(bytes) as i64 - (1 << 31);
+            let (year, month, day) = civil_from_days(days);
+            format!("{:04}-{:02}-{:02}", year, month, day)
+        }
+        ColType::Uuid | ColType::Timeuuid if bytes.len() == 16 => format_uuid(bytes),
+        ColType::Inet if bytes.len() == 4 => {
+            Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]).to_string()
+        }
+        ColType::Inet if bytes.len() == 16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(bytes);
+            Ipv6Addr::from(octets).to_string()
+        }
+        ColType::Blob => {
+            match options.blobs {
+                BlobFormat::Hex => format!("0x{}", hex(bytes)),
+                BlobFormat::Base64 => base64::encode(bytes, Alphabet::Standard),
+            }
+        }
+        ColType::List | ColType::Set | ColType::Map => {
+            format_collection(col_type, bytes, options)
+                .unwrap_or_else(|| format!("0x{}", hex(bytes)))
+        }
+        _ => format!("0x{}", hex(bytes)),
+    }
+}
+
+fn format_collection(col_type: &ColTypeOption,
+                     bytes: &[u8],
+                     options: &CsvOptions)
+                     -> Option<String> {
+    match col_type.value {
+        Some(ColTypeOptionValue::CList(ref element)) |
+        Some(ColTypeOptionValue::CSet(ref element)) => {
+            let elements = match validation::split_elements(bytes, 1) {
+                Some(elements) => elements,
+                None => return None,
+            };
+            let elements: Vec<_> = elements.iter()
+                .map(|element_bytes| format_element(element, element_bytes, options))
+                .collect();
+            Some(format!("[{}]", elements.join(",")))
+        }
+        Some(ColTypeOptionValue::CMap((ref key, ref value))) => {
+            let elements = match validation::split_elements(bytes, 2) {
+                Some(elements) => elements,
+                None => return None,
+            };
+            let entries: Vec<_> = elements.chunks(2)
+                .map(|pair| {
+                         format!("{}:{}",
+                                 format_element(key, pair[0], options),
+                                 format_element(value, pair[1], options))
+                     })
+                .collect();
+            Some(format!("{{{}}}", entries.join(",")))
+        }
+        _ => None,
+    }
+}
+
+/// Formats an element of a collection, textual elements are quoted like JSON strings.
+fn format_element(col_type: &ColTypeOption, bytes: &[u8], options: &CsvOptions) -> String {
+    let value = format_value(col_type, bytes, options);
+    match col_type.id {
+        ColType::Boolean | ColType::Tinyint | ColType::Smallint | ColType::Int |
+        ColType::Bigint | ColType::Counter | ColType::Float | ColType::Double |
+        ColType::List | ColType::Set | ColType::Map => value,
+        _ => format!("{:?}", value),
+    }
+}
+
+fn read_be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, b| n << 8 | *b as u64)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn format_uuid(bytes: &[u8]) -> String {
+    format!("{}-{}-{}-{}-{}",
+            hex(&bytes[0..4]),
+            hex(&bytes[4..6]),
+            hex(&bytes[6..8]),
+            hex(&bytes[8..10]),
+            hex(&bytes[10..16]))
+}
+
+/// Formats milliseconds since the epoch as `2017-06-01T12:30:00.000Z`.
+fn format_timestamp(millis: i64) -> String {
+    let days = div_floor(millis, 86400000);
+    let millis_of_day = millis - days * 86400000;
+    let (year, month, day) = civil_from_days(days);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            millis_of_day / 3600000,
+            millis_of_day / 60000 % 60,
+            millis_of_day / 1000 % 60,
+            millis_of_day % 1000)
+}
+
+fn div_floor(a: i64, b: i64) -> i64 {
+    if a >= 0 { a / b } else { (a - b + 1) / b }
+}
+
+/// Converts days since 1970-01-01 into a date of the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = div_floor(z, 146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use cdrs::frame::frame_result::{ColType, ColTypeOption, ColTypeOptionValue};
+
+    use super::*;
+
+    fn simple(id: ColType) -> ColTypeOption {
+        ColTypeOption {
+            id: id,
+            value: None,
+        }
+    }
+
+    fn format(id: ColType, bytes: &[u8]) -> String {
+        format_value(&simple(id), bytes, &CsvOptions::default())
+    }
+
+    #[test]
+    fn quotes_fields() {
+        assert_eq!(quote("plain"), "plain");
+        assert_eq!(quote("a,b"), "\"a,b\"");
+        assert_eq!(quote("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(quote("two\nlines"), "\"two\nlines\"");
+
+        let mut csv = vec![];
+        write_record(&mut csv,
+                     &["1".to_string(), "a,b".to_string(), String::new()])
+                .unwrap();
+        assert_eq!(csv, b"1,\"a,b\",\r\n".to_vec());
+    }
+
+    #[test]
+    fn formats_scalars() {
+        assert_eq!(format(ColType::Int, &[0xff, 0xff, 0xff, 0xfe]), "-2");
+        assert_eq!(format(ColType::Boolean, &[1]), "true");
+        assert_eq!(format(ColType::Double, &[0x3f, 0xf8, 0, 0, 0, 0, 0, 0]), "1.5");
+        assert_eq!(format(ColType::Inet, &[127, 0, 0, 1]), "127.0.0.1");
+        assert_eq!(format(ColType::Uuid,
+                          &[0x55, 0x0e, 0x84, 0x00, 0xe2, 0x9b, 0x41, 0xd4, 0xa7, 0x16, 0x44,
+                            0x66, 0x55, 0x44, 0x00, 0x00]),
+                   "550e8400-e29b-41d4-a716-446655440000");
+    }
+
+    #[test]
+    fn formats_dates_as_iso8601() {
+        // 2017-06-01T12:30:15.250Z
+        let millis: i64 = 1496320215250;
+        let bytes: Vec<u8> = (0..8).rev().map(|i| (millis >> (8 * i)) as u8).collect();
+        assert_eq!(format(ColType::Timestamp, &bytes), "2017-06-01T12:30:15.250Z");
+
+        // a day before the epoch
+        assert_eq!(format(ColType::Date, &[0x7f, 0xff, 0xff, 0xff]), "1969-12-31");
+    }
+
+    #[test]
+    fn formats_blobs() {
+        assert_eq!(format(ColType::Blob, &[0xca, 0xfe]), "0xcafe");
+
+        let options = CsvOptions { blobs: BlobFormat::Base64, ..CsvOptions::default() };
+        assert_eq!(format_value(&simple(ColType::Blob), b"hello", &options), "aGVsbG8=");
+    }
+
+    #[test]
+    fn formats_collections() {
+        let list = ColTypeOption {
+            id: ColType::List,
+            value: Some(ColTypeOptionValue::CList(Box::new(simple(ColType::Varchar)))),
+        };
+        let bytes = [0, 0, 0, 2, 0, 0, 0, 1, b'a', 0, 0, 0, 3, b'b', b'"', b'c'];
+        assert_eq!(format_value(&list, &bytes, &CsvOptions::default()),
+                   "[\"a\",\"b\\\"c\"]");
+
+        let map = ColTypeOption {
+            id: ColType::Map,
+            value: Some(ColTypeOptionValue::CMap((Box::new(simple(ColType::Varchar)),
+                                                  Box::new(simple(ColType::Int))))),
+        };
+        let bytes = [0, 0, 0, 1, 0, 0, 0, 1, b'k', 0, 0, 0, 4, 0, 0, 0, 7];
+        assert_eq!(format_value(&map, &bytes, &CsvOptions::default()),
+                   "{\"k\":7}");
+    }
+}
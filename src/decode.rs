@@ -0,0 +1,134 @@
+//! Offloading of conversions of result rows into Rust types.
+//!
+//! Converting a big result on a reactor thread stalls I/O of every other
+//! connection of the reactor. A session with a decode executor ships conversions
+//! of each page to it instead, e.g. to a `futures_cpupool::CpuPool`, and pages
+//! are delivered in order as before. Conversions run inline by default.
+//!
+//! An executor is set on a session, a `SessionBuilder` or a `Cluster`, which
+//! passes it to sessions of its pools, and it may be shared by all of them.
+
+use std::sync::Arc;
+
+use cdrs::types::rows::Row;
+use futures::future::{self, Executor, Future};
+use futures::sync::oneshot;
+
+use client::CDRSFuture;
+use rows::TryFromRow;
+use error;
+
+/// A conversion of a page which is run by a decode executor.
+pub type DecodeTask = Box<Future<Item = (), Error = ()> + Send>;
+
+/// Executor which runs conversions of rows. It's shared by sessions which
+/// have a clone of it.
+pub type DecodeExecutor = Arc<Executor<DecodeTask> + Send + Sync>;
+
+/// Converts `rows` into `R` on `executor`, or inline if there is no executor.
+pub fn decode_rows<R>(executor: Option<&DecodeExecutor>, rows: Vec<Row>) -> CDRSFuture<Vec<R>>
+    where R: TryFromRow + Send + 'static
+{
+    let executor = match executor {
+        Some(executor) => executor,
+        None => {
+            let decoded: error::Result<Vec<R>> = rows.into_iter().map(R::try_from_row).collect();
+            return future::result(decoded).boxed();
+        }
+    };
+
+    let (sender, receiver) = oneshot::channel();
+    let task = future::lazy(move || {
+                                let decoded: error::Result<Vec<R>> =
+                                    rows.into_iter().map(R::try_from_row).collect();
+                                let _ = sender.send(decoded);
+                                Ok(())
+                            });
+    if let Err(err) = executor.execute(Box::new(task)) {
+        return future::err(format!("Decode executor rejected a page: {:?}", err.kind()).into())
+                   .boxed();
+    }
+
+    receiver.then(|result| match result {
+                      Ok(decoded) => decoded,
+                      Err(_) => Err("Decode executor dropped a page".into()),
+                  })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use futures::{Future, Stream};
+    use tokio_core::reactor::{Core, Timeout};
+    use cdrs::query::QueryBuilder;
+
+    use super::*;
+    use mock::{self, MockTransport, ThreadExecutor, RESULT};
+
+    struct Slow(i32);
+
+    impl TryFromRow for Slow {
+        fn try_from_row(row: Row) -> error::Result<Slow> {
+            thread::sleep(Duration::from_millis(50));
+            ::rows::column(&row, "id", "0").map(Slow)
+        }
+    }
+
+    #[test]
+    fn keeps_reactor_free_while_decoding() {
+        let transport = MockTransport::new();
+        let rows: Vec<_> = (0..4).map(|i| vec![mock::int(i)]).collect();
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::rows_body(&[("id", mock::INT)], &rows, None)));
+
+        let mut session = mock::session(transport);
+        session.set_decode_executor(Arc::new(ThreadExecutor));
+
+        let mut core = Core::new().unwrap();
+        let started = Instant::now();
+        let small = Timeout::new(Duration::from_millis(10), &core.handle())
+            .unwrap()
+            .map(|_| started.elapsed())
+            .map_err(error::Error::from);
+        let select = QueryBuilder::new("SELECT id FROM t").finalize();
+        let big = session.query_all_into::<Slow, _>(select)
+            .map(|(_, rows)| (rows, started.elapsed()));
+
+        let (small, (rows, big)) = core.run(small.join(big)).unwrap();
+        assert_eq!(rows.iter().map(|row| row.0).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        assert!(big >= Duration::from_millis(200));
+        assert!(small < Duration::from_millis(100), "{:?}", small);
+    }
+    /// Id of a row along with a thread which converted it.
+    struct Decoded(i32, thread::ThreadId);
+
+    impl TryFromRow for Decoded {
+        fn try_from_row(row: Row) -> error::Result<Decoded> {
+            ::rows::column(&row, "id", "0").map(|id| Decoded(id, thread::current().id()))
+        }
+    }
+
+    #[test]
+    fn streams_pages_converted_on_executor() {
+        let page = |ids: &[i32], paging_state: Option<&[u8]>| {
+            let rows: Vec<_> = ids.iter().map(|id| vec![mock::int(*id)]).collect();
+            mock::response(RESULT,
+                           0,
+                           &mock::rows_body(&[("id", mock::INT)], &rows, paging_state))
+        };
+        let transport = MockTransport::new();
+        transport.push_read(page(&[0, 1], Some(b"p1")));
+        transport.push_read(page(&[2], None));
+
+        let mut session = mock::session(transport);
+        session.set_decode_executor(Arc::new(ThreadExecutor));
+        let select = QueryBuilder::new("SELECT id FROM t").finalize();
+        let rows = session.query_stream_into::<Decoded, _>(select).collect().wait().unwrap();
+
+        assert_eq!(rows.iter().map(|row| row.0).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert!(rows.iter().all(|row| row.1 != thread::current().id()));
+    }
+}
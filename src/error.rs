@@ -0,0 +1,927 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::result;
+use std::time::Duration;
+
+use cdrs::consistency::Consistency;
+use cdrs::error as cdrs_error;
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// CDRS Future error which could be returned by any future produced by this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// Internal IO error.
+    Io(io::Error),
+    /// Error with a description.
+    General(String),
+    /// Any other error reported by underlying `cdrs` crate.
+    Cdrs(cdrs_error::Error),
+    /// Error a server answered a request with, along with its message.
+    Server { error: ServerError, message: String },
+    /// Response frame broke the protocol. A connection which produced it is closed
+    /// because there is no way to resynchronize the stream.
+    ProtocolViolation(ProtocolViolation),
+    /// Query returned more rows than a caller agreed to keep in memory.
+    TooManyRows { max_rows: usize },
+    /// Number of bound values differs from the number of markers in a statement.
+    BindArity { markers: usize, values: usize },
+    /// Request was shed locally because every candidate host is overloaded.
+    Backpressure,
+    /// Requested schema object, e.g. a table, doesn't exist.
+    NotFound(String),
+    /// Bound value doesn't match a type of its marker.
+    BoundValue {
+        index: usize,
+        expected: String,
+        provided: String,
+    },
+    /// Value of a column cannot be converted into a field it's mapped to.
+    Conversion {
+        column: String,
+        field: String,
+        reason: String,
+    },
+    /// Connection handshake didn't complete within a given time.
+    HandshakeTimeout(Duration),
+    /// Query which should return at most one row returned more. `rows` is how many
+    /// were received before the query was stopped, so there may be more of them.
+    UnexpectedRows { rows: usize },
+    /// Query which should return a single column returned other columns.
+    UnexpectedColumns { columns: Vec<String> },
+    /// Request frame body is longer than a server accepts.
+    FrameTooLarge { length: usize, max: usize },
+    /// Server doesn't support a requested compression algorithm.
+    UnsupportedCompression {
+        requested: String,
+        supported: Vec<String>,
+    },
+    /// Request waited for a free connection until its deadline passed
+    /// and was never sent.
+    DeadlineExceeded { waited: Duration },
+    /// Pool is draining before a shutdown and doesn't take new requests.
+    ShuttingDown,
+    /// No session of a pool became free within `PoolOptions::checkout_timeout`.
+    PoolTimeout { waited: Duration },
+    /// Every stream id of a multiplexed connection is taken by a request in flight,
+    /// see `Multiplexer::fail_when_exhausted`.
+    StreamIdExhausted { in_flight: usize },
+    /// Compressed body of a response couldn't be decompressed, e.g. it was truncated
+    /// or the stream got desynchronized. A connection which received it is closed.
+    DecompressionFailed {
+        codec: String,
+        compressed_len: usize,
+        /// Length of the decompressed body announced by the body itself, if a codec
+        /// announces it.
+        expected_len: Option<usize>,
+        context: FrameContext,
+    },
+    /// Checksum of received bytes differs from the one sent along with them.
+    /// A connection which received them is closed.
+    ChecksumMismatch {
+        kind: ChecksumKind,
+        expected: u32,
+        actual: u32,
+        context: FrameContext,
+    },
+    /// Server requires an authenticator other than the one a client provided.
+    AuthenticatorMismatch { server: String, client: String },
+    /// Server requires authentication, but a client has no credentials to offer.
+    AuthenticationRequired { server: String },
+    /// No response to a request came within a given time. A connection which
+    /// sent it is closed, because a late response would be taken for a response
+    /// to the next request.
+    RequestTimeout(Duration),
+    /// Connection was lost while a request which is not idempotent was in flight,
+    /// so it's unknown whether a server applied it.
+    ConnectionReset,
+    /// Batch mixes counter updates with other statements or its type doesn't
+    /// match its statements. A server rejects such batches as invalid requests.
+    CounterBatch(String),
+    /// Error of a stage of a request which takes several ones, e.g. `prepare`
+    /// or `execute` of a statement which is prepared on demand.
+    Stage {
+        stage: &'static str,
+        error: Box<Error>,
+    },
+    /// Error of a connection to a node, e.g. a refused connection or a failed
+    /// authentication, along with the address of the node.
+    Connect { address: String, error: Box<Error> },
+    /// Nodes didn't agree on schema within a given time after it was changed.
+    SchemaDisagreement { waited: Duration },
+}
+
+impl Error {
+    /// Returns `true` if the error leaves a connection in a state it cannot recover
+    /// from, so the connection has to be closed.
+    pub fn breaks_connection(&self) -> bool {
+        match *self {
+            Error::ProtocolViolation(_) |
+            Error::DecompressionFailed { .. } |
+            Error::ChecksumMismatch { .. } |
+            Error::RequestTimeout(_) => true,
+            Error::Stage { ref error, .. } => error.breaks_connection(),
+            _ => false,
+        }
+    }
+
+    /// Turns a body of an ERROR response into `Error::Server`. An error whose details
+    /// cannot be read becomes `ServerError::Other`, a body without even a code and
+    /// a message becomes `Error::General`.
+    pub fn from_error_body(body: &[u8]) -> Error {
+        let (error, message) = match ServerError::from_body(body) {
+            Ok(error) => error,
+            Err(err) => {
+                let mut reader = ErrorBodyReader { body: body };
+                match (reader.int(), reader.string()) {
+                    (Ok(code), Ok(message)) => (ServerError::Other(code), message),
+                    _ => return err,
+                }
+            }
+        };
+        Error::Server {
+            error: error,
+            message: message,
+        }
+    }
+
+    /// Returns the error a server reported, looking into errors of stages.
+    pub fn server_error(&self) -> Option<&ServerError> {
+        match *self {
+            Error::Server { ref error, .. } => Some(error),
+            Error::Stage { ref error, .. } |
+            Error::Connect { ref error, .. } => error.server_error(),
+            _ => None,
+        }
+    }
+
+    /// Wraps an error of a `stage` of a request.
+    pub fn in_stage(stage: &'static str, error: Error) -> Error {
+        Error::Stage {
+            stage: stage,
+            error: Box::new(error),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "IO error: {}", err),
+            Error::General(ref err) => write!(f, "General error: {}", err),
+            Error::Cdrs(ref err) => write!(f, "CDRS error: {}", err),
+            Error::Server { ref error, ref message } => {
+                write!(f, "Server error {}: {}", error, message)
+            }
+            Error::ProtocolViolation(ref violation) => {
+                write!(f, "Protocol violation: {}", violation)
+            }
+            Error::TooManyRows { max_rows } => {
+                write!(f,
+                       "Query returned more than {} rows. Use a paged query to process \
+                        such results.",
+                       max_rows)
+            }
+            Error::BindArity { markers, values } => {
+                write!(f,
+                       "Statement has {} bind markers, but {} values are bound",
+                       markers,
+                       values)
+            }
+            Error::Backpressure => {
+                write!(f, "Every host is overloaded, the request was not sent")
+            }
+            Error::NotFound(ref what) => write!(f, "{} not found", what),
+            Error::BoundValue { index, ref expected, ref provided } => {
+                write!(f,
+                       "Bound value {}: expected {}, provided {}",
+                       index,
+                       expected,
+                       provided)
+            }
+            Error::Conversion { ref column, ref field, ref reason } => {
+                write!(f,
+                       "Cannot convert column `{}` into field `{}`: {}",
+                       column,
+                       field,
+                       reason)
+            }
+            Error::HandshakeTimeout(timeout) => {
+                write!(f, "Handshake did not complete within {:?}", timeout)
+            }
+            Error::RequestTimeout(timeout) => {
+                write!(f, "No response to a request within {:?}", timeout)
+            }
+            Error::ConnectionReset => {
+                write!(f, "Connection was reset, state of the request is unknown")
+            }
+            Error::CounterBatch(ref reason) => write!(f, "Invalid counter batch: {}", reason),
+            Error::UnexpectedRows { rows } => {
+                write!(f, "Query returned at least {} rows, but at most one was expected", rows)
+            }
+            Error::UnexpectedColumns { ref columns } => {
+                write!(f,
+                       "Query returned {} columns [{}], but a single one was expected",
+                       columns.len(),
+                       columns.join(", "))
+            }
+            Error::FrameTooLarge { length, max } => {
+                write!(f,
+                       "Request frame body of {} bytes exceeds the limit of {} bytes",
+                       length,
+                       max)
+            }
+            Error::UnsupportedCompression { ref requested, ref supported } => {
+                write!(f,
+                       "Compression `{}` was requested, but the server supports only [{}]",
+                       requested,
+                       supported.join(", "))
+            }
+            Error::DeadlineExceeded { waited } => {
+                write!(f,
+                       "Request waited {:?} for a connection and its deadline passed",
+                       waited)
+            }
+            Error::ShuttingDown => write!(f, "Pool is shutting down, the request was not sent"),
+            Error::PoolTimeout { waited } => {
+                write!(f, "No connection of the pool became free within {:?}", waited)
+            }
+            Error::StreamIdExhausted { in_flight } => {
+                write!(f,
+                       "Every stream id of the connection is in use by {} requests in flight",
+                       in_flight)
+            }
+            Error::DecompressionFailed { ref codec, compressed_len, expected_len, ref context } => {
+                try!(write!(f,
+                            "Cannot decompress {} body of {} bytes",
+                            codec,
+                            compressed_len));
+                if let Some(expected_len) = expected_len {
+                    try!(write!(f, " into {} bytes", expected_len));
+                }
+                write!(f, " ({})", context)
+            }
+            Error::ChecksumMismatch { kind, expected, actual, ref context } => {
+                write!(f,
+                       "{} checksum mismatch: expected {:#x}, got {:#x} ({})",
+                       kind,
+                       expected,
+                       actual,
+                       context)
+            }
+            Error::AuthenticatorMismatch { ref server, ref client } => {
+                write!(f,
+                       "Server requires authenticator {}, but the client provided {}",
+                       server,
+                       client)
+            }
+            Error::AuthenticationRequired { ref server } => {
+                write!(f,
+                       "Server requires authentication scheme {}, but no credentials were \
+                        configured",
+                       server)
+            }
+            Error::Stage { stage, ref error } => write!(f, "{} failed: {}", stage, error),
+            Error::Connect { ref address, ref error } => {
+                write!(f, "Cannot connect to {}: {}", address, error)
+            }
+            Error::SchemaDisagreement { waited } => {
+                write!(f, "Nodes did not agree on schema within {:?}", waited)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref err) => err.description(),
+            Error::General(ref err) => err.as_str(),
+            Error::Cdrs(ref err) => err.description(),
+            Error::Server { .. } => "server error",
+            Error::ProtocolViolation(_) => "protocol violation",
+            Error::TooManyRows { .. } => "too many rows",
+            Error::BindArity { .. } => "wrong number of bound values",
+            Error::Backpressure => "every host is overloaded",
+            Error::NotFound(_) => "not found",
+            Error::BoundValue { .. } => "bound value doesn't match its marker",
+            Error::Conversion { .. } => "column conversion error",
+            Error::HandshakeTimeout(_) => "handshake timed out",
+            Error::RequestTimeout(_) => "request timed out",
+            Error::ConnectionReset => "connection was reset",
+            Error::CounterBatch(_) => "invalid counter batch",
+            Error::UnexpectedRows { .. } => "more than one row",
+            Error::UnexpectedColumns { .. } => "not a single column",
+            Error::FrameTooLarge { .. } => "request frame is too large",
+            Error::UnsupportedCompression { .. } => "compression is not supported",
+            Error::DeadlineExceeded { .. } => "deadline exceeded in a queue",
+            Error::ShuttingDown => "pool is shutting down",
+            Error::PoolTimeout { .. } => "pool checkout timed out",
+            Error::StreamIdExhausted { .. } => "stream ids are exhausted",
+            Error::DecompressionFailed { .. } => "decompression failed",
+            Error::ChecksumMismatch { .. } => "checksum mismatch",
+            Error::AuthenticatorMismatch { .. } => "authenticator mismatch",
+            Error::AuthenticationRequired { .. } => "authentication required",
+            Error::SchemaDisagreement { .. } => "schema disagreement",
+            Error::Stage { ref error, .. } |
+            Error::Connect { ref error, .. } => error.description(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<cdrs_error::Error> for Error {
+    fn from(err: cdrs_error::Error) -> Error {
+        match err {
+            cdrs_error::Error::Io(err) => Error::Io(err),
+            cdrs_error::Error::General(err) => Error::General(err),
+            err => Error::Cdrs(err),
+        }
+    }
+}
+
+impl From<ProtocolViolation> for Error {
+    fn from(violation: ProtocolViolation) -> Error {
+        Error::ProtocolViolation(violation)
+    }
+}
+
+impl From<String> for Error {
+    fn from(err: String) -> Error {
+        Error::General(err)
+    }
+}
+
+impl<'a> From<&'a str> for Error {
+    fn from(err: &str) -> Error {
+        Error::General(err.to_string())
+    }
+}
+
+/// Error a server reports in an ERROR response along with details the protocol
+/// defines for it, e.g. how many replicas were alive for `Unavailable`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerError {
+    Server,
+    Protocol,
+    BadCredentials,
+    /// Not enough replicas were alive to serve a request.
+    Unavailable {
+        consistency: Consistency,
+        required: i32,
+        alive: i32,
+    },
+    Overloaded,
+    IsBootstrapping,
+    Truncate,
+    /// Replicas didn't acknowledge a write in time, though they may apply it.
+    WriteTimeout {
+        consistency: Consistency,
+        received: i32,
+        block_for: i32,
+        /// E.g. `SIMPLE`, `BATCH` or `CAS`.
+        write_type: String,
+    },
+    /// Replicas didn't answer a read in time.
+    ReadTimeout {
+        consistency: Consistency,
+        received: i32,
+        block_for: i32,
+        /// Whether the replica asked for data answered.
+        data_present: bool,
+    },
+    ReadFailure {
+        consistency: Consistency,
+        received: i32,
+        block_for: i32,
+        failures: i32,
+        data_present: bool,
+    },
+    FunctionFailure {
+        keyspace: String,
+        function: String,
+        arg_types: Vec<String>,
+    },
+    WriteFailure {
+        consistency: Consistency,
+        received: i32,
+        block_for: i32,
+        failures: i32,
+        write_type: String,
+    },
+    Syntax,
+    Unauthorized,
+    Invalid,
+    Config,
+    /// A keyspace or a table which is being created exists. `table` is empty
+    /// for a keyspace.
+    AlreadyExists { keyspace: String, table: String },
+    /// A server doesn't know a prepared statement with `id`.
+    Unprepared { id: Vec<u8> },
+    /// Error of a code the crate doesn't know or one whose details are malformed.
+    Other(i32),
+}
+
+impl ServerError {
+    /// Reads a body of an ERROR response into the error and its message.
+    pub fn from_body(body: &[u8]) -> Result<(ServerError, String)> {
+        let mut reader = ErrorBodyReader { body: body };
+        let code = try!(reader.int());
+        let message = try!(reader.string());
+
+        let error = match code {
+            0x0000 => ServerError::Server,
+            0x000A => ServerError::Protocol,
+            0x0100 => ServerError::BadCredentials,
+            0x1000 => {
+                ServerError::Unavailable {
+                    consistency: try!(reader.consistency()),
+                    required: try!(reader.int()),
+                    alive: try!(reader.int()),
+                }
+            }
+            0x1001 => ServerError::Overloaded,
+            0x1002 => ServerError::IsBootstrapping,
+            0x1003 => ServerError::Truncate,
+            0x1100 => {
+                ServerError::WriteTimeout {
+                    consistency: try!(reader.consistency()),
+                    received: try!(reader.int()),
+                    block_for: try!(reader.int()),
+                    write_type: try!(reader.string()),
+                }
+            }
+            0x1200 => {
+                ServerError::ReadTimeout {
+                    consistency: try!(reader.consistency()),
+                    received: try!(reader.int()),
+                    block_for: try!(reader.int()),
+                    data_present: try!(reader.byte()) != 0,
+                }
+            }
+            0x1300 => {
+                ServerError::ReadFailure {
+                    consistency: try!(reader.consistency()),
+                    received: try!(reader.int()),
+                    block_for: try!(reader.int()),
+                    failures: try!(reader.int()),
+                    data_present: try!(reader.byte()) != 0,
+                }
+            }
+            0x1400 => {
+                let keyspace = try!(reader.string());
+                let function = try!(reader.string());
+                let mut arg_types = vec![];
+                for _ in 0..try!(reader.short()) {
+                    arg_types.push(try!(reader.string()));
+                }
+                ServerError::FunctionFailure {
+                    keyspace: keyspace,
+                    function: function,
+                    arg_types: arg_types,
+                }
+            }
+            0x1500 => {
+                ServerError::WriteFailure {
+                    consistency: try!(reader.consistency()),
+                    received: try!(reader.int()),
+                    block_for: try!(reader.int()),
+                    failures: try!(reader.int()),
+                    write_type: try!(reader.string()),
+                }
+            }
+            0x2000 => ServerError::Syntax,
+            0x2100 => ServerError::Unauthorized,
+            0x2200 => ServerError::Invalid,
+            0x2300 => ServerError::Config,
+            0x2400 => {
+                ServerError::AlreadyExists {
+                    keyspace: try!(reader.string()),
+                    table: try!(reader.string()),
+                }
+            }
+            0x2500 => ServerError::Unprepared { id: try!(reader.short_bytes()).to_vec() },
+            code => ServerError::Other(code),
+        };
+        Ok((error, message))
+    }
+
+    /// Error code the protocol assigns to the error.
+    pub fn code(&self) -> i32 {
+        match *self {
+            ServerError::Server => 0x0000,
+            ServerError::Protocol => 0x000A,
+            ServerError::BadCredentials => 0x0100,
+            ServerError::Unavailable { .. } => 0x1000,
+            ServerError::Overloaded => 0x1001,
+            ServerError::IsBootstrapping => 0x1002,
+            ServerError::Truncate => 0x1003,
+            ServerError::WriteTimeout { .. } => 0x1100,
+            ServerError::ReadTimeout { .. } => 0x1200,
+            ServerError::ReadFailure { .. } => 0x1300,
+            ServerError::FunctionFailure { .. } => 0x1400,
+            ServerError::WriteFailure { .. } => 0x1500,
+            ServerError::Syntax => 0x2000,
+            ServerError::Unauthorized => 0x2100,
+            ServerError::Invalid => 0x2200,
+            ServerError::Config => 0x2300,
+            ServerError::AlreadyExists { .. } => 0x2400,
+            ServerError::Unprepared { .. } => 0x2500,
+            ServerError::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:#06x}", self.code()));
+        match *self {
+            ServerError::Unavailable { ref consistency, required, alive } => {
+                write!(f,
+                       " (Unavailable, {:?}: {} replicas required, {} alive)",
+                       consistency,
+                       required,
+                       alive)
+            }
+            ServerError::WriteTimeout { ref consistency, received, block_for, ref write_type } => {
+                write!(f,
+                       " (Write timeout of {} write, {:?}: {} of {} acknowledged)",
+                       write_type,
+                       consistency,
+                       received,
+                       block_for)
+            }
+            ServerError::ReadTimeout { ref consistency, received, block_for, data_present } => {
+                write!(f,
+                       " (Read timeout, {:?}: {} of {} responded, data {})",
+                       consistency,
+                       received,
+                       block_for,
+                       if data_present { "present" } else { "missing" })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads fields of an ERROR body.
+struct ErrorBodyReader<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> ErrorBodyReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.body.len() < len {
+            return Err(Error::General("ERROR body is shorter than its error code requires"
+                                          .to_string()));
+        }
+        let (bytes, rest) = self.body.split_at(len);
+        self.body = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(try!(self.take(1))[0])
+    }
+
+    fn short(&mut self) -> Result<u16> {
+        let bytes = try!(self.take(2));
+        Ok(((bytes[0] as u16) << 8) | bytes[1] as u16)
+    }
+
+    fn int(&mut self) -> Result<i32> {
+        let bytes = try!(self.take(4));
+        Ok(((bytes[0] as i32) << 24) | ((bytes[1] as i32) << 16) | ((bytes[2] as i32) << 8) |
+           bytes[3] as i32)
+    }
+
+    fn short_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = try!(self.short());
+        self.take(len as usize)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let bytes = try!(self.short_bytes());
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn consistency(&mut self) -> Result<Consistency> {
+        let consistency = match try!(self.short()) {
+            0x0000 => Consistency::Any,
+            0x0001 => Consistency::One,
+            0x0002 => Consistency::Two,
+            0x0003 => Consistency::Three,
+            0x0004 => Consistency::Quorum,
+            0x0005 => Consistency::All,
+            0x0006 => Consistency::LocalQuorum,
+            0x0007 => Consistency::EachQuorum,
+            0x0008 => Consistency::Serial,
+            0x0009 => Consistency::LocalSerial,
+            0x000A => Consistency::LocalOne,
+            other => {
+                return Err(Error::General(format!("Unknown consistency {:#06x} in ERROR body",
+                                                  other)))
+            }
+        };
+        Ok(consistency)
+    }
+}
+
+/// Describes which field of a response frame header is not what the client expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolViolation {
+    /// Protocol version differs from the negotiated one.
+    Version { expected: u8, actual: u8 },
+    /// Direction bit of the version byte says it is a request, not a response.
+    Direction { actual: u8 },
+    /// Stream id doesn't correspond to any outstanding request.
+    StreamId { expected: i16, actual: i16 },
+    /// Opcode is unknown or cannot be sent by a server.
+    Opcode { actual: u8 },
+    /// Flags which were not negotiated by the client.
+    Flags { unexpected: u8 },
+    /// Negative body length.
+    Length { actual: i32 },
+}
+
+impl ProtocolViolation {
+    /// Name of the header field which broke the protocol.
+    pub fn field(&self) -> &'static str {
+        match *self {
+            ProtocolViolation::Version { .. } => "version",
+            ProtocolViolation::Direction { .. } => "direction",
+            ProtocolViolation::StreamId { .. } => "stream",
+            ProtocolViolation::Opcode { .. } => "opcode",
+            ProtocolViolation::Flags { .. } => "flags",
+            ProtocolViolation::Length { .. } => "length",
+        }
+    }
+}
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProtocolViolation::Version { expected, actual } => {
+                write!(f,
+                       "`{}` field: expected {:#04x}, got {:#04x}",
+                       self.field(),
+                       expected,
+                       actual)
+            }
+            ProtocolViolation::Direction { actual } => {
+                write!(f,
+                       "`{}` field: {:#04x} is not a response version",
+                       self.field(),
+                       actual)
+            }
+            ProtocolViolation::StreamId { expected, actual } => {
+                write!(f,
+                       "`{}` field: expected {}, got {}",
+                       self.field(),
+                       expected,
+                       actual)
+            }
+            ProtocolViolation::Opcode { actual } => {
+                write!(f,
+                       "`{}` field: {:#04x} is not a response opcode",
+                       self.field(),
+                       actual)
+            }
+            ProtocolViolation::Flags { unexpected } => {
+                write!(f,
+                       "`{}` field: {:#04x} were not negotiated",
+                       self.field(),
+                       unexpected)
+            }
+            ProtocolViolation::Length { actual } => {
+                write!(f, "`{}` field: {} is negative", self.field(), actual)
+            }
+        }
+    }
+}
+
+/// Part of received bytes a checksum covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    Header,
+    Payload,
+}
+
+impl fmt::Display for ChecksumKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChecksumKind::Header => write!(f, "Header"),
+            ChecksumKind::Payload => write!(f, "Payload"),
+        }
+    }
+}
+
+/// Where corrupted bytes were received.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameContext {
+    /// Address of a server, if the connection knows it.
+    pub peer: Option<SocketAddr>,
+    /// Opcode of the frame being decoded or, if its header wasn't read, of the last
+    /// frame the connection received.
+    pub opcode: Option<u8>,
+}
+
+impl fmt::Display for FrameContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peer {
+            Some(peer) => try!(write!(f, "peer {}", peer)),
+            None => try!(write!(f, "unknown peer")),
+        }
+        match self.opcode {
+            Some(opcode) => write!(f, ", opcode {:#04x}", opcode),
+            None => write!(f, ", no frame received yet"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock;
+
+    fn push_short(body: &mut Vec<u8>, s: u16) {
+        body.push((s >> 8) as u8);
+        body.push(s as u8);
+    }
+
+    fn push_int(body: &mut Vec<u8>, i: i32) {
+        body.extend_from_slice(&[(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+    }
+
+    fn push_string(body: &mut Vec<u8>, s: &str) {
+        push_short(body, s.len() as u16);
+        body.extend_from_slice(s.as_bytes());
+    }
+
+    fn decode(body: &[u8]) -> ServerError {
+        let (error, message) = ServerError::from_body(body).unwrap();
+        assert_eq!(message, "failed");
+        error
+    }
+
+    #[test]
+    fn decodes_errors_without_details() {
+        let errors = vec![(0x0000, ServerError::Server),
+                          (0x000A, ServerError::Protocol),
+                          (0x0100, ServerError::BadCredentials),
+                          (0x1001, ServerError::Overloaded),
+                          (0x1002, ServerError::IsBootstrapping),
+                          (0x1003, ServerError::Truncate),
+                          (0x2000, ServerError::Syntax),
+                          (0x2100, ServerError::Unauthorized),
+                          (0x2200, ServerError::Invalid),
+                          (0x2300, ServerError::Config),
+                          (0x7777, ServerError::Other(0x7777))];
+        for (code, expected) in errors {
+            let error = decode(&mock::error_body(code, "failed"));
+            assert_eq!(error.code(), code);
+            assert_eq!(error, expected);
+        }
+    }
+
+    #[test]
+    fn decodes_unavailable() {
+        let mut body = mock::error_body(0x1000, "failed");
+        push_short(&mut body, 0x0004);
+        push_int(&mut body, 2);
+        push_int(&mut body, 1);
+        let error = decode(&body);
+        assert_eq!(error,
+                   ServerError::Unavailable {
+                       consistency: Consistency::Quorum,
+                       required: 2,
+                       alive: 1,
+                   });
+        assert_eq!(error.code(), 0x1000);
+    }
+
+    #[test]
+    fn decodes_timeouts() {
+        let mut body = mock::error_body(0x1100, "failed");
+        push_short(&mut body, 0x0006);
+        push_int(&mut body, 1);
+        push_int(&mut body, 2);
+        push_string(&mut body, "BATCH_LOG");
+        assert_eq!(decode(&body),
+                   ServerError::WriteTimeout {
+                       consistency: Consistency::LocalQuorum,
+                       received: 1,
+                       block_for: 2,
+                       write_type: "BATCH_LOG".to_string(),
+                   });
+
+        let mut body = mock::error_body(0x1200, "failed");
+        push_short(&mut body, 0x0001);
+        push_int(&mut body, 0);
+        push_int(&mut body, 1);
+        body.push(0);
+        assert_eq!(decode(&body),
+                   ServerError::ReadTimeout {
+                       consistency: Consistency::One,
+                       received: 0,
+                       block_for: 1,
+                       data_present: false,
+                   });
+    }
+
+    #[test]
+    fn decodes_failures() {
+        let mut body = mock::error_body(0x1300, "failed");
+        push_short(&mut body, 0x0005);
+        push_int(&mut body, 2);
+        push_int(&mut body, 3);
+        push_int(&mut body, 1);
+        body.push(1);
+        assert_eq!(decode(&body),
+                   ServerError::ReadFailure {
+                       consistency: Consistency::All,
+                       received: 2,
+                       block_for: 3,
+                       failures: 1,
+                       data_present: true,
+                   });
+
+        let mut body = mock::error_body(0x1500, "failed");
+        push_short(&mut body, 0x000A);
+        push_int(&mut body, 0);
+        push_int(&mut body, 1);
+        push_int(&mut body, 1);
+        push_string(&mut body, "SIMPLE");
+        assert_eq!(decode(&body),
+                   ServerError::WriteFailure {
+                       consistency: Consistency::LocalOne,
+                       received: 0,
+                       block_for: 1,
+                       failures: 1,
+                       write_type: "SIMPLE".to_string(),
+                   });
+
+        let mut body = mock::error_body(0x1400, "failed");
+        push_string(&mut body, "ks");
+        push_string(&mut body, "avg");
+        push_short(&mut body, 2);
+        push_string(&mut body, "int");
+        push_string(&mut body, "bigint");
+        assert_eq!(decode(&body),
+                   ServerError::FunctionFailure {
+                       keyspace: "ks".to_string(),
+                       function: "avg".to_string(),
+                       arg_types: vec!["int".to_string(), "bigint".to_string()],
+                   });
+    }
+
+    #[test]
+    fn decodes_already_exists_and_unprepared() {
+        let mut body = mock::error_body(0x2400, "failed");
+        push_string(&mut body, "ks");
+        push_string(&mut body, "");
+        assert_eq!(decode(&body),
+                   ServerError::AlreadyExists {
+                       keyspace: "ks".to_string(),
+                       table: String::new(),
+                   });
+
+        let mut body = mock::error_body(0x2500, "failed");
+        push_short(&mut body, 3);
+        body.extend_from_slice(&[0xCA, 0xFE, 0x01]);
+        assert_eq!(decode(&body), ServerError::Unprepared { id: vec![0xCA, 0xFE, 0x01] });
+    }
+
+    #[test]
+    fn keeps_code_of_errors_with_malformed_details() {
+        let mut body = mock::error_body(0x1000, "failed");
+        push_short(&mut body, 0x0004);
+        assert!(ServerError::from_body(&body).is_err());
+        match Error::from_error_body(&body) {
+            Error::Server { error: ServerError::Other(0x1000), ref message } => {
+                assert_eq!(message, "failed")
+            }
+            other => panic!("server error expected, got {:?}", other),
+        }
+
+        match Error::from_error_body(&[0x00, 0x00]) {
+            Error::General(_) => (),
+            other => panic!("general error expected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn finds_server_errors_of_stages() {
+        let invalid = Error::from_error_body(&mock::error_body(0x2200, "no table t"));
+        let error = Error::in_stage("execute", invalid);
+        assert_eq!(error.server_error(), Some(&ServerError::Invalid));
+        assert_eq!(Error::ConnectionReset.server_error(), None);
+    }
+}
@@ -0,0 +1,553 @@
+//! Low level read and write loops shared by every request path.
+//!
+//! The rules are:
+//!
+//! * `Interrupted` is retried immediately;
+//! * `WouldBlock` is never an error, it means "yield to the reactor and poll again";
+//! * `write` returning zero bytes is retried a few times before it's reported as
+//!   `WriteZero`, so bytes are never silently lost;
+//! * everything else is a genuine error.
+
+use std::cmp::{self, Ordering};
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+use futures::{Async, Future, Poll};
+use futures::task;
+use tokio_timer::Delay;
+use zeroize::Zeroize;
+
+/// How many times a zero-length write is retried before it's reported as an error.
+pub const MAX_ZERO_WRITES: usize = 3;
+
+/// Reads into `buf` following the rules above.
+pub fn read<R: io::Read>(reader: &mut R, buf: &mut [u8]) -> Poll<usize, io::Error> {
+    loop {
+        match reader.read(buf) {
+            Ok(n) => return Ok(Async::Ready(n)),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(Async::NotReady)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Writes a part of `buf` following the rules above. Returns a number of written bytes
+/// which is never zero for non-empty `buf`.
+pub fn write<W: io::Write>(writer: &mut W, buf: &[u8]) -> Poll<usize, io::Error> {
+    let mut zero_writes = 0;
+
+    loop {
+        match writer.write(buf) {
+            Ok(0) if !buf.is_empty() => {
+                zero_writes += 1;
+                if zero_writes > MAX_ZERO_WRITES {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "transport has not accepted any bytes"));
+                }
+            }
+            Ok(n) => return Ok(Async::Ready(n)),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(Async::NotReady)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Flushes `writer` following the rules above.
+pub fn flush<W: io::Write>(writer: &mut W) -> Poll<(), io::Error> {
+    loop {
+        match writer.flush() {
+            Ok(()) => return Ok(Async::Ready(())),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(Async::NotReady)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Defines when a transport is flushed while a frame is being written.
+/// It's always flushed once queued frames are written completely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FlushPolicy {
+    /// Flush after every written chunk.
+    EveryChunk,
+    /// Flush once at least given number of bytes were written since the last flush.
+    EveryBytes(usize),
+    /// Flush only when all queued frames are written.
+    FrameEnd,
+}
+
+/// Options of the frame write path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WriteOptions {
+    /// Max number of bytes written before the writer yields to other tasks.
+    pub chunk_size: usize,
+    pub flush_policy: FlushPolicy,
+    /// Time limit for writing a whole frame, not a single chunk.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            flush_policy: FlushPolicy::FrameEnd,
+            timeout: None,
+        }
+    }
+}
+
+/// Default size of a written chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A timer which wakes a writer blocked on a transport once its deadline passes.
+/// Writers are compared by their deadlines, so timers are equal, and a clone
+/// sets a timer of its own once it blocks.
+#[derive(Default)]
+struct DeadlineTimer(Option<Delay>);
+
+impl Clone for DeadlineTimer {
+    fn clone(&self) -> DeadlineTimer {
+        DeadlineTimer(None)
+    }
+}
+
+impl PartialEq for DeadlineTimer {
+    fn eq(&self, _: &DeadlineTimer) -> bool {
+        true
+    }
+}
+
+impl Eq for DeadlineTimer {}
+
+impl PartialOrd for DeadlineTimer {
+    fn partial_cmp(&self, other: &DeadlineTimer) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DeadlineTimer {
+    fn cmp(&self, _: &DeadlineTimer) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+/// Outgoing frames which are written in chunks across as many polls as a transport needs.
+/// After each chunk the writer yields, so a huge frame doesn't starve other tasks
+/// running on the same reactor. A writer which is blocked on a transport wakes
+/// up once its timeout passes, a timer of the current reactor tracks it.
+#[derive(Default, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FrameWriter {
+    options: WriteOptions,
+    buffer: Vec<u8>,
+    position: usize,
+    unflushed: usize,
+    flush_pending: bool,
+    deadline: Option<Instant>,
+    timer: DeadlineTimer,
+    sensitive: bool,
+    written: u64,
+}
+
+impl fmt::Debug for FrameWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // queued bytes may contain credentials, so only their number is shown
+        f.debug_struct("FrameWriter")
+            .field("options", &self.options)
+            .field("queued", &(self.buffer.len() - self.position))
+            .field("unflushed", &self.unflushed)
+            .field("flush_pending", &self.flush_pending)
+            .field("deadline", &self.deadline)
+            .field("sensitive", &self.sensitive)
+            .field("written", &self.written)
+            .finish()
+    }
+}
+
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        if self.sensitive {
+            self.buffer.zeroize();
+        }
+    }
+}
+
+impl FrameWriter {
+    pub fn new() -> FrameWriter {
+        FrameWriter::default()
+    }
+
+    pub fn with_options(options: WriteOptions) -> FrameWriter {
+        let mut writer = FrameWriter::default();
+        writer.options = options;
+        writer
+    }
+
+    pub fn set_options(&mut self, options: WriteOptions) {
+        self.options = options;
+    }
+
+    pub fn options(&self) -> &WriteOptions {
+        &self.options
+    }
+
+    /// Queues frame bytes. Bytes of a previous frame which were not written yet
+    /// are kept in front of them.
+    pub fn push(&mut self, bytes: Vec<u8>) {
+        if self.is_empty() {
+            self.buffer = bytes;
+            self.position = 0;
+            self.deadline = self.options.timeout.map(|timeout| Instant::now() + timeout);
+            self.timer = DeadlineTimer::default();
+        } else {
+            self.append(&bytes);
+        }
+    }
+
+    /// Queues frame bytes which contain secrets, e.g. credentials. The buffer is
+    /// zeroed once they are written or when the writer is dropped, so are `bytes`
+    /// if they are copied behind queued ones.
+    ///
+    /// It only covers memory of the writer: copies made by a transport or by
+    /// the kernel are out of its reach.
+    pub fn push_sensitive(&mut self, mut bytes: Vec<u8>) {
+        if self.is_empty() {
+            self.push(bytes);
+        } else {
+            self.append(&bytes);
+            bytes.zeroize();
+        }
+        self.sensitive = true;
+    }
+
+    /// Appends bytes behind queued ones. A buffer with secrets is grown by hand
+    /// and wiped, as growing it in place would leave them in the freed memory.
+    fn append(&mut self, bytes: &[u8]) {
+        if self.sensitive && self.buffer.capacity() - self.buffer.len() < bytes.len() {
+            let mut grown = Vec::with_capacity(self.buffer.len() + bytes.len());
+            grown.extend_from_slice(&self.buffer);
+            grown.extend_from_slice(bytes);
+            self.buffer.zeroize();
+            self.buffer = grown;
+        } else {
+            self.buffer.extend_from_slice(bytes);
+        }
+    }
+
+    /// Number of bytes written since the writer was created.
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// Returns `true` if there is nothing to write.
+    pub fn is_empty(&self) -> bool {
+        self.position == self.buffer.len()
+    }
+
+    /// Writes queued bytes until all of them are accepted by `writer`.
+    pub fn poll_write<W: io::Write>(&mut self, writer: &mut W) -> Poll<(), io::Error> {
+        let result = self.poll_queued(writer);
+        if let Ok(Async::NotReady) = result {
+            try!(self.wait_for_deadline());
+        }
+        result
+    }
+
+    fn poll_queued<W: io::Write>(&mut self, writer: &mut W) -> Poll<(), io::Error> {
+        loop {
+            if self.flush_pending {
+                try_ready!(flush(writer));
+                self.flush_pending = false;
+                self.unflushed = 0;
+            }
+
+            if self.is_empty() {
+                break;
+            }
+
+            try!(self.check_deadline());
+
+            let chunk_end = cmp::min(self.buffer.len(), self.position + self.options.chunk_size);
+            while self.position < chunk_end {
+                let n = try_ready!(write(writer, &self.buffer[self.position..chunk_end]));
+                self.position += n;
+                self.unflushed += n;
+                self.written += n as u64;
+            }
+
+            // whatever the policy is, the end of a frame is always flushed: a buffered
+            // transport would otherwise keep a request and its response would never come
+            self.flush_pending = self.is_empty() ||
+                                 match self.options.flush_policy {
+                                     FlushPolicy::EveryChunk => true,
+                                     FlushPolicy::EveryBytes(bytes) => self.unflushed >= bytes,
+                                     FlushPolicy::FrameEnd => false,
+                                 };
+
+            if !self.is_empty() {
+                // let other tasks run before the next chunk
+                task::current().notify();
+                return Ok(Async::NotReady);
+            }
+        }
+
+        if self.sensitive {
+            self.buffer.zeroize();
+            self.sensitive = false;
+        }
+        self.buffer.clear();
+        self.position = 0;
+        self.deadline = None;
+        self.timer = DeadlineTimer::default();
+        Ok(Async::Ready(()))
+    }
+
+    /// Whole allocated memory of the buffer, including its spare capacity.
+    #[cfg(test)]
+    pub fn allocated(&self) -> &[u8] {
+        use std::slice;
+        unsafe { slice::from_raw_parts(self.buffer.as_ptr(), self.buffer.capacity()) }
+    }
+
+    fn check_deadline(&self) -> io::Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(timed_out()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes the current task be notified once the deadline passes, so a write
+    /// which never makes progress fails instead of waiting forever.
+    fn wait_for_deadline(&mut self) -> io::Result<()> {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(()),
+        };
+        try!(self.check_deadline());
+
+        let timer = self.timer.0.get_or_insert_with(|| Delay::new(deadline));
+        match timer.poll() {
+            Ok(Async::Ready(())) => Err(timed_out()),
+            // outside of a reactor the deadline is only checked on polls
+            Ok(Async::NotReady) | Err(_) => Ok(()),
+        }
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "frame write timed out")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use futures::Async;
+
+    use super::*;
+    use mock::{MockTransport, WriteStep};
+
+    #[test]
+    fn read_retries_interrupted() {
+        let transport = MockTransport::new();
+        transport.push_read_error(io::ErrorKind::Interrupted);
+        transport.push_read(vec![1, 2]);
+
+        let mut buf = [0; 4];
+        let mut reader = transport.clone();
+        assert_eq!(read(&mut reader, &mut buf).unwrap(), Async::Ready(2));
+        assert_eq!(&buf[..2], &[1, 2]);
+    }
+
+    #[test]
+    fn read_yields_on_would_block() {
+        let mut transport = MockTransport::new();
+        let mut buf = [0; 4];
+        assert_eq!(read(&mut transport, &mut buf).unwrap(), Async::NotReady);
+    }
+
+    #[test]
+    fn writer_survives_interrupts_and_zero_writes() {
+        let transport = MockTransport::new();
+        transport.push_write(WriteStep::Error(io::ErrorKind::Interrupted));
+        transport.push_write(WriteStep::Accept(2));
+        transport.push_write(WriteStep::Accept(0));
+        transport.push_write(WriteStep::Accept(0));
+        transport.push_write(WriteStep::Error(io::ErrorKind::WouldBlock));
+
+        let mut writer = FrameWriter::new();
+        writer.push(vec![1, 2, 3, 4, 5]);
+
+        let mut w = transport.clone();
+        assert_eq!(writer.poll_write(&mut w).unwrap(), Async::NotReady);
+        assert_eq!(writer.poll_write(&mut w).unwrap(), Async::Ready(()));
+        assert!(writer.is_empty());
+        assert_eq!(transport.written(), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn writer_reports_persistent_zero_writes() {
+        let transport = MockTransport::new();
+        for _ in 0..MAX_ZERO_WRITES + 1 {
+            transport.push_write(WriteStep::Accept(0));
+        }
+
+        let mut writer = FrameWriter::new();
+        writer.push(vec![1, 2, 3]);
+
+        let err = writer.poll_write(&mut transport.clone()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn writes_big_frame_in_chunks_without_starving_reactor() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use futures::future;
+        use tokio_core::reactor::Core;
+
+        let frame: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+        let transport = MockTransport::new();
+        for _ in 0..1000 {
+            transport.push_write(WriteStep::Accept(1000));
+        }
+
+        let mut core = Core::new().unwrap();
+        let ticks = Rc::new(Cell::new(0));
+        let ticker_ticks = ticks.clone();
+        core.handle().spawn(future::poll_fn(move || -> Poll<(), ()> {
+                                                ticker_ticks.set(ticker_ticks.get() + 1);
+                                                task::current().notify();
+                                                Ok(Async::NotReady)
+                                            }));
+
+        let mut writer = FrameWriter::with_options(WriteOptions {
+                                                       chunk_size: 256 * 1024,
+                                                       flush_policy: FlushPolicy::EveryChunk,
+                                                       timeout: None,
+                                                   });
+        writer.push(frame.clone());
+        let mut w = transport.clone();
+        core.run(future::poll_fn(move || writer.poll_write(&mut w)))
+            .unwrap();
+
+        assert!(transport.written() == frame);
+        assert_eq!(transport.flushes(), 12);
+        assert!(ticks.get() >= 11);
+    }
+
+    #[test]
+    fn flushes_every_n_bytes() {
+        use futures::future::{self, Future};
+
+        let transport = MockTransport::new();
+        let mut writer = FrameWriter::with_options(WriteOptions {
+                                                       chunk_size: 10,
+                                                       flush_policy: FlushPolicy::EveryBytes(25),
+                                                       timeout: None,
+                                                   });
+        writer.push(vec![0; 100]);
+        let mut w = transport.clone();
+        future::poll_fn(move || writer.poll_write(&mut w))
+            .wait()
+            .unwrap();
+
+        assert_eq!(transport.written().len(), 100);
+        // after 30, 60 and 90 bytes and at the end of the frame
+        assert_eq!(transport.flushes(), 4);
+    }
+
+    #[test]
+    fn timeout_covers_whole_frame() {
+        use std::thread;
+        use std::time::Duration;
+        use futures::future::{self, Future};
+
+        let transport = MockTransport::new();
+        let mut writer = FrameWriter::with_options(WriteOptions {
+                                                       chunk_size: 10,
+                                                       flush_policy: FlushPolicy::FrameEnd,
+                                                       timeout: Some(Duration::from_millis(20)),
+                                                   });
+        writer.push(vec![0; 100]);
+        let mut w = transport.clone();
+        let err = future::poll_fn(move || {
+                                      thread::sleep(Duration::from_millis(5));
+                                      writer.poll_write(&mut w)
+                                  })
+                .wait()
+                .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(transport.written().len() < 100);
+    }
+
+    #[test]
+    fn timeout_wakes_blocked_writer() {
+        use std::time::Duration;
+        use futures::future::{self, Either, Future};
+        use tokio_core::reactor::{Core, Timeout};
+
+        let transport = MockTransport::new();
+        transport.push_write(WriteStep::Error(io::ErrorKind::WouldBlock));
+        let mut writer = FrameWriter::with_options(WriteOptions {
+                                                       timeout: Some(Duration::from_millis(20)),
+                                                       ..WriteOptions::default()
+                                                   });
+        writer.push(vec![0; 10]);
+
+        // nothing but the deadline wakes the write up
+        let mut core = Core::new().unwrap();
+        let mut w = transport.clone();
+        let write = future::poll_fn(move || writer.poll_write(&mut w));
+        let guard = Timeout::new(Duration::from_secs(5), &core.handle()).unwrap();
+        match core.run(write.select2(guard)) {
+            Err(Either::A((err, _))) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            _ => panic!("the blocked write should time out"),
+        }
+        assert!(transport.written().is_empty());
+    }
+
+    #[test]
+    fn wipes_sensitive_bytes() {
+        let transport = MockTransport::new();
+        let mut writer = FrameWriter::new();
+        writer.push_sensitive(b"secret".to_vec());
+
+        let debug = format!("{:?}", writer);
+        assert!(!debug.contains("115, 101, 99"), "{}", debug);
+
+        let mut w = transport.clone();
+        assert_eq!(writer.poll_write(&mut w).unwrap(), Async::Ready(()));
+        assert_eq!(transport.written(), b"secret".to_vec());
+        assert!(writer.allocated().len() >= 6);
+        assert!(writer.allocated().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn wipes_sensitive_bytes_queued_behind_others() {
+        let transport = MockTransport::new();
+        transport.push_write(WriteStep::Accept(2));
+        transport.push_write(WriteStep::Error(io::ErrorKind::WouldBlock));
+        let mut writer = FrameWriter::new();
+        writer.push(b"startup".to_vec());
+        let mut w = transport.clone();
+        assert_eq!(writer.poll_write(&mut w).unwrap(), Async::NotReady);
+
+        writer.push_sensitive(b"secret".to_vec());
+        // the buffer grows while it keeps the secret
+        writer.push(vec![1; 64]);
+        assert_eq!(writer.poll_write(&mut w).unwrap(), Async::Ready(()));
+
+        let mut expected = b"startupsecret".to_vec();
+        expected.extend_from_slice(&[1; 64]);
+        assert_eq!(transport.written(), expected);
+        assert!(writer.allocated().iter().all(|b| *b == 0));
+    }
+}
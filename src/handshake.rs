@@ -0,0 +1,326 @@
+//! Bounded connection handshakes.
+//!
+//! A handshake is everything from STARTUP to the end of authentication, so
+//! a node which accepts connections but never answers doesn't hang a client.
+//! `connect` tries contact points in order and moves to the next one once
+//! handshakes with the current one time out a configured number of times.
+//!
+//! Before STARTUP a requested compression is checked against the one a server
+//! advertises in SUPPORTED, so a mismatch is reported clearly instead of
+//! as a server error. The check costs a round trip and can be turned off.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::future::{self, Either, Future, Loop};
+use tokio_core::reactor::{Handle, Timeout};
+use cdrs::compression::Compression;
+use cdrs::transport::CDRSTransport;
+
+use auth::SaslAuthenticator;
+use client::{CDRS, Session};
+use error;
+use supported::SupportedOptions;
+
+/// Future of a handshake. It depends on a reactor, so unlike `CDRSFuture` it's not `Send`.
+pub type HandshakeFuture<T> = Box<Future<Item = T, Error = error::Error>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HandshakeOptions {
+    /// Time limit of a whole handshake, `None` waits forever.
+    pub timeout: Option<Duration>,
+    /// Number of extra handshakes with a node which timed out before
+    /// the next contact point is tried.
+    pub retries: usize,
+    /// Check a requested compression against SUPPORTED before STARTUP.
+    pub check_compression: bool,
+    /// Pick a compression the server supports instead of failing the check.
+    pub negotiate_compression: bool,
+}
+
+impl Default for HandshakeOptions {
+    fn default() -> HandshakeOptions {
+        HandshakeOptions {
+            timeout: Some(Duration::from_secs(5)),
+            retries: 0,
+            check_compression: true,
+            negotiate_compression: false,
+        }
+    }
+}
+
+pub use supported::COMPRESSION;
+
+/// Returns a compression to start a connection with. `requested` is returned as
+/// is if the server supports it, otherwise a supported one is picked if `negotiate`
+/// is set, or `Error::UnsupportedCompression` is returned.
+pub fn choose_compression(requested: Compression,
+                          supported: &SupportedOptions,
+                          negotiate: bool)
+                          -> error::Result<Compression> {
+    let name = match requested.as_str() {
+        Some(name) => name,
+        None => return Ok(requested),
+    };
+    if supported.supports_compression(requested) {
+        return Ok(requested);
+    }
+
+    if negotiate {
+        let fallback = [Compression::Lz4, Compression::Snappy]
+            .iter()
+            .cloned()
+            .find(|compression| supported.supports_compression(*compression));
+        return Ok(fallback.unwrap_or(Compression::None));
+    }
+
+    Err(error::Error::UnsupportedCompression {
+            requested: name.to_string(),
+            supported: supported.values(COMPRESSION).to_vec(),
+        })
+}
+
+/// Starts a session which fails with `Error::HandshakeTimeout` if the handshake,
+/// including the compression check, doesn't complete within `options.timeout`.
+/// A connection is dropped on timeout.
+pub fn start<T, X>(cdrs: CDRS<T, X>,
+                   compressor: Compression,
+                   options: &HandshakeOptions,
+                   handle: &Handle)
+                   -> HandshakeFuture<Session<T, X>>
+    where T: SaslAuthenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    let startup = if options.check_compression {
+        let negotiate = options.negotiate_compression;
+        cdrs.supported()
+            .and_then(move |(cdrs, supported)| {
+                          let compressor = try!(choose_compression(compressor,
+                                                                   &supported,
+                                                                   negotiate));
+                          Ok((cdrs, compressor))
+                      })
+            .and_then(|(cdrs, compressor)| cdrs.start(compressor))
+            .boxed()
+    } else {
+        cdrs.start(compressor)
+    };
+
+    let timeout = match options.timeout {
+        Some(timeout) => timeout,
+        None => return Box::new(startup),
+    };
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+
+    Box::new(startup.select2(timer).then(move |result| match result {
+                                             Ok(Either::A((session, _))) => Ok(session),
+                                             Ok(Either::B(_)) => {
+                                                 Err(error::Error::HandshakeTimeout(timeout))
+                                             }
+                                             Err(Either::A((err, _))) => Err(err),
+                                             Err(Either::B((err, _))) => Err(err.into()),
+                                         }))
+}
+
+/// Connects to the first of `hosts` which completes a handshake and returns
+/// its address along with a session. `connect` opens a connection to a host
+/// and is called for every attempt, retries included.
+///
+/// If no host succeeds the error of the last attempt is returned.
+pub fn connect<T, X, C>(hosts: Vec<SocketAddr>,
+                        compressor: Compression,
+                        options: HandshakeOptions,
+                        handle: &Handle,
+                        connect: C)
+                        -> HandshakeFuture<(SocketAddr, Session<T, X>)>
+    where T: SaslAuthenticator + Send + 'static,
+          X: CDRSTransport + 'static,
+          C: FnMut(SocketAddr) -> error::Result<CDRS<T, X>> + 'static
+{
+    let handle = handle.clone();
+    let hosts: VecDeque<SocketAddr> = hosts.into_iter().collect();
+
+    Box::new(future::loop_fn((hosts, 0, connect, None), move |state| {
+        let (mut hosts, retried, mut connect, last_error): (VecDeque<SocketAddr>,
+                                                             usize,
+                                                             C,
+                                                             Option<error::Error>) = state;
+        let host = match hosts.front() {
+            Some(host) => *host,
+            None => {
+                let err = last_error.unwrap_or_else(|| "No hosts to connect to".into());
+                return Box::new(future::err(err)) as HandshakeFuture<_>;
+            }
+        };
+
+        let cdrs = match connect(host) {
+            Ok(cdrs) => cdrs,
+            Err(err) => {
+                hosts.pop_front();
+                return Box::new(future::ok(Loop::Continue((hosts, 0, connect, Some(err)))));
+            }
+        };
+
+        Box::new(start(cdrs, compressor, &options, &handle).then(move |result| {
+            match result {
+                Ok(session) => Ok(Loop::Break((host, session))),
+                Err(err @ error::Error::HandshakeTimeout(_)) if retried < options.retries => {
+                    Ok(Loop::Continue((hosts, retried + 1, connect, Some(err))))
+                }
+                Err(err) => {
+                    hosts.pop_front();
+                    Ok(Loop::Continue((hosts, 0, connect, Some(err))))
+                }
+            }
+        }))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio_core::reactor::Core;
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::IntoBytes;
+    use cdrs::compression::Compression;
+    use cdrs::frame::Frame;
+
+    use super::*;
+    use client::CassandraOptions;
+    use mock::{self, MockTransport, READY, SUPPORTED};
+
+    fn snappy_only() -> SupportedOptions {
+        let mut options = CassandraOptions::new();
+        options.insert(COMPRESSION.to_string(), vec!["snappy".to_string()]);
+        options.into()
+    }
+
+    #[test]
+    fn rejects_unsupported_compression() {
+        let err = choose_compression(Compression::Lz4, &snappy_only(), false).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "Compression `lz4` was requested, but the server supports only [snappy]");
+
+        assert_eq!(choose_compression(Compression::None, &snappy_only(), false).unwrap(),
+                   Compression::None);
+    }
+
+    #[test]
+    fn negotiates_supported_compression() {
+        assert_eq!(choose_compression(Compression::Lz4, &snappy_only(), true).unwrap(),
+                   Compression::Snappy);
+        assert_eq!(choose_compression(Compression::Lz4, &CassandraOptions::new().into(), true)
+                       .unwrap(),
+                   Compression::None);
+    }
+
+    #[test]
+    fn checks_compression_before_startup() {
+        let transport = MockTransport::new();
+        let supported = mock::supported_body(&[(COMPRESSION, &["snappy"])]);
+        transport.push_read(mock::response(SUPPORTED, 0, &supported));
+
+        let mut core = Core::new().unwrap();
+        let handshake = start(CDRS::new(transport.clone(), NoneAuthenticator),
+                              Compression::Lz4,
+                              &HandshakeOptions::default(),
+                              &core.handle());
+
+        match core.run(handshake) {
+            Err(error::Error::UnsupportedCompression { ref requested, .. }) => {
+                assert_eq!(requested, "lz4")
+            }
+            Err(err) => panic!("UnsupportedCompression expected, got {:?}", err),
+            Ok(_) => panic!("UnsupportedCompression expected"),
+        }
+        // nothing but OPTIONS was sent
+        assert_eq!(transport.written(), Frame::new_req_options().into_cbytes());
+    }
+
+    #[test]
+    fn compression_check_can_be_skipped() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(READY, 0, &[]));
+        let options = HandshakeOptions { check_compression: false, ..HandshakeOptions::default() };
+
+        let mut core = Core::new().unwrap();
+        let handshake = start(CDRS::new(transport.clone(), NoneAuthenticator),
+                              Compression::None,
+                              &options,
+                              &core.handle());
+
+        core.run(handshake).unwrap();
+        assert_eq!(transport.written(),
+                   Frame::new_req_startup(None).into_cbytes());
+    }
+
+    #[test]
+    fn moves_on_from_silent_host() {
+        let silent: SocketAddr = "127.0.0.1:9042".parse().unwrap();
+        let ready: SocketAddr = "127.0.0.2:9042".parse().unwrap();
+
+        let silent_transport = MockTransport::new();
+        let ready_transport = MockTransport::new();
+        ready_transport.push_read(mock::response(READY, 0, &[]));
+
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let recorded = attempts.clone();
+        let options = HandshakeOptions {
+            timeout: Some(Duration::from_millis(50)),
+            retries: 1,
+            check_compression: false,
+            ..HandshakeOptions::default()
+        };
+
+        let mut core = Core::new().unwrap();
+        let started = Instant::now();
+        let handshake = connect(vec![silent, ready],
+                                Compression::None,
+                                options,
+                                &core.handle(),
+                                move |host| {
+            recorded.lock().unwrap().push(host);
+            let transport = if host == silent {
+                silent_transport.clone()
+            } else {
+                ready_transport.clone()
+            };
+            Ok(CDRS::new(transport, NoneAuthenticator))
+        });
+        let (host, _) = core.run(handshake).unwrap();
+
+        assert_eq!(host, ready);
+        assert_eq!(*attempts.lock().unwrap(), vec![silent, silent, ready]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn times_out_when_every_host_is_silent() {
+        let host: SocketAddr = "127.0.0.1:9042".parse().unwrap();
+        let transport = MockTransport::new();
+        let options = HandshakeOptions {
+            timeout: Some(Duration::from_millis(20)),
+            ..HandshakeOptions::default()
+        };
+
+        let mut core = Core::new().unwrap();
+        let handshake = connect(vec![host],
+                                Compression::None,
+                                options,
+                                &core.handle(),
+                                move |_| Ok(CDRS::new(transport.clone(), NoneAuthenticator)));
+
+        match core.run(handshake) {
+            Err(error::Error::HandshakeTimeout(timeout)) => {
+                assert_eq!(timeout, Duration::from_millis(20))
+            }
+            Err(err) => panic!("HandshakeTimeout expected, got {:?}", err),
+            Ok(_) => panic!("HandshakeTimeout expected"),
+        }
+    }
+}
@@ -0,0 +1,534 @@
+//! Generation of INSERT statements for types which know their columns.
+
+use cdrs::consistency::Consistency;
+use cdrs::frame::Frame;
+use cdrs::frame::frame_response::ResponseBody;
+use cdrs::frame::frame_result::ResResultBody;
+use cdrs::types::IntoRustByName;
+use cdrs::types::rows::Row;
+use cdrs::types::value::Value;
+
+use rows;
+use error;
+
+/// Column of a lightweight transaction result which says if it was applied.
+pub const APPLIED: &'static str = "[applied]";
+
+/// Words which can't be used as identifiers unless they are quoted.
+const RESERVED: &'static [&'static str] =
+    &["add", "allow", "alter", "and", "apply", "asc", "authorize", "batch", "begin", "by",
+      "columnfamily", "create", "delete", "desc", "describe", "drop", "entries", "execute",
+      "from", "full", "grant", "if", "in", "index", "infinity", "insert", "into", "keyspace",
+      "limit", "modify", "nan", "norecursive", "not", "null", "of", "on", "or", "order",
+      "primary", "rename", "replace", "revoke", "schema", "select", "set", "table", "to",
+      "token", "truncate", "unlogged", "update", "use", "using", "where", "with"];
+
+/// Quotes an identifier if it's case sensitive, has special characters
+/// or is a reserved word. Identifiers which don't need quotes are kept as is.
+pub fn quote_identifier(name: &str) -> String {
+    let plain = name.chars().next().map_or(false, |c| c.is_ascii_lowercase()) &&
+                name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') &&
+                !RESERVED.contains(&name);
+
+    if plain {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+/// Options of `Session::insert_into`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InsertOptions {
+    if_not_exists: bool,
+    ttl: Option<i32>,
+    timestamp: Option<i64>,
+    consistency: Option<Consistency>,
+}
+
+impl Default for InsertOptions {
+    fn default() -> InsertOptions {
+        InsertOptions {
+            if_not_exists: false,
+            ttl: None,
+            timestamp: None,
+            consistency: None,
+        }
+    }
+}
+
+impl InsertOptions {
+    pub fn new() -> InsertOptions {
+        InsertOptions::default()
+    }
+
+    /// Makes the insert a lightweight transaction which doesn't overwrite
+    /// an existing row.
+    pub fn if_not_exists(mut self, if_not_exists: bool) -> InsertOptions {
+        self.if_not_exists = if_not_exists;
+        self
+    }
+
+    /// Time to live of inserted values in seconds.
+    pub fn ttl(mut self, ttl: i32) -> InsertOptions {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Write time of inserted values in microseconds since the epoch.
+    pub fn timestamp(mut self, timestamp: i64) -> InsertOptions {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Consistency of the insert. It's left to the session by default,
+    /// see `Session::set_default_consistency`, and is `One` without a default.
+    pub fn consistency(mut self, consistency: Consistency) -> InsertOptions {
+        self.consistency = Some(consistency);
+        self
+    }
+
+    pub fn get_consistency(&self) -> Option<Consistency> {
+        self.consistency.clone()
+    }
+
+    /// Values bound to markers of the `USING` clause.
+    pub fn values(&self) -> Vec<Value> {
+        let mut values = vec![];
+        if let Some(ttl) = self.ttl {
+            values.push(Value::from(ttl));
+        }
+        if let Some(timestamp) = self.timestamp {
+            values.push(Value::from(timestamp));
+        }
+        values
+    }
+}
+
+/// Quotes parts of `keyspace.table` or `table`. A part which is already quoted
+/// is kept as is, and dots inside of quotes don't separate parts.
+pub fn quote_table(table: &str) -> String {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut quoted = false;
+    for c in table.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' if !quoted => {
+                parts.push(part);
+                part = String::new();
+                continue;
+            }
+            _ => {}
+        }
+        part.push(c);
+    }
+    parts.push(part);
+
+    let parts: Vec<_> = parts.iter()
+        .map(|part| if part.len() > 1 && part.starts_with('"') && part.ends_with('"') {
+                 part.clone()
+             } else {
+                 quote_identifier(part)
+             })
+        .collect();
+    parts.join(".")
+}
+
+/// Builds `INSERT INTO table (c1, c2) VALUES (?, ?)` along with clauses of `options`.
+/// `table` is either `table` or `keyspace.table`, see `quote_table`.
+///
+/// TTL and timestamp are bound to markers rather than inlined, so inserts
+/// which differ only in them share a prepared statement. A server doesn't take
+/// a timestamp of a lightweight transaction, so `IF NOT EXISTS` along with
+/// a timestamp is an error.
+pub fn insert_cql(table: &str,
+                  columns: &[&str],
+                  options: &InsertOptions)
+                  -> error::Result<String> {
+    if options.if_not_exists && options.timestamp.is_some() {
+        return Err("IF NOT EXISTS insert can't have a timestamp".into());
+    }
+
+    let names: Vec<_> = columns.iter().map(|column| quote_identifier(column)).collect();
+    let markers: Vec<_> = columns.iter().map(|_| "?").collect();
+
+    let mut cql = format!("INSERT INTO {} ({}) VALUES ({})",
+                          quote_table(table),
+                          names.join(", "),
+                          markers.join(", "));
+
+    if options.if_not_exists {
+        cql.push_str(" IF NOT EXISTS");
+    }
+
+    let mut using = vec![];
+    if options.ttl.is_some() {
+        using.push("TTL ?");
+    }
+    if options.timestamp.is_some() {
+        using.push("TIMESTAMP ?");
+    }
+    if !using.is_empty() {
+        cql.push_str(" USING ");
+        cql.push_str(&using.join(" AND "));
+    }
+
+    Ok(cql)
+}
+
+/// Decodes a response to an insert. A lightweight transaction reports
+/// whether it was applied, any other insert is always applied.
+pub fn is_applied(frame: Frame) -> error::Result<bool> {
+    match try!(frame.get_body()) {
+        ResponseBody::Result(ResResultBody::Rows(rows_body)) => {
+            match Row::from_frame_body(rows_body).first() {
+                Some(row) => match row.get_by_name(APPLIED) {
+                    Some(applied) => applied.map_err(|err| err.into()),
+                    None => Ok(true),
+                },
+                None => Ok(true),
+            }
+        }
+        ResponseBody::Result(_) => Ok(true),
+        ResponseBody::Error(_) => Err(error::Error::from_error_body(&frame.body)),
+        _ => Err("Unexpected type of frame. Result frame is expected".into()),
+    }
+}
+
+/// Outcome of a conditional statement, e.g. `INSERT ... IF NOT EXISTS`
+/// or `UPDATE ... IF`, see `Session::query_cas`.
+pub struct CasResult {
+    pub applied: bool,
+    /// The row a rejected statement checked its condition against: `[applied]`
+    /// followed by current values of the columns. It's `None` if the statement
+    /// was applied.
+    pub existing: Option<Row>,
+}
+
+impl CasResult {
+    /// Decodes rows of a response to a conditional statement. A statement without
+    /// conditions returns no rows and is always applied.
+    pub fn from_rows(rows: Vec<Row>) -> error::Result<CasResult> {
+        let row = match rows.into_iter().next() {
+            Some(row) => row,
+            None => {
+                return Ok(CasResult {
+                              applied: true,
+                              existing: None,
+                          })
+            }
+        };
+        let applied = try!(rows::column(&row, APPLIED, "applied"));
+        Ok(CasResult {
+               applied: applied,
+               existing: if applied { None } else { Some(row) },
+           })
+    }
+}
+
+/// Outcome of a conditional batch.
+///
+/// A server checks conditions of all statements of a batch before it applies
+/// any of them, so a batch is either applied as a whole or not at all: a single
+/// `[applied] = false` means that nothing of the batch was written. A rejected
+/// batch returns a row per conditional statement with `[applied]`, the primary
+/// key columns of the row it checked and current values of columns its
+/// condition refers to.
+pub enum BatchLwtResult {
+    AllApplied,
+    Rejected { rows: Vec<Row> },
+}
+
+impl BatchLwtResult {
+    /// Decodes a response to a batch. A batch without conditions is always applied.
+    pub fn from_frame(frame: Frame) -> error::Result<BatchLwtResult> {
+        match try!(frame.get_body()) {
+            ResponseBody::Result(ResResultBody::Rows(rows_body)) => {
+                let rows = Row::from_frame_body(rows_body);
+                let applied = match rows.first().and_then(|row| row.get_by_name(APPLIED)) {
+                    Some(applied) => try!(applied),
+                    None => true,
+                };
+                if applied {
+                    Ok(BatchLwtResult::AllApplied)
+                } else {
+                    Ok(BatchLwtResult::Rejected { rows: rows })
+                }
+            }
+            ResponseBody::Result(_) => Ok(BatchLwtResult::AllApplied),
+            ResponseBody::Error(_) => Err(error::Error::from_error_body(&frame.body)),
+            _ => Err("Unexpected type of frame. Result frame is expected".into()),
+        }
+    }
+
+    pub fn is_applied(&self) -> bool {
+        match *self {
+            BatchLwtResult::AllApplied => true,
+            BatchLwtResult::Rejected { .. } => false,
+        }
+    }
+
+    /// Rows of a rejected batch, an applied batch has none.
+    pub fn rows(&self) -> &[Row] {
+        match *self {
+            BatchLwtResult::AllApplied => &[],
+            BatchLwtResult::Rejected { ref rows } => rows,
+        }
+    }
+
+    /// Reads `column` of the row at `index`, e.g. a key column which tells
+    /// a statement the row belongs to. Null is an error.
+    pub fn column<T>(&self, index: usize, column: &str) -> error::Result<T>
+        where Row: IntoRustByName<T>
+    {
+        match self.rows().get(index) {
+            Some(row) => rows::column(row, column, column),
+            None => Err(format!("Batch result has no row {}", index).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use futures::Future;
+    use cdrs::compression::Compression;
+    use cdrs::frame::parser::parse_frame;
+    use cdrs::IntoBytes;
+
+    use super::*;
+    use codec;
+    use mock::{self, MockTransport, RESULT, PREPARE, EXECUTE};
+    use values::{Columns, IntoQueryValues};
+
+    #[test]
+    fn quotes_identifiers_when_needed() {
+        assert_eq!(quote_identifier("user_name"), "user_name");
+        assert_eq!(quote_identifier("userName"), "\"userName\"");
+        assert_eq!(quote_identifier("select"), "\"select\"");
+        assert_eq!(quote_identifier("2fa"), "\"2fa\"");
+        assert_eq!(quote_identifier("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn builds_insert() {
+        assert_eq!(insert_cql("users", &["id", "name"], &InsertOptions::new()).unwrap(),
+                   "INSERT INTO users (id, name) VALUES (?, ?)");
+        assert_eq!(insert_cql("ks.Users", &["id", "from"], &InsertOptions::new()).unwrap(),
+                   "INSERT INTO ks.\"Users\" (id, \"from\") VALUES (?, ?)");
+    }
+
+    #[test]
+    fn keeps_quoted_table_names() {
+        assert_eq!(quote_table("\"Users\""), "\"Users\"");
+        assert_eq!(quote_table("ks.\"Users\""), "ks.\"Users\"");
+        assert_eq!(quote_table("\"My.Ks\".\"a.b\""), "\"My.Ks\".\"a.b\"");
+        assert_eq!(quote_table("\"say \"\"hi\"\"\".users"), "\"say \"\"hi\"\"\".users");
+        assert_eq!(quote_table("Ks.users"), "\"Ks\".users");
+    }
+
+    #[test]
+    fn builds_insert_with_options() {
+        let options = InsertOptions::new().if_not_exists(true).ttl(60);
+        assert_eq!(insert_cql("users", &["id"], &options).unwrap(),
+                   "INSERT INTO users (id) VALUES (?) IF NOT EXISTS USING TTL ?");
+        assert_eq!(mock::encoded(&options.values()), mock::encoded(&[Value::from(60)]));
+
+        let options = InsertOptions::new().ttl(60).timestamp(1000);
+        assert_eq!(insert_cql("users", &["id"], &options).unwrap(),
+                   "INSERT INTO users (id) VALUES (?) USING TTL ? AND TIMESTAMP ?");
+        assert_eq!(mock::encoded(&options.values()),
+                   mock::encoded(&[Value::from(60), Value::from(1000i64)]));
+    }
+
+    #[test]
+    fn rejects_timestamp_of_lightweight_transaction() {
+        let options = InsertOptions::new().if_not_exists(true).timestamp(1000);
+        assert!(insert_cql("users", &["id"], &options).is_err());
+
+        let transport = MockTransport::new();
+        match mock::session(transport.clone()).insert_into("users", alice(), options).wait() {
+            Err(err) => {
+                assert_eq!(err.to_string(),
+                           "General error: IF NOT EXISTS insert can't have a timestamp")
+            }
+            Ok(_) => panic!("insert is rejected"),
+        }
+        assert!(transport.written().is_empty());
+    }
+
+    struct User {
+        id: i32,
+        name: String,
+    }
+
+    impl Columns for User {
+        fn columns() -> &'static [&'static str] {
+            &["id", "name"]
+        }
+    }
+
+    impl IntoQueryValues for User {
+        fn arity() -> Option<usize> {
+            Some(2)
+        }
+
+        fn into_query_values(self) -> Vec<Value> {
+            vec![Value::from(self.id), Value::from(self.name)]
+        }
+    }
+
+    fn alice() -> User {
+        User {
+            id: 1,
+            name: "alice".to_string(),
+        }
+    }
+
+    fn prepared_response(markers: &[(&str, u16)], columns: &[(&str, u16)]) -> Vec<u8> {
+        mock::response(RESULT, 0, &mock::prepared_body(b"insert", markers, columns))
+    }
+
+    #[test]
+    fn reuses_prepared_insert() {
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("id", mock::INT), ("name", mock::VARCHAR)], &[]));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let (session, applied) = mock::session(transport.clone())
+            .insert_into("users", alice(), InsertOptions::new())
+            .wait()
+            .unwrap();
+        assert!(applied);
+        let (session, _) = session
+            .insert_into("users", alice(), InsertOptions::new())
+            .wait()
+            .unwrap();
+
+        assert_eq!(mock::opcodes(&transport.written()), vec![PREPARE, EXECUTE, EXECUTE]);
+        assert!(session
+                    .prepared_cache()
+                    .lock()
+                    .unwrap()
+                    .get("INSERT INTO users (id, name) VALUES (?, ?)")
+                    .is_some());
+    }
+
+    #[test]
+    fn leaves_consistency_of_insert_to_session() {
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("id", mock::INT), ("name", mock::VARCHAR)], &[]));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let mut session = mock::session(transport.clone());
+        session.set_default_consistency(Consistency::LocalQuorum);
+        let (session, _) = session.insert_into("users", alice(), InsertOptions::new())
+            .wait()
+            .unwrap();
+        let options = InsertOptions::new().consistency(Consistency::Two);
+        session.insert_into("users", alice(), options).wait().unwrap();
+
+        let insert = "INSERT INTO users (id, name) VALUES (?, ?)".to_string();
+        let prepare_len = Frame::new_req_prepare(insert, vec![]).into_cbytes().len();
+        let written = transport.written()[prepare_len..].to_vec();
+        let executions: Vec<_> = written.chunks(written.len() / 2).collect();
+        // consistency follows the id of the statement
+        let offset = codec::HEADER_LEN + 2 + b"insert".len();
+        assert_eq!(&executions[0][offset..offset + 2], &[0, 6]);
+        assert_eq!(&executions[1][offset..offset + 2], &[0, 2]);
+    }
+
+    #[test]
+    fn reports_lightweight_transaction_outcome() {
+        let columns = [(APPLIED, mock::BOOLEAN), ("id", mock::INT), ("name", mock::VARCHAR)];
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("id", mock::INT), ("name", mock::VARCHAR)],
+                                              &columns));
+        let rows = vec![vec![mock::boolean(false), mock::int(1), mock::text("bob")]];
+        transport.push_read(mock::response(RESULT, 0, &mock::rows_body(&columns, &rows, None)));
+
+        let (session, applied) = mock::session(transport)
+            .insert_into("users", alice(), InsertOptions::new().if_not_exists(true))
+            .wait()
+            .unwrap();
+
+        assert!(!applied);
+        assert!(session
+                    .prepared_cache()
+                    .lock()
+                    .unwrap()
+                    .get("INSERT INTO users (id, name) VALUES (?, ?) IF NOT EXISTS")
+                    .is_some());
+    }
+
+    fn batch_response(columns: &[(&str, u16)], rows: &[Vec<Option<Vec<u8>>>]) -> Frame {
+        let bytes = mock::response(RESULT, 0, &mock::rows_body(columns, rows, None));
+        parse_frame(&mut Cursor::new(bytes), &Compression::None).unwrap()
+    }
+
+    fn cas_response(columns: &[(&str, u16)], rows: &[Vec<Option<Vec<u8>>>]) -> Vec<u8> {
+        mock::response(RESULT, 0, &mock::rows_body(columns, rows, None))
+    }
+
+    #[test]
+    fn reports_whether_cas_was_applied() {
+        use cdrs::query::QueryBuilder;
+
+        let transport = MockTransport::new();
+        transport.push_read(cas_response(&[(APPLIED, mock::BOOLEAN)],
+                                         &[vec![mock::boolean(true)]]));
+        let columns = [(APPLIED, mock::BOOLEAN), ("id", mock::INT), ("name", mock::VARCHAR)];
+        transport.push_read(cas_response(&columns,
+                                         &[vec![mock::boolean(false),
+                                                mock::int(1),
+                                                mock::text("alice")]]));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let insert = || {
+            QueryBuilder::new("INSERT INTO users (id, name) VALUES (1, 'bob') IF NOT EXISTS")
+                .finalize()
+        };
+        let session = mock::session(transport.clone());
+        let (session, result) = session.query_cas(insert()).wait().unwrap();
+        assert!(result.applied);
+        assert!(result.existing.is_none());
+
+        let (session, result) = session.query_cas(insert()).wait().unwrap();
+        assert!(!result.applied);
+        let existing = result.existing.expect("rejected insert returns the existing row");
+        let name: String = rows::column(&existing, "name", "name").unwrap();
+        assert_eq!(name, "alice");
+
+        // a statement without conditions has no result rows
+        let update = QueryBuilder::new("UPDATE users SET name = 'bob' WHERE id = 1").finalize();
+        let (_, result) = session.query_cas(update).wait().unwrap();
+        assert!(result.applied);
+    }
+
+    #[test]
+    fn decodes_applied_batch() {
+        let frame = batch_response(&[(APPLIED, mock::BOOLEAN)], &[vec![mock::boolean(true)]]);
+        let result = BatchLwtResult::from_frame(frame).unwrap();
+
+        assert!(result.is_applied());
+        assert!(result.rows().is_empty());
+    }
+
+    #[test]
+    fn decodes_rejected_batch() {
+        let columns = [(APPLIED, mock::BOOLEAN), ("user_id", mock::INT), ("day", mock::VARCHAR)];
+        let rows = vec![vec![mock::boolean(false), mock::int(1), mock::text("mon")],
+                        vec![mock::boolean(false), mock::int(2), mock::text("tue")]];
+        let result = BatchLwtResult::from_frame(batch_response(&columns, &rows)).unwrap();
+
+        assert!(!result.is_applied());
+        assert_eq!(result.rows().len(), 2);
+        let user_id: i32 = result.column(1, "user_id").unwrap();
+        let day: String = result.column(1, "day").unwrap();
+        assert_eq!((user_id, day.as_str()), (2, "tue"));
+        assert!(result.column::<i32>(2, "user_id").is_err());
+    }
+}
@@ -1,9 +1,79 @@
+// the crate keeps to idioms of the Rust it was written for, e.g. `try!`, boxed
+// futures and explicit field names, so lints suggesting newer ones are off
+#![allow(deprecated, bare_trait_objects)]
+#![allow(clippy::redundant_field_names,
+         clippy::redundant_static_lifetimes,
+         clippy::question_mark,
+         clippy::needless_borrowed_reference,
+         clippy::io_other_error,
+         clippy::unnecessary_map_or,
+         clippy::mem_replace_with_default,
+         clippy::match_like_matches_macro,
+         clippy::legacy_numeric_constants,
+         clippy::manual_clamp,
+         clippy::derivable_impls,
+         clippy::get_first,
+         clippy::needless_lifetimes)]
+
+#[macro_use]
 extern crate futures;
 extern crate tokio_core;
+extern crate tokio_executor;
+extern crate tokio_timer;
 extern crate cdrs;
+#[macro_use]
+extern crate log;
+extern crate net2;
+extern crate uuid;
+extern crate zeroize;
+#[cfg(feature = "tls")]
+extern crate native_tls;
+#[cfg(feature = "tls")]
+extern crate tokio_tls;
 
+pub mod auth;
+pub mod backoff;
+pub mod base64;
+pub mod batch;
+pub mod builder;
+pub mod bulk;
 pub mod client;
+pub mod cluster;
+pub mod codec;
+pub mod csv;
+pub mod decode;
+pub mod error;
+pub mod frame_io;
+pub mod handshake;
+pub mod insert;
+pub mod load_balancing;
+pub mod metrics;
+pub mod multiplex;
+pub mod paging;
+pub mod pool;
+pub mod prepared;
+pub mod reconnect;
+pub mod request;
+pub mod response;
+pub mod retry;
+pub mod rows;
+pub mod scan;
+pub mod script;
+pub mod scoring;
+pub mod schema;
+pub mod scylla;
+pub mod setup;
+pub mod supported;
+pub mod timestamp;
+pub mod token;
+pub mod tracing;
 pub mod transport;
+pub mod url;
+pub mod validation;
+pub mod values;
+
+#[cfg(test)]
+mod mock;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,351 @@
+//! Policies which decide in which order hosts are tried by a request.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use scoring::HostScores;
+
+/// Builds a query plan: hosts in the order a request should try them.
+pub trait LoadBalancingPolicy {
+    fn plan(&self, hosts: &[SocketAddr]) -> Vec<SocketAddr>;
+
+    /// Builds a plan along with reasons of its order. Nothing is sent.
+    /// Policies which reorder plans of other policies annotate hosts they moved.
+    fn explain(&self, hosts: &[SocketAddr]) -> QueryPlanExplanation {
+        QueryPlanExplanation::from_plan(self.plan(hosts))
+    }
+}
+
+/// Why a host got its position in a plan.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Annotation {
+    /// Weight of a host derived from its score, hosts with bigger weights
+    /// come first more often.
+    Weight(f64),
+    /// A host reported it's overloaded, so it was moved to the end of a plan.
+    BackingOff(Duration),
+}
+
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Annotation::Weight(weight) => write!(f, "weight {:.2}", weight),
+            Annotation::BackingOff(interval) => write!(f, "backing off for {:?}", interval),
+        }
+    }
+}
+
+/// A host of a plan along with reasons of its position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostExplanation {
+    pub host: SocketAddr,
+    pub annotations: Vec<Annotation>,
+}
+
+/// A plan built by `LoadBalancingPolicy::explain`. It's displayed in a condensed
+/// form, e.g. `10.0.0.2:9042 (weight 1.00), 10.0.0.1:9042 (backing off for 10s)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryPlanExplanation {
+    pub hosts: Vec<HostExplanation>,
+}
+
+impl QueryPlanExplanation {
+    pub fn from_plan(plan: Vec<SocketAddr>) -> QueryPlanExplanation {
+        QueryPlanExplanation {
+            hosts: plan.into_iter()
+                .map(|host| {
+                         HostExplanation {
+                             host: host,
+                             annotations: vec![],
+                         }
+                     })
+                .collect(),
+        }
+    }
+
+    /// Hosts in the order of the plan.
+    pub fn plan(&self) -> Vec<SocketAddr> {
+        self.hosts.iter().map(|explanation| explanation.host).collect()
+    }
+}
+
+impl fmt::Display for QueryPlanExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, explanation) in self.hosts.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, ", "));
+            }
+            try!(write!(f, "{}", explanation.host));
+            for (j, annotation) in explanation.annotations.iter().enumerate() {
+                let separator = if j == 0 { " (" } else { ", " };
+                try!(write!(f, "{}{}", separator, annotation));
+            }
+            if !explanation.annotations.is_empty() {
+                try!(write!(f, ")"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts every plan from the next host.
+#[derive(Debug, Default)]
+pub struct RoundRobinPolicy {
+    next: AtomicUsize,
+}
+
+impl RoundRobinPolicy {
+    pub fn new() -> RoundRobinPolicy {
+        RoundRobinPolicy::default()
+    }
+}
+
+impl LoadBalancingPolicy for RoundRobinPolicy {
+    fn plan(&self, hosts: &[SocketAddr]) -> Vec<SocketAddr> {
+        if hosts.is_empty() {
+            return vec![];
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % hosts.len();
+        hosts[start..].iter().chain(&hosts[..start]).cloned().collect()
+    }
+}
+
+/// Datacenters of hosts, as `system.local` and `system.peers` report them.
+pub type Datacenters = Arc<Mutex<HashMap<SocketAddr, String>>>;
+
+/// Prefers hosts of a local datacenter. Local hosts are tried round-robin, then
+/// hosts whose datacenter isn't known yet, then at most `used_hosts_per_remote_dc`
+/// hosts of every remote datacenter, so requests cross to another datacenter
+/// only if no local host responds.
+#[derive(Debug)]
+pub struct DcAwarePolicy {
+    local_dc: String,
+    used_hosts_per_remote_dc: usize,
+    datacenters: Datacenters,
+    next: AtomicUsize,
+}
+
+impl DcAwarePolicy {
+    pub fn new<S: Into<String>>(local_dc: S,
+                                used_hosts_per_remote_dc: usize,
+                                datacenters: Datacenters)
+                                -> DcAwarePolicy {
+        DcAwarePolicy {
+            local_dc: local_dc.into(),
+            used_hosts_per_remote_dc: used_hosts_per_remote_dc,
+            datacenters: datacenters,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalancingPolicy for DcAwarePolicy {
+    fn plan(&self, hosts: &[SocketAddr]) -> Vec<SocketAddr> {
+        let datacenters = self.datacenters.lock().unwrap();
+        let mut local = vec![];
+        let mut unknown = vec![];
+        let mut remote: HashMap<&str, usize> = HashMap::new();
+        let mut remote_hosts = vec![];
+        for host in hosts {
+            match datacenters.get(host) {
+                Some(dc) if *dc == self.local_dc => local.push(*host),
+                Some(dc) => {
+                    let used = remote.entry(dc.as_str()).or_insert(0);
+                    if *used < self.used_hosts_per_remote_dc {
+                        *used += 1;
+                        remote_hosts.push(*host);
+                    }
+                }
+                None => unknown.push(*host),
+            }
+        }
+
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let start = if local.is_empty() { 0 } else { next % local.len() };
+        local[start..]
+            .iter()
+            .chain(&local[..start])
+            .chain(&unknown)
+            .chain(&remote_hosts)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Reorders plans of another policy so that hosts with better scores are tried
+/// first more often.
+///
+/// Candidates are ordered by a weighted random draw rather than sorted, so every
+/// host keeps getting a share of traffic proportional to its weight and its score
+/// can recover.
+#[derive(Debug)]
+pub struct ScoreAwarePolicy<P> {
+    inner: P,
+    scores: Arc<Mutex<HostScores>>,
+    random: Mutex<XorShift>,
+}
+
+impl<P: LoadBalancingPolicy> ScoreAwarePolicy<P> {
+    pub fn new(inner: P, scores: Arc<Mutex<HostScores>>) -> ScoreAwarePolicy<P> {
+        ScoreAwarePolicy {
+            inner: inner,
+            scores: scores,
+            random: Mutex::new(XorShift::new(0x2545_F491_4F6C_DD1D)),
+        }
+    }
+
+    /// Scores the policy orders hosts by. Outcomes of requests should be recorded there.
+    pub fn scores(&self) -> Arc<Mutex<HostScores>> {
+        self.scores.clone()
+    }
+}
+
+impl<P: LoadBalancingPolicy> LoadBalancingPolicy for ScoreAwarePolicy<P> {
+    fn plan(&self, hosts: &[SocketAddr]) -> Vec<SocketAddr> {
+        self.explain(hosts).plan()
+    }
+
+    fn explain(&self, hosts: &[SocketAddr]) -> QueryPlanExplanation {
+        let explanation = self.inner.explain(hosts);
+        let scores = self.scores.lock().unwrap();
+        let mut random = self.random.lock().unwrap();
+
+        // weighted random order: a host with weight `w` gets a key `u ^ (1 / w)`
+        let mut keyed: Vec<(f64, HostExplanation)> = explanation.hosts
+            .into_iter()
+            .map(|mut explanation| {
+                     let weight = scores.weight(&explanation.host);
+                     explanation.annotations.push(Annotation::Weight(weight));
+                     (random.next_f64().powf(1.0 / weight), explanation)
+                 })
+            .collect();
+        keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        QueryPlanExplanation { hosts: keyed.into_iter().map(|(_, host)| host).collect() }
+    }
+}
+
+/// Small PRNG, good enough to spread traffic.
+#[derive(Debug)]
+struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        XorShift { state: seed }
+    }
+
+    /// A number within `(0, 1]`.
+    fn next_f64(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        ((self.state >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use scoring::{HostScores, Outcome, ScoringOptions};
+
+    #[test]
+    fn round_robin_rotates() {
+        let hosts: Vec<SocketAddr> = vec!["10.0.0.1:9042".parse().unwrap(),
+                                          "10.0.0.2:9042".parse().unwrap()];
+        let policy = RoundRobinPolicy::new();
+        assert_eq!(policy.plan(&hosts), vec![hosts[0], hosts[1]]);
+        assert_eq!(policy.plan(&hosts), vec![hosts[1], hosts[0]]);
+    }
+
+    #[test]
+    fn dc_aware_prefers_local_hosts() {
+        let hosts: Vec<SocketAddr> = (1..6)
+            .map(|i| format!("10.0.0.{}:9042", i).parse().unwrap())
+            .collect();
+        let datacenters: Datacenters = Arc::new(Mutex::new(HashMap::new()));
+        {
+            let mut datacenters = datacenters.lock().unwrap();
+            for (host, dc) in hosts.iter().zip(&["dc1", "dc2", "dc1", "dc2", "dc3"]) {
+                datacenters.insert(*host, dc.to_string());
+            }
+        }
+
+        let policy = DcAwarePolicy::new("dc1", 1, datacenters.clone());
+        assert_eq!(policy.plan(&hosts), vec![hosts[0], hosts[2], hosts[1], hosts[4]]);
+        assert_eq!(policy.plan(&hosts), vec![hosts[2], hosts[0], hosts[1], hosts[4]]);
+
+        let local_only = DcAwarePolicy::new("dc1", 0, datacenters.clone());
+        assert_eq!(local_only.plan(&hosts), vec![hosts[0], hosts[2]]);
+
+        // hosts of an unknown datacenter come after local ones
+        datacenters.lock().unwrap().remove(&hosts[3]);
+        assert_eq!(local_only.plan(&hosts), vec![hosts[2], hosts[0], hosts[3]]);
+    }
+
+    fn first_choices(policy: &ScoreAwarePolicy<RoundRobinPolicy>,
+                     hosts: &[SocketAddr])
+                     -> Vec<usize> {
+        let mut counts = vec![0; hosts.len()];
+        for _ in 0..10000 {
+            let first = policy.plan(hosts)[0];
+            counts[hosts.iter().position(|host| *host == first).unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn shifts_traffic_from_timing_out_host() {
+        let hosts: Vec<SocketAddr> = vec!["10.0.0.1:9042".parse().unwrap(),
+                                          "10.0.0.2:9042".parse().unwrap(),
+                                          "10.0.0.3:9042".parse().unwrap()];
+        let scores = Arc::new(Mutex::new(HostScores::new(ScoringOptions {
+                                                             window: 100,
+                                                             ..ScoringOptions::default()
+                                                         })));
+        let policy = ScoreAwarePolicy::new(RoundRobinPolicy::new(), scores.clone());
+
+        {
+            let mut scores = scores.lock().unwrap();
+            for i in 0..100 {
+                let outcome = if i % 5 == 0 {
+                    Outcome::Timeout
+                } else {
+                    Outcome::Success
+                };
+                scores.record(hosts[0], outcome);
+                scores.record(hosts[1], Outcome::Success);
+                scores.record(hosts[2], Outcome::Success);
+            }
+            assert_eq!(scores.stats(&hosts[0]).timeouts, 20);
+        }
+
+        let counts = first_choices(&policy, &hosts);
+        assert!(counts[0] * 10 < counts[1] * 8 && counts[0] * 10 < counts[2] * 8,
+                "{:?}",
+                counts);
+        // the host still gets traffic to recover
+        assert!(counts[0] > 1000, "{:?}", counts);
+
+        {
+            let mut scores = scores.lock().unwrap();
+            for _ in 0..100 {
+                scores.record(hosts[0], Outcome::Success);
+            }
+        }
+
+        let counts = first_choices(&policy, &hosts);
+        assert!(counts[0] * 10 > counts[1] * 9 && counts[0] * 10 > counts[2] * 9,
+                "{:?}",
+                counts);
+    }
+}
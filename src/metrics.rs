@@ -0,0 +1,538 @@
+//! Per-host request metrics: requests, errors by kind, timeouts, latency,
+//! requests in flight and open connections.
+//!
+//! Requests are measured by wrapping their futures with `track`. Hosts which left
+//! the topology keep their metrics for a grace period and are pruned afterwards,
+//! so the number of series doesn't grow without bound.
+//!
+//! Requests of a single session are reported to a `SessionMetricsObserver`, see
+//! `Session::metrics_observer`. `CountingObserver` keeps totals of them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use cdrs::frame::{Frame, Opcode};
+use futures::{Async, Future, Poll};
+
+use error;
+
+/// Upper bounds of latency buckets in milliseconds.
+pub const LATENCY_BUCKETS_MS: &'static [u64] = &[1, 2, 5, 10, 25, 50, 100, 250, 500, 1000,
+                                                  2500, 5000, 10000];
+
+/// Latency histogram with fixed buckets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    /// Counts of buckets of `LATENCY_BUCKETS_MS`, the last one counts slower requests.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            buckets: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+            count: 0,
+            sum: Duration::from_secs(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, latency: Duration) {
+        let millis = latency.as_secs() * 1000 + latency.subsec_nanos() as u64 / 1000000;
+        let bucket = LATENCY_BUCKETS_MS.iter()
+            .position(|bound| millis <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    /// Upper bound of a bucket where a given quantile falls, `None` if nothing
+    /// was observed or it falls into the last unbounded bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += *count;
+            if seen >= rank {
+                return LATENCY_BUCKETS_MS.get(bucket).map(|ms| Duration::from_millis(*ms));
+            }
+        }
+        None
+    }
+}
+
+/// Metrics of a single host.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HostMetrics {
+    pub requests: u64,
+    /// Failed requests by kind, see `error_kind`.
+    pub errors: BTreeMap<&'static str, u64>,
+    pub timeouts: u64,
+    /// Requests which waited for a connection until their deadline passed.
+    /// They were never sent, so they are not counted in `requests`.
+    pub expired_in_queue: u64,
+    pub latency: Histogram,
+    pub in_flight: usize,
+    pub connections: usize,
+    /// Connections which failed to open or to set up.
+    pub connect_failures: u64,
+}
+
+/// Short name of a kind of an error used as a label.
+pub fn error_kind(err: &error::Error) -> &'static str {
+    match *err {
+        error::Error::Io(ref err) if err.kind() == io::ErrorKind::TimedOut => "timeout",
+        error::Error::HandshakeTimeout(_) |
+        error::Error::RequestTimeout(_) => "timeout",
+        error::Error::Io(_) |
+        error::Error::ConnectionReset => "io",
+        error::Error::Cdrs(_) |
+        error::Error::Server { .. } => "server",
+        error::Error::ProtocolViolation(_) => "protocol",
+        error::Error::Backpressure |
+        error::Error::PoolTimeout { .. } |
+        error::Error::StreamIdExhausted { .. } => "backpressure",
+        error::Error::DeadlineExceeded { .. } => "deadline",
+        // corrupted frames usually come from the network rather than a server
+        error::Error::DecompressionFailed { .. } |
+        error::Error::ChecksumMismatch { .. } => "corruption",
+        error::Error::Stage { ref error, .. } |
+        error::Error::Connect { ref error, .. } => error_kind(error),
+        _ => "client",
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    metrics: HostMetrics,
+    /// When a host left the topology.
+    removed_at: Option<Instant>,
+}
+
+/// Metrics of every host a client talks to.
+#[derive(Debug, Clone)]
+pub struct HostMetricsRegistry {
+    grace: Duration,
+    hosts: HashMap<SocketAddr, Entry>,
+}
+
+impl HostMetricsRegistry {
+    /// Metrics of a host which left the topology are kept for `grace`.
+    pub fn new(grace: Duration) -> HostMetricsRegistry {
+        HostMetricsRegistry {
+            grace: grace,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Sets how long metrics of a host which left the topology are kept.
+    pub fn set_grace(&mut self, grace: Duration) {
+        self.grace = grace;
+    }
+
+    fn entry(&mut self, host: SocketAddr) -> &mut HostMetrics {
+        &mut self.hosts
+                  .entry(host)
+                  .or_insert_with(|| {
+                                      Entry {
+                                          metrics: HostMetrics::default(),
+                                          removed_at: None,
+                                      }
+                                  })
+                  .metrics
+    }
+
+    /// Records a request sent to `host`.
+    pub fn start(&mut self, host: SocketAddr) {
+        let metrics = self.entry(host);
+        metrics.requests += 1;
+        metrics.in_flight += 1;
+    }
+
+    /// Records a response, or a failure, of a request started with `start`.
+    pub fn finish(&mut self, host: SocketAddr, err: Option<&error::Error>, latency: Duration) {
+        let metrics = self.entry(host);
+        metrics.in_flight = metrics.in_flight.saturating_sub(1);
+        metrics.latency.observe(latency);
+
+        if let Some(err) = err {
+            let kind = error_kind(err);
+            if kind == "timeout" {
+                metrics.timeouts += 1;
+            }
+            *metrics.errors.entry(kind).or_insert(0) += 1;
+        }
+    }
+
+    /// Records a request which expired in a queue before it was sent to `host`.
+    pub fn expire_in_queue(&mut self, host: SocketAddr) {
+        self.entry(host).expired_in_queue += 1;
+    }
+
+    pub fn connect_failed(&mut self, host: SocketAddr) {
+        self.entry(host).connect_failures += 1;
+    }
+
+    /// Records a request which was dropped before it completed.
+    pub fn cancel(&mut self, host: SocketAddr) {
+        let metrics = self.entry(host);
+        metrics.in_flight = metrics.in_flight.saturating_sub(1);
+    }
+
+    pub fn set_connections(&mut self, host: SocketAddr, connections: usize) {
+        self.entry(host).connections = connections;
+    }
+
+    pub fn host(&self, host: &SocketAddr) -> Option<&HostMetrics> {
+        self.hosts.get(host).map(|entry| &entry.metrics)
+    }
+
+    pub fn host_metrics(&self) -> HashMap<SocketAddr, HostMetrics> {
+        self.hosts
+            .iter()
+            .map(|(host, entry)| (*host, entry.metrics.clone()))
+            .collect()
+    }
+
+    /// Marks hosts which are not in `hosts` removed and prunes ones which were
+    /// removed longer than the grace period ago. A host which comes back keeps
+    /// its metrics.
+    pub fn update_topology(&mut self, hosts: &[SocketAddr]) {
+        let now = Instant::now();
+        for (host, entry) in self.hosts.iter_mut() {
+            if hosts.contains(host) {
+                entry.removed_at = None;
+            } else if entry.removed_at.is_none() {
+                entry.removed_at = Some(now);
+            }
+        }
+
+        let grace = self.grace;
+        self.hosts.retain(|_, entry| {
+                              entry.removed_at
+                                  .map_or(true, |removed_at| now.duration_since(removed_at) < grace)
+                          });
+    }
+
+    /// Renders metrics in Prometheus text format with a `host` label.
+    pub fn prometheus(&self) -> String {
+        let mut hosts: Vec<_> = self.hosts.iter().collect();
+        hosts.sort_by_key(|&(host, _)| *host);
+
+        let mut text = String::new();
+        for (host, entry) in hosts {
+            let metrics = &entry.metrics;
+            let _ = writeln!(text, "cdrs_requests_total{{host=\"{}\"}} {}", host, metrics.requests);
+            for (kind, count) in &metrics.errors {
+                let _ = writeln!(text,
+                                 "cdrs_errors_total{{host=\"{}\",kind=\"{}\"}} {}",
+                                 host,
+                                 kind,
+                                 count);
+            }
+            let _ = writeln!(text, "cdrs_timeouts_total{{host=\"{}\"}} {}", host, metrics.timeouts);
+            let _ = writeln!(text,
+                             "cdrs_expired_in_queue_total{{host=\"{}\"}} {}",
+                             host,
+                             metrics.expired_in_queue);
+            let _ = writeln!(text, "cdrs_in_flight{{host=\"{}\"}} {}", host, metrics.in_flight);
+            let _ = writeln!(text,
+                             "cdrs_connections{{host=\"{}\"}} {}",
+                             host,
+                             metrics.connections);
+
+            let _ = writeln!(text,
+                             "cdrs_connect_failures_total{{host=\"{}\"}} {}",
+                             host,
+                             metrics.connect_failures);
+
+            let mut cumulative = 0;
+            for (bucket, count) in metrics.latency.buckets.iter().enumerate() {
+                cumulative += *count;
+                let le = LATENCY_BUCKETS_MS.get(bucket)
+                    .map(|ms| format!("{}", *ms as f64 / 1000.0))
+                    .unwrap_or("+Inf".to_string());
+                let _ = writeln!(text,
+                                 "cdrs_latency_seconds_bucket{{host=\"{}\",le=\"{}\"}} {}",
+                                 host,
+                                 le,
+                                 cumulative);
+            }
+            let sum = metrics.latency.sum;
+            let _ = writeln!(text,
+                             "cdrs_latency_seconds_sum{{host=\"{}\"}} {}",
+                             host,
+                             sum.as_secs() as f64 + sum.subsec_nanos() as f64 / 1e9);
+            let _ = writeln!(text,
+                             "cdrs_latency_seconds_count{{host=\"{}\"}} {}",
+                             host,
+                             metrics.latency.count);
+        }
+        text
+    }
+}
+
+/// Wraps a request future sent to `host` so it's measured in `metrics`.
+pub fn track<F>(metrics: Arc<Mutex<HostMetricsRegistry>>, host: SocketAddr, future: F) -> Tracked<F>
+    where F: Future<Error = error::Error>
+{
+    Tracked {
+        future: future,
+        metrics: metrics,
+        host: host,
+        started: None,
+    }
+}
+
+/// Future returned by `track`.
+pub struct Tracked<F> {
+    future: F,
+    metrics: Arc<Mutex<HostMetricsRegistry>>,
+    host: SocketAddr,
+    started: Option<Instant>,
+}
+
+impl<F: Future<Error = error::Error>> Future for Tracked<F> {
+    type Item = F::Item;
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, error::Error> {
+        let started = match self.started {
+            Some(started) => started,
+            None => {
+                self.metrics.lock().unwrap().start(self.host);
+                let now = Instant::now();
+                self.started = Some(now);
+                now
+            }
+        };
+
+        let result = match self.future.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(item)) => Ok(item),
+            Err(err) => Err(err),
+        };
+
+        self.metrics
+            .lock()
+            .unwrap()
+            .finish(self.host, result.as_ref().err(), started.elapsed());
+        self.started = None;
+        result.map(Async::Ready)
+    }
+}
+
+impl<F> Drop for Tracked<F> {
+    fn drop(&mut self) {
+        if self.started.is_some() {
+            if let Ok(mut metrics) = self.metrics.lock() {
+                metrics.cancel(self.host);
+            }
+        }
+    }
+}
+
+/// Id of a request given by `SessionMetricsObserver::on_request_start` and passed
+/// back along with its outcome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestToken(pub u64);
+
+/// An observer of a session shared with other sessions, e.g. of a pool.
+pub type SharedObserver = Arc<SessionMetricsObserver + Send + Sync>;
+
+/// Observer of requests of a session, see `Session::metrics_observer`. Every
+/// started request is completed exactly once, with a response or with an error.
+/// A server error comes as a response with `Opcode::Error`.
+pub trait SessionMetricsObserver {
+    fn on_request_start(&self, opcode: &Opcode) -> RequestToken;
+
+    fn on_request_complete(&self,
+                           token: RequestToken,
+                           result: Result<&Frame, &error::Error>,
+                           latency: Duration);
+
+    /// Bytes written and read by a request, `read` includes a response
+    /// and events or other frames which came before it.
+    fn on_bytes(&self, written: usize, read: usize);
+
+    /// Requests of a multiplexed connection in flight and ones queued for a slot,
+    /// see `Multiplexer::metrics_observer`. It's called whenever either changes.
+    fn on_in_flight(&self, _in_flight: usize, _queued: usize) {}
+}
+
+/// Observer which counts requests and bytes.
+#[derive(Debug, Default)]
+pub struct CountingObserver {
+    started: AtomicUsize,
+    completed: AtomicUsize,
+    server_errors: AtomicUsize,
+    failed: AtomicUsize,
+    written: AtomicUsize,
+    read: AtomicUsize,
+    in_flight: AtomicUsize,
+    queued: AtomicUsize,
+}
+
+impl CountingObserver {
+    pub fn new() -> CountingObserver {
+        CountingObserver::default()
+    }
+
+    pub fn started(&self) -> usize {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    /// Requests which got a response, server errors included.
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::SeqCst)
+    }
+
+    pub fn server_errors(&self) -> usize {
+        self.server_errors.load(Ordering::SeqCst)
+    }
+
+    /// Requests which failed without a response, e.g. with a timeout.
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    pub fn bytes_written(&self) -> usize {
+        self.written.load(Ordering::SeqCst)
+    }
+
+    pub fn bytes_read(&self) -> usize {
+        self.read.load(Ordering::SeqCst)
+    }
+
+    /// Requests in flight on a multiplexed connection as last reported.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Requests queued on a multiplexed connection as last reported.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+impl SessionMetricsObserver for CountingObserver {
+    fn on_request_start(&self, _opcode: &Opcode) -> RequestToken {
+        RequestToken(self.started.fetch_add(1, Ordering::SeqCst) as u64)
+    }
+
+    fn on_request_complete(&self,
+                           _token: RequestToken,
+                           result: Result<&Frame, &error::Error>,
+                           _latency: Duration) {
+        match result {
+            Ok(frame) => {
+                if frame.opcode == Opcode::Error {
+                    self.server_errors.fetch_add(1, Ordering::SeqCst);
+                }
+                self.completed.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(_) => {
+                self.failed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn on_bytes(&self, written: usize, read: usize) {
+        self.written.fetch_add(written, Ordering::SeqCst);
+        self.read.fetch_add(read, Ordering::SeqCst);
+    }
+
+    fn on_in_flight(&self, in_flight: usize, queued: usize) {
+        self.in_flight.store(in_flight, Ordering::SeqCst);
+        self.queued.store(queued, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use futures::future;
+    use tokio_core::reactor::{Core, Timeout};
+
+    use super::*;
+
+    fn host(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn separates_hosts_by_latency() {
+        let metrics = Arc::new(Mutex::new(HostMetricsRegistry::new(Duration::from_secs(60))));
+        let mut core = Core::new().unwrap();
+
+        let requests: Vec<_> = (0..5)
+            .flat_map(|_| vec![(host(9042), 1), (host(9043), 120)])
+            .map(|(host, millis)| {
+                     let delay = Timeout::new(Duration::from_millis(millis), &core.handle())
+                         .unwrap()
+                         .map_err(error::Error::from);
+                     track(metrics.clone(), host, delay)
+                 })
+            .collect();
+        core.run(future::join_all(requests)).unwrap();
+
+        let metrics = metrics.lock().unwrap();
+        let fast = metrics.host(&host(9042)).unwrap();
+        let slow = metrics.host(&host(9043)).unwrap();
+        assert_eq!((fast.requests, slow.requests), (5, 5));
+        assert_eq!((fast.in_flight, slow.in_flight), (0, 0));
+        assert!(fast.latency.quantile(0.9).unwrap() <= Duration::from_millis(50));
+        assert!(slow.latency.quantile(0.5).unwrap() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn counts_errors_by_kind() {
+        let mut metrics = HostMetricsRegistry::new(Duration::from_secs(60));
+        let timeout = error::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        let reset = error::Error::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+
+        for err in &[Some(&timeout), Some(&reset), None] {
+            metrics.start(host(9042));
+            metrics.finish(host(9042), *err, Duration::from_millis(3));
+        }
+
+        let stats = metrics.host(&host(9042)).unwrap();
+        assert_eq!(stats.requests, 3);
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(stats.errors.get("io"), Some(&1));
+        assert_eq!(stats.errors.get("timeout"), Some(&1));
+        assert!(metrics.prometheus()
+                    .contains("cdrs_errors_total{host=\"127.0.0.1:9042\",kind=\"io\"} 1"));
+    }
+
+    #[test]
+    fn retires_removed_hosts() {
+        let mut metrics = HostMetricsRegistry::new(Duration::from_secs(0));
+        metrics.start(host(9042));
+        metrics.start(host(9043));
+
+        metrics.update_topology(&[host(9042)]);
+        assert!(metrics.host(&host(9042)).is_some());
+        assert!(metrics.host(&host(9043)).is_none());
+
+        let mut metrics = HostMetricsRegistry::new(Duration::from_secs(60));
+        metrics.start(host(9043));
+        metrics.update_topology(&[]);
+        assert!(metrics.host(&host(9043)).is_some());
+    }
+}
@@ -0,0 +1,545 @@
+//! Scripted in-memory transport used by tests.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use cdrs::IntoBytes;
+use cdrs::authenticators::NoneAuthenticator;
+use cdrs::transport::CDRSTransport;
+use cdrs::types::value::Value;
+use futures::future::{ExecuteError, Executor, Future};
+
+use client::{CDRS, Session};
+use decode::DecodeTask;
+
+/// Decode executor which runs every task on a thread of its own.
+pub struct ThreadExecutor;
+
+impl Executor<DecodeTask> for ThreadExecutor {
+    fn execute(&self, task: DecodeTask) -> Result<(), ExecuteError<DecodeTask>> {
+        thread::spawn(move || task.wait());
+        Ok(())
+    }
+}
+
+/// What a next `write` call does.
+#[derive(Debug, Clone, Copy)]
+pub enum WriteStep {
+    /// Accept at most given number of bytes.
+    Accept(usize),
+    /// Fail with an error of a given kind.
+    Error(io::ErrorKind),
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    reads: VecDeque<Result<Vec<u8>, io::ErrorKind>>,
+    writes: VecDeque<WriteStep>,
+    written: Vec<u8>,
+    flushes: usize,
+    closes: usize,
+    buffered: bool,
+    unflushed: Vec<u8>,
+}
+
+impl MockState {
+    fn accept(&mut self, bytes: &[u8]) {
+        if self.buffered {
+            self.unflushed.extend_from_slice(bytes);
+        } else {
+            self.written.extend_from_slice(bytes);
+        }
+    }
+}
+
+/// Transport which returns scripted reads and records everything written to it.
+/// Clones share the same state, so a test can keep a handle after the transport
+/// is moved into `CDRS`. When no reads are scripted `WouldBlock` is returned,
+/// when no writes are scripted everything is accepted.
+///
+/// A buffered transport keeps written bytes until it's flushed and doesn't return
+/// scripted reads until anything was flushed, like a server which never got a request.
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    pub fn buffered() -> MockTransport {
+        let transport = MockTransport::default();
+        transport.state.lock().unwrap().buffered = true;
+        transport
+    }
+
+    pub fn push_read(&self, bytes: Vec<u8>) {
+        self.state.lock().unwrap().reads.push_back(Ok(bytes));
+    }
+
+    pub fn push_read_error(&self, kind: io::ErrorKind) {
+        self.state.lock().unwrap().reads.push_back(Err(kind));
+    }
+
+    pub fn push_write(&self, step: WriteStep) {
+        self.state.lock().unwrap().writes.push_back(step);
+    }
+
+    pub fn written(&self) -> Vec<u8> {
+        self.state.lock().unwrap().written.clone()
+    }
+
+    pub fn flushes(&self) -> usize {
+        self.state.lock().unwrap().flushes
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closes() > 0
+    }
+
+    pub fn closes(&self) -> usize {
+        self.state.lock().unwrap().closes
+    }
+}
+
+impl io::Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.buffered && state.written.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "nothing was flushed"));
+        }
+
+        match state.reads.pop_front() {
+            Some(Ok(mut bytes)) => {
+                let n = cmp::min(buf.len(), bytes.len());
+                buf[..n].copy_from_slice(&bytes[..n]);
+                let rest = bytes.split_off(n);
+                if !rest.is_empty() {
+                    state.reads.push_front(Ok(rest));
+                }
+                Ok(n)
+            }
+            Some(Err(kind)) => Err(io::Error::new(kind, "scripted read error")),
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "no data")),
+        }
+    }
+}
+
+impl io::Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.writes.pop_front() {
+            Some(WriteStep::Accept(max)) => {
+                let n = cmp::min(max, buf.len());
+                state.accept(&buf[..n]);
+                Ok(n)
+            }
+            Some(WriteStep::Error(kind)) => Err(io::Error::new(kind, "scripted write error")),
+            None => {
+                state.accept(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.flushes += 1;
+        let unflushed = mem::replace(&mut state.unflushed, vec![]);
+        state.written.extend(unflushed);
+        Ok(())
+    }
+}
+
+impl CDRSTransport for MockTransport {
+    fn try_clone(&self) -> io::Result<MockTransport> {
+        Ok(self.clone())
+    }
+
+    fn close(&mut self, _close: net::Shutdown) -> io::Result<()> {
+        self.state.lock().unwrap().closes += 1;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, _dur: Option<time::Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Opcodes of frames, as written by `response` and read by `opcodes`.
+pub const ERROR: u8 = 0x00;
+pub const STARTUP: u8 = 0x01;
+pub const READY: u8 = 0x02;
+pub const AUTHENTICATE: u8 = 0x03;
+pub const OPTIONS: u8 = 0x05;
+pub const SUPPORTED: u8 = 0x06;
+pub const QUERY: u8 = 0x07;
+pub const RESULT: u8 = 0x08;
+pub const PREPARE: u8 = 0x09;
+pub const EXECUTE: u8 = 0x0A;
+pub const REGISTER: u8 = 0x0B;
+pub const EVENT: u8 = 0x0C;
+pub const BATCH: u8 = 0x0D;
+pub const AUTH_CHALLENGE: u8 = 0x0E;
+pub const AUTH_RESPONSE: u8 = 0x0F;
+pub const AUTH_SUCCESS: u8 = 0x10;
+
+/// Session which talks over `transport` without authentication.
+pub fn session(transport: MockTransport) -> Session<NoneAuthenticator, MockTransport> {
+    Session::start(CDRS::new(transport, NoneAuthenticator))
+}
+
+/// Builds bytes of a protocol v4 response frame.
+pub fn response(opcode: u8, stream: i16, body: &[u8]) -> Vec<u8> {
+    let len = body.len() as i32;
+    let mut frame = vec![0x84,
+                         0,
+                         (stream >> 8) as u8,
+                         stream as u8,
+                         opcode,
+                         (len >> 24) as u8,
+                         (len >> 16) as u8,
+                         (len >> 8) as u8,
+                         len as u8];
+    frame.extend_from_slice(body);
+    frame
+}
+
+/// Builds bytes of a protocol v4 response frame with the tracing flag.
+pub fn traced_response(opcode: u8, stream: i16, tracing_id: [u8; 16], body: &[u8]) -> Vec<u8> {
+    let mut traced_body = tracing_id.to_vec();
+    traced_body.extend_from_slice(body);
+    let mut frame = response(opcode, stream, &traced_body);
+    frame[1] = 0x02;
+    frame
+}
+
+/// Builds bytes of a protocol v4 response frame with the warning flag.
+pub fn warned_response(opcode: u8, stream: i16, warnings: &[&str], body: &[u8]) -> Vec<u8> {
+    let mut warned_body = vec![(warnings.len() >> 8) as u8, warnings.len() as u8];
+    for warning in warnings {
+        push_string(&mut warned_body, warning);
+    }
+    warned_body.extend_from_slice(body);
+    let mut frame = response(opcode, stream, &warned_body);
+    frame[1] = 0x08;
+    frame
+}
+
+/// Type id of CQL `boolean` to be used in `rows_body` columns.
+pub const BOOLEAN: u16 = 0x0004;
+/// Type id of CQL `bigint` to be used in `rows_body` columns.
+pub const BIGINT: u16 = 0x0002;
+/// Type id of CQL `int` to be used in `rows_body` columns.
+pub const INT: u16 = 0x0009;
+/// Type id of CQL `uuid` to be used in `rows_body` columns.
+pub const UUID: u16 = 0x000C;
+/// Type id of CQL `varchar` to be used in `rows_body` columns.
+pub const VARCHAR: u16 = 0x000D;
+/// Type id of CQL `inet` to be used in `rows_body` columns.
+pub const INET: u16 = 0x0010;
+/// Type id of CQL `set` to be used in `rows_body` columns. Elements of such
+/// columns are always of `varchar` type.
+pub const VARCHAR_SET: u16 = 0x0022;
+/// Type id of CQL `map` to be used in `rows_body` columns. Keys and values
+/// of such columns are always of `varchar` type.
+pub const VARCHAR_MAP: u16 = 0x0021;
+
+fn push_string(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(&[(s.len() >> 8) as u8, s.len() as u8]);
+    body.extend_from_slice(s.as_bytes());
+}
+
+fn push_int(body: &mut Vec<u8>, i: i32) {
+    body.extend_from_slice(&[(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+}
+
+/// Serialized `int` value.
+pub fn int(i: i32) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    push_int(&mut bytes, i);
+    Some(bytes)
+}
+
+/// Serialized `boolean` value.
+pub fn boolean(b: bool) -> Option<Vec<u8>> {
+    Some(vec![b as u8])
+}
+
+/// Serialized `bigint` value.
+pub fn bigint(i: i64) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    push_int(&mut bytes, (i >> 32) as i32);
+    push_int(&mut bytes, i as i32);
+    Some(bytes)
+}
+
+/// Serialized `varchar` value.
+pub fn text(s: &str) -> Option<Vec<u8>> {
+    Some(s.as_bytes().to_vec())
+}
+
+/// Serialized `set<varchar>` value.
+pub fn text_set(elements: &[&str]) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    push_int(&mut bytes, elements.len() as i32);
+    for element in elements {
+        push_int(&mut bytes, element.len() as i32);
+        bytes.extend_from_slice(element.as_bytes());
+    }
+    Some(bytes)
+}
+
+/// Serialized `map<varchar, varchar>` value.
+pub fn text_map(entries: &[(&str, &str)]) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    push_int(&mut bytes, entries.len() as i32);
+    for &(key, value) in entries {
+        for text in &[key, value] {
+            push_int(&mut bytes, text.len() as i32);
+            bytes.extend_from_slice(text.as_bytes());
+        }
+    }
+    Some(bytes)
+}
+
+/// Serialized IPv4 `inet` value.
+pub fn inet(octets: [u8; 4]) -> Option<Vec<u8>> {
+    Some(octets.to_vec())
+}
+
+/// Body of a RESULT frame of `Rows` kind. `None` cells are nulls.
+pub fn rows_body(columns: &[(&str, u16)],
+                 rows: &[Vec<Option<Vec<u8>>>],
+                 paging_state: Option<&[u8]>)
+                 -> Vec<u8> {
+    let mut body = vec![];
+    push_rows_header(&mut body, 0x0001, columns.len(), paging_state);
+    push_col_specs(&mut body, columns);
+    push_rows(&mut body, rows);
+    body
+}
+
+/// Body of a RESULT frame of `Rows` kind which is a response to EXECUTE
+/// with SKIP_METADATA flag.
+pub fn rows_body_without_metadata(columns_count: usize,
+                                  rows: &[Vec<Option<Vec<u8>>>],
+                                  paging_state: Option<&[u8]>)
+                                  -> Vec<u8> {
+    let mut body = vec![];
+    push_rows_header(&mut body, 0x0004, columns_count, paging_state);
+    push_rows(&mut body, rows);
+    body
+}
+
+fn push_rows_header(body: &mut Vec<u8>,
+                    flags: i32,
+                    columns_count: usize,
+                    paging_state: Option<&[u8]>) {
+    push_int(body, 0x0002);
+    push_int(body, flags | if paging_state.is_some() { 0x0002 } else { 0 });
+    push_int(body, columns_count as i32);
+    if let Some(state) = paging_state {
+        push_int(body, state.len() as i32);
+        body.extend_from_slice(state);
+    }
+}
+
+fn push_rows(body: &mut Vec<u8>, rows: &[Vec<Option<Vec<u8>>>]) {
+    push_int(body, rows.len() as i32);
+    for row in rows {
+        for cell in row {
+            match *cell {
+                Some(ref bytes) => {
+                    push_int(body, bytes.len() as i32);
+                    body.extend_from_slice(bytes);
+                }
+                None => push_int(body, -1),
+            }
+        }
+    }
+}
+
+/// Bytes of `values` to compare them, cdrs values aren't `PartialEq`.
+pub fn encoded(values: &[Value]) -> Vec<Vec<u8>> {
+    values.iter().map(|value| value.into_cbytes()).collect()
+}
+
+/// Opcodes of frames in `bytes` which is a sequence of v4 frames.
+pub fn opcodes(bytes: &[u8]) -> Vec<u8> {
+    let mut opcodes = vec![];
+    let mut rest = bytes;
+    while rest.len() >= 9 {
+        let len = ((rest[5] as usize) << 24) | ((rest[6] as usize) << 16) |
+                  ((rest[7] as usize) << 8) | rest[8] as usize;
+        opcodes.push(rest[4]);
+        rest = &rest[cmp::min(9 + len, rest.len())..];
+    }
+    opcodes
+}
+
+/// Stream ids of frames in `bytes` which is a sequence of v4 frames.
+pub fn streams(bytes: &[u8]) -> Vec<i16> {
+    let mut streams = vec![];
+    let mut rest = bytes;
+    while rest.len() >= 9 {
+        let len = ((rest[5] as usize) << 24) | ((rest[6] as usize) << 16) |
+                  ((rest[7] as usize) << 8) | rest[8] as usize;
+        streams.push(((rest[2] as i16) << 8) | rest[3] as i16);
+        rest = &rest[cmp::min(9 + len, rest.len())..];
+    }
+    streams
+}
+
+/// Page sizes of QUERY frames in `bytes`, `None` for queries without one.
+pub fn page_sizes(bytes: &[u8]) -> Vec<Option<i32>> {
+    let int = |b: &[u8]| {
+        ((b[0] as i32) << 24) | ((b[1] as i32) << 16) | ((b[2] as i32) << 8) | b[3] as i32
+    };
+    let mut page_sizes = vec![];
+    let mut rest = bytes;
+    while rest.len() >= 9 {
+        let len = int(&rest[5..9]) as usize;
+        if rest[4] == QUERY {
+            let body = &rest[9..9 + len];
+            // query string, consistency and flags
+            let mut at = 4 + int(body) as usize + 2;
+            let flags = body[at];
+            at += 1;
+            if flags & 0x01 != 0 {
+                let values = ((body[at] as usize) << 8) | body[at + 1] as usize;
+                at += 2;
+                for _ in 0..values {
+                    at += 4 + cmp::max(int(&body[at..]), 0) as usize;
+                }
+            }
+            page_sizes.push(if flags & 0x04 != 0 { Some(int(&body[at..])) } else { None });
+        }
+        rest = &rest[cmp::min(9 + len, rest.len())..];
+    }
+    page_sizes
+}
+
+/// Body of a RESULT frame of `Void` kind.
+pub fn void_body() -> Vec<u8> {
+    vec![0, 0, 0, 1]
+}
+
+fn push_col_specs(body: &mut Vec<u8>, columns: &[(&str, u16)]) {
+    push_string(body, "ks");
+    push_string(body, "table");
+    for &(name, type_id) in columns {
+        push_string(body, name);
+        body.extend_from_slice(&[(type_id >> 8) as u8, type_id as u8]);
+        if type_id == VARCHAR_SET {
+            body.extend_from_slice(&[(VARCHAR >> 8) as u8, VARCHAR as u8]);
+        }
+        if type_id == VARCHAR_MAP {
+            body.extend_from_slice(&[(VARCHAR >> 8) as u8, VARCHAR as u8]);
+            body.extend_from_slice(&[(VARCHAR >> 8) as u8, VARCHAR as u8]);
+        }
+    }
+}
+
+/// Body of a RESULT frame of `SetKeyspace` kind.
+pub fn set_keyspace_body(keyspace: &str) -> Vec<u8> {
+    let mut body = vec![];
+    push_int(&mut body, 0x0003);
+    push_string(&mut body, keyspace);
+    body
+}
+
+/// Body of a RESULT frame of `SchemaChange` kind about a table.
+pub fn schema_change_body(change_type: &str, keyspace: &str, table: &str) -> Vec<u8> {
+    let mut body = vec![];
+    push_int(&mut body, 0x0005);
+    push_string(&mut body, change_type);
+    push_string(&mut body, "TABLE");
+    push_string(&mut body, keyspace);
+    push_string(&mut body, table);
+    body
+}
+
+/// Body of an EVENT frame of `SCHEMA_CHANGE` type about a table.
+pub fn schema_event_body(change_type: &str, keyspace: &str, table: &str) -> Vec<u8> {
+    let mut body = vec![];
+    push_string(&mut body, "SCHEMA_CHANGE");
+    push_string(&mut body, change_type);
+    push_string(&mut body, "TABLE");
+    push_string(&mut body, keyspace);
+    push_string(&mut body, table);
+    body
+}
+
+/// Body of an EVENT frame of `TOPOLOGY_CHANGE` or `STATUS_CHANGE` type about a node.
+pub fn node_event_body(event_type: &str, change: &str, octets: [u8; 4], port: i32) -> Vec<u8> {
+    let mut body = vec![];
+    push_string(&mut body, event_type);
+    push_string(&mut body, change);
+    // cdrs reads the size of an address as a short
+    body.extend_from_slice(&[0, octets.len() as u8]);
+    body.extend_from_slice(&octets);
+    push_int(&mut body, port);
+    body
+}
+
+/// Body of a RESULT frame of `Prepared` kind.
+pub fn prepared_body(id: &[u8], markers: &[(&str, u16)], columns: &[(&str, u16)]) -> Vec<u8> {
+    let mut body = vec![];
+    push_int(&mut body, 0x0004);
+    body.extend_from_slice(&[(id.len() >> 8) as u8, id.len() as u8]);
+    body.extend_from_slice(id);
+
+    push_int(&mut body, 0x0001);
+    push_int(&mut body, markers.len() as i32);
+    push_int(&mut body, 0);
+    push_col_specs(&mut body, markers);
+
+    push_int(&mut body, 0x0001);
+    push_int(&mut body, columns.len() as i32);
+    push_col_specs(&mut body, columns);
+
+    body
+}
+
+/// Body of a SUPPORTED frame.
+pub fn supported_body(options: &[(&str, &[&str])]) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&[(options.len() >> 8) as u8, options.len() as u8]);
+    for &(key, values) in options {
+        push_string(&mut body, key);
+        body.extend_from_slice(&[(values.len() >> 8) as u8, values.len() as u8]);
+        for value in values {
+            push_string(&mut body, value);
+        }
+    }
+    body
+}
+
+/// Body of an ERROR frame.
+pub fn error_body(code: i32, message: &str) -> Vec<u8> {
+    let mut body = vec![];
+    push_int(&mut body, code);
+    push_string(&mut body, message);
+    body
+}
+
+/// Body of a WRITE_TIMEOUT error of a SIMPLE write which blocks for 2 replicas.
+pub fn write_timeout_body(received: i32) -> Vec<u8> {
+    let mut body = error_body(0x1100, "timed out");
+    // LOCAL_QUORUM
+    body.extend_from_slice(&[0x00, 0x06]);
+    push_int(&mut body, received);
+    push_int(&mut body, 2);
+    push_string(&mut body, "SIMPLE");
+    body
+}
@@ -0,0 +1,917 @@
+//! Concurrent requests on a single connection.
+//!
+//! A `Session` has one request in flight at a time. A `Multiplexer` sends every
+//! request on a stream id no other outstanding request has, and a `Dispatcher`
+//! running on a reactor writes requests and hands each response to the future
+//! waiting on its stream. Responses may come in any order and an ERROR response
+//! fails only the request it answers, while a broken connection fails all of them.
+//!
+//! An id is reused once a response to it arrives, even if the future waiting
+//! for it was dropped, so a late response is never taken for another one, see
+//! `StreamIds`. A request whose future is dropped while it's queued never takes
+//! an id. If every id is in use, requests are queued until one is freed, or fail
+//! with `Error::StreamIdExhausted`, see `Multiplexer::fail_when_exhausted`.
+//!
+//! A connection has at most `max_in_flight` requests in flight. Requests beyond
+//! it wait in a queue and are written in order as responses free their slots.
+//!
+//! Events come on a stream of their own, so a multiplexer which registered for
+//! them keeps serving requests. Every `EventListener` gets each event the
+//! connection receives until it's stopped or dropped.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+use futures::{Async, Future, Poll, Stream};
+use futures::future;
+use futures::sync::{mpsc, oneshot};
+use futures::task::{self, Task};
+
+use cdrs::authenticators::Authenticator;
+use cdrs::compression::Compression;
+use cdrs::frame::{Frame, Opcode};
+use cdrs::frame::events::{ServerEvent, SimpleServerEvent};
+use cdrs::frame::frame_response::ResponseBody;
+use cdrs::query::Query;
+use cdrs::transport::CDRSTransport;
+
+use client::{self, CDRS, CDRSFuture};
+use codec::{self, EVENT_STREAM_ID, Expectation};
+use metrics::SharedObserver;
+use request::{Override, RequestOptions};
+use error;
+
+/// Number of stream ids a client may use, negative ones are reserved for events.
+pub const MAX_STREAMS: usize = 32768;
+
+/// Number of requests a connection has in flight by default, see `Multiplexer::max_in_flight`.
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 1024;
+
+type Responder = oneshot::Sender<error::Result<Frame>>;
+type EventSink = mpsc::UnboundedSender<error::Result<Frame>>;
+
+struct Inner<T: Authenticator, X> {
+    cdrs: CDRS<T, X>,
+    compressor: Compression,
+    ids: StreamIds,
+    pending: HashMap<i16, Responder>,
+    fail_when_exhausted: bool,
+    max_in_flight: usize,
+    /// Requests which wait for a slot, in order.
+    queued: VecDeque<(Frame, Override, Responder)>,
+    observer: Option<SharedObserver>,
+    listeners: HashMap<usize, EventSink>,
+    next_listener: usize,
+    dispatcher: Option<Task>,
+    /// Kind and description of an error which closed the connection.
+    closed: Option<(io::ErrorKind, String)>,
+}
+
+impl<T: Authenticator, X: CDRSTransport> Inner<T, X> {
+    /// Writes a request, or queues it if the connection has `max_in_flight`
+    /// requests in flight or others are queued already.
+    fn send(&mut self,
+            frame: Frame,
+            compression: Override,
+            responder: Responder)
+            -> error::Result<()> {
+        if let Some(ref closed) = self.closed {
+            return Err(connection_closed(closed));
+        }
+
+        let exhausted = self.ids.len() >= MAX_STREAMS;
+        if exhausted && self.fail_when_exhausted {
+            return Err(error::Error::StreamIdExhausted { in_flight: self.ids.len() });
+        }
+
+        if exhausted || self.pending.len() >= self.max_in_flight || !self.queued.is_empty() {
+            self.queued.push_back((frame, compression, responder));
+        } else {
+            let stream = try!(self.write(frame, compression));
+            self.pending.insert(stream, responder);
+            self.notify_dispatcher();
+        }
+        self.observe();
+        Ok(())
+    }
+
+    /// Writes a request on a free stream id and returns the id.
+    fn write(&mut self, mut frame: Frame, compression: Override) -> error::Result<i16> {
+        let stream = match self.ids.allocate() {
+            Some(stream) => stream,
+            None => return Err(error::Error::StreamIdExhausted { in_flight: self.ids.len() }),
+        };
+        frame.stream = stream as _;
+        let compressor = self.compressor;
+        if let Err(err) = self.cdrs.queue_frame_with(frame, &compressor, compression) {
+            // nothing was written, so no response comes to the id
+            self.ids.release(stream);
+            return Err(err);
+        }
+        Ok(stream)
+    }
+
+    /// Writes queued requests while there are free slots. Requests whose futures
+    /// were dropped are skipped.
+    fn send_queued(&mut self) {
+        while self.pending.len() < self.max_in_flight && self.ids.len() < MAX_STREAMS {
+            let (frame, compression, responder) = match self.queued.pop_front() {
+                Some(queued) => queued,
+                None => break,
+            };
+            if responder.is_canceled() {
+                continue;
+            }
+            match self.write(frame, compression) {
+                Ok(stream) => {
+                    self.pending.insert(stream, responder);
+                }
+                Err(err) => drop(responder.send(Err(err))),
+            }
+        }
+    }
+
+    fn observe(&self) {
+        if let Some(ref observer) = self.observer {
+            observer.on_in_flight(self.pending.len(), self.queued.len());
+        }
+    }
+
+    fn dispatch(&mut self, frame: Frame) {
+        let stream = frame.stream as i16;
+        if stream == EVENT_STREAM_ID {
+            // a listener which is gone is removed along with its handle
+            for sink in self.listeners.values() {
+                drop(sink.unbounded_send(Ok(codec::clone_frame(&frame))));
+            }
+            return;
+        }
+        match self.pending.remove(&stream) {
+            // the receiver is gone if a caller isn't interested in the response anymore
+            Some(responder) => {
+                self.ids.release(stream);
+                drop(responder.send(Ok(frame)));
+            }
+            None => warn!("Response to stream {} which has no request in flight", stream),
+        }
+        self.send_queued();
+        self.observe();
+    }
+
+    /// Fails requests in flight and the ones sent later with `err`.
+    fn close(&mut self, err: &error::Error) {
+        let kind = match *err {
+            error::Error::Io(ref err) => err.kind(),
+            _ => io::ErrorKind::ConnectionAborted,
+        };
+        let closed = (kind, err.to_string());
+        for (stream, responder) in self.pending.drain() {
+            self.ids.release(stream);
+            drop(responder.send(Err(connection_closed(&closed))));
+        }
+        for (_, _, responder) in self.queued.drain(..) {
+            drop(responder.send(Err(connection_closed(&closed))));
+        }
+        for (_, sink) in self.listeners.drain() {
+            drop(sink.unbounded_send(Err(connection_closed(&closed))));
+        }
+        self.closed = Some(closed);
+        let _ = self.cdrs.drop_connection();
+        self.observe();
+    }
+
+    fn notify_dispatcher(&self) {
+        if let Some(ref task) = self.dispatcher {
+            task.notify();
+        }
+    }
+}
+
+/// Sends requests concurrently on a connection. Clones share the connection.
+pub struct Multiplexer<T: Authenticator, X> {
+    inner: Arc<Mutex<Inner<T, X>>>,
+}
+
+impl<T: Authenticator, X> Clone for Multiplexer<T, X> {
+    fn clone(&self) -> Multiplexer<T, X> {
+        Multiplexer { inner: self.inner.clone() }
+    }
+}
+
+impl<T, X> Multiplexer<T, X>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    /// Sends a request frame and resolves into a response as it is, server errors
+    /// are not turned into `Err`. The frame gets a free stream id, which overrides
+    /// its own one. The future fails if the connection is closed before a response.
+    pub fn request(&self, frame: Frame) -> CDRSFuture<Frame> {
+        self.request_with(frame, Override::Auto)
+    }
+
+    /// Sends a hand-built frame and resolves into a response as it is, see
+    /// `Session::send_frame`. The frame gets a stream id of the allocator, so it
+    /// doesn't collide with other requests in flight. A frame longer than
+    /// the connection accepts fails with `FrameTooLarge` and is never written.
+    pub fn send_frame(&self, frame: Frame) -> CDRSFuture<Frame> {
+        self.request_with(frame, Override::Auto)
+    }
+
+    /// Sends a query with given options and resolves into a response, see `request`.
+    pub fn query(&self, query: Query, options: RequestOptions) -> CDRSFuture<Frame> {
+        self.request_with(client::query_frame(query, options.flags()),
+                          options.get_compression())
+    }
+
+    /// Registers for `events`, which are delivered to the returned listener while
+    /// the multiplexer keeps serving requests. Registration can't be undone
+    /// on a connection, so other listeners which have not asked for the events
+    /// get them as well.
+    pub fn listen_for(&self, events: Vec<SimpleServerEvent>) -> CDRSFuture<EventListener<T, X>> {
+        let (sink, receiver) = mpsc::unbounded();
+        let id = {
+            // events may follow READY right away, so the listener has to be there first
+            let mut inner = self.inner.lock().unwrap();
+            let id = inner.next_listener;
+            inner.next_listener += 1;
+            inner.listeners.insert(id, sink);
+            id
+        };
+        let listener = EventListener {
+            id: id,
+            inner: Arc::downgrade(&self.inner),
+            events: receiver,
+        };
+
+        self.request(Frame::new_req_register(events))
+            .then(move |result| match result {
+                      Ok(ref response) if response.opcode == Opcode::Ready => Ok(listener),
+                      Ok(response) => Err(client::unexpected_response("REGISTER", response)),
+                      Err(err) => Err(err),
+                  })
+            .boxed()
+    }
+
+    /// Number of requests waiting for responses.
+    pub fn in_flight(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+
+    /// Number of requests waiting for a slot, see `max_in_flight`.
+    pub fn queued(&self) -> usize {
+        self.inner.lock().unwrap().queued.len()
+    }
+
+    fn request_with(&self, frame: Frame, compression: Override) -> CDRSFuture<Frame> {
+        let (responder, response) = oneshot::channel();
+        if let Err(err) = self.inner.lock().unwrap().send(frame, compression, responder) {
+            return future::err(err).boxed();
+        }
+
+        response.then(|result| match result {
+                          Ok(result) => result,
+                          Err(oneshot::Canceled) => {
+                              let closed = (io::ErrorKind::ConnectionAborted,
+                                            "connection is dropped".to_string());
+                              Err(connection_closed(&closed))
+                          }
+                      })
+            .boxed()
+    }
+}
+
+impl<T: Authenticator, X: CDRSTransport> Multiplexer<T, X> {
+    /// The method limits the number of requests in flight on the connection,
+    /// it's `DEFAULT_MAX_IN_FLIGHT` by default. Clones share the limit.
+    pub fn max_in_flight(&mut self, max_in_flight: usize) -> &mut Self {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.max_in_flight = max_in_flight.max(1);
+            inner.send_queued();
+            inner.observe();
+            inner.notify_dispatcher();
+        }
+        self
+    }
+
+    /// Makes requests fail with `Error::StreamIdExhausted` while every stream id
+    /// is in use, instead of waiting in the queue for one.
+    pub fn fail_when_exhausted(&mut self, fail: bool) -> &mut Self {
+        self.inner.lock().unwrap().fail_when_exhausted = fail;
+        self
+    }
+
+    /// Reports requests in flight and queued ones to `observer` whenever their
+    /// numbers change. It's called while the connection is locked, so it must
+    /// not use the multiplexer.
+    pub fn metrics_observer(&mut self, observer: SharedObserver) -> &mut Self {
+        self.inner.lock().unwrap().observer = Some(observer);
+        self
+    }
+}
+
+impl<T: Authenticator, X> Drop for Multiplexer<T, X> {
+    fn drop(&mut self) {
+        // the dispatcher finishes once the last multiplexer is gone
+        if let Ok(inner) = self.inner.lock() {
+            if let Some(ref task) = inner.dispatcher {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// Writes requests of multiplexers and dispatches responses to them. It finishes
+/// and closes the connection once every multiplexer is dropped and no request is
+/// in flight. It fails if the connection breaks, failing requests in flight as well.
+pub struct Dispatcher<T: Authenticator, X: CDRSTransport> {
+    inner: Arc<Mutex<Inner<T, X>>>,
+}
+
+impl<T: Authenticator, X: CDRSTransport> Future for Dispatcher<T, X> {
+    type Item = ();
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<(), error::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.dispatcher = Some(task::current());
+
+        loop {
+            if inner.pending.is_empty() && Arc::strong_count(&self.inner) == 1 {
+                let _ = inner.cdrs.drop_connection();
+                return Ok(Async::Ready(()));
+            }
+
+            let compressor = inner.compressor;
+            let expectation = Expectation::multiplexed(&compressor);
+            match inner.cdrs.poll_response(&compressor, &expectation) {
+                Ok(Async::Ready(frame)) => inner.dispatch(frame),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    inner.close(&err);
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Authenticator, X: CDRSTransport> Drop for Dispatcher<T, X> {
+    fn drop(&mut self) {
+        // nothing reads responses anymore
+        if let Ok(mut inner) = self.inner.lock() {
+            if inner.closed.is_none() {
+                let dropped = io::Error::new(io::ErrorKind::ConnectionAborted,
+                                             "dispatcher is dropped");
+                inner.close(&dropped.into());
+            }
+        }
+    }
+}
+
+/// Stream of events a multiplexed connection receives. It fails once
+/// the connection is closed.
+pub struct EventListener<T: Authenticator, X> {
+    id: usize,
+    inner: Weak<Mutex<Inner<T, X>>>,
+    events: mpsc::UnboundedReceiver<error::Result<Frame>>,
+}
+
+impl<T: Authenticator, X> EventListener<T, X> {
+    /// Stops delivering events to the listener.
+    pub fn stop(&mut self) {
+        if let Some(inner) = self.inner.upgrade() {
+            if let Ok(mut inner) = inner.lock() {
+                inner.listeners.remove(&self.id);
+            }
+        }
+        self.events.close();
+    }
+}
+
+impl<T: Authenticator, X> Stream for EventListener<T, X> {
+    type Item = ServerEvent;
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<Option<ServerEvent>, error::Error> {
+        let frame = match self.events.poll() {
+            Ok(Async::Ready(Some(frame))) => try!(frame),
+            Ok(Async::Ready(None)) |
+            Err(()) => return Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+        };
+        match try!(frame.get_body()) {
+            ResponseBody::Event(event) => Ok(Async::Ready(Some(event.event))),
+            _ => Err(client::unexpected_response("EVENT stream", frame)),
+        }
+    }
+}
+
+impl<T: Authenticator, X> Drop for EventListener<T, X> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Builds a multiplexer of a started connection and its dispatcher, stream ids
+/// are taken from `next_stream` onwards.
+pub fn new<T, X>(cdrs: CDRS<T, X>,
+                 compressor: Compression,
+                 next_stream: i16)
+                 -> (Multiplexer<T, X>, Dispatcher<T, X>)
+    where T: Authenticator,
+          X: CDRSTransport
+{
+    let inner = Arc::new(Mutex::new(Inner {
+                                        cdrs: cdrs,
+                                        compressor: compressor,
+                                        ids: StreamIds::new(next_stream),
+                                        pending: HashMap::new(),
+                                        fail_when_exhausted: false,
+                                        max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+                                        queued: VecDeque::new(),
+                                        observer: None,
+                                        listeners: HashMap::new(),
+                                        next_listener: 0,
+                                        dispatcher: None,
+                                        closed: None,
+                                    }));
+    (Multiplexer { inner: inner.clone() }, Dispatcher { inner: inner })
+}
+
+/// Stream ids of requests in flight on a connection. Ids are taken in turn
+/// from 0 to `i16::MAX`, skipping ones in use and wrapping around, so an id
+/// freed by a response is reused as late as possible. Negative ids are reserved
+/// for events and never taken.
+pub struct StreamIds {
+    next: i16,
+    in_use: Vec<u64>,
+    len: usize,
+}
+
+impl StreamIds {
+    /// Creates an allocator which takes ids from `next` onwards.
+    pub fn new(next: i16) -> StreamIds {
+        StreamIds {
+            next: next.max(0),
+            in_use: vec![0; MAX_STREAMS / 64],
+            len: 0,
+        }
+    }
+
+    /// Takes a free id, `None` if every id is in use.
+    pub fn allocate(&mut self) -> Option<i16> {
+        if self.len >= MAX_STREAMS {
+            return None;
+        }
+        loop {
+            let stream = self.next;
+            self.next = self.next.checked_add(1).unwrap_or(0);
+            if !self.is_in_use(stream) {
+                self.in_use[stream as usize / 64] |= 1 << (stream as usize % 64);
+                self.len += 1;
+                return Some(stream);
+            }
+        }
+    }
+
+    /// Frees `stream` once nothing can come to it anymore. Returns `false`
+    /// if it was not in use.
+    pub fn release(&mut self, stream: i16) -> bool {
+        if !self.is_in_use(stream) {
+            return false;
+        }
+        self.in_use[stream as usize / 64] &= !(1 << (stream as usize % 64));
+        self.len -= 1;
+        true
+    }
+
+    pub fn is_in_use(&self, stream: i16) -> bool {
+        stream >= 0 && self.in_use[stream as usize / 64] & (1 << (stream as usize % 64)) != 0
+    }
+
+    /// Number of ids in use.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+fn connection_closed(closed: &(io::ErrorKind, String)) -> error::Error {
+    let (kind, ref description) = *closed;
+    io::Error::new(kind, format!("Connection is closed: {}", description)).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::i16;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use futures::future;
+    use tokio_core::reactor::Core;
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::frame::events::{StatusChange, StatusChangeType};
+    use cdrs::query::QueryBuilder;
+
+    use super::*;
+    use client::Session;
+    use metrics::{RequestToken, SessionMetricsObserver};
+    use mock::{self, MockTransport, ERROR, READY, QUERY, RESULT, REGISTER, EVENT, SUPPORTED};
+    use retry;
+
+    fn multiplexer(transport: &MockTransport)
+                   -> (Multiplexer<NoneAuthenticator, MockTransport>,
+                       Dispatcher<NoneAuthenticator, MockTransport>) {
+        mock::session(transport.clone()).multiplex()
+    }
+
+    fn select(multiplexer: &Multiplexer<NoneAuthenticator, MockTransport>) -> CDRSFuture<Frame> {
+        multiplexer.query(QueryBuilder::new("SELECT * FROM t").finalize(),
+                          RequestOptions::new())
+    }
+
+    #[test]
+    fn dispatches_out_of_order_responses() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(ERROR, 2, &mock::error_body(0x2200, "invalid")));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transport.push_read(mock::response(RESULT,
+                                           1,
+                                           &mock::rows_body(&[("id", mock::INT)],
+                                                            &[vec![mock::int(1)]],
+                                                            None)));
+
+        let (multiplexer, dispatcher) = multiplexer(&transport);
+        let requests = vec![select(&multiplexer), select(&multiplexer), select(&multiplexer)];
+        assert_eq!(multiplexer.in_flight(), 3);
+
+        let mut core = Core::new().unwrap();
+        core.handle().spawn(dispatcher.map_err(|err| panic!("dispatcher failed: {}", err)));
+        let responses = core.run(future::join_all(requests)).unwrap();
+
+        // every request was written before any response was read
+        assert_eq!(mock::streams(&transport.written()), vec![0, 1, 2]);
+        let streams: Vec<_> = responses.iter().map(|frame| frame.stream as i16).collect();
+        assert_eq!(streams, vec![0, 1, 2]);
+        // a server error belongs to its own request only
+        assert_eq!(retry::error_code(&responses[0]), None);
+        assert_eq!(retry::error_code(&responses[1]), None);
+        assert_eq!(retry::error_code(&responses[2]), Some(0x2200));
+        assert_eq!(multiplexer.in_flight(), 0);
+    }
+
+    #[test]
+    fn sends_hand_built_frames_on_free_stream_ids() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 2, &mock::void_body()));
+        transport.push_read(mock::response(SUPPORTED, 1, &mock::supported_body(&[])));
+
+        let mut cdrs = CDRS::new(transport.clone(), NoneAuthenticator);
+        cdrs.max_body_len(16);
+        let (multiplexer, dispatcher) = Session::start(cdrs).multiplex();
+        let query = QueryBuilder::new("SELECT * FROM t").finalize();
+        match multiplexer.send_frame(client::query_frame(query, vec![])).wait() {
+            Err(error::Error::FrameTooLarge { max: 16, .. }) => (),
+            other => panic!("FrameTooLarge expected, got {:?}", other),
+        }
+        assert!(transport.written().is_empty());
+
+        let mut options = Frame::new_req_options();
+        options.stream = 7;
+        let requests = vec![multiplexer.send_frame(options),
+                            multiplexer.send_frame(Frame::new_req_register(vec![]))];
+
+        let mut core = Core::new().unwrap();
+        core.handle().spawn(dispatcher.map_err(|err| panic!("dispatcher failed: {}", err)));
+        let responses = core.run(future::join_all(requests)).unwrap();
+
+        // the id of the rejected frame is released, but it's not reused right away
+        assert_eq!(mock::streams(&transport.written()), vec![1, 2]);
+        assert_eq!(responses[0].opcode, Opcode::Supported);
+        assert_eq!(responses[1].opcode, Opcode::Result);
+    }
+
+    #[test]
+    fn broken_connection_fails_every_request() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 1, &mock::void_body()));
+        transport.push_read_error(io::ErrorKind::ConnectionReset);
+
+        let (multiplexer, dispatcher) = multiplexer(&transport);
+        let requests = vec![select(&multiplexer), select(&multiplexer), select(&multiplexer)];
+        match dispatcher.wait() {
+            Err(error::Error::Io(ref err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionReset)
+            }
+            other => panic!("IO error expected, got {:?}", other),
+        }
+
+        let results: Vec<_> = requests.into_iter().map(|request| request.wait()).collect();
+        assert!(results[1].is_ok());
+        for result in &[&results[0], &results[2]] {
+            match **result {
+                Err(error::Error::Io(ref err)) => {
+                    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset)
+                }
+                ref other => panic!("IO error expected, got {:?}", other.as_ref().map(|_| ())),
+            }
+        }
+        assert!(select(&multiplexer).wait().is_err());
+        assert!(transport.is_closed());
+    }
+
+    #[test]
+    fn finishes_once_multiplexers_are_dropped() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let (multiplexer, dispatcher) = multiplexer(&transport);
+        let request = select(&multiplexer);
+        drop(multiplexer);
+        // the dispatcher waits for the response to the request in flight
+        dispatcher.wait().unwrap();
+        assert!(request.wait().is_ok());
+        assert!(transport.is_closed());
+    }
+
+    fn status_change(change: &str, host: u8) -> Vec<u8> {
+        mock::response(EVENT,
+                       EVENT_STREAM_ID,
+                       &mock::node_event_body("STATUS_CHANGE", change, [10, 0, 0, host], 9042))
+    }
+
+    fn next_status(listener: EventListener<NoneAuthenticator, MockTransport>,
+                   core: &mut Core)
+                   -> (Option<(StatusChangeType, String)>,
+                       EventListener<NoneAuthenticator, MockTransport>) {
+        match core.run(listener.into_future()) {
+            Ok((Some(ServerEvent::StatusChange(StatusChange { change_type, addr })), listener)) => {
+                (Some((change_type, addr.addr.to_string())), listener)
+            }
+            Ok((None, listener)) => (None, listener),
+            Ok((Some(_), _)) => panic!("status change expected"),
+            Err((err, _)) => panic!("{:?}", err),
+        }
+    }
+
+    #[test]
+    fn serves_requests_while_listening_for_events() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(READY, 0, &[]));
+        transport.push_read(status_change("UP", 2));
+
+        let (multiplexer, dispatcher) = multiplexer(&transport);
+        let mut core = Core::new().unwrap();
+        core.handle().spawn(dispatcher.map_err(|err| panic!("dispatcher failed: {}", err)));
+        let listener = core.run(multiplexer.listen_for(vec![SimpleServerEvent::StatusChange]))
+            .unwrap();
+
+        // queries keep working after the connection registered for events
+        transport.push_read(mock::response(RESULT, 1, &mock::void_body()));
+        transport.push_read(status_change("DOWN", 3));
+        let response = core.run(select(&multiplexer)).unwrap();
+        assert_eq!(retry::error_code(&response), None);
+
+        let (event, listener) = next_status(listener, &mut core);
+        assert_eq!(event, Some((StatusChangeType::Up, "10.0.0.2:9042".to_string())));
+        let (event, mut listener) = next_status(listener, &mut core);
+        assert_eq!(event, Some((StatusChangeType::Down, "10.0.0.3:9042".to_string())));
+
+        // a stopped listener gets no more events
+        listener.stop();
+        transport.push_read(status_change("UP", 3));
+        transport.push_read(mock::response(RESULT, 2, &mock::void_body()));
+        core.run(select(&multiplexer)).unwrap();
+        assert_eq!(next_status(listener, &mut core).0, None);
+        assert_eq!(mock::opcodes(&transport.written()), vec![REGISTER, QUERY, QUERY]);
+    }
+
+    #[test]
+    fn recycles_stream_ids() {
+        let mut ids = StreamIds::new(0);
+        for stream in 0..3 {
+            assert_eq!(ids.allocate(), Some(stream));
+        }
+
+        // ids wrap around and skip the ones still in use
+        assert!(ids.release(1));
+        assert!(!ids.release(1));
+        let mut ids = StreamIds { next: i16::MAX, ..ids };
+        assert_eq!(ids.allocate(), Some(i16::MAX));
+        assert_eq!(ids.allocate(), Some(1));
+        assert_eq!(ids.allocate(), Some(3));
+        assert_eq!(ids.len(), 5);
+
+        // events have negative ids, they are never in use
+        assert!(!ids.is_in_use(EVENT_STREAM_ID));
+        assert!(!ids.release(EVENT_STREAM_ID));
+        assert_eq!(StreamIds::new(-5).allocate(), Some(0));
+    }
+
+    #[test]
+    fn exhausts_stream_ids() {
+        let mut ids = StreamIds::new(100);
+        for _ in 0..MAX_STREAMS {
+            assert!(ids.allocate().is_some());
+        }
+        assert_eq!(ids.len(), MAX_STREAMS);
+        assert_eq!(ids.allocate(), None);
+
+        assert!(ids.release(7));
+        assert_eq!(ids.allocate(), Some(7));
+        assert_eq!(ids.allocate(), None);
+    }
+
+    #[test]
+    fn never_allocates_an_id_twice() {
+        let mut ids = StreamIds::new(0);
+        let mut allocated = vec![];
+        let mut in_use = HashMap::new();
+        // xorshift, so the pattern is the same on every run
+        let mut seed: u32 = 0x9e3779b9;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+
+        // ids wrap around several times, while no more than a thousand are in use
+        for round in 0..200000 {
+            match random() % 4 {
+                0 | 1 if allocated.len() < 1000 => {
+                    let stream = ids.allocate().expect("an id is free");
+                    assert!(stream >= 0);
+                    assert!(in_use.insert(stream, round).is_none(), "{} is taken twice", stream);
+                    allocated.push(stream);
+                }
+                0..=2 if !allocated.is_empty() => {
+                    let stream = allocated.swap_remove(random() as usize % allocated.len());
+                    in_use.remove(&stream);
+                    assert!(ids.release(stream));
+                }
+                _ => {
+                    // mostly an id which is not in use, or a negative one
+                    let stream = random() as i16;
+                    let expected = in_use.remove(&stream).is_some();
+                    if expected {
+                        allocated.retain(|&allocated| allocated != stream);
+                    }
+                    assert_eq!(ids.release(stream), expected, "release of {}", stream);
+                }
+            }
+            assert_eq!(ids.len(), in_use.len());
+        }
+    }
+
+    #[test]
+    fn queues_or_fails_once_stream_ids_are_exhausted() {
+        let transport = MockTransport::new();
+        let (mut multiplexer, _dispatcher) = multiplexer(&transport);
+        multiplexer.max_in_flight(MAX_STREAMS + 10);
+
+        let requests: Vec<_> = (0..MAX_STREAMS).map(|_| select(&multiplexer)).collect();
+        assert_eq!(multiplexer.in_flight(), MAX_STREAMS);
+
+        let queued = select(&multiplexer);
+        assert_eq!(multiplexer.queued(), 1);
+
+        multiplexer.fail_when_exhausted(true);
+        match select(&multiplexer).wait() {
+            Err(error::Error::StreamIdExhausted { in_flight }) => {
+                assert_eq!(in_flight, MAX_STREAMS)
+            }
+            other => panic!("StreamIdExhausted expected, got {:?}", other.map(|_| ())),
+        }
+        drop((requests, queued));
+    }
+
+    /// Keeps the peak numbers of requests in flight and queued ones.
+    #[derive(Default)]
+    struct PeakObserver {
+        in_flight: AtomicUsize,
+        queued: AtomicUsize,
+        last: Mutex<(usize, usize)>,
+    }
+
+    impl SessionMetricsObserver for PeakObserver {
+        fn on_request_start(&self, _opcode: &Opcode) -> RequestToken {
+            RequestToken(0)
+        }
+
+        fn on_request_complete(&self,
+                               _token: RequestToken,
+                               _result: Result<&Frame, &error::Error>,
+                               _latency: Duration) {
+        }
+
+        fn on_bytes(&self, _written: usize, _read: usize) {}
+
+        fn on_in_flight(&self, in_flight: usize, queued: usize) {
+            if in_flight > self.in_flight.load(Ordering::SeqCst) {
+                self.in_flight.store(in_flight, Ordering::SeqCst);
+            }
+            if queued > self.queued.load(Ordering::SeqCst) {
+                self.queued.store(queued, Ordering::SeqCst);
+            }
+            *self.last.lock().unwrap() = (in_flight, queued);
+        }
+    }
+
+    #[test]
+    fn queues_requests_beyond_the_limit() {
+        let transport = MockTransport::new();
+        let observer = Arc::new(PeakObserver::default());
+        let (mut multiplexer, dispatcher) = multiplexer(&transport);
+        multiplexer.max_in_flight(2).metrics_observer(observer.clone());
+
+        let requests: Vec<_> = (0..5).map(|_| select(&multiplexer)).collect();
+        assert_eq!((multiplexer.in_flight(), multiplexer.queued()), (2, 3));
+
+        for stream in 0..5 {
+            transport.push_read(mock::response(RESULT, stream, &mock::void_body()));
+        }
+        let mut core = Core::new().unwrap();
+        core.handle().spawn(dispatcher.map_err(|err| panic!("dispatcher failed: {}", err)));
+        let responses = core.run(future::join_all(requests)).unwrap();
+
+        // queued requests were written in order
+        assert_eq!(mock::streams(&transport.written()), vec![0, 1, 2, 3, 4]);
+        let streams: Vec<_> = responses.iter().map(|frame| frame.stream as i16).collect();
+        assert_eq!(streams, vec![0, 1, 2, 3, 4]);
+        assert_eq!(observer.in_flight.load(Ordering::SeqCst), 2);
+        assert_eq!(observer.queued.load(Ordering::SeqCst), 3);
+        assert_eq!(*observer.last.lock().unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn skips_dropped_queued_requests() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transport.push_read(mock::response(RESULT, 1, &mock::void_body()));
+        let (mut multiplexer, dispatcher) = multiplexer(&transport);
+        multiplexer.max_in_flight(1);
+
+        let first = select(&multiplexer);
+        drop(select(&multiplexer));
+        let third = select(&multiplexer);
+        assert_eq!(multiplexer.queued(), 2);
+
+        let mut core = Core::new().unwrap();
+        core.handle().spawn(dispatcher.map_err(|err| panic!("dispatcher failed: {}", err)));
+        core.run(first.join(third)).unwrap();
+        assert_eq!(mock::opcodes(&transport.written()), vec![QUERY, QUERY]);
+    }
+
+    #[test]
+    fn fails_queued_requests_once_connection_breaks() {
+        let transport = MockTransport::new();
+        transport.push_read_error(io::ErrorKind::ConnectionReset);
+        let (mut multiplexer, dispatcher) = multiplexer(&transport);
+        multiplexer.max_in_flight(1);
+
+        let requests: Vec<_> = (0..3).map(|_| select(&multiplexer)).collect();
+        assert_eq!(multiplexer.queued(), 2);
+        assert!(dispatcher.wait().is_err());
+
+        for request in requests {
+            match request.wait() {
+                Err(error::Error::Io(ref err)) => {
+                    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset)
+                }
+                other => panic!("IO error expected, got {:?}", other.map(|_| ())),
+            }
+        }
+        assert_eq!(multiplexer.queued(), 0);
+        // only the first request was written
+        assert_eq!(mock::opcodes(&transport.written()), vec![QUERY]);
+    }
+
+    #[test]
+    fn serves_many_requests_under_a_low_limit() {
+        const REQUESTS: i16 = 10000;
+
+        let transport = MockTransport::new();
+        for stream in 0..REQUESTS {
+            transport.push_read(mock::response(RESULT, stream, &mock::void_body()));
+        }
+        let observer = Arc::new(PeakObserver::default());
+        let (mut multiplexer, dispatcher) = multiplexer(&transport);
+        multiplexer.max_in_flight(8).metrics_observer(observer.clone());
+
+        let requests: Vec<_> = (0..REQUESTS).map(|_| select(&multiplexer)).collect();
+        let mut core = Core::new().unwrap();
+        core.handle().spawn(dispatcher.map_err(|err| panic!("dispatcher failed: {}", err)));
+        let responses = core.run(future::join_all(requests)).unwrap();
+
+        for (stream, response) in responses.iter().enumerate() {
+            assert_eq!(response.stream as usize, stream);
+        }
+        assert_eq!(observer.in_flight.load(Ordering::SeqCst), 8);
+        assert_eq!(observer.queued.load(Ordering::SeqCst), REQUESTS as usize - 8);
+        assert_eq!(*observer.last.lock().unwrap(), (0, 0));
+    }
+}
@@ -0,0 +1,325 @@
+//! Paged queries: page decoding and page size control.
+
+use std::cmp;
+use std::fmt;
+use std::str::FromStr;
+
+use cdrs::frame::Frame;
+use cdrs::frame::frame_response::ResponseBody;
+use cdrs::frame::frame_result::ResResultBody;
+use cdrs::types::CBytes;
+use cdrs::types::rows::Row;
+
+use base64::{self, Alphabet};
+use error;
+
+/// Page size used when a session is not configured otherwise.
+pub const DEFAULT_PAGE_SIZE: i32 = 5000;
+
+/// Rows of a single page along with a state which allows to request the next one.
+#[derive(Debug)]
+pub struct Page {
+    pub rows: Vec<Row>,
+    /// `None` if this page is the last one.
+    pub paging_state: Option<PagingState>,
+    /// Whether there are pages after this one.
+    pub more_pages: bool,
+}
+
+impl Page {
+    /// Decodes a response to a query. Results which don't carry rows (e.g. `Void`)
+    /// make an empty last page, server errors are returned as errors.
+    pub fn from_frame(frame: Frame) -> error::Result<Page> {
+        let body = try!(frame.get_body());
+        Page::from_response(&frame, body)
+    }
+
+    /// Works as `from_frame` with a body which is already decoded from `frame`.
+    pub fn from_response(frame: &Frame, body: ResponseBody) -> error::Result<Page> {
+        match body {
+            ResponseBody::Error(_) => Err(error::Error::from_error_body(&frame.body)),
+            body => Page::from_body(body),
+        }
+    }
+
+    /// Works as `from_frame` with a body which is already decoded. Without the frame
+    /// a server error is `Error::Server` built of its code and message only, so
+    /// errors with details become `ServerError::Other`, prefer `from_response`.
+    pub fn from_body(body: ResponseBody) -> error::Result<Page> {
+        match body {
+            ResponseBody::Result(ResResultBody::Rows(rows_body)) => {
+                let paging_state = rows_body.metadata
+                    .paging_state
+                    .clone()
+                    .map(|paging_state| paging_state.into_plain())
+                    .map(PagingState::new);
+                Ok(Page {
+                       rows: Row::from_frame_body(rows_body),
+                       more_pages: paging_state.is_some(),
+                       paging_state: paging_state,
+                   })
+            }
+            ResponseBody::Result(_) => {
+                Ok(Page {
+                       rows: vec![],
+                       paging_state: None,
+                       more_pages: false,
+                   })
+            }
+            ResponseBody::Error(err) => {
+                let mut body = vec![];
+                push_int(&mut body, err.error_code);
+                let message = err.message.into_plain();
+                body.extend_from_slice(&[(message.len() >> 8) as u8, message.len() as u8]);
+                body.extend_from_slice(message.as_bytes());
+                Err(error::Error::from_error_body(&body))
+            }
+            _ => Err("Unexpected type of frame. Result frame is expected".into()),
+        }
+    }
+
+    pub fn is_last(&self) -> bool {
+        !self.more_pages
+    }
+}
+
+fn push_int(bytes: &mut Vec<u8>, i: i32) {
+    bytes.extend_from_slice(&[(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+}
+
+/// Position in results of a query a server returns along with a page which is not
+/// the last one. It's opaque to a client, which only sends it back to get the next
+/// page. It's a string of URL-safe base64, so it can be handed to an HTTP client
+/// and parsed back later to resume on any session.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PagingState(Vec<u8>);
+
+impl PagingState {
+    pub fn new(bytes: Vec<u8>) -> PagingState {
+        PagingState(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<PagingState> for CBytes {
+    fn from(paging_state: PagingState) -> CBytes {
+        CBytes::new(paging_state.0)
+    }
+}
+
+impl fmt::Display for PagingState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&base64::encode(&self.0, Alphabet::UrlSafe))
+    }
+}
+
+impl FromStr for PagingState {
+    type Err = error::Error;
+
+    /// Parses a state formatted by `to_string`. A server checks whether the state
+    /// belongs to a query, so a state of another query fails the query.
+    fn from_str(s: &str) -> error::Result<PagingState> {
+        match base64::decode(s, Alphabet::UrlSafe) {
+            Some(ref bytes) if bytes.is_empty() => {
+                Err(error::Error::General("Paging state is empty".to_string()))
+            }
+            Some(bytes) => Ok(PagingState(bytes)),
+            None => {
+                Err(error::Error::General(format!("Paging state {:?} is not URL-safe base64", s)))
+            }
+        }
+    }
+}
+
+/// Defines how many rows are requested per page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageSizing {
+    /// The same number of rows for every page.
+    Fixed(i32),
+    /// Number of rows is adjusted after every page to fit a bytes budget.
+    Adaptive(AdaptivePageSize),
+}
+
+impl Default for PageSizing {
+    fn default() -> PageSizing {
+        PageSizing::Fixed(DEFAULT_PAGE_SIZE)
+    }
+}
+
+impl PageSizing {
+    /// Number of rows to request in the next page.
+    pub fn page_size(&self) -> i32 {
+        match *self {
+            PageSizing::Fixed(page_size) => page_size,
+            PageSizing::Adaptive(ref adaptive) => adaptive.page_size(),
+        }
+    }
+
+    /// Records the size of a received page.
+    pub fn observe(&mut self, page_bytes: usize, rows: usize) {
+        if let PageSizing::Adaptive(ref mut adaptive) = *self {
+            adaptive.observe(page_bytes, rows);
+        }
+    }
+}
+
+/// A decision made after a page was received. Useful for tuning the budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageSizeDecision {
+    pub page_bytes: usize,
+    pub rows: usize,
+    /// Estimated width of a row in bytes.
+    pub row_width: usize,
+    pub next_page_size: i32,
+}
+
+/// Adjusts a page size so that pages get close to a bytes budget.
+///
+/// Row width is estimated as a moving average of observed pages, so a single
+/// page of unusually wide rows doesn't throw the page size off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdaptivePageSize {
+    target_bytes: usize,
+    min_rows: i32,
+    max_rows: i32,
+    current: i32,
+    row_width: Option<usize>,
+    last_decision: Option<PageSizeDecision>,
+}
+
+impl AdaptivePageSize {
+    /// Starts from `initial` rows per page and then targets `target_bytes` per page
+    /// keeping the number of rows within `[min_rows, max_rows]`.
+    pub fn new(initial: i32,
+               target_bytes: usize,
+               min_rows: i32,
+               max_rows: i32)
+               -> AdaptivePageSize {
+        let min_rows = cmp::max(min_rows, 1);
+        let max_rows = cmp::max(max_rows, min_rows);
+
+        AdaptivePageSize {
+            target_bytes: target_bytes,
+            min_rows: min_rows,
+            max_rows: max_rows,
+            current: clamp(initial, min_rows, max_rows),
+            row_width: None,
+            last_decision: None,
+        }
+    }
+
+    pub fn page_size(&self) -> i32 {
+        self.current
+    }
+
+    /// The latest adjustment, if any page was observed.
+    pub fn last_decision(&self) -> Option<PageSizeDecision> {
+        self.last_decision
+    }
+
+    pub fn observe(&mut self, page_bytes: usize, rows: usize) {
+        if rows == 0 {
+            return;
+        }
+
+        let width = cmp::max(page_bytes / rows, 1);
+        let row_width = match self.row_width {
+            Some(previous) => (previous + width) / 2,
+            None => width,
+        };
+        self.row_width = Some(row_width);
+
+        let fitting_rows = (self.target_bytes / row_width) as i64;
+        self.current = clamp(cmp::min(fitting_rows, i32::max_value() as i64) as i32,
+                             self.min_rows,
+                             self.max_rows);
+        self.last_decision = Some(PageSizeDecision {
+                                      page_bytes: page_bytes,
+                                      rows: rows,
+                                      row_width: row_width,
+                                      next_page_size: self.current,
+                                  });
+    }
+}
+
+fn clamp(value: i32, min: i32, max: i32) -> i32 {
+    cmp::max(min, cmp::min(value, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec;
+    use error::ServerError;
+    use mock::{self, ERROR};
+
+    #[test]
+    fn decoded_server_errors_are_typed() {
+        let bytes = mock::response(ERROR, 0, &mock::error_body(0x2200, "bad query"));
+        let frame = codec::parse_response(bytes).unwrap();
+        match Page::from_body(frame.get_body().unwrap()) {
+            Err(error::Error::Server { error: ServerError::Invalid, message }) => {
+                assert_eq!(message, "bad query")
+            }
+            other => panic!("server error expected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn paging_state_round_trips_through_string() {
+        let paging_state = PagingState::new(vec![0x00, 0xfb, 0xff, 0x10, 0x3e]);
+        let encoded = paging_state.to_string();
+        assert_eq!(encoded, "APv_ED4");
+        assert_eq!(encoded.parse::<PagingState>().unwrap(), paging_state);
+    }
+
+    #[test]
+    fn rejects_malformed_paging_states() {
+        assert!("APv/ED4".parse::<PagingState>().is_err());
+        assert!("A".parse::<PagingState>().is_err());
+        assert!("".parse::<PagingState>().is_err());
+    }
+
+    #[test]
+    fn fixed_page_size_never_changes() {
+        let mut sizing = PageSizing::Fixed(100);
+        sizing.observe(1024 * 1024, 100);
+        assert_eq!(sizing.page_size(), 100);
+    }
+
+    #[test]
+    fn converges_to_bytes_budget() {
+        let mut adaptive = AdaptivePageSize::new(5000, 1024 * 1024, 10, 10000);
+
+        // 10KB rows: 5000 of them would be a 50MB page
+        for _ in 0..5 {
+            let rows = adaptive.page_size() as usize;
+            adaptive.observe(rows * 10 * 1024, rows);
+        }
+        assert_eq!(adaptive.page_size(), 102);
+
+        // rows got narrow, page size grows back
+        for _ in 0..10 {
+            let rows = adaptive.page_size() as usize;
+            adaptive.observe(rows * 200, rows);
+        }
+        let page_bytes = adaptive.page_size() as usize * 200;
+        assert!(page_bytes > 1024 * 1024 * 9 / 10 && page_bytes <= 1024 * 1024);
+        assert_eq!(adaptive.last_decision().unwrap().next_page_size,
+                   adaptive.page_size());
+    }
+
+    #[test]
+    fn respects_bounds() {
+        let mut adaptive = AdaptivePageSize::new(100, 1024, 10, 1000);
+        adaptive.observe(100 * 1024 * 1024, 100);
+        assert_eq!(adaptive.page_size(), 10);
+
+        let mut adaptive = AdaptivePageSize::new(100, 1024 * 1024 * 1024, 10, 1000);
+        adaptive.observe(100 * 8, 100);
+        assert_eq!(adaptive.page_size(), 1000);
+    }
+}
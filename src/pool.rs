@@ -0,0 +1,2047 @@
+//! A pool of sessions to a single host.
+//!
+//! A session handles one request at a time, so a request which finds no idle
+//! session waits in a queue. A queued request may have a deadline: once it passes
+//! the request fails with `Error::DeadlineExceeded` instead of being sent, since
+//! nobody would read its response. Expired requests are failed when a session
+//! is released and by a periodic sweep, so they don't hold queue slots.
+//!
+//! A pool with a connector grows while its sessions stay saturated, i.e. the
+//! average number of requests in flight or waiting per session exceeds a high-water
+//! mark for a sustained interval, and closes sessions which stay idle too long.
+//! Growth and eviction decisions are reported as `PoolEvent`s.
+//!
+//! Sessions a pool opens run setup actions, e.g. `USE keyspace`, before they
+//! serve requests. A session which fails setup is closed. They also prepare
+//! statements of a `PreparedRegistry`, so their first executions don't need to.
+//!
+//! A session whose request failed with an IO error, or whose connection broke
+//! the protocol, is discarded. So is an idle session which doesn't answer
+//! a heartbeat; heartbeats also keep idle connections alive through firewalls.
+//! A checkout which finds no idle session connects a replacement while the pool
+//! is below its minimal size.
+//!
+//! On shutdown a pool is drained: it stops taking requests, lets ones it took
+//! complete until a deadline and closes its sessions.
+//!
+//! A shard-aware pool to a Scylla node routes a request with a known token to
+//! a session of the shard which owns it, other requests and ones whose shard
+//! has no idle session take any session. Sessions it opens detect their shard,
+//! see `Session::detect_shard`, and `fill_shards` opens sessions to shards
+//! which have none.
+//!
+//! Waiting checkouts are served in the order they were made, so a task which
+//! checks out often can't starve others. `Pool::get` wraps a session into
+//! a `PooledSession`, which gives it back once dropped. A session dropped along
+//! with a request in flight is closed, see `Session`, and the pool stops counting
+//! it, so a replacement can be connected.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cdrs::authenticators::Authenticator;
+use cdrs::frame::Frame;
+use cdrs::frame::events::{ServerEvent, SimpleServerEvent};
+use cdrs::query::{Query, QueryBatch, QueryParams};
+use cdrs::transport::CDRSTransport;
+use cdrs::types::CBytesShort;
+use cdrs::types::rows::Row;
+use cdrs::types::value::Value;
+use futures::{Async, Poll};
+use futures::future::{self, Future, Loop};
+use futures::stream::Stream;
+use futures::sync::oneshot;
+use tokio_core::reactor::{Handle, Interval, Timeout};
+use tokio_timer::Delay;
+
+use client::{self, CDRSFuture, CDRSStream, Session};
+use decode::DecodeExecutor;
+use metrics::{HostMetricsRegistry, SharedObserver};
+use prepared::{self, PreparedCaches, PreparedRegistry, TypedPrepared};
+use request::Statement;
+use script;
+use scylla::ShardInfo;
+use setup::{self, SetupAction};
+use error;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolOptions {
+    /// Max number of requests waiting for a session. Requests beyond it fail
+    /// with `Error::Backpressure`.
+    pub max_waiters: usize,
+    /// Average number of requests in flight or waiting per session above which
+    /// the pool is saturated. `None` turns growth off.
+    pub high_water: Option<f64>,
+    /// How long the pool has to stay saturated before a session is added.
+    pub sustain: Duration,
+    /// The pool doesn't grow beyond it.
+    pub max_size: usize,
+    /// Sessions idle for longer are closed. `None` keeps them forever.
+    pub idle_timeout: Option<Duration>,
+    /// Idle sessions are not closed below it.
+    pub min_size: usize,
+    /// Time a checkout waits for a session before it fails with `Error::PoolTimeout`.
+    /// `None` waits until a deadline of a request, if any.
+    pub checkout_timeout: Option<Duration>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> PoolOptions {
+        PoolOptions {
+            max_waiters: 1024,
+            high_water: None,
+            sustain: Duration::from_secs(1),
+            max_size: 8,
+            idle_timeout: None,
+            min_size: 1,
+            checkout_timeout: None,
+        }
+    }
+}
+
+/// Max number of events a pool keeps until they are taken.
+pub const MAX_EVENTS: usize = 64;
+
+/// A change of a pool size and its reason.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PoolEvent {
+    /// A session was added because sessions were saturated with a given load.
+    Grew { size: usize, load: f64 },
+    /// A session was needed but connecting failed.
+    GrowthFailed { load: f64, reason: String },
+    /// A session was closed after being idle for a given time.
+    Shrank { size: usize, idle: Duration },
+}
+
+/// Outcome of `Pool::drain`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrainReport {
+    /// Requests in flight or queued when draining began which completed in time.
+    pub completed: usize,
+    /// Requests which didn't complete before the deadline. Queued ones are failed
+    /// with `Error::ShuttingDown`, sessions of ones in flight are closed once
+    /// they are released.
+    pub aborted: usize,
+}
+
+/// Progress of a drain until its deadline.
+struct Drain {
+    outstanding: usize,
+    completed: usize,
+    done: Option<oneshot::Sender<()>>,
+}
+
+/// Opens a new session to a pool host.
+pub type Connector<T, X> = Arc<Fn() -> CDRSFuture<Session<T, X>> + Send + Sync>;
+
+/// Opens a new session to a given shard of a pool host, e.g. with
+/// `TransportTcp::new_to_shard`.
+pub type ShardConnector<T, X> = Arc<Fn(ShardInfo, u32) -> CDRSFuture<Session<T, X>> + Send + Sync>;
+
+type Checkout<T, X> = error::Result<Session<T, X>>;
+
+struct Waiter<T: Authenticator + 'static, X: CDRSTransport + 'static> {
+    sender: oneshot::Sender<Checkout<T, X>>,
+    deadline: Option<Instant>,
+    queued_at: Instant,
+}
+
+impl<T: Authenticator, X: CDRSTransport> Waiter<T, X> {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.map_or(false, |deadline| deadline <= now)
+    }
+}
+
+struct Inner<T: Authenticator + 'static, X: CDRSTransport + 'static> {
+    /// Idle sessions along with the time they became idle.
+    idle: Vec<(Session<T, X>, Instant)>,
+    waiters: VecDeque<Waiter<T, X>>,
+    expired: u64,
+    /// Number of sessions, idle and busy ones.
+    size: usize,
+    saturated_since: Option<Instant>,
+    connecting: bool,
+    events: VecDeque<PoolEvent>,
+    draining: bool,
+    /// Set while a drain waits for requests, it's `None` after its deadline.
+    drain: Option<Drain>,
+    /// Sharding parameters of the host, learnt from its sessions.
+    shard_info: Option<ShardInfo>,
+    /// Number of sessions per shard.
+    shard_sizes: Vec<usize>,
+}
+
+impl<T: Authenticator, X: CDRSTransport> Inner<T, X> {
+    /// Average number of requests in flight or waiting per session.
+    fn load(&self) -> f64 {
+        let busy = self.size - self.idle.len();
+        (busy + self.waiters.len()) as f64 / self.size.max(1) as f64
+    }
+
+    /// Counts a session which joins the pool in its shard.
+    fn add_to_shard(&mut self, info: Option<ShardInfo>) {
+        let info = match info {
+            Some(info) => info,
+            None => return,
+        };
+        if self.shard_info.map_or(true, |known| known.nr_shards != info.nr_shards) {
+            self.shard_info = Some(info);
+            self.shard_sizes = vec![0; info.nr_shards as usize];
+        }
+        self.shard_sizes[info.shard as usize] += 1;
+    }
+
+    /// Stops counting a session which leaves the pool in its shard.
+    fn remove_from_shard(&mut self, info: Option<ShardInfo>) {
+        let shard = info.and_then(|info| self.shard_sizes.get_mut(info.shard as usize));
+        if let Some(size) = shard {
+            *size = size.saturating_sub(1);
+        }
+    }
+
+    fn push_event(&mut self, event: PoolEvent) {
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Completes a drain once every session is idle and nobody waits for one.
+    fn notify_drained(&mut self) {
+        let waiting = self.waiters.iter().any(|waiter| !waiter.sender.is_canceled());
+        if self.idle.len() < self.size || waiting {
+            return;
+        }
+        if let Some(done) = self.drain.as_mut().and_then(|drain| drain.done.take()) {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Sessions to a single host. Clones share the same sessions and queue.
+pub struct Pool<T: Authenticator + 'static, X: CDRSTransport + 'static> {
+    host: SocketAddr,
+    options: PoolOptions,
+    metrics: Option<Arc<Mutex<HostMetricsRegistry>>>,
+    observer: Option<SharedObserver>,
+    decode_executor: Option<DecodeExecutor>,
+    connector: Option<Connector<T, X>>,
+    shard_aware: bool,
+    shard_connector: Option<ShardConnector<T, X>>,
+    setup: Arc<Vec<SetupAction<T, X>>>,
+    registry: Option<Arc<Mutex<PreparedRegistry>>>,
+    /// Prepared caches of sessions of the pool, see `follow_schema_changes`.
+    caches: PreparedCaches,
+    inner: Arc<Mutex<Inner<T, X>>>,
+}
+
+impl<T: Authenticator, X: CDRSTransport> Clone for Pool<T, X> {
+    fn clone(&self) -> Pool<T, X> {
+        Pool {
+            host: self.host,
+            options: self.options,
+            metrics: self.metrics.clone(),
+            observer: self.observer.clone(),
+            decode_executor: self.decode_executor.clone(),
+            connector: self.connector.clone(),
+            shard_aware: self.shard_aware,
+            shard_connector: self.shard_connector.clone(),
+            setup: self.setup.clone(),
+            registry: self.registry.clone(),
+            caches: self.caches.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, X> Pool<T, X>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    pub fn new(host: SocketAddr, sessions: Vec<Session<T, X>>) -> Pool<T, X> {
+        let now = Instant::now();
+        let caches = PreparedCaches::new();
+        for session in &sessions {
+            caches.add(&session.prepared_cache());
+        }
+        let mut inner = Inner {
+            size: sessions.len(),
+            idle: vec![],
+            waiters: VecDeque::new(),
+            expired: 0,
+            saturated_since: None,
+            connecting: false,
+            events: VecDeque::new(),
+            draining: false,
+            drain: None,
+            shard_info: None,
+            shard_sizes: vec![],
+        };
+        for session in sessions {
+            inner.add_to_shard(session.shard_info());
+            inner.idle.push((session, now));
+        }
+
+        Pool {
+            host: host,
+            options: PoolOptions::default(),
+            metrics: None,
+            observer: None,
+            decode_executor: None,
+            connector: None,
+            shard_aware: false,
+            shard_connector: None,
+            setup: Arc::new(vec![]),
+            registry: None,
+            caches: caches,
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// The method overrides options of the pool.
+    pub fn options(&mut self, options: PoolOptions) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// Records requests and the number of sessions of the pool in `metrics`.
+    pub fn metrics(&mut self, metrics: Arc<Mutex<HostMetricsRegistry>>) -> &mut Self {
+        self.metrics = Some(metrics);
+        let size = self.size();
+        self.report_size(size);
+        self
+    }
+
+    /// Sets `observer` on every session of the pool, see `Session::metrics_observer`.
+    /// Busy sessions get it once they are released.
+    pub fn metrics_observer(&mut self, observer: SharedObserver) -> &mut Self {
+        for &mut (ref mut session, _) in &mut self.inner.lock().unwrap().idle {
+            session.metrics_observer(observer.clone());
+        }
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Sets `executor` on every session of the pool, see `Session::set_decode_executor`.
+    /// Busy sessions get it once they are released.
+    pub fn decode_executor(&mut self, executor: DecodeExecutor) -> &mut Self {
+        for &mut (ref mut session, _) in &mut self.inner.lock().unwrap().idle {
+            session.set_decode_executor(executor.clone());
+        }
+        self.decode_executor = Some(executor);
+        self
+    }
+
+    /// Lets the pool open sessions with `connect` when it grows.
+    pub fn connector<F>(&mut self, connect: F) -> &mut Self
+        where F: Fn() -> CDRSFuture<Session<T, X>> + Send + Sync + 'static
+    {
+        self.connector = Some(Arc::new(connect));
+        self
+    }
+
+    /// Makes the pool route requests with a known token to sessions of the shard
+    /// which owns it, see `checkout_routed`. Sessions the pool opens detect
+    /// their shard. It's off by default.
+    pub fn shard_aware(&mut self, shard_aware: bool) -> &mut Self {
+        self.shard_aware = shard_aware;
+        self
+    }
+
+    /// Lets the pool open sessions to given shards with `connect`, see `fill_shards`.
+    pub fn shard_connector<F>(&mut self, connect: F) -> &mut Self
+        where F: Fn(ShardInfo, u32) -> CDRSFuture<Session<T, X>> + Send + Sync + 'static
+    {
+        self.shard_connector = Some(Arc::new(connect));
+        self
+    }
+
+    /// Actions run on every session the pool opens, in order. Sessions given
+    /// to `Pool::new` are expected to be set up already.
+    pub fn on_connection_setup(&mut self, actions: Vec<SetupAction<T, X>>) -> &mut Self {
+        self.setup = Arc::new(actions);
+        self
+    }
+
+    /// Statements shared with other pools which every new session prepares.
+    pub fn prepared_registry(&mut self, registry: Arc<Mutex<PreparedRegistry>>) -> &mut Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub fn host(&self) -> SocketAddr {
+        self.host
+    }
+
+    /// Number of sessions, idle and busy ones.
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+
+    /// Number of idle sessions.
+    pub fn idle(&self) -> usize {
+        self.inner.lock().unwrap().idle.len()
+    }
+
+    /// Number of requests waiting for a session.
+    pub fn waiting(&self) -> usize {
+        self.inner.lock().unwrap().waiters.len()
+    }
+
+    /// Number of requests which expired in the queue.
+    pub fn expired(&self) -> u64 {
+        self.inner.lock().unwrap().expired
+    }
+
+    /// Sharding parameters of a Scylla host learnt from sessions of the pool.
+    /// `ShardInfo::shard` is the one of a session they were learnt from.
+    pub fn shard_info(&self) -> Option<ShardInfo> {
+        self.inner.lock().unwrap().shard_info
+    }
+
+    /// Number of sessions per shard of the host, idle and busy ones.
+    /// It's empty unless shards are known.
+    pub fn shard_sizes(&self) -> Vec<usize> {
+        self.inner.lock().unwrap().shard_sizes.clone()
+    }
+
+    /// Takes events which happened since the last call, up to `MAX_EVENTS` latest ones.
+    pub fn take_events(&self) -> Vec<PoolEvent> {
+        self.inner.lock().unwrap().events.drain(..).collect()
+    }
+
+    /// Takes an idle session, or waits for one until `deadline`. A session has to
+    /// be given back with `release`.
+    pub fn checkout(&self, deadline: Option<Instant>) -> CDRSFuture<Session<T, X>> {
+        self.checkout_routed(None, deadline)
+    }
+
+    /// Works as `checkout`, but a shard-aware pool takes an idle session of
+    /// the shard which owns `token` if there is one.
+    pub fn checkout_routed(&self,
+                           token: Option<i64>,
+                           deadline: Option<Instant>)
+                           -> CDRSFuture<Session<T, X>> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.draining {
+            return future::err(error::Error::ShuttingDown).boxed();
+        }
+        if inner.waiters.is_empty() {
+            let owner = match (self.shard_aware, token, inner.shard_info) {
+                (true, Some(token), Some(info)) => Some(info.shard_of(token)),
+                _ => None,
+            };
+            let owned = owner.and_then(|owner| {
+                inner.idle
+                    .iter()
+                    .rposition(|&(ref session, _)| {
+                                   session.shard_info().map(|info| info.shard) == Some(owner)
+                               })
+            });
+            if let Some(i) = owned {
+                let (session, _) = inner.idle.remove(i);
+                return future::ok(session).boxed();
+            }
+            if let Some((session, _)) = inner.idle.pop() {
+                return future::ok(session).boxed();
+            }
+            if self.connector.is_some() && inner.size < self.options.min_size {
+                inner.size += 1;
+                self.report_size(inner.size);
+                drop(inner);
+                let pool = self.clone();
+                return self.connect()
+                           .map_err(move |err| {
+                                        let mut inner = pool.inner.lock().unwrap();
+                                        inner.size -= 1;
+                                        pool.report_size(inner.size);
+                                        err
+                                    })
+                           .boxed();
+            }
+        }
+        if deadline.map_or(false, |deadline| deadline <= now) {
+            drop(inner);
+            self.expire();
+            return future::err(error::Error::DeadlineExceeded { waited: Duration::from_secs(0) })
+                       .boxed();
+        }
+        if inner.waiters.len() >= self.options.max_waiters {
+            return future::err(error::Error::Backpressure).boxed();
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        inner.waiters.push_back(Waiter {
+                                    sender: sender,
+                                    deadline: deadline,
+                                    queued_at: now,
+                                });
+        match self.options.checkout_timeout {
+            Some(timeout) => {
+                let waiting = WaitForSession {
+                    receiver: receiver,
+                    timeout: Delay::new(now + timeout),
+                    queued_at: now,
+                };
+                waiting.boxed()
+            }
+            None => {
+                receiver.then(|result| match result {
+                                  Ok(checkout) => checkout,
+                                  Err(_) => Err(pool_dropped()),
+                              })
+                    .boxed()
+            }
+        }
+    }
+
+    /// Works as `checkout` without a deadline, but the session is given back
+    /// once the returned guard is dropped, or closed if it broke meanwhile.
+    pub fn get(&self) -> CDRSFuture<PooledSession<T, X>> {
+        let pool = self.clone();
+        self.checkout(None).map(move |session| PooledSession::new(session, pool)).boxed()
+    }
+
+    /// Gives a session back. It's handed to the first waiting request whose deadline
+    /// hasn't passed, expired ones are failed on the way. A session released after
+    /// a drain deadline is closed.
+    pub fn release(&self, mut session: Session<T, X>) {
+        if let Some(ref observer) = self.observer {
+            session.metrics_observer(observer.clone());
+        }
+        if let Some(ref executor) = self.decode_executor {
+            session.set_decode_executor(executor.clone());
+        }
+        let now = Instant::now();
+        let mut expired = 0;
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.draining {
+            match inner.drain {
+                Some(ref mut drain) => drain.completed += 1,
+                None => {
+                    session.end();
+                    inner.size -= 1;
+                    self.report_size(inner.size);
+                    inner.remove_from_shard(session.shard_info());
+                    return;
+                }
+            }
+        }
+
+        loop {
+            let waiter = match inner.waiters.pop_front() {
+                Some(waiter) => waiter,
+                None => {
+                    inner.idle.push((session, now));
+                    break;
+                }
+            };
+
+            if waiter.is_expired(now) {
+                let waited = now.duration_since(waiter.queued_at);
+                let _ = waiter.sender.send(Err(error::Error::DeadlineExceeded { waited: waited }));
+                expired += 1;
+                continue;
+            }
+
+            match waiter.sender.send(Ok(session)) {
+                Ok(()) => break,
+                // a caller stopped waiting
+                Err(Ok(returned)) => session = returned,
+                Err(Err(_)) => unreachable!("only sessions are sent on release"),
+            }
+        }
+
+        inner.notify_drained();
+        drop(inner);
+        for _ in 0..expired {
+            self.expire();
+        }
+    }
+
+    /// Closes a session which cannot serve requests anymore instead of giving it back.
+    pub fn discard(&self, mut session: Session<T, X>) {
+        session.end();
+        self.forget(session.shard_info());
+    }
+
+    /// Stops counting a session which is gone, e.g. one dropped along with
+    /// a request in flight.
+    fn forget(&self, shard_info: Option<ShardInfo>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.size -= 1;
+        self.report_size(inner.size);
+        inner.remove_from_shard(shard_info);
+        if inner.draining {
+            if let Some(ref mut drain) = inner.drain {
+                drain.completed += 1;
+            }
+        }
+        inner.notify_drained();
+    }
+
+    /// Fails queued requests whose deadline passed and drops ones nobody waits for.
+    /// Returns a number of failed requests.
+    pub fn sweep(&self) -> usize {
+        let now = Instant::now();
+        let mut expired = 0;
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let waiters = ::std::mem::replace(&mut inner.waiters, VecDeque::new());
+            for waiter in waiters {
+                if waiter.sender.is_canceled() {
+                    continue;
+                }
+                if waiter.is_expired(now) {
+                    let waited = now.duration_since(waiter.queued_at);
+                    let _ = waiter.sender
+                        .send(Err(error::Error::DeadlineExceeded { waited: waited }));
+                    expired += 1;
+                } else {
+                    inner.waiters.push_back(waiter);
+                }
+            }
+        }
+
+        for _ in 0..expired {
+            self.expire();
+        }
+        expired
+    }
+
+    /// Sweeps the queue, closes sessions idle for too long and adds a session
+    /// if the pool has been saturated long enough. The future completes once
+    /// a new session is connected and never fails.
+    pub fn maintain(&self) -> CDRSFuture<()> {
+        self.sweep();
+
+        let now = Instant::now();
+        let load = {
+            let mut inner = self.inner.lock().unwrap();
+            self.evict_idle(&mut inner, now);
+            match self.needs_growth(&mut inner, now) {
+                Some(load) => {
+                    inner.connecting = true;
+                    load
+                }
+                None => return future::ok(()).boxed(),
+            }
+        };
+
+        let pool = self.clone();
+        self.connect()
+            .then(move |result| {
+                let session = {
+                    let mut inner = pool.inner.lock().unwrap();
+                    inner.connecting = false;
+                    inner.saturated_since = None;
+                    match result {
+                        Ok(session) => {
+                            inner.size += 1;
+                            let size = inner.size;
+                            pool.report_size(size);
+                            inner.push_event(PoolEvent::Grew {
+                                                 size: size,
+                                                 load: load,
+                                             });
+                            session
+                        }
+                        Err(err) => {
+                            inner.push_event(PoolEvent::GrowthFailed {
+                                                 load: load,
+                                                 reason: err.to_string(),
+                                             });
+                            return Ok(());
+                        }
+                    }
+                };
+                pool.release(session);
+                Ok(())
+            })
+            .boxed()
+    }
+
+    /// Opens sessions until the pool has `PoolOptions::min_size` of them.
+    /// It fails if a session fails to connect or to set up.
+    pub fn fill(&self) -> CDRSFuture<()> {
+        let pool = self.clone();
+        future::loop_fn((), move |_| {
+            if pool.size() >= pool.options.min_size {
+                return future::ok(Loop::Break(())).boxed();
+            }
+
+            let filled = pool.clone();
+            pool.connect()
+                .map(move |session| {
+                         filled.grow();
+                         filled.release(session);
+                         Loop::Continue(())
+                     })
+                .boxed()
+        })
+                .boxed()
+    }
+
+    /// Opens a session with the shard connector to every shard of the host which
+    /// has none. Shards are learnt from sessions, so the pool needs one first.
+    /// A session which lands on another shard is kept as well. It fails if
+    /// a session fails to connect or to set up.
+    pub fn fill_shards(&self) -> CDRSFuture<()> {
+        let connect = match self.shard_connector {
+            Some(ref connect) => connect.clone(),
+            None => return future::err("Pool has no shard connector".into()).boxed(),
+        };
+        let (info, missing) = {
+            let inner = self.inner.lock().unwrap();
+            let info = match inner.shard_info {
+                Some(info) => info,
+                None => return future::err("Shards of the pool host are unknown".into()).boxed(),
+            };
+            let missing: Vec<u32> = inner.shard_sizes
+                .iter()
+                .enumerate()
+                .filter(|&(_, &size)| size == 0)
+                .map(|(shard, _)| shard as u32)
+                .collect();
+            (info, missing)
+        };
+
+        let pool = self.clone();
+        future::loop_fn(missing.into_iter(), move |mut missing| {
+            let shard = match missing.next() {
+                Some(shard) => shard,
+                None => return future::ok(Loop::Break(())).boxed(),
+            };
+
+            let filled = pool.clone();
+            pool.connect_with(connect(info, shard))
+                .map(move |session| {
+                         filled.grow();
+                         filled.release(session);
+                         Loop::Continue(missing)
+                     })
+                .boxed()
+        })
+                .boxed()
+    }
+
+    /// Counts a session which was connected to fill the pool.
+    fn grow(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.size += 1;
+        self.report_size(inner.size);
+    }
+
+    /// Reports the number of sessions of the pool to its metrics.
+    fn report_size(&self, size: usize) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.lock().unwrap().set_connections(self.host, size);
+        }
+    }
+
+    /// Opens a session with the connector and runs setup actions on it.
+    fn connect(&self) -> CDRSFuture<Session<T, X>> {
+        match self.connector {
+            Some(ref connect) => self.connect_with(connect()),
+            None => future::err("Pool has no connector".into()).boxed(),
+        }
+    }
+
+    /// Sets up a session which is being connected and counts it in its shard.
+    /// A shard-aware pool detects the shard first. Failures are counted in metrics.
+    fn connect_with(&self, connecting: CDRSFuture<Session<T, X>>) -> CDRSFuture<Session<T, X>> {
+        let shard_aware = self.shard_aware;
+        let inner = self.inner.clone();
+        let actions = self.setup.clone();
+        let registry = self.registry.clone();
+        let metrics = self.metrics.clone();
+        let observer = self.observer.clone();
+        let executor = self.decode_executor.clone();
+        let caches = self.caches.clone();
+        let host = self.host;
+
+        let detected = connecting.and_then(move |session| {
+            if shard_aware && session.shard_info().is_none() {
+                session.detect_shard()
+            } else {
+                future::ok(session).boxed()
+            }
+        });
+        detected.map(move |mut session| {
+                     if let Some(observer) = observer {
+                         session.metrics_observer(observer);
+                     }
+                     if let Some(executor) = executor {
+                         session.set_decode_executor(executor);
+                     }
+                     caches.add(&session.prepared_cache());
+                     session
+                 })
+            .and_then(move |session| setup::run(session, actions))
+            .and_then(move |(session, result)| match (result, registry) {
+                          (Ok(()), Some(registry)) => prepare_registered(session, registry, host),
+                          (result, _) => future::ok((session, result)).boxed(),
+                      })
+            .and_then(|(mut session, result)| match result {
+                          Ok(()) => Ok(session),
+                          Err(err) => {
+                              session.end();
+                              Err(err)
+                          }
+                      })
+            .then(move |result| {
+                      match (&result, metrics) {
+                          (&Ok(ref session), _) => {
+                              inner.lock().unwrap().add_to_shard(session.shard_info())
+                          }
+                          (&Err(_), Some(metrics)) => metrics.lock().unwrap().connect_failed(host),
+                          (&Err(_), None) => (),
+                      }
+                      result
+                  })
+            .boxed()
+    }
+
+    /// Maintains the pool every `interval` on a reactor of `handle`.
+    /// The future never completes unless the timer fails.
+    pub fn maintain_every(&self,
+                          interval: Duration,
+                          handle: &Handle)
+                          -> Box<Future<Item = (), Error = error::Error>> {
+        let pool = self.clone();
+        match Interval::new(interval, handle) {
+            Ok(timer) => {
+                Box::new(timer.map_err(error::Error::from).for_each(move |_| pool.maintain()))
+            }
+            Err(err) => Box::new(future::err(err.into())),
+        }
+    }
+
+    /// Sends a heartbeat on sessions which have been idle since before `idle_since`,
+    /// see `Session::heartbeat`. A session which answers is idle from now on, one
+    /// which doesn't is discarded, so a next request connects a new session rather
+    /// than hangs on a dead connection. Resolves into a number of discarded sessions.
+    pub fn heartbeat(&self, idle_since: Instant, timeout: Duration) -> CDRSFuture<usize> {
+        let stale = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.draining {
+                return future::ok(0).boxed();
+            }
+            let idle = ::std::mem::replace(&mut inner.idle, vec![]);
+            let (stale, fresh): (Vec<_>, Vec<_>) =
+                idle.into_iter().partition(|&(_, since)| since <= idle_since);
+            inner.idle = fresh;
+            stale
+        };
+
+        let checks: Vec<_> = stale.into_iter()
+            .map(|(session, _)| {
+                let pool = self.clone();
+                session.heartbeat(timeout).map(move |(session, result)| match result {
+                                                   Ok(()) => {
+                                                       pool.release(session);
+                                                       0
+                                                   }
+                                                   Err(_) => {
+                                                       pool.discard(session);
+                                                       1
+                                                   }
+                                               })
+            })
+            .collect();
+        future::join_all(checks).map(|discarded| discarded.iter().sum()).boxed()
+    }
+
+    /// Sends heartbeats every `interval` on a reactor of `handle` on sessions which
+    /// stayed idle for the whole interval. Requests reset the idle time, so busy
+    /// sessions get no heartbeats. The future never completes unless the timer fails.
+    pub fn heartbeat_every(&self,
+                           interval: Duration,
+                           handle: &Handle)
+                           -> Box<Future<Item = (), Error = error::Error>> {
+        let pool = self.clone();
+        let mut previous_tick = Instant::now();
+        match Interval::new(interval, handle) {
+            Ok(timer) => {
+                Box::new(timer.map_err(error::Error::from).for_each(move |_| {
+                    let idle_since = previous_tick;
+                    previous_tick = Instant::now();
+                    pool.heartbeat(idle_since, interval).map(|_| ())
+                }))
+            }
+            Err(err) => Box::new(future::err(err.into())),
+        }
+    }
+
+    /// Stops taking requests, waits up to `deadline` for requests the pool took
+    /// to complete and closes idle sessions. New checkouts fail with
+    /// `Error::ShuttingDown` right away.
+    pub fn drain(&self,
+                 deadline: Duration,
+                 handle: &Handle)
+                 -> Box<Future<Item = DrainReport, Error = error::Error>> {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let busy = inner.size - inner.idle.len();
+            let waiting = inner.waiters
+                .iter()
+                .filter(|waiter| !waiter.sender.is_canceled())
+                .count();
+            inner.draining = true;
+            inner.drain = Some(Drain {
+                                   outstanding: busy + waiting,
+                                   completed: 0,
+                                   done: Some(sender),
+                               });
+            inner.notify_drained();
+        }
+
+        let timeout = match Timeout::new(deadline, handle) {
+            Ok(timeout) => timeout,
+            Err(err) => return Box::new(future::err(err.into())),
+        };
+        let pool = self.clone();
+        Box::new(receiver.select2(timeout).then(move |_| Ok(pool.close_drained())))
+    }
+
+    /// Fails requests which still wait for a session and closes idle sessions.
+    fn close_drained(&self) -> DrainReport {
+        let mut inner = self.inner.lock().unwrap();
+        for waiter in inner.waiters.drain(..) {
+            let _ = waiter.sender.send(Err(error::Error::ShuttingDown));
+        }
+        let idle = ::std::mem::replace(&mut inner.idle, vec![]);
+        inner.size -= idle.len();
+        self.report_size(inner.size);
+        for (mut session, _) in idle {
+            session.end();
+            inner.remove_from_shard(session.shard_info());
+        }
+
+        match inner.drain.take() {
+            Some(drain) => {
+                let completed = drain.completed.min(drain.outstanding);
+                DrainReport {
+                    completed: completed,
+                    aborted: drain.outstanding - completed,
+                }
+            }
+            None => DrainReport::default(),
+        }
+    }
+
+    /// Returns the current load if a session should be added.
+    fn needs_growth(&self, inner: &mut Inner<T, X>, now: Instant) -> Option<f64> {
+        if inner.draining {
+            return None;
+        }
+        let high_water = match self.options.high_water {
+            Some(high_water) if self.connector.is_some() => high_water,
+            _ => return None,
+        };
+
+        let load = inner.load();
+        if load <= high_water {
+            inner.saturated_since = None;
+            return None;
+        }
+
+        let since = *inner.saturated_since.get_or_insert(now);
+        if now.duration_since(since) >= self.options.sustain && !inner.connecting &&
+           inner.size < self.options.max_size {
+            Some(load)
+        } else {
+            None
+        }
+    }
+
+    fn evict_idle(&self, inner: &mut Inner<T, X>, now: Instant) {
+        let idle_timeout = match self.options.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+
+        // the longest idle sessions are at the beginning
+        while inner.size > self.options.min_size {
+            let idle = match inner.idle.first() {
+                Some(&(_, since)) => now.duration_since(since),
+                None => break,
+            };
+            if idle < idle_timeout {
+                break;
+            }
+
+            let (mut session, _) = inner.idle.remove(0);
+            session.end();
+            inner.size -= 1;
+            inner.remove_from_shard(session.shard_info());
+            let size = inner.size;
+            self.report_size(size);
+            inner.push_event(PoolEvent::Shrank {
+                                 size: size,
+                                 idle: idle,
+                             });
+        }
+    }
+
+    /// Prepares `query` on a pooled session and resolves into an id the host
+    /// assigned. The statement is registered, so sessions opened later prepare it too.
+    pub fn prepare(&self, query: String) -> CDRSFuture<CBytesShort> {
+        let pool = self.clone();
+        self.get()
+            .and_then(move |session| {
+                let frame = Frame::new_req_prepare(query.clone(), vec![]);
+                session.try_request(frame).then(move |result| {
+                    let (session, result) = result.expect("try_request never fails");
+                    let id = result.and_then(|frame| {
+                        TypedPrepared::<Vec<Value>, Row>::from_frame(query.clone(),
+                                                                     frame,
+                                                                     session.prepared_cache())
+                            .map(|prepared| prepared.id().clone())
+                    });
+                    drop(session);
+
+                    if let (&Ok(ref id), Some(registry)) = (&id, pool.registry.as_ref()) {
+                        let mut registry = registry.lock().unwrap();
+                        registry.register(&query);
+                        registry.set_id(&query, pool.host, id.clone());
+                    }
+                    id
+                })
+            })
+            .boxed()
+    }
+
+    /// Opens a connection with the connector which listens for schema changes and
+    /// passes them to prepared caches of sessions of the pool, see `PreparedCache`.
+    /// It has to be spawned on a reactor and runs until the connection ends.
+    pub fn follow_schema_changes(&self) -> CDRSFuture<()> {
+        let caches = self.caches.clone();
+        self.listen_for_schema_changes()
+            .and_then(move |events| prepared::follow_schema_events(vec![caches], events))
+            .boxed()
+    }
+
+    /// Opens a connection with the connector which is registered for schema changes.
+    pub fn listen_for_schema_changes(&self) -> CDRSFuture<CDRSStream<ServerEvent>> {
+        let connect = match self.connector {
+            Some(ref connect) => connect.clone(),
+            None => return future::err("Pool has no connector".into()).boxed(),
+        };
+        connect()
+            .and_then(|session| session.listen_for_async(vec![SimpleServerEvent::SchemaChange]))
+            .boxed()
+    }
+
+    /// Prepared caches of sessions of the pool, including ones it opens later.
+    pub fn prepared_caches(&self) -> PreparedCaches {
+        self.caches.clone()
+    }
+
+    /// Sends a request frame on a pooled session once one is free. The request
+    /// fails without being sent if no session is free before `deadline`.
+    pub fn request(&self, frame: Frame, deadline: Option<Instant>) -> CDRSFuture<Frame> {
+        self.try_request(frame, deadline).and_then(|result| result).boxed()
+    }
+
+    /// Works as `request`, but tells whether the request was sent. The future fails
+    /// if no session was checked out, e.g. a connection couldn't be opened, so the
+    /// request wasn't sent. Otherwise it resolves into the result of the request.
+    pub fn try_request(&self,
+                       frame: Frame,
+                       deadline: Option<Instant>)
+                       -> CDRSFuture<error::Result<Frame>> {
+        self.try_request_with(move |_| frame, deadline)
+    }
+
+    /// Works as `try_request` building the frame on the checked out session,
+    /// e.g. with defaults of the session, see `Session::with_defaults`.
+    pub fn try_request_with<F>(&self,
+                               frame: F,
+                               deadline: Option<Instant>)
+                               -> CDRSFuture<error::Result<Frame>>
+        where F: FnOnce(&Session<T, X>) -> Frame + Send + 'static
+    {
+        self.try_request_routed_with(frame, None, deadline)
+    }
+
+    /// Works as `try_request_with` on a session of the shard which owns `token`
+    /// if the pool is shard-aware, see `checkout_routed`.
+    pub fn try_request_routed_with<F>(&self,
+                                      frame: F,
+                                      token: Option<i64>,
+                                      deadline: Option<Instant>)
+                                      -> CDRSFuture<error::Result<Frame>>
+        where F: FnOnce(&Session<T, X>) -> Frame + Send + 'static
+    {
+        let pool = self.clone();
+        self.checkout_routed(token, deadline)
+            .and_then(move |session| {
+                let frame = frame(&session);
+                if let Some(ref metrics) = pool.metrics {
+                    metrics.lock().unwrap().start(pool.host);
+                }
+                let started = Instant::now();
+
+                let session = PooledSession::new(session, pool.clone());
+                session.try_request(frame).then(move |result| {
+                    let (session, result) = result.expect("try_request never fails");
+                    if let Some(ref metrics) = pool.metrics {
+                        metrics.lock()
+                            .unwrap()
+                            .finish(pool.host, result.as_ref().err(), started.elapsed());
+                    }
+                    // a broken session is discarded
+                    drop(session);
+                    Ok(result)
+                })
+            })
+            .boxed()
+    }
+
+    /// Sends a query on a pooled session once one is free. Defaults of the session,
+    /// e.g. its consistency, apply as they do to `Session::query`.
+    pub fn query<Q>(&self, query: Q) -> CDRSFuture<Frame>
+        where Q: Into<Statement<Query>> + Send + 'static
+    {
+        let frame = move |session: &Session<T, X>| {
+            client::query_frame(session.with_defaults(query.into()), vec![])
+        };
+        self.try_request_with(frame, None).and_then(|result| result).boxed()
+    }
+
+    /// Executes a prepared statement on a pooled session once one is free.
+    /// The statement has to be prepared on the host, e.g. with `prepare`.
+    pub fn execute<P>(&self, id: &CBytesShort, query_parameters: P) -> CDRSFuture<Frame>
+        where P: Into<Statement<QueryParams>> + Send + 'static
+    {
+        let id = id.clone();
+        let frame = move |session: &Session<T, X>| {
+            let query_parameters = session.with_defaults(query_parameters.into());
+            Frame::new_req_execute(&id, query_parameters, vec![])
+        };
+        self.try_request_with(frame, None).and_then(|result| result).boxed()
+    }
+
+    /// Sends a batch on a pooled session once one is free.
+    pub fn batch<B>(&self, batch: B) -> CDRSFuture<Frame>
+        where B: Into<Statement<QueryBatch>> + Send + 'static
+    {
+        let frame = move |session: &Session<T, X>| {
+            Frame::new_req_batch(session.with_defaults(batch.into()), vec![])
+        };
+        self.try_request_with(frame, None).and_then(|result| result).boxed()
+    }
+
+    fn expire(&self) {
+        self.inner.lock().unwrap().expired += 1;
+        if let Some(ref metrics) = self.metrics {
+            metrics.lock().unwrap().expire_in_queue(self.host);
+        }
+    }
+}
+
+/// A session checked out with `Pool::get`. It's released when dropped,
+/// or discarded if it's marked as broken.
+pub struct PooledSession<T: Authenticator + Send + 'static, X: CDRSTransport + 'static> {
+    /// It's `None` while a request is in flight.
+    session: Option<Session<T, X>>,
+    /// Shard of the session, it's forgotten in it if the session is dropped.
+    shard_info: Option<ShardInfo>,
+    pool: Pool<T, X>,
+    broken: bool,
+}
+
+impl<T, X> PooledSession<T, X>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    fn new(session: Session<T, X>, pool: Pool<T, X>) -> PooledSession<T, X> {
+        PooledSession {
+            shard_info: session.shard_info(),
+            session: Some(session),
+            pool: pool,
+            broken: false,
+        }
+    }
+
+    /// Sends a request frame, see `Session::try_request`. The session is marked
+    /// as broken if the request fails with an IO error or breaks the connection.
+    /// If the returned future is dropped before the response, the session is
+    /// closed and the pool forgets it.
+    pub fn try_request(mut self, frame: Frame) -> CDRSFuture<(Self, error::Result<Frame>)> {
+        let session = self.session.take().expect("session is given back on drop only");
+        session.try_request(frame)
+            .map(move |(session, result)| {
+                     self.session = Some(session);
+                     if let Err(ref err) = result {
+                         self.broken |= is_broken(err);
+                     }
+                     (self, result)
+                 })
+            .boxed()
+    }
+
+    /// Makes the session be closed instead of given back, e.g. after it failed
+    /// a request which wasn't sent with `try_request`.
+    pub fn mark_broken(&mut self) {
+        self.broken = true;
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+}
+
+impl<T: Authenticator + Send, X: CDRSTransport> Deref for PooledSession<T, X> {
+    type Target = Session<T, X>;
+
+    fn deref(&self) -> &Session<T, X> {
+        self.session.as_ref().expect("session is given back on drop only")
+    }
+}
+
+impl<T: Authenticator + Send, X: CDRSTransport> DerefMut for PooledSession<T, X> {
+    fn deref_mut(&mut self) -> &mut Session<T, X> {
+        self.session.as_mut().expect("session is given back on drop only")
+    }
+}
+
+impl<T: Authenticator + Send, X: CDRSTransport> Drop for PooledSession<T, X> {
+    fn drop(&mut self) {
+        let session = match self.session.take() {
+            Some(session) => session,
+            // the session was dropped along with a request in flight
+            None => return self.pool.forget(self.shard_info),
+        };
+        if self.broken {
+            self.pool.discard(session);
+        } else {
+            self.pool.release(session);
+        }
+    }
+}
+
+/// A checkout which waits for a session until `PoolOptions::checkout_timeout`.
+struct WaitForSession<T: Authenticator + 'static, X: CDRSTransport + 'static> {
+    receiver: oneshot::Receiver<Checkout<T, X>>,
+    timeout: Delay,
+    queued_at: Instant,
+}
+
+impl<T: Authenticator, X: CDRSTransport> Future for WaitForSession<T, X> {
+    type Item = Session<T, X>;
+    type Error = error::Error;
+
+    fn poll(&mut self) -> Poll<Session<T, X>, error::Error> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(checkout)) => return checkout.map(Async::Ready),
+            Ok(Async::NotReady) => {}
+            Err(_) => return Err(pool_dropped()),
+        }
+        match self.timeout.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {}
+            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err).into()),
+        }
+
+        // a session released right before the timeout must not get lost
+        self.receiver.close();
+        match self.receiver.poll() {
+            Ok(Async::Ready(checkout)) => checkout.map(Async::Ready),
+            _ => Err(error::Error::PoolTimeout { waited: self.queued_at.elapsed() }),
+        }
+    }
+}
+
+fn pool_dropped() -> error::Error {
+    "Pool was dropped while a request waited".into()
+}
+
+/// Returns `true` if a session which failed a request with `err` has to be closed.
+fn is_broken(err: &error::Error) -> bool {
+    match *err {
+        error::Error::Io(_) => true,
+        _ => err.breaks_connection(),
+    }
+}
+
+/// Prepares statements of `registry` on a new session and records their ids.
+/// A statement which a server refuses to prepare, e.g. one of a dropped table,
+/// doesn't fail the session.
+fn prepare_registered<T, X>(session: Session<T, X>,
+                            registry: Arc<Mutex<PreparedRegistry>>,
+                            host: SocketAddr)
+                            -> CDRSFuture<(Session<T, X>, error::Result<()>)>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    let pending: VecDeque<String> = registry.lock().unwrap().statements().into_iter().collect();
+
+    future::loop_fn((session, pending), move |(session, mut pending)| {
+        let query = match pending.pop_front() {
+            Some(query) => query,
+            None => return future::ok(Loop::Break((session, Ok(())))).boxed(),
+        };
+
+        let registry = registry.clone();
+        let frame = Frame::new_req_prepare(query.clone(), vec![]);
+        session.try_request(frame)
+            .map(move |(session, result)| {
+                let prepared = result.and_then(script::check_response).and_then(|frame| {
+                    TypedPrepared::<Vec<Value>, Row>::from_frame(query.clone(),
+                                                                 frame,
+                                                                 session.prepared_cache())
+                });
+                match prepared {
+                    Ok(prepared) => {
+                        registry.lock().unwrap().set_id(&query, host, prepared.id().clone())
+                    }
+                    Err(error::Error::Server { .. }) => (),
+                    Err(err) => return Loop::Break((session, Err(err))),
+                }
+                Loop::Continue((session, pending))
+            })
+            .boxed()
+    })
+            .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+    use std::net;
+    use std::thread;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use futures::{future, Future};
+    use tokio_core::reactor::{Core, Timeout};
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::consistency::Consistency;
+    use cdrs::query::QueryParamsBuilder;
+    use cdrs::IntoBytes;
+    use cdrs::frame::Frame;
+
+    use super::*;
+    use client::{CDRS, Session};
+    use mock::{self, MockTransport, ERROR, READY, OPTIONS, SUPPORTED, QUERY, RESULT, PREPARE,
+               EXECUTE, EVENT};
+
+    fn pool(transport: &MockTransport, n: usize) -> Pool<NoneAuthenticator, MockTransport> {
+        let sessions = (0..n)
+            .map(|_| {
+                     let cdrs = CDRS::new(transport.clone(), NoneAuthenticator);
+                     Session::start(cdrs)
+                 })
+            .collect();
+        Pool::new("127.0.0.1:9042".parse().unwrap(), sessions)
+    }
+
+    /// Makes a session on `transport` detect a given shard of four.
+    fn push_shard(transport: &MockTransport, shard: u32) {
+        use scylla::{SCYLLA_NR_SHARDS, SCYLLA_SHARD};
+
+        let shard = shard.to_string();
+        let supported = mock::supported_body(&[(SCYLLA_SHARD, &[&shard[..]]),
+                                               (SCYLLA_NR_SHARDS, &["4"])]);
+        transport.push_read(mock::response(SUPPORTED, 0, &supported));
+    }
+
+    fn shard_session(transport: &MockTransport,
+                     shard: u32)
+                     -> Session<NoneAuthenticator, MockTransport> {
+        push_shard(transport, shard);
+        mock::session(transport.clone())
+            .detect_shard()
+            .wait()
+            .unwrap()
+    }
+
+    fn soon(millis: u64) -> Option<Instant> {
+        Some(Instant::now() + Duration::from_millis(millis))
+    }
+
+    #[test]
+    fn expires_requests_behind_slow_one() {
+        let transport = MockTransport::new();
+        let metrics = Arc::new(Mutex::new(HostMetricsRegistry::new(Duration::from_secs(60))));
+        let mut pool = pool(&transport, 1);
+        pool.metrics(metrics.clone());
+
+        let mut core = Core::new().unwrap();
+        core.handle().spawn(pool.maintain_every(Duration::from_millis(5), &core.handle())
+                                .map_err(|_| ()));
+
+        // a slow request holds the only session
+        let slow = core.run(pool.checkout(None)).unwrap();
+        let first = pool.request(Frame::new_req_options(), soon(20));
+        let second = pool.request(Frame::new_req_options(), soon(20));
+        let patient = pool.request(Frame::new_req_options(), None);
+
+        for expired in [first, second] {
+            match core.run(expired) {
+                Err(error::Error::DeadlineExceeded { waited }) => {
+                    assert!(waited >= Duration::from_millis(20))
+                }
+                Err(err) => panic!("DeadlineExceeded expected, got {:?}", err),
+                Ok(_) => panic!("DeadlineExceeded expected"),
+            }
+        }
+        assert_eq!(pool.waiting(), 1);
+        assert!(transport.written().is_empty());
+
+        transport.push_read(mock::response(SUPPORTED, 0, &mock::supported_body(&[])));
+        pool.release(slow);
+        core.run(patient).unwrap();
+
+        assert_eq!(transport.written(), Frame::new_req_options().into_cbytes());
+        assert_eq!(pool.expired(), 2);
+        assert_eq!(pool.idle(), 1);
+        let metrics = metrics.lock().unwrap();
+        let host = metrics.host(&pool.host()).unwrap();
+        assert_eq!((host.expired_in_queue, host.requests, host.timeouts), (2, 1, 0));
+    }
+
+    #[test]
+    fn skips_expired_waiters_on_release() {
+        let transport = MockTransport::new();
+        let pool = pool(&transport, 1);
+
+        let session = pool.checkout(None).wait().unwrap();
+        let expired = pool.checkout(soon(1));
+        let waiting = pool.checkout(soon(60000));
+        ::std::thread::sleep(Duration::from_millis(5));
+
+        pool.release(session);
+        assert!(expired.wait().is_err());
+        assert!(waiting.wait().is_ok());
+        assert_eq!(pool.expired(), 1);
+    }
+
+    #[test]
+    fn bounds_the_queue() {
+        let transport = MockTransport::new();
+        let mut pool = pool(&transport, 0);
+        pool.options(PoolOptions { max_waiters: 1, ..PoolOptions::default() });
+
+        let _waiting = pool.checkout(None);
+        match pool.checkout(None).wait() {
+            Err(error::Error::Backpressure) => (),
+            other => panic!("Backpressure expected, got {:?}", other.map(|_| ())),
+        }
+        match pool.checkout(Some(Instant::now())).wait() {
+            Err(error::Error::DeadlineExceeded { .. }) => (),
+            other => panic!("DeadlineExceeded expected, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn grows_under_load_and_shrinks_when_idle() {
+        let transport = MockTransport::new();
+        let connected = transport.clone();
+        let mut pool = pool(&transport, 1);
+        pool.options(PoolOptions {
+                         high_water: Some(0.5),
+                         sustain: Duration::from_millis(10),
+                         max_size: 3,
+                         idle_timeout: Some(Duration::from_millis(30)),
+                         ..PoolOptions::default()
+                     });
+        pool.connector(move || {
+                           let cdrs = CDRS::new(connected.clone(), NoneAuthenticator);
+                           let session = Session::start(cdrs);
+                           future::ok(session).boxed()
+                       });
+
+        let mut core = Core::new().unwrap();
+        core.handle().spawn(pool.maintain_every(Duration::from_millis(5), &core.handle())
+                                .map_err(|_| ()));
+
+        // slow requests hold sessions while more of them wait
+        let held = core.run(pool.checkout(None)).unwrap();
+        let waiting = pool.checkout(None).join(pool.checkout(None));
+        let (first, second) = core.run(waiting).unwrap();
+        assert_eq!(pool.size(), 3);
+
+        for session in [held, first, second] {
+            pool.release(session);
+        }
+        core.run(Timeout::new(Duration::from_millis(100), &core.handle()).unwrap()).unwrap();
+        assert_eq!(pool.size(), 1);
+
+        let events = pool.take_events();
+        let grew = events.iter()
+            .filter(|event| match **event {
+                        PoolEvent::Grew { load, .. } => load > 0.5,
+                        _ => false,
+                    })
+            .count();
+        let shrank = events.iter()
+            .filter(|event| match **event {
+                        PoolEvent::Shrank { idle, .. } => idle >= Duration::from_millis(30),
+                        _ => false,
+                    })
+            .count();
+        assert_eq!((grew, shrank), (2, 2));
+    }
+
+    fn set_up_transport(prepared: Vec<u8>) -> MockTransport {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transport.push_read(prepared);
+        transport
+    }
+
+    #[test]
+    fn replaces_sessions_broken_by_io_errors() {
+        let broken = MockTransport::new();
+        broken.push_read_error(io::ErrorKind::ConnectionReset);
+        let replacement = MockTransport::new();
+        replacement.push_read(mock::response(SUPPORTED, 0, &mock::supported_body(&[])));
+        let connected = replacement.clone();
+
+        let mut pool = pool(&broken, 1);
+        pool.connector(move || {
+                           let cdrs = CDRS::new(connected.clone(), NoneAuthenticator);
+                           future::ok(Session::start(cdrs)).boxed()
+                       });
+
+        assert!(pool.request(Frame::new_req_options(), None).wait().is_err());
+        assert!(broken.is_closed());
+        assert_eq!((pool.size(), pool.idle()), (0, 0));
+
+        pool.request(Frame::new_req_options(), None).wait().unwrap();
+        assert_eq!((pool.size(), pool.idle()), (1, 1));
+        assert_eq!(replacement.written(), Frame::new_req_options().into_cbytes());
+    }
+
+    #[test]
+    fn sends_heartbeats_on_idle_sessions() {
+        let transport = MockTransport::new();
+        for _ in 0..10 {
+            transport.push_read(mock::response(SUPPORTED, 0, &mock::supported_body(&[])));
+        }
+        let pool = pool(&transport, 1);
+
+        let mut core = Core::new().unwrap();
+        let heartbeats = pool.heartbeat_every(Duration::from_millis(20), &core.handle());
+        let timeout = Timeout::new(Duration::from_millis(110), &core.handle()).unwrap();
+        let _ = core.run(heartbeats.select2(timeout));
+
+        let sent = mock::opcodes(&transport.written());
+        assert!(sent.len() >= 3, "{:?}", sent);
+        assert!(sent.iter().all(|&opcode| opcode == OPTIONS));
+        assert_eq!((pool.size(), pool.idle()), (1, 1));
+
+        // sessions in use and ones used since don't need heartbeats
+        let idle_since = Instant::now();
+        let busy = pool.checkout(None).wait().unwrap();
+        let written = transport.written().len();
+        assert_eq!(pool.heartbeat(idle_since, Duration::from_millis(20)).wait().unwrap(), 0);
+        pool.release(busy);
+        assert_eq!(pool.heartbeat(idle_since, Duration::from_millis(20)).wait().unwrap(), 0);
+        assert_eq!(transport.written().len(), written);
+    }
+
+    #[test]
+    fn discards_sessions_which_miss_heartbeats() {
+        let transport = MockTransport::new();
+        let pool = pool(&transport, 1);
+
+        let mut core = Core::new().unwrap();
+        let heartbeat = pool.heartbeat(Instant::now(), Duration::from_millis(20));
+        assert_eq!(core.run(heartbeat).unwrap(), 1);
+        assert_eq!(pool.size(), 0);
+        assert!(transport.is_closed());
+    }
+
+    /// Answers every request of `connections` connections with a Void result after `delay`.
+    fn serve_slowly(listener: net::TcpListener,
+                    connections: usize,
+                    delay: Duration)
+                    -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let servers: Vec<_> = (0..connections)
+                .map(|_| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    thread::spawn(move || {
+                        let mut header = [0; 9];
+                        while stream.read_exact(&mut header).is_ok() {
+                            let len = ((header[5] as usize) << 24) | ((header[6] as usize) << 16) |
+                                      ((header[7] as usize) << 8) |
+                                      header[8] as usize;
+                            let mut body = vec![0; len];
+                            stream.read_exact(&mut body).unwrap();
+                            thread::sleep(delay);
+                            let id = ((header[2] as i16) << 8) | header[3] as i16;
+                            let response = mock::response(RESULT, id, &mock::void_body());
+                            stream.write_all(&response).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for server in servers {
+                server.join().unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn runs_requests_concurrently_on_separate_sessions() {
+        use cdrs::query::QueryBuilder;
+        use transport::TransportTcp;
+
+        let run_two_queries = |size: usize| {
+            let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = serve_slowly(listener, size, Duration::from_millis(100));
+
+            let mut core = Core::new().unwrap();
+            let sessions = (0..size)
+                .map(|_| {
+                         let transport = core.run(TransportTcp::new(addr, &core.handle()))
+                             .unwrap();
+                         Session::start(CDRS::new(transport, NoneAuthenticator))
+                     })
+                .collect();
+            let pool = Pool::new(addr, sessions);
+
+            let started = Instant::now();
+            let query = || QueryBuilder::new("SELECT * FROM t").finalize();
+            core.run(pool.query(query()).join(pool.query(query()))).unwrap();
+            let elapsed = started.elapsed();
+
+            drop(pool);
+            server.join().unwrap();
+            elapsed
+        };
+
+        assert!(run_two_queries(2) < Duration::from_millis(190));
+        assert!(run_two_queries(1) >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn applies_defaults_of_sessions() {
+        use cdrs::query::QueryBuilder;
+        use codec;
+
+        const SELECT: &'static str = "SELECT * FROM t";
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        let mut session = mock::session(transport.clone());
+        session.set_default_consistency(Consistency::LocalQuorum);
+        let pool = Pool::new("127.0.0.1:9042".parse().unwrap(), vec![session]);
+
+        let select = || QueryBuilder::new(SELECT).finalize();
+        pool.query(Statement::new(select())).wait().unwrap();
+        pool.query(select()).wait().unwrap();
+
+        // consistency follows the query
+        let offset = codec::HEADER_LEN + 4 + SELECT.len();
+        let written = transport.written();
+        let queries: Vec<_> = written.chunks(written.len() / 2).collect();
+        assert_eq!(&queries[0][offset..offset + 2], &[0, 6]);
+        assert_eq!(&queries[1][offset..offset + 2], &[0, 1]);
+    }
+
+    #[test]
+    fn sets_decode_executor_on_sessions() {
+        use mock::ThreadExecutor;
+
+        let transport = MockTransport::new();
+        let mut pool = pool(&transport, 2);
+        let busy = pool.checkout(None).wait().unwrap();
+        pool.options(PoolOptions { min_size: 3, ..PoolOptions::default() })
+            .decode_executor(Arc::new(ThreadExecutor))
+            .connector(move || future::ok(mock::session(transport.clone())).boxed());
+
+        pool.release(busy);
+        pool.fill().wait().unwrap();
+        let sessions: Vec<_> = (0..3).map(|_| pool.checkout(None).wait().unwrap()).collect();
+        assert!(sessions.iter().all(|session| session.decode_executor().is_some()));
+    }
+
+    #[test]
+    fn follows_schema_changes_for_sessions() {
+        use codec::EVENT_STREAM_ID;
+
+        const SELECT: &'static str = "SELECT id FROM t";
+
+        let transport = MockTransport::new();
+        let prepared = mock::prepared_body(b"select", &[], &[("id", mock::INT)]);
+        transport.push_read(mock::response(RESULT, 0, &prepared));
+        let (session, _) = mock::session(transport.clone())
+            .prepare_cached(SELECT.to_string())
+            .wait()
+            .unwrap();
+        let cache = session.prepared_cache();
+
+        let events = MockTransport::new();
+        events.push_read(mock::response(READY, 0, &[]));
+        events.push_read(mock::response(EVENT,
+                                        EVENT_STREAM_ID,
+                                        &mock::schema_event_body("UPDATED", "ks", "table")));
+        events.push_read_error(io::ErrorKind::ConnectionReset);
+        let mut pool = Pool::new("127.0.0.1:9042".parse().unwrap(), vec![session]);
+        pool.connector(move || {
+                           let cdrs = CDRS::new(events.clone(), NoneAuthenticator);
+                           future::ok(Session::start(cdrs)).boxed()
+                       });
+
+        assert!(pool.follow_schema_changes().wait().is_err());
+        assert!(cache.lock().unwrap().get(SELECT).unwrap().stale);
+    }
+
+    #[test]
+    fn sets_up_new_sessions() {
+        let prepared = mock::response(RESULT,
+                                      0,
+                                      &mock::prepared_body(b"select", &[], &[("id", mock::INT)]));
+        let failing = set_up_transport(mock::response(ERROR,
+                                                      0,
+                                                      &mock::error_body(0x2200, "no table t")));
+        let transports = vec![set_up_transport(prepared.clone()),
+                              failing.clone(),
+                              set_up_transport(prepared)];
+        let opened: VecDeque<_> = transports.iter().cloned().collect();
+        let opened = Arc::new(Mutex::new(opened));
+        let metrics = Arc::new(Mutex::new(HostMetricsRegistry::new(Duration::from_secs(60))));
+
+        let mut pool = pool(&MockTransport::new(), 0);
+        pool.options(PoolOptions { min_size: 2, ..PoolOptions::default() })
+            .metrics(metrics.clone())
+            .on_connection_setup(vec![SetupAction::use_keyspace("ks"),
+                                      SetupAction::prepare(vec!["SELECT id FROM t".to_string()])])
+            .connector(move || {
+                           let transport = opened.lock().unwrap().pop_front().unwrap();
+                           let cdrs = CDRS::new(transport, NoneAuthenticator);
+                           future::ok(Session::start(cdrs)).boxed()
+                       });
+
+        assert!(pool.fill().wait().is_err());
+        assert_eq!(pool.size(), 1);
+        assert!(failing.is_closed());
+        // the failed session is replaced
+        pool.fill().wait().unwrap();
+        assert_eq!(pool.size(), 2);
+
+        for transport in &transports {
+            assert_eq!(mock::opcodes(&transport.written()), vec![QUERY, PREPARE]);
+        }
+        let session = pool.checkout(None).wait().unwrap();
+        assert!(session.prepared_cache().lock().unwrap().get("SELECT id FROM t").is_some());
+        let metrics = metrics.lock().unwrap();
+        assert_eq!(metrics.host(&pool.host()).unwrap().connect_failures, 1);
+    }
+
+    #[test]
+    fn prepares_registered_statements_on_new_sessions() {
+        let select_a = "SELECT a FROM t";
+        let select_b = "SELECT b FROM t";
+        let registry = Arc::new(Mutex::new(PreparedRegistry::default()));
+        registry.lock().unwrap().register(select_a);
+        registry.lock().unwrap().register(select_b);
+
+        let transport = MockTransport::new();
+        for id in &[b"b", b"a"] {
+            let body = mock::prepared_body(*id, &[], &[("a", mock::INT)]);
+            transport.push_read(mock::response(RESULT, 0, &body));
+        }
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let connected = transport.clone();
+        let mut pool = pool(&transport, 0);
+        pool.prepared_registry(registry.clone())
+            .connector(move || {
+                           let cdrs = CDRS::new(connected.clone(), NoneAuthenticator);
+                           future::ok(Session::start(cdrs)).boxed()
+                       });
+        pool.fill().wait().unwrap();
+        assert_eq!(mock::opcodes(&transport.written()), vec![PREPARE, PREPARE]);
+
+        let id = registry.lock().unwrap().id(select_a, &pool.host()).unwrap();
+        assert_eq!(id.clone().into_plain(), b"a".to_vec());
+        let params = QueryParamsBuilder::new(Consistency::One).finalize();
+        pool.request(Frame::new_req_execute(&id, params, vec![]), None)
+            .wait()
+            .unwrap();
+        assert_eq!(mock::opcodes(&transport.written()), vec![PREPARE, PREPARE, EXECUTE]);
+    }
+
+    #[test]
+    fn drains_in_flight_requests_before_closing() {
+        let transport = MockTransport::new();
+        let pool = pool(&transport, 2);
+        let mut core = Core::new().unwrap();
+
+        // a slow request holds a session for 30ms
+        let slow = core.run(pool.checkout(None)).unwrap();
+        let released = pool.clone();
+        let finish = Timeout::new(Duration::from_millis(30), &core.handle())
+            .unwrap()
+            .map(move |_| released.release(slow))
+            .map_err(|_| ());
+        core.handle().spawn(finish);
+
+        let started = Instant::now();
+        let drain = pool.drain(Duration::from_secs(5), &core.handle());
+        let rejected_at = Instant::now();
+        match pool.checkout(None).wait() {
+            Err(error::Error::ShuttingDown) => (),
+            other => panic!("ShuttingDown expected, got {:?}", other.map(|_| ())),
+        }
+        assert!(rejected_at.elapsed() < Duration::from_millis(10));
+
+        let report = core.run(drain).unwrap();
+        assert_eq!(report,
+                   DrainReport {
+                       completed: 1,
+                       aborted: 0,
+                   });
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(pool.size(), 0);
+        assert!(transport.is_closed());
+    }
+
+    #[test]
+    fn aborts_requests_past_drain_deadline() {
+        let transport = MockTransport::new();
+        let pool = pool(&transport, 1);
+        let mut core = Core::new().unwrap();
+
+        let stuck = core.run(pool.checkout(None)).unwrap();
+        let queued = pool.checkout(None);
+        let report = core.run(pool.drain(Duration::from_millis(20), &core.handle())).unwrap();
+
+        assert_eq!(report,
+                   DrainReport {
+                       completed: 0,
+                       aborted: 2,
+                   });
+        match queued.wait() {
+            Err(error::Error::ShuttingDown) => (),
+            other => panic!("ShuttingDown expected, got {:?}", other.map(|_| ())),
+        }
+        assert!(!transport.is_closed());
+        pool.release(stuck);
+        assert!(transport.is_closed());
+        assert_eq!(pool.size(), 0);
+    }
+
+    /// Polls `checkout` once within a task of `core`.
+    fn poll_once<F>(core: &mut Core, checkout: &mut F) -> Option<Result<F::Item, F::Error>>
+        where F: Future
+    {
+        let polled = core.run(future::lazy(|| Ok::<_, ()>(checkout.poll()))).unwrap();
+        match polled {
+            Ok(Async::Ready(item)) => Some(Ok(item)),
+            Ok(Async::NotReady) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    #[test]
+    fn serves_checkouts_in_order() {
+        let transport = MockTransport::new();
+        let pool = pool(&transport, 2);
+        let mut core = Core::new().unwrap();
+
+        let mut pending: VecDeque<_> = (0..10).map(|i| (i, pool.get())).collect();
+        assert_eq!(pool.waiting(), 8);
+        let mut held = VecDeque::new();
+        let mut served = vec![];
+        while !pending.is_empty() {
+            for _ in 0..pending.len() {
+                let (i, mut checkout) = pending.pop_front().unwrap();
+                match poll_once(&mut core, &mut checkout) {
+                    Some(Ok(session)) => {
+                        served.push(i);
+                        held.push_back(session);
+                    }
+                    Some(Err(err)) => panic!("checkout {} failed: {:?}", i, err),
+                    None => pending.push_back((i, checkout)),
+                }
+            }
+            assert!(held.len() <= 2, "{} sessions are held", held.len());
+
+            drop(held.pop_front());
+            // a checkout made now queues behind waiting ones
+            if !pending.is_empty() {
+                assert!(poll_once(&mut core, &mut pool.get()).is_none());
+            }
+        }
+        assert_eq!(served, (0..10).collect::<Vec<_>>());
+
+        drop(held);
+        assert_eq!((pool.size(), pool.idle(), pool.waiting()), (2, 2, 0));
+    }
+
+    #[test]
+    fn times_out_checkouts() {
+        let transport = MockTransport::new();
+        let mut pool = pool(&transport, 1);
+        pool.options(PoolOptions {
+                         checkout_timeout: Some(Duration::from_millis(20)),
+                         ..PoolOptions::default()
+                     });
+        let mut core = Core::new().unwrap();
+
+        let held = core.run(pool.get()).unwrap();
+        let started = Instant::now();
+        let checkouts = future::join_all((0..5).map(|_| pool.get().then(Ok::<_, ()>)));
+        for checkout in core.run(checkouts).unwrap() {
+            match checkout {
+                Err(error::Error::PoolTimeout { waited }) => {
+                    assert!(waited >= Duration::from_millis(20))
+                }
+                Err(err) => panic!("PoolTimeout expected, got {:?}", err),
+                Ok(_) => panic!("PoolTimeout expected"),
+            }
+        }
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        // timed out checkouts don't take the session
+        drop(held);
+        assert_eq!(pool.idle(), 1);
+        let session = core.run(pool.get()).unwrap();
+        let waiting = pool.get();
+        drop(session);
+        core.run(waiting).unwrap();
+    }
+
+    #[test]
+    fn discards_broken_pooled_sessions() {
+        let transport = MockTransport::new();
+        transport.push_read_error(io::ErrorKind::ConnectionReset);
+        let pool = pool(&transport, 1);
+        let mut core = Core::new().unwrap();
+
+        let session = core.run(pool.get()).unwrap();
+        let waiting = pool.get();
+        let (session, result) = core.run(session.try_request(Frame::new_req_options())).unwrap();
+        assert!(result.is_err());
+        assert!(session.is_broken());
+        drop(session);
+
+        assert!(transport.is_closed());
+        assert_eq!(pool.size(), 0);
+        // the broken session is not handed to a waiting checkout
+        let mut waiting = waiting;
+        assert!(poll_once(&mut core, &mut waiting).is_none());
+    }
+
+    #[test]
+    fn dropped_requests_dont_poison_sessions() {
+        let transport = MockTransport::new();
+        // the response is cut short and its rest comes too late
+        let late = mock::supported_body(&[("COMPRESSION", &["lz4"])]);
+        let late = mock::response(SUPPORTED, 0, &late);
+        transport.push_read(late[..12].to_vec());
+        let replacement = MockTransport::new();
+        replacement.push_read(mock::response(SUPPORTED, 0, &mock::supported_body(&[])));
+        let connected = replacement.clone();
+
+        let mut pool = pool(&transport, 1);
+        pool.connector(move || {
+                           let cdrs = CDRS::new(connected.clone(), NoneAuthenticator);
+                           future::ok(Session::start(cdrs)).boxed()
+                       });
+
+        let mut core = Core::new().unwrap();
+        let timeout = Timeout::new(Duration::from_millis(20), &core.handle()).unwrap();
+        let request = pool.request(Frame::new_req_options(), None);
+        match core.run(request.select2(timeout)) {
+            Ok(future::Either::B(_)) => {}
+            _ => panic!("the request is expected to lose the race"),
+        }
+        transport.push_read(late[12..].to_vec());
+
+        assert!(transport.is_closed());
+        assert_eq!(pool.size(), 0);
+
+        let frame = core.run(pool.request(Frame::new_req_options(), None)).unwrap();
+        // the response is the replacement's, not the rest of the late one
+        assert_eq!(frame.body, mock::supported_body(&[]));
+        assert_eq!(replacement.written(), Frame::new_req_options().into_cbytes());
+        assert_eq!((pool.size(), pool.idle()), (1, 1));
+    }
+
+    #[test]
+    fn shard_aware_pool_routes_by_token() {
+        let shard0 = MockTransport::new();
+        let shard2 = MockTransport::new();
+        let sessions = vec![shard_session(&shard0, 0), shard_session(&shard2, 2)];
+        let mut pool = Pool::new("127.0.0.1:9042".parse().unwrap(), sessions);
+        assert_eq!(pool.shard_info().map(|info| info.nr_shards), Some(4));
+        assert_eq!(pool.shard_sizes(), vec![1, 0, 1, 0]);
+
+        // tokens are ignored unless the pool is shard-aware
+        let shard_of = |session: &Session<NoneAuthenticator, MockTransport>| {
+            session.shard_info().map(|info| info.shard)
+        };
+        let session = pool.checkout_routed(Some(i64::min_value()), None).wait().unwrap();
+        assert_eq!(shard_of(&session), Some(2));
+        pool.release(session);
+
+        // 0 is a token of shard 2 and the min one is of shard 0, see `ShardInfo::shard_of`
+        pool.shard_aware(true);
+        for &(token, shard) in &[(0, 2), (i64::min_value(), 0), (0, 2)] {
+            let session = pool.checkout_routed(Some(token), None).wait().unwrap();
+            assert_eq!(shard_of(&session), Some(shard));
+            pool.release(session);
+        }
+
+        shard0.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        pool.try_request_routed_with(|_| Frame::new_req_options(), Some(i64::min_value()), None)
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert_eq!(mock::opcodes(&shard0.written()), vec![OPTIONS, OPTIONS]);
+        assert_eq!(mock::opcodes(&shard2.written()), vec![OPTIONS]);
+
+        // shard 3 has no session, so any one is taken
+        let session = pool.checkout_routed(Some(i64::max_value()), None).wait().unwrap();
+        assert!(session.shard_info().is_some());
+        let shard = shard_of(&session).unwrap() as usize;
+        pool.discard(session);
+        let mut sizes = vec![1, 0, 1, 0];
+        sizes[shard] = 0;
+        assert_eq!(pool.shard_sizes(), sizes);
+    }
+
+    #[test]
+    fn fills_missing_shards() {
+        let mut pool = Pool::new("127.0.0.1:9042".parse().unwrap(),
+                                 vec![shard_session(&MockTransport::new(), 1)]);
+        assert!(pool.fill_shards().wait().is_err());
+
+        let requested = Arc::new(Mutex::new(vec![]));
+        let connected = requested.clone();
+        pool.shard_aware(true).shard_connector(move |info, shard| {
+            assert_eq!(info.nr_shards, 4);
+            connected.lock().unwrap().push(shard);
+            // the pool detects the shard the session landed on
+            let transport = MockTransport::new();
+            push_shard(&transport, shard);
+            future::ok(mock::session(transport)).boxed()
+        });
+
+        pool.fill_shards().wait().unwrap();
+        assert_eq!(*requested.lock().unwrap(), vec![0, 2, 3]);
+        assert_eq!(pool.shard_sizes(), vec![1, 1, 1, 1]);
+        assert_eq!((pool.size(), pool.idle()), (4, 4));
+
+        pool.fill_shards().wait().unwrap();
+        assert_eq!(requested.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,1473 @@
+//! Prepared statements which know types of their bound values and result rows.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+
+use cdrs::authenticators::Authenticator;
+use cdrs::consistency::Consistency;
+use cdrs::frame::{Frame, Opcode};
+use cdrs::frame::events::{ChangeSchemeOptions, ChangeType, SchemaChange, ServerEvent, Target};
+use cdrs::frame::frame_response::ResponseBody;
+use cdrs::frame::frame_result::{BodyResResultPrepared, BodyResResultRows, ColSpec, ResResultBody,
+                                RowsMetadata};
+use cdrs::frame::frame_query::QueryFlags;
+use cdrs::query::{QueryParams, QueryParamsBuilder};
+use cdrs::transport::CDRSTransport;
+use cdrs::IntoBytes;
+use cdrs::types::{CBytes, CBytesShort};
+use futures::future;
+use futures::Future;
+use futures::stream::Stream;
+
+use client::{CDRSFuture, Session};
+use decode;
+use paging::Page;
+use rows::TryFromRow;
+use values::IntoQueryValues;
+use validation::{self, Validation};
+use error;
+
+/// Prepared statement along with metadata of its bind markers and result columns.
+/// It's created by `Session::prepare_statement`.
+#[derive(Debug, Clone)]
+pub struct PreparedStatement {
+    pub id: CBytesShort,
+    /// Text of the statement. It's needed to prepare it again on another connection.
+    pub query: String,
+    /// Bind markers in order of the statement.
+    pub params_metadata: Vec<ColSpec>,
+    /// Columns of result rows. It's empty for statements without results, e.g. inserts.
+    pub result_metadata: Vec<ColSpec>,
+}
+
+impl PreparedStatement {
+    /// Builds a statement from a response to PREPARE request of `query`.
+    pub fn from_frame(query: String, frame: Frame) -> error::Result<PreparedStatement> {
+        let prepared = try!(prepared_result(frame));
+        Ok(PreparedStatement {
+               id: prepared.id,
+               query: query,
+               params_metadata: prepared.metadata.col_specs,
+               result_metadata: prepared.result_metadata.col_specs,
+           })
+    }
+
+    /// Works as `from_frame` and puts the statement along with its result metadata
+    /// into `cache`, so executions can skip the metadata.
+    pub fn from_frame_cached(query: String,
+                             frame: Frame,
+                             cache: &Mutex<PreparedCache>)
+                             -> error::Result<PreparedStatement> {
+        // `prepared_result` reports a server error of an ERROR frame
+        let raw_metadata = match frame.opcode {
+            Opcode::Result => try!(prepared_result_metadata(&frame.body)),
+            _ => None,
+        };
+        let prepared = try!(prepared_result(frame));
+        let statement = PreparedStatement {
+            id: prepared.id.clone(),
+            query: query,
+            params_metadata: prepared.metadata.col_specs,
+            result_metadata: prepared.result_metadata.col_specs.clone(),
+        };
+
+        let mut cache = cache.lock().unwrap();
+        cache.insert(statement.query.clone(), prepared.id, prepared.result_metadata);
+        if let Some(raw_metadata) = raw_metadata {
+            cache.set_raw_result_metadata(&statement.query, raw_metadata);
+        }
+        Ok(statement)
+    }
+}
+
+/// Identifies a prepared statement to execute, see `Session::execute`.
+pub trait StatementId {
+    fn statement_id(&self) -> &CBytesShort;
+
+    /// Text of the statement if its result metadata may be in a prepared cache,
+    /// see `PreparedCache::restore_metadata`.
+    fn query(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl StatementId for CBytesShort {
+    fn statement_id(&self) -> &CBytesShort {
+        self
+    }
+}
+
+impl StatementId for PreparedStatement {
+    fn statement_id(&self) -> &CBytesShort {
+        &self.id
+    }
+
+    fn query(&self) -> Option<&str> {
+        Some(&self.query)
+    }
+}
+
+impl<P, R> StatementId for TypedPrepared<P, R> {
+    fn statement_id(&self) -> &CBytesShort {
+        &self.id
+    }
+}
+
+/// Reads a body of a response to PREPARE request. A server error becomes `Err`.
+fn prepared_result(frame: Frame) -> error::Result<BodyResResultPrepared> {
+    match try!(frame.get_body()) {
+        ResponseBody::Result(ResResultBody::Prepared(prepared)) => Ok(prepared),
+        ResponseBody::Error(_) => Err(error::Error::from_error_body(&frame.body)),
+        _ => Err("Unexpected type of frame. Prepared result is expected".into()),
+    }
+}
+
+/// Prepared statement which binds values of type `P` and maps result rows into `R`.
+///
+/// It's created by `Session::prepare_typed_as` which checks that `P` provides
+/// as many values as the statement has bind markers. Metadata of its results is
+/// kept in a prepared cache of the session.
+#[derive(Debug)]
+pub struct TypedPrepared<P, R> {
+    id: CBytesShort,
+    query: String,
+    markers: Vec<ColSpec>,
+    cache: Arc<Mutex<PreparedCache>>,
+    consistency: Consistency,
+    validation: Validation,
+    types: PhantomData<fn(P) -> R>,
+}
+
+impl<P: IntoQueryValues, R: TryFromRow> TypedPrepared<P, R> {
+    /// Builds a statement from a response to PREPARE request of `query`
+    /// and puts it into `cache`.
+    pub fn from_frame(query: String,
+                      frame: Frame,
+                      cache: Arc<Mutex<PreparedCache>>)
+                      -> error::Result<TypedPrepared<P, R>> {
+        let prepared = try!(prepared_result(frame));
+        let markers = prepared.metadata.col_specs;
+        try!(check_arity(markers.len(), P::arity()));
+
+        // statements without results, e.g. inserts, have a table in metadata of markers
+        let markers_table = prepared.metadata
+            .global_table_spec
+            .as_ref()
+            .map(|&(ref keyspace, ref table)| {
+                     (keyspace.as_str().to_string(), table.as_str().to_string())
+                 });
+        {
+            let mut cache = cache.lock().unwrap();
+            cache.insert(query.clone(), prepared.id.clone(), prepared.result_metadata);
+            cache.set_markers(&query, markers.iter().map(|spec| spec.name.as_plain()).collect());
+            if let Some((keyspace, table)) = markers_table {
+                cache.set_table(&query, keyspace, table);
+            }
+        }
+
+        Ok(TypedPrepared {
+               id: prepared.id,
+               query: query,
+               markers: markers,
+               cache: cache,
+               consistency: Consistency::One,
+               validation: Validation::default(),
+               types: PhantomData,
+           })
+    }
+
+    /// Server side id of the statement.
+    pub fn id(&self) -> &CBytesShort {
+        &self.id
+    }
+
+    /// Text of the statement. It's needed to prepare it again on another connection.
+    pub fn query(&self) -> &str {
+        self.query.as_str()
+    }
+
+    /// Number of bind markers in the statement.
+    pub fn markers(&self) -> usize {
+        self.markers.len()
+    }
+
+    /// The method overrides consistency of executions. It's `One` by default.
+    pub fn consistency(&mut self, consistency: Consistency) -> &mut Self {
+        self.consistency = consistency;
+        self
+    }
+
+    /// The method turns validation of bound values on or off. It's off by default.
+    pub fn validation(&mut self, validation: Validation) -> &mut Self {
+        self.validation = validation;
+        self
+    }
+
+    /// Binds `params` checking them according to the validation setting.
+    pub fn query_parameters(&self, params: P) -> error::Result<QueryParams> {
+        let values = params.into_query_values();
+        match self.validation {
+            Validation::Strict => {
+                let types: Vec<_> = self.markers.iter().map(|spec| &spec.col_type).collect();
+                try!(validation::validate(&types, &values));
+            }
+            Validation::Off => try!(check_arity(self.markers.len(), Some(values.len()))),
+        }
+
+        let mut query_parameters = QueryParamsBuilder::new(self.consistency.clone())
+            .values(values)
+            .finalize();
+        if self.cache.lock().unwrap().skip_metadata(&self.query) {
+            query_parameters.flags.push(QueryFlags::SkipMetadata);
+        }
+        Ok(query_parameters)
+    }
+
+    /// Executes the statement with `params` and converts rows of the first page.
+    /// It resolves into the session along with the rows.
+    pub fn execute<T, X>(&self,
+                         session: Session<T, X>,
+                         params: P)
+                         -> CDRSFuture<(Session<T, X>, Vec<R>)>
+        where T: Authenticator + Send + 'static,
+              X: CDRSTransport + 'static,
+              R: Send + 'static
+    {
+        let query_parameters = match self.query_parameters(params) {
+            Ok(query_parameters) => query_parameters,
+            Err(err) => return future::err(err).boxed(),
+        };
+
+        let cache = self.cache.clone();
+        let query = self.query.clone();
+        let executor = session.decode_executor().cloned();
+        session.execute(&self.id, query_parameters, false, false)
+            .and_then(move |(session, frame)| {
+                          let page = try!(cache.lock().unwrap().decode_page(&query, frame));
+                          Ok((session, page))
+                      })
+            .and_then(move |(session, page)| {
+                          decode::decode_rows(executor.as_ref(), page.rows)
+                              .map(move |rows| (session, rows))
+                      })
+            .boxed()
+    }
+}
+
+/// Default number of statements kept by `PreparedCache`.
+pub const DEFAULT_CACHE_CAPACITY: usize = 1000;
+/// Error code of `Unprepared` server error.
+pub const UNPREPARED: i32 = 0x2500;
+
+/// Flag of rows metadata which says that a paging state follows.
+const HAS_MORE_PAGES: i32 = 0x0002;
+/// Flag of rows metadata which says that column specs are omitted.
+const NO_METADATA: i32 = 0x0004;
+/// Flag of protocol v5 rows metadata which says that result metadata of a statement
+/// changed and its new id follows.
+const METADATA_CHANGED: i32 = 0x0008;
+/// Kind of a RESULT body with rows.
+const RESULT_ROWS: i32 = 0x0002;
+/// Flag of metadata which says that a single table spec precedes column specs.
+const GLOBAL_TABLES_SPEC: i32 = 0x0001;
+
+/// A statement known to `PreparedCache`.
+#[derive(Debug, Clone)]
+pub struct CachedStatement {
+    pub id: CBytesShort,
+    /// Keyspace and table of results if a server reported them.
+    pub table: Option<(String, String)>,
+    pub result_metadata: RowsMetadata,
+    /// Id of the result metadata a protocol v5 server assigned, it's sent with
+    /// executions. It's `None` on v4 connections.
+    pub result_metadata_id: Option<CBytesShort>,
+    /// Names of bind markers, in order.
+    pub markers: Vec<String>,
+    /// Result metadata as a server sent it, without a paging state. It's empty
+    /// unless the statement was cached by `PreparedStatement::from_frame_cached`.
+    pub raw_result_metadata: Vec<u8>,
+    /// Result metadata may be outdated, so it has to be requested with the next execution.
+    pub stale: bool,
+    last_used: u64,
+}
+
+/// Prepared statements of a session along with metadata of their results.
+///
+/// If skipping of metadata is turned on with `set_skip_metadata`, executions of
+/// a statement with fresh metadata ask a server to skip metadata in responses
+/// and rows are decoded with the cached one. A schema change of
+/// a table marks metadata of statements which read it stale, so the next execution
+/// gets metadata from a server and refreshes the cache. A dropped table or keyspace
+/// evicts statements which use it. Schema changes are not delivered to a session,
+/// they come from another connection which listens for them, see
+/// `Session::follow_schema_changes` and `Pool::follow_schema_changes`.
+///
+/// An execution which uses an id of a statement evicted meanwhile fails with
+/// a server error, the next one prepares the statement again and fails if its
+/// table is still missing.
+///
+/// On protocol v5 a server tracks result metadata itself: an execution sends the id of
+/// metadata the client has and a response which says the metadata changed carries
+/// new metadata along with its id, see `swap_metadata`. A cache belongs to a single
+/// session, so ids of v5 connections and v4 ones without them don't mix.
+///
+/// The least recently used statements are evicted when the cache is full.
+#[derive(Debug)]
+pub struct PreparedCache {
+    capacity: usize,
+    statements: HashMap<String, CachedStatement>,
+    clock: u64,
+    skips_metadata: bool,
+}
+
+impl Default for PreparedCache {
+    fn default() -> PreparedCache {
+        PreparedCache::with_capacity(DEFAULT_CACHE_CAPACITY)
+    }
+}
+
+impl PreparedCache {
+    pub fn new() -> PreparedCache {
+        PreparedCache::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> PreparedCache {
+        PreparedCache {
+            capacity: capacity,
+            statements: HashMap::new(),
+            clock: 0,
+            skips_metadata: false,
+        }
+    }
+
+    /// Turns skipping of result metadata on or off, it's off by default. Rows
+    /// are decoded with outdated metadata if a table changes while schema change
+    /// events don't reach the cache, so it should be on only if they do.
+    pub fn set_skip_metadata(&mut self, skip_metadata: bool) {
+        self.skips_metadata = skip_metadata;
+    }
+
+    /// Changes the number of kept statements, evicting the least recently used
+    /// ones if there are more.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn insert(&mut self, query: String, id: CBytesShort, result_metadata: RowsMetadata) {
+        self.clock += 1;
+        let table = result_metadata.global_table_space
+            .as_ref()
+            .and_then(|spec| if spec.len() == 2 {
+                          Some((spec[0].as_str().to_string(), spec[1].as_str().to_string()))
+                      } else {
+                          None
+                      });
+
+        self.statements.insert(query,
+                               CachedStatement {
+                                   id: id,
+                                   table: table,
+                                   result_metadata: result_metadata,
+                                   result_metadata_id: None,
+                                   markers: vec![],
+                                   raw_result_metadata: vec![],
+                                   stale: false,
+                                   last_used: self.clock,
+                               });
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.statements.len() > self.capacity {
+            let oldest = self.statements
+                .iter()
+                .min_by_key(|&(_, statement)| statement.last_used)
+                .map(|(query, _)| query.clone());
+            match oldest {
+                Some(query) => self.statements.remove(&query),
+                None => break,
+            };
+        }
+    }
+
+    /// Sets an id of result metadata a v5 server returned along with a statement.
+    pub fn set_result_metadata_id(&mut self, query: &str, id: CBytesShort) {
+        if let Some(statement) = self.statements.get_mut(query) {
+            statement.result_metadata_id = Some(id);
+        }
+    }
+
+    /// Replaces result metadata of a statement and its id at once, after a v5
+    /// response said the metadata changed.
+    pub fn swap_metadata(&mut self, query: &str, id: CBytesShort, result_metadata: RowsMetadata) {
+        if let Some(statement) = self.statements.get_mut(query) {
+            statement.result_metadata = result_metadata;
+            statement.result_metadata.paging_state = None;
+            statement.result_metadata_id = Some(id);
+            statement.stale = false;
+        }
+    }
+
+    pub fn set_raw_result_metadata(&mut self, query: &str, raw_metadata: Vec<u8>) {
+        if let Some(statement) = self.statements.get_mut(query) {
+            statement.raw_result_metadata = raw_metadata;
+        }
+    }
+
+    pub fn set_markers(&mut self, query: &str, markers: Vec<String>) {
+        if let Some(statement) = self.statements.get_mut(query) {
+            statement.markers = markers;
+        }
+    }
+
+    /// Sets a table of a statement if its results didn't tell it.
+    pub fn set_table(&mut self, query: &str, keyspace: String, table: String) {
+        if let Some(statement) = self.statements.get_mut(query) {
+            if statement.table.is_none() {
+                statement.table = Some((keyspace, table));
+            }
+        }
+    }
+
+    pub fn get(&self, query: &str) -> Option<&CachedStatement> {
+        self.statements.get(query)
+    }
+
+    /// Returns an id of a statement and marks the statement used.
+    pub fn id(&mut self, query: &str) -> Option<CBytesShort> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.statements.get_mut(query).map(|statement| {
+                                               statement.last_used = clock;
+                                               statement.id.clone()
+                                           })
+    }
+
+    /// Evicts a statement, e.g. after a server reported that it's unprepared.
+    pub fn remove(&mut self, query: &str) {
+        self.statements.remove(query);
+    }
+
+    pub fn len(&self) -> usize {
+        self.statements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    /// Returns `true` if executions of `query` can skip result metadata.
+    pub fn skip_metadata(&self, query: &str) -> bool {
+        self.skips_metadata &&
+        self.get(query)
+            .map(|statement| !statement.stale && !statement.result_metadata.col_specs.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Returns `true` if responses to executions of `query` which skip result
+    /// metadata can get it back with `restore_metadata`.
+    pub fn can_restore_metadata(&self, query: &str) -> bool {
+        self.skips_metadata &&
+        self.get(query)
+            .map(|statement| !statement.stale && !statement.raw_result_metadata.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Puts cached result metadata of `query` into a response which skipped it,
+    /// so it's decoded as if the metadata was sent. Metadata of a response, e.g. one
+    /// to an execution after a schema change, replaces the cached one.
+    pub fn restore_metadata(&mut self, query: &str, frame: &mut Frame) -> error::Result<()> {
+        if frame.opcode != Opcode::Result {
+            return Ok(());
+        }
+        let statement = match self.statements.get_mut(query) {
+            Some(statement) => statement,
+            None => return Ok(()),
+        };
+
+        if !statement.raw_result_metadata.is_empty() {
+            let restored = try!(insert_metadata(&frame.body, &statement.raw_result_metadata));
+            if let Some(body) = restored {
+                frame.body = body;
+                return Ok(());
+            }
+        }
+
+        let raw_metadata = match try!(rows_metadata(&frame.body)) {
+            Some(raw_metadata) => raw_metadata,
+            None => return Ok(()),
+        };
+        if raw_metadata != statement.raw_result_metadata {
+            if let ResponseBody::Result(ResResultBody::Rows(rows_body)) = try!(frame.get_body()) {
+                statement.result_metadata = rows_body.metadata;
+                statement.result_metadata.paging_state = None;
+            }
+            statement.raw_result_metadata = raw_metadata;
+        }
+        statement.stale = false;
+        Ok(())
+    }
+
+    /// Evicts statements which use a table.
+    pub fn invalidate_table(&mut self, keyspace: &str, table: &str) {
+        self.statements.retain(|_, statement| match statement.table {
+                                   Some((ref ks, ref t)) => ks != keyspace || t != table,
+                                   None => true,
+                               });
+    }
+
+    /// Evicts statements which use any table of a keyspace.
+    pub fn invalidate_keyspace(&mut self, keyspace: &str) {
+        self.statements.retain(|_, statement| match statement.table {
+                                   Some((ref ks, _)) => ks != keyspace,
+                                   None => true,
+                               });
+    }
+
+    /// Marks metadata of statements affected by a schema change stale and evicts
+    /// statements of a dropped table or keyspace. A change of a user defined type
+    /// affects every table of its keyspace.
+    pub fn on_schema_change(&mut self, change: &SchemaChange) {
+        if change.change_type == ChangeType::Dropped {
+            match change.options {
+                ChangeSchemeOptions::Keyspace(ref keyspace) => {
+                    self.invalidate_keyspace(keyspace)
+                }
+                ChangeSchemeOptions::TableType((ref keyspace, ref table))
+                    if change.target == Target::Table => self.invalidate_table(keyspace, table),
+                _ => (),
+            }
+        }
+
+        let (keyspace, table) = match change.options {
+            ChangeSchemeOptions::Keyspace(ref keyspace) => (keyspace, None),
+            ChangeSchemeOptions::TableType((ref keyspace, _)) if change.target == Target::Type => {
+                (keyspace, None)
+            }
+            ChangeSchemeOptions::TableType((ref keyspace, ref table)) => (keyspace, Some(table)),
+            _ => return,
+        };
+
+        for statement in self.statements.values_mut() {
+            let affected = match statement.table {
+                Some((ref ks, ref t)) => ks == keyspace && table.map_or(true, |table| t == table),
+                None => true,
+            };
+            if affected {
+                statement.stale = true;
+            }
+        }
+    }
+
+    /// Decodes a response to an execution of `query`. Rows without metadata are decoded
+    /// with the cached one, metadata of a response replaces the cached one.
+    pub fn decode_page(&mut self, query: &str, frame: Frame) -> error::Result<Page> {
+        if let Some(statement) = self.statements.get(query) {
+            if frame.opcode == Opcode::Result {
+                let rows_body = try!(rows_without_metadata(&frame.body,
+                                                           &statement.result_metadata));
+                if let Some(rows_body) = rows_body {
+                    return Page::from_body(ResponseBody::Result(ResResultBody::Rows(rows_body)));
+                }
+            }
+        }
+
+        let rows_body = match try!(frame.get_body()) {
+            ResponseBody::Result(ResResultBody::Rows(rows_body)) => rows_body,
+            body => return Page::from_response(&frame, body),
+        };
+
+        if let Some(statement) = self.statements.get_mut(query) {
+            statement.result_metadata = rows_body.metadata.clone();
+            statement.result_metadata.paging_state = None;
+            statement.stale = false;
+        }
+
+        Page::from_body(ResponseBody::Result(ResResultBody::Rows(rows_body)))
+    }
+}
+
+/// Returns `true` if `frame` is an `Unprepared` error, i.e. a server doesn't know
+/// an executed statement anymore.
+pub fn is_unprepared(frame: &Frame) -> bool {
+    let mut reader = BodyReader { body: &frame.body, position: 0 };
+    frame.opcode == Opcode::Error && reader.int().ok() == Some(UNPREPARED)
+}
+
+/// Builds a body of a protocol v5 EXECUTE request: ids of a statement and of its
+/// result metadata followed by serialized query parameters. A v4 body has no
+/// metadata id.
+pub fn execute_body_v5(id: &CBytesShort,
+                       result_metadata_id: &CBytesShort,
+                       query_parameters: Vec<u8>)
+                       -> Vec<u8> {
+    let mut body = id.clone().into_cbytes();
+    body.extend(result_metadata_id.clone().into_cbytes());
+    body.extend(query_parameters);
+    body
+}
+
+/// Reads a new result metadata id from a protocol v5 RESULT body with rows.
+/// It's `None` unless the body says that result metadata changed.
+pub fn new_metadata_id(body: &[u8]) -> error::Result<Option<CBytesShort>> {
+    let mut reader = BodyReader { body: body, position: 0 };
+    if try!(reader.int()) != RESULT_ROWS {
+        return Ok(None);
+    }
+    let flags = try!(reader.int());
+    try!(reader.int()); // columns count
+    if flags & HAS_MORE_PAGES != 0 {
+        let len = try!(reader.int());
+        try!(reader.take(len.max(0) as usize));
+    }
+    if flags & METADATA_CHANGED == 0 {
+        return Ok(None);
+    }
+
+    let len = try!(reader.take(2));
+    let len = ((len[0] as usize) << 8) | len[1] as usize;
+    let id = try!(reader.take(len));
+    Ok(Some(CBytesShort::new(id.to_vec())))
+}
+
+/// Reads result metadata of a PREPARED body as `CachedStatement::raw_result_metadata`.
+/// It's `None` for statements without results.
+fn prepared_result_metadata(body: &[u8]) -> error::Result<Option<Vec<u8>>> {
+    let mut reader = BodyReader { body: body, position: 0 };
+    try!(reader.int()); // kind
+    try!(reader.short_bytes()); // id
+    let flags = try!(reader.int());
+    let count = try!(reader.int());
+    let pk_count = try!(reader.int());
+    try!(reader.take(2 * pk_count.max(0) as usize));
+    try!(reader.col_specs(flags, count));
+
+    let flags = try!(reader.int());
+    let count = try!(reader.int());
+    if flags & NO_METADATA != 0 || count == 0 {
+        return Ok(None);
+    }
+    let specs = reader.position;
+    try!(reader.col_specs(flags, count));
+    Ok(Some(metadata_bytes(flags, count, &body[specs..reader.position])))
+}
+
+/// Reads metadata of a RESULT body with rows without its paging state. It's `None`
+/// for other bodies and ones which skipped metadata.
+fn rows_metadata(body: &[u8]) -> error::Result<Option<Vec<u8>>> {
+    let mut reader = BodyReader { body: body, position: 0 };
+    if try!(reader.int()) != RESULT_ROWS {
+        return Ok(None);
+    }
+    let flags = try!(reader.int());
+    let count = try!(reader.int());
+    if flags & HAS_MORE_PAGES != 0 {
+        let len = try!(reader.int());
+        try!(reader.take(len.max(0) as usize));
+    }
+    if flags & METADATA_CHANGED != 0 {
+        try!(reader.short_bytes());
+    }
+    if flags & NO_METADATA != 0 {
+        return Ok(None);
+    }
+
+    let specs = reader.position;
+    try!(reader.col_specs(flags, count));
+    Ok(Some(metadata_bytes(flags, count, &body[specs..reader.position])))
+}
+
+fn metadata_bytes(flags: i32, count: i32, specs: &[u8]) -> Vec<u8> {
+    let mut metadata = Vec::with_capacity(8 + specs.len());
+    push_int(&mut metadata, flags & GLOBAL_TABLES_SPEC);
+    push_int(&mut metadata, count);
+    metadata.extend_from_slice(specs);
+    metadata
+}
+
+/// Puts `metadata` of `rows_metadata` into a RESULT body with rows which skipped it.
+/// It's `None` for other bodies.
+fn insert_metadata(body: &[u8], metadata: &[u8]) -> error::Result<Option<Vec<u8>>> {
+    let mut reader = BodyReader { body: body, position: 0 };
+    if try!(reader.int()) != RESULT_ROWS {
+        return Ok(None);
+    }
+    let flags = try!(reader.int());
+    try!(reader.int()); // columns count
+    if flags & NO_METADATA == 0 {
+        return Ok(None);
+    }
+    let paging_state = reader.position;
+    if flags & HAS_MORE_PAGES != 0 {
+        let len = try!(reader.int());
+        try!(reader.take(len.max(0) as usize));
+    }
+    let rows = reader.position;
+
+    let mut metadata_reader = BodyReader { body: metadata, position: 0 };
+    let metadata_flags = try!(metadata_reader.int());
+
+    let mut restored = Vec::with_capacity(body.len() + metadata.len());
+    restored.extend_from_slice(&body[..4]);
+    push_int(&mut restored, metadata_flags | (flags & HAS_MORE_PAGES));
+    restored.extend_from_slice(&metadata[4..8]);
+    restored.extend_from_slice(&body[paging_state..rows]);
+    restored.extend_from_slice(&metadata[8..]);
+    restored.extend_from_slice(&body[rows..]);
+    Ok(Some(restored))
+}
+
+/// Reads rows of a RESULT body which skipped metadata along with `metadata`, as cdrs
+/// reads column specs regardless of the flag. It's `None` for other bodies.
+fn rows_without_metadata(body: &[u8],
+                         metadata: &RowsMetadata)
+                         -> error::Result<Option<BodyResResultRows>> {
+    let mut reader = BodyReader { body: body, position: 0 };
+    if try!(reader.int()) != RESULT_ROWS {
+        return Ok(None);
+    }
+    let flags = try!(reader.int());
+    let columns_count = try!(reader.int());
+    if flags & NO_METADATA == 0 {
+        return Ok(None);
+    }
+    let mut metadata = metadata.clone();
+    metadata.paging_state = None;
+    if flags & HAS_MORE_PAGES != 0 {
+        metadata.paging_state = Some(CBytes::new(try!(reader.bytes())));
+    }
+
+    let rows_count = try!(reader.int());
+    let mut rows_content = Vec::with_capacity(rows_count.max(0) as usize);
+    for _ in 0..rows_count {
+        let mut row = Vec::with_capacity(columns_count.max(0) as usize);
+        for _ in 0..columns_count {
+            row.push(CBytes::new(try!(reader.bytes())));
+        }
+        rows_content.push(row);
+    }
+    Ok(Some(BodyResResultRows {
+                metadata: metadata,
+                rows_count: rows_count,
+                rows_content: rows_content,
+            }))
+}
+
+fn push_int(bytes: &mut Vec<u8>, i: i32) {
+    bytes.extend_from_slice(&[(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+}
+
+struct BodyReader<'a> {
+    body: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BodyReader<'a> {
+    fn take(&mut self, len: usize) -> error::Result<&'a [u8]> {
+        if self.body.len() - self.position < len {
+            return Err("RESULT body is shorter than its metadata says".into());
+        }
+        let bytes = &self.body[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn int(&mut self) -> error::Result<i32> {
+        let bytes = try!(self.take(4));
+        Ok(((bytes[0] as i32) << 24) | ((bytes[1] as i32) << 16) | ((bytes[2] as i32) << 8) |
+           bytes[3] as i32)
+    }
+
+    /// Reads `[bytes]`, null is read as empty bytes as cdrs does.
+    fn bytes(&mut self) -> error::Result<Vec<u8>> {
+        let len = try!(self.int());
+        self.take(len.max(0) as usize).map(|bytes| bytes.to_vec())
+    }
+
+    fn short(&mut self) -> error::Result<u16> {
+        let bytes = try!(self.take(2));
+        Ok(((bytes[0] as u16) << 8) | bytes[1] as u16)
+    }
+
+    /// Skips `[short bytes]` or `[string]`.
+    fn short_bytes(&mut self) -> error::Result<&'a [u8]> {
+        let len = try!(self.short());
+        self.take(len as usize)
+    }
+
+    /// Skips `count` column specs of metadata with `flags`.
+    fn col_specs(&mut self, flags: i32, count: i32) -> error::Result<()> {
+        if flags & GLOBAL_TABLES_SPEC != 0 {
+            try!(self.short_bytes());
+            try!(self.short_bytes());
+        }
+        for _ in 0..count {
+            if flags & GLOBAL_TABLES_SPEC == 0 {
+                try!(self.short_bytes());
+                try!(self.short_bytes());
+            }
+            try!(self.short_bytes());
+            try!(self.col_type());
+        }
+        Ok(())
+    }
+
+    /// Skips an `[option]` of a column type along with types it's made of.
+    fn col_type(&mut self) -> error::Result<()> {
+        match try!(self.short()) {
+            // custom
+            0x0000 => {
+                try!(self.short_bytes());
+            }
+            // list and set
+            0x0020 | 0x0022 => try!(self.col_type()),
+            // map
+            0x0021 => {
+                try!(self.col_type());
+                try!(self.col_type());
+            }
+            // user defined type
+            0x0030 => {
+                try!(self.short_bytes());
+                try!(self.short_bytes());
+                for _ in 0..try!(self.short()) {
+                    try!(self.short_bytes());
+                    try!(self.col_type());
+                }
+            }
+            // tuple
+            0x0031 => {
+                for _ in 0..try!(self.short()) {
+                    try!(self.col_type());
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Passes schema changes of `events` to `cache`. It's meant to run on a thread
+/// of an event listener registered for schema changes and returns once the
+/// events end. See `Session::follow_schema_changes` for a listener on a reactor.
+pub fn follow_schema_changes<I>(cache: Arc<Mutex<PreparedCache>>, events: I)
+    where I: IntoIterator<Item = ServerEvent>
+{
+    for event in events {
+        if let ServerEvent::SchemaChange(ref change) = event {
+            cache.lock().unwrap().on_schema_change(change);
+        }
+    }
+}
+
+/// Prepared caches of several sessions, e.g. of sessions of a pool, which follow
+/// the same schema changes. Clones share the caches. Caches of dropped sessions
+/// are forgotten.
+#[derive(Debug, Clone, Default)]
+pub struct PreparedCaches {
+    caches: Arc<Mutex<Vec<Weak<Mutex<PreparedCache>>>>>,
+}
+
+impl PreparedCaches {
+    pub fn new() -> PreparedCaches {
+        PreparedCaches::default()
+    }
+
+    pub fn add(&self, cache: &Arc<Mutex<PreparedCache>>) {
+        self.caches.lock().unwrap().push(Arc::downgrade(cache));
+    }
+
+    /// Passes `change` to every cache, see `PreparedCache::on_schema_change`.
+    pub fn on_schema_change(&self, change: &SchemaChange) {
+        self.caches.lock().unwrap().retain(|cache| match cache.upgrade() {
+                                               Some(cache) => {
+                                                   cache.lock().unwrap().on_schema_change(change);
+                                                   true
+                                               }
+                                               None => false,
+                                           });
+    }
+}
+
+/// Passes schema changes of `events`, e.g. of `Session::listen_for_async`, to every
+/// one of `caches`. Resolves once the events end and fails with their error.
+pub fn follow_schema_events<S>(caches: Vec<PreparedCaches>, events: S) -> CDRSFuture<()>
+    where S: Stream<Item = ServerEvent, Error = error::Error> + Send + 'static
+{
+    events.for_each(move |event| {
+                       if let ServerEvent::SchemaChange(ref change) = event {
+                           for caches in &caches {
+                               caches.on_schema_change(change);
+                           }
+                       }
+                       Ok(())
+                   })
+        .boxed()
+}
+
+/// Default number of statements kept by `PreparedRegistry`.
+pub const DEFAULT_REGISTRY_CAPACITY: usize = 1000;
+
+#[derive(Debug, Clone)]
+struct RegisteredStatement {
+    /// Ids are assigned by each host separately.
+    ids: HashMap<SocketAddr, CBytesShort>,
+    last_used: u64,
+}
+
+/// Statements prepared through pools, shared by them, so every new session
+/// prepares them before it serves requests and their first executions don't
+/// hit `Unprepared` errors. The least recently used statements are evicted
+/// when the registry is full.
+#[derive(Debug)]
+pub struct PreparedRegistry {
+    capacity: usize,
+    statements: HashMap<String, RegisteredStatement>,
+    clock: u64,
+}
+
+impl Default for PreparedRegistry {
+    fn default() -> PreparedRegistry {
+        PreparedRegistry::new(DEFAULT_REGISTRY_CAPACITY)
+    }
+}
+
+impl PreparedRegistry {
+    pub fn new(capacity: usize) -> PreparedRegistry {
+        PreparedRegistry {
+            capacity: capacity,
+            statements: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Adds a statement, or marks a known one used.
+    pub fn register(&mut self, query: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        self.statements
+            .entry(query.to_string())
+            .or_insert_with(|| {
+                                RegisteredStatement {
+                                    ids: HashMap::new(),
+                                    last_used: clock,
+                                }
+                            })
+            .last_used = clock;
+
+        while self.statements.len() > self.capacity {
+            let oldest = self.statements
+                .iter()
+                .min_by_key(|&(_, statement)| statement.last_used)
+                .map(|(query, _)| query.clone());
+            match oldest {
+                Some(query) => self.statements.remove(&query),
+                None => break,
+            };
+        }
+    }
+
+    /// Remembers an id `host` assigned to a registered statement.
+    pub fn set_id(&mut self, query: &str, host: SocketAddr, id: CBytesShort) {
+        if let Some(statement) = self.statements.get_mut(query) {
+            statement.ids.insert(host, id);
+        }
+    }
+
+    /// Returns an id of a statement on `host` and marks the statement used.
+    pub fn id(&mut self, query: &str, host: &SocketAddr) -> Option<CBytesShort> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.statements.get_mut(query).and_then(|statement| {
+                                                    statement.last_used = clock;
+                                                    statement.ids.get(host).cloned()
+                                                })
+    }
+
+    /// Registered statements, the most recently used first.
+    pub fn statements(&self) -> Vec<String> {
+        let mut statements: Vec<_> = self.statements.iter().collect();
+        statements.sort_by_key(|&(_, statement)| Reverse(statement.last_used));
+        statements.into_iter().map(|(query, _)| query.clone()).collect()
+    }
+
+    /// Forgets ids of a host, e.g. when it leaves a cluster.
+    pub fn remove_host(&mut self, host: &SocketAddr) {
+        for statement in self.statements.values_mut() {
+            statement.ids.remove(host);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.statements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+}
+
+fn check_arity(markers: usize, values: Option<usize>) -> error::Result<()> {
+    match values {
+        Some(values) if values != markers => {
+            Err(error::Error::BindArity {
+                    markers: markers,
+                    values: values,
+                })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::types::rows::Row;
+
+    use super::*;
+    use client::Session;
+    use mock::{self, MockTransport, ERROR, READY, RESULT, REGISTER, EVENT};
+    use rows;
+    use error;
+
+    #[derive(Debug, PartialEq)]
+    struct User {
+        id: i32,
+        name: String,
+    }
+
+    impl TryFromRow for User {
+        fn try_from_row(row: Row) -> error::Result<User> {
+            Ok(User {
+                   id: try!(rows::column(&row, "id", "id")),
+                   name: try!(rows::column(&row, "name", "name")),
+               })
+        }
+    }
+
+    const SELECT_USERS: &'static str = "SELECT id, name FROM users WHERE group = ? AND age > ?";
+
+    fn prepared_response(markers: &[(&str, u16)]) -> Vec<u8> {
+        let columns = [("id", mock::INT), ("name", mock::VARCHAR)];
+        mock::response(RESULT, 0, &mock::prepared_body(b"users", markers, &columns))
+    }
+
+    #[test]
+    fn prepares_statement_with_metadata() {
+        use cdrs::query::QueryParamsBuilder;
+
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("group", mock::INT), ("age", mock::INT)]));
+        let (_, prepared) = mock::session(transport)
+            .prepare_statement(SELECT_USERS.to_string())
+            .wait()
+            .unwrap();
+
+        assert_eq!(prepared.id.clone().into_plain(), b"users".to_vec());
+        assert_eq!(prepared.query, SELECT_USERS);
+        let columns = |specs: &[ColSpec]| -> Vec<String> {
+            specs.iter()
+                .map(|spec| format!("{} {:?}", spec.name.as_str(), spec.col_type.id))
+                .collect()
+        };
+        assert_eq!(columns(&prepared.params_metadata), vec!["group Int", "age Int"]);
+        assert_eq!(columns(&prepared.result_metadata), vec!["id Int", "name Varchar"]);
+
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        let params = QueryParamsBuilder::new(Consistency::One).finalize();
+        mock::session(transport.clone()).execute(&prepared, params, false, false).wait().unwrap();
+        assert_eq!(&transport.written()[9..9 + 2 + 5], b"\x00\x05users");
+    }
+
+    #[test]
+    fn prepare_statement_fails_on_server_errors() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(0x2200, "no table t")));
+        let result = mock::session(transport)
+            .prepare_statement("SELECT a FROM t".to_string())
+            .wait();
+        match result {
+            Err(error::Error::Server { ref error, ref message }) => {
+                assert_eq!(*error, error::ServerError::Invalid);
+                assert_eq!(message, "no table t");
+            }
+            other => panic!("server error expected, got {:?}", other.map(|(_, p)| p.id)),
+        }
+    }
+
+    #[test]
+    fn executes_into_struct() {
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("group", mock::INT), ("age", mock::INT)]));
+        let (_, prepared) = mock::session(transport)
+            .prepare_typed_as::<(i32, i32), User>(SELECT_USERS.to_string())
+            .wait()
+            .unwrap();
+        assert_eq!(prepared.markers(), 2);
+
+        let transport = MockTransport::new();
+        let rows = vec![vec![mock::int(1), mock::text("alice")],
+                        vec![mock::int(2), mock::text("bob")]];
+        let columns = [("id", mock::INT), ("name", mock::VARCHAR)];
+        transport.push_read(mock::response(RESULT, 0, &mock::rows_body(&columns, &rows, None)));
+        let (_, users) = prepared
+            .execute(mock::session(transport), (10, 18))
+            .wait()
+            .unwrap();
+
+        assert_eq!(users,
+                   vec![User {
+                            id: 1,
+                            name: "alice".to_string(),
+                        },
+                        User {
+                            id: 2,
+                            name: "bob".to_string(),
+                        }]);
+    }
+
+    #[test]
+    fn fails_on_arity_mismatch() {
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("group", mock::INT)]));
+        let result = mock::session(transport)
+            .prepare_typed_as::<(i32, i32), User>(SELECT_USERS.to_string())
+            .wait();
+
+        match result {
+            Err(err @ error::Error::BindArity { markers: 1, values: 2 }) => {
+                let message = format!("{}", err);
+                assert!(message.contains("1") && message.contains("2"));
+            }
+            other => panic!("BindArity expected, got {:?}", other.map(|(_, p)| p.markers())),
+        }
+    }
+
+    /// Flags of query parameters of an EXECUTE frame of a statement with id `users`.
+    fn execute_flags(transport: &MockTransport) -> u8 {
+        transport.written()[9 + 2 + b"users".len() + 2]
+    }
+
+    const SKIP_METADATA: u8 = 0x02;
+
+    #[test]
+    fn refreshes_metadata_after_schema_change() {
+        use cdrs::frame::events::{ChangeType, SchemaChange};
+
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("group", mock::INT), ("age", mock::INT)]));
+        let session = mock::session(transport);
+        let cache = session.prepared_cache();
+        cache.lock().unwrap().set_skip_metadata(true);
+        let (_, prepared) = session
+            .prepare_typed_as::<(i32, i32), User>(SELECT_USERS.to_string())
+            .wait()
+            .unwrap();
+
+        let transport = MockTransport::new();
+        let rows = vec![vec![mock::int(1), mock::text("alice")]];
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::rows_body_without_metadata(2, &rows, None)));
+        let (_, users) = prepared
+            .execute(mock::session(transport.clone()), (10, 18))
+            .wait()
+            .unwrap();
+        assert_eq!(execute_flags(&transport) & SKIP_METADATA, SKIP_METADATA);
+        assert_eq!(users[0].name, "alice");
+
+        // ALTER TABLE users ADD email text
+        cache.lock()
+            .unwrap()
+            .on_schema_change(&SchemaChange {
+                                   change_type: ChangeType::Updated,
+                                   target: Target::Table,
+                                   options: ChangeSchemeOptions::TableType(("ks".to_string(),
+                                                                            "table".to_string())),
+                               });
+        assert!(cache.lock().unwrap().get(SELECT_USERS).unwrap().stale);
+
+        let transport = MockTransport::new();
+        let rows = vec![vec![mock::int(1), mock::text("alice"), mock::text("a@example.com")]];
+        let columns = [("id", mock::INT), ("name", mock::VARCHAR), ("email", mock::VARCHAR)];
+        transport.push_read(mock::response(RESULT, 0, &mock::rows_body(&columns, &rows, None)));
+        prepared
+            .execute(mock::session(transport.clone()), (10, 18))
+            .wait()
+            .unwrap();
+        assert_eq!(execute_flags(&transport) & SKIP_METADATA, 0);
+
+        let cache = cache.lock().unwrap();
+        let statement = cache.get(SELECT_USERS).unwrap();
+        assert!(!statement.stale);
+        assert_eq!(statement.result_metadata.col_specs.len(), 3);
+    }
+
+    #[test]
+    fn follows_schema_changes_of_listener() {
+        use std::io;
+        use codec::EVENT_STREAM_ID;
+
+        let events = MockTransport::new();
+        events.push_read(mock::response(READY, 0, &[]));
+        events.push_read(mock::response(EVENT,
+                                        EVENT_STREAM_ID,
+                                        &mock::schema_event_body("UPDATED", "ks", "table")));
+        events.push_read_error(io::ErrorKind::ConnectionReset);
+        let listener = mock::session(events.clone());
+
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("group", mock::INT), ("age", mock::INT)]));
+        let (session, _) = mock::session(transport)
+            .prepare_typed_as::<(i32, i32), User>(SELECT_USERS.to_string())
+            .wait()
+            .unwrap();
+        let cache = session.prepared_cache();
+        assert!(!cache.lock().unwrap().get(SELECT_USERS).unwrap().stale);
+
+        // the follower ends along with the connection of the listener
+        assert!(session.follow_schema_changes(listener).wait().is_err());
+        assert_eq!(mock::opcodes(&events.written()), vec![REGISTER]);
+        assert!(cache.lock().unwrap().get(SELECT_USERS).unwrap().stale);
+    }
+
+    #[test]
+    fn skips_result_metadata_of_prepared_statements() {
+        use cdrs::frame::events::{ChangeType, SchemaChange};
+        use cdrs::query::QueryParamsBuilder;
+        use cdrs::types::IntoRustByName;
+
+        let names: Vec<String> = (0..31).map(|i| format!("column_{:02}", i)).collect();
+        let columns: Vec<(&str, u16)> = names.iter()
+            .map(|name| (name.as_str(), mock::INT))
+            .collect();
+        let row = |count: i32| vec![(0..count).map(mock::int).collect::<Vec<_>>()];
+
+        // 30 int columns: 657 bytes of a response with metadata, 256 without it
+        let full = mock::rows_body(&columns[..30], &row(30), None);
+        let skipped = mock::rows_body_without_metadata(30, &row(30), None);
+        assert_eq!((full.len(), skipped.len()), (657, 256));
+
+        let transport = MockTransport::new();
+        let prepared_body = mock::prepared_body(b"users", &[], &columns[..30]);
+        transport.push_read(mock::response(RESULT, 0, &prepared_body));
+        transport.push_read(mock::response(RESULT, 0, &skipped));
+        transport.push_read(mock::response(RESULT, 0, &mock::rows_body(&columns, &row(31), None)));
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::rows_body_without_metadata(31, &row(31), None)));
+
+        let session = mock::session(transport.clone());
+        let cache = session.prepared_cache();
+        cache.lock().unwrap().set_skip_metadata(true);
+        let query = "SELECT * FROM ks.table";
+        let (session, prepared) = session.prepare_statement(query.to_string()).wait().unwrap();
+
+        // flags of EXECUTE and the last column of the first row
+        let execute = |session: Session<NoneAuthenticator, MockTransport>, columns: usize| {
+            let start = transport.written().len();
+            let params = QueryParamsBuilder::new(Consistency::One).finalize();
+            let (session, frame) = session.execute(&prepared, params, false, false)
+                .wait()
+                .unwrap();
+            let flags = transport.written()[start + 9 + 2 + b"users".len() + 2];
+            let rows = Page::from_frame(frame).unwrap().rows;
+            let last: i32 = rows[0].get_by_name(&names[columns - 1]).unwrap().unwrap();
+            (session, flags & SKIP_METADATA, last)
+        };
+
+        let (session, skip, last) = execute(session, 30);
+        assert_eq!((skip, last), (SKIP_METADATA, 29));
+
+        // ALTER TABLE ks.table ADD column_30 int
+        cache.lock()
+            .unwrap()
+            .on_schema_change(&SchemaChange {
+                                   change_type: ChangeType::Updated,
+                                   target: Target::Table,
+                                   options: ChangeSchemeOptions::TableType(("ks".to_string(),
+                                                                            "table".to_string())),
+                               });
+        let (session, skip, last) = execute(session, 31);
+        assert_eq!((skip, last), (0, 30));
+
+        let (_, skip, last) = execute(session, 31);
+        assert_eq!((skip, last), (SKIP_METADATA, 30));
+        let cache = cache.lock().unwrap();
+        assert_eq!(cache.get(query).unwrap().result_metadata.col_specs.len(), 31);
+    }
+
+    #[test]
+    fn keeps_result_metadata_by_default() {
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("group", mock::INT), ("age", mock::INT)]));
+        let (_, prepared) = mock::session(transport)
+            .prepare_typed_as::<(i32, i32), User>(SELECT_USERS.to_string())
+            .wait()
+            .unwrap();
+
+        let transport = MockTransport::new();
+        let columns = [("id", mock::INT), ("name", mock::VARCHAR)];
+        let rows = vec![vec![mock::int(1), mock::text("alice")]];
+        transport.push_read(mock::response(RESULT, 0, &mock::rows_body(&columns, &rows, None)));
+        prepared.execute(mock::session(transport.clone()), (10, 18)).wait().unwrap();
+        assert_eq!(execute_flags(&transport) & SKIP_METADATA, 0);
+    }
+
+    #[test]
+    fn registry_evicts_least_recently_used() {
+        let host = "127.0.0.1:9042".parse().unwrap();
+        let mut registry = PreparedRegistry::new(2);
+        registry.register("SELECT a FROM t");
+        registry.register("SELECT b FROM t");
+        registry.set_id("SELECT a FROM t", host, CBytesShort::new(b"a".to_vec()));
+
+        assert!(registry.id("SELECT a FROM t", &host).is_some());
+        registry.register("SELECT c FROM t");
+
+        assert_eq!(registry.statements(), vec!["SELECT c FROM t", "SELECT a FROM t"]);
+        assert_eq!(registry.id("SELECT a FROM t", &host).unwrap().into_plain(), b"a".to_vec());
+        assert!(registry.id("SELECT c FROM t", &host).is_none());
+    }
+
+    fn table_dropped(keyspace: &str, table: &str) -> SchemaChange {
+        SchemaChange {
+            change_type: ChangeType::Dropped,
+            target: Target::Table,
+            options: ChangeSchemeOptions::TableType((keyspace.to_string(), table.to_string())),
+        }
+    }
+
+    #[test]
+    fn evicts_statements_of_dropped_tables() {
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("group", mock::INT), ("age", mock::INT)]));
+        let session = mock::session(transport);
+        let cache = session.prepared_cache();
+        let (_, prepared) = session
+            .prepare_typed_as::<(i32, i32), User>(SELECT_USERS.to_string())
+            .wait()
+            .unwrap();
+
+        follow_schema_changes(cache.clone(),
+                              vec![ServerEvent::SchemaChange(table_dropped("ks", "other"))]);
+        assert!(cache.lock().unwrap().get(SELECT_USERS).is_some());
+        follow_schema_changes(cache.clone(),
+                              vec![ServerEvent::SchemaChange(table_dropped("ks", "table"))]);
+        assert!(cache.lock().unwrap().get(SELECT_USERS).is_none());
+
+        // an execution which still holds the id fails with a server error
+        let transport = MockTransport::new();
+        let unconfigured = mock::error_body(0x2200, "unconfigured table table");
+        transport.push_read(mock::response(ERROR, 0, &unconfigured));
+        transport.push_read(mock::response(ERROR, 0, &unconfigured));
+        match prepared.execute(mock::session(transport.clone()), (10, 18)).wait() {
+            Err(error::Error::Server { .. }) => (),
+            other => panic!("server error expected, got {:?}", other.map(|(_, users)| users.len())),
+        }
+        // and so does preparing it again
+        let session = mock::session(transport);
+        let cache = session.prepared_cache();
+        assert!(session
+                    .prepare_typed_as::<(i32, i32), User>(SELECT_USERS.to_string())
+                    .wait()
+                    .is_err());
+        assert_eq!(cache.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn evicts_statements_of_dropped_keyspace() {
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("group", mock::INT), ("age", mock::INT)]));
+        let session = mock::session(transport);
+        let cache = session.prepared_cache();
+        session
+            .prepare_typed_as::<(i32, i32), User>(SELECT_USERS.to_string())
+            .wait()
+            .unwrap();
+
+        let dropped = SchemaChange {
+            change_type: ChangeType::Dropped,
+            target: Target::Keyspace,
+            options: ChangeSchemeOptions::Keyspace("ks".to_string()),
+        };
+        cache.lock().unwrap().on_schema_change(&dropped);
+        assert_eq!(cache.lock().unwrap().len(), 0);
+    }
+
+    fn prepared_cache() -> Arc<Mutex<PreparedCache>> {
+        let transport = MockTransport::new();
+        transport.push_read(prepared_response(&[("group", mock::INT), ("age", mock::INT)]));
+        let session = mock::session(transport);
+        let cache = session.prepared_cache();
+        session.prepare_typed_as::<(i32, i32), User>(SELECT_USERS.to_string())
+            .wait()
+            .unwrap();
+        cache
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let users = prepared_cache();
+        let metadata = users.lock().unwrap().get(SELECT_USERS).unwrap().result_metadata.clone();
+        let mut cache = PreparedCache::with_capacity(2);
+        let insert = |cache: &mut PreparedCache, query: &str| {
+            cache.insert(query.to_string(), CBytesShort::new(vec![]), metadata.clone())
+        };
+        insert(&mut cache, "SELECT a FROM t");
+        insert(&mut cache, "SELECT b FROM t");
+
+        assert!(cache.id("SELECT a FROM t").is_some());
+        insert(&mut cache, "SELECT c FROM t");
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("SELECT b FROM t").is_none());
+        assert!(cache.get("SELECT a FROM t").is_some());
+
+        cache.set_capacity(1);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("SELECT c FROM t").is_some());
+    }
+
+    #[test]
+    fn sends_result_metadata_id_with_execute() {
+        let cache = prepared_cache();
+        let mut cache = cache.lock().unwrap();
+        assert!(cache.get(SELECT_USERS).unwrap().result_metadata_id.is_none());
+        cache.set_result_metadata_id(SELECT_USERS, CBytesShort::new(b"m1".to_vec()));
+
+        let statement = cache.get(SELECT_USERS).unwrap();
+        let metadata_id = statement.result_metadata_id.clone().unwrap();
+        let body = execute_body_v5(&statement.id, &metadata_id, vec![0, 1]);
+        assert_eq!(body, b"\x00\x05users\x00\x02m1\x00\x01".to_vec());
+    }
+
+    #[test]
+    fn swaps_metadata_when_it_changed() {
+        let mut body = vec![0, 0, 0, 2, 0, 0, 0, (METADATA_CHANGED | HAS_MORE_PAGES) as u8];
+        body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        body.extend_from_slice(b"ps\x00\x02m2");
+        let new_id = new_metadata_id(&body).unwrap().unwrap();
+        assert!(new_metadata_id(&mock::void_body()).unwrap().is_none());
+        assert!(new_metadata_id(&body[..body.len() - 1]).is_err());
+
+        let cache = prepared_cache();
+        let mut cache = cache.lock().unwrap();
+        cache.set_result_metadata_id(SELECT_USERS, CBytesShort::new(b"m1".to_vec()));
+        let mut metadata = cache.get(SELECT_USERS).unwrap().result_metadata.clone();
+        metadata.col_specs.truncate(1);
+        metadata.columns_count = 1;
+        cache.swap_metadata(SELECT_USERS, new_id, metadata);
+
+        let statement = cache.get(SELECT_USERS).unwrap();
+        assert_eq!(statement.result_metadata.columns_count, 1);
+        assert_eq!(statement.result_metadata_id.clone().unwrap().into_plain(),
+                   b"m2".to_vec());
+    }
+}
@@ -0,0 +1,315 @@
+//! Sessions which reconnect after their connection is lost.
+//!
+//! A `ReconnectingSession` opens sessions with a connector, e.g. one which
+//! connects a `TransportTcp` to a remembered address and starts a session with
+//! a remembered authenticator and compression. Everything a connection needs
+//! before it serves requests, e.g. `USE` of a keyspace or registration for events,
+//! belongs to the connector as well, so it's redone on every reconnection.
+//!
+//! Once a request fails with an IO error, or with an error which breaks the
+//! connection, the connector is retried with an exponential backoff. An idempotent
+//! request is sent once more on the new session, any other one fails with
+//! `Error::ConnectionReset`, since a server might have applied it.
+
+use std::cmp;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cdrs::authenticators::Authenticator;
+use cdrs::frame::Frame;
+use cdrs::query::Query;
+use cdrs::transport::CDRSTransport;
+use futures::future::{self, Future, Loop};
+use tokio_timer::Delay;
+
+use client::{self, CDRSFuture, Session};
+use codec;
+use pool::Connector;
+use request::RequestOptions;
+use error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReconnectOptions {
+    /// Delay before the second attempt to connect, the first one is made at once.
+    /// It doubles with every next attempt.
+    pub base: Duration,
+    /// The longest delay between attempts.
+    pub max: Duration,
+    /// Number of attempts after which reconnection gives up, `None` never does.
+    pub max_attempts: Option<usize>,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> ReconnectOptions {
+        ReconnectOptions {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectOptions {
+    /// Delay before a given attempt, the first one is 0.
+    pub fn delay(&self, attempt: usize) -> Duration {
+        if attempt == 0 {
+            return Duration::from_secs(0);
+        }
+        let factor = 1u32 << cmp::min(attempt - 1, 31);
+        self.base.checked_mul(factor).map_or(self.max, |delay| cmp::min(delay, self.max))
+    }
+}
+
+/// A session which is replaced by a new one once its connection is lost.
+pub struct ReconnectingSession<T: Authenticator + 'static, X: CDRSTransport + 'static> {
+    /// It's `None` after a connection is lost until it's replaced.
+    session: Option<Session<T, X>>,
+    connector: Connector<T, X>,
+    options: ReconnectOptions,
+    reconnects: usize,
+}
+
+impl<T, X> ReconnectingSession<T, X>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    /// Opens the first session with `connect`, with retries as on reconnection.
+    pub fn connect<F>(connect: F, options: ReconnectOptions) -> CDRSFuture<Self>
+        where F: Fn() -> CDRSFuture<Session<T, X>> + Send + Sync + 'static
+    {
+        let unconnected = ReconnectingSession {
+            session: None,
+            connector: Arc::new(connect),
+            options: options,
+            reconnects: 0,
+        };
+        unconnected.reconnect()
+            .map(|mut session| {
+                     session.reconnects = 0;
+                     session
+                 })
+            .boxed()
+    }
+
+    /// Number of times the session was replaced since it first connected.
+    pub fn reconnects(&self) -> usize {
+        self.reconnects
+    }
+
+    /// Sends a request, see `Session::try_request`. If the connection is lost
+    /// the session reconnects, and the request is sent again if it's `idempotent`.
+    /// A request which is not fails with `Error::ConnectionReset`. The future
+    /// fails only if reconnection gives up, other errors come along with the session.
+    pub fn request(self,
+                   frame: Frame,
+                   idempotent: bool)
+                   -> CDRSFuture<(Self, error::Result<Frame>)> {
+        let retry = if idempotent {
+            Some(codec::clone_frame(&frame))
+        } else {
+            None
+        };
+
+        self.request_once(frame)
+            .and_then(move |(session, result)| match result {
+                          Err(ref err) if is_connection_lost(err) => {
+                              session.reconnect()
+                                  .and_then(move |session| match retry {
+                                                Some(frame) => session.request_once(frame),
+                                                None => {
+                                                    let reset = error::Error::ConnectionReset;
+                                                    future::ok((session, Err(reset))).boxed()
+                                                }
+                                            })
+                                  .boxed()
+                          }
+                          result => future::ok((session, result)).boxed(),
+                      })
+            .boxed()
+    }
+
+    /// Sends a query with `options`, whether it's idempotent included, see `request`.
+    pub fn query(self,
+                 query: Query,
+                 options: RequestOptions)
+                 -> CDRSFuture<(Self, error::Result<Frame>)> {
+        self.request(client::query_frame(query, options.flags()),
+                     options.is_idempotent())
+    }
+
+    /// Sends an idempotent query, which is sent again if the connection is lost.
+    pub fn query_idempotent(self, query: Query) -> CDRSFuture<(Self, error::Result<Frame>)> {
+        self.query(query, RequestOptions::new().idempotent(true))
+    }
+
+    /// Sends a request on the current session, connecting one first if the
+    /// connection was lost.
+    fn request_once(self, frame: Frame) -> CDRSFuture<(Self, error::Result<Frame>)> {
+        let connected = if self.session.is_some() {
+            future::ok(self).boxed()
+        } else {
+            self.reconnect()
+        };
+
+        connected.and_then(|mut this| {
+                      let session = this.session.take().expect("session is connected");
+                      session.try_request(frame).map(move |(session, result)| {
+                          match result {
+                              Err(ref err) if is_connection_lost(err) => drop(session),
+                              _ => this.session = Some(session),
+                          }
+                          (this, result)
+                      })
+                  })
+            .boxed()
+    }
+
+    /// Opens a new session with the connector, retrying with a backoff.
+    fn reconnect(self) -> CDRSFuture<Self> {
+        let options = self.options;
+        future::loop_fn((self, 0), move |(mut this, attempt)| {
+            let delay = options.delay(attempt);
+            let connect = this.connector.clone();
+            Delay::new(Instant::now() + delay)
+                .map_err(|err| error::Error::Io(io::Error::new(io::ErrorKind::Other, err)))
+                .and_then(move |_| connect())
+                .then(move |result| match result {
+                          Ok(session) => {
+                              this.session = Some(session);
+                              this.reconnects += 1;
+                              Ok(Loop::Break(this))
+                          }
+                          Err(err) => {
+                              let attempts = attempt + 1;
+                              if options.max_attempts.map_or(false, |max| attempts >= max) {
+                                  Err(err)
+                              } else {
+                                  warn!("Reconnection attempt {} failed: {}", attempts, err);
+                                  Ok(Loop::Continue((this, attempts)))
+                              }
+                          }
+                      })
+        })
+                .boxed()
+    }
+}
+
+/// Returns `true` if a request failed because its connection is lost.
+fn is_connection_lost(err: &error::Error) -> bool {
+    match *err {
+        error::Error::Io(_) => true,
+        _ => err.breaks_connection(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+    use tokio_core::reactor::Core;
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::query::QueryBuilder;
+
+    use super::*;
+    use client::CDRS;
+    use mock::{self, RESULT};
+    use transport::TransportTcp;
+
+    /// Reads a frame and returns its stream id, `None` once a connection is closed.
+    fn read_frame(stream: &mut net::TcpStream) -> Option<i16> {
+        let mut header = [0; 9];
+        if stream.read_exact(&mut header).is_err() {
+            return None;
+        }
+        let len = ((header[5] as usize) << 24) | ((header[6] as usize) << 16) |
+                  ((header[7] as usize) << 8) | header[8] as usize;
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).unwrap();
+        Some(((header[2] as i16) << 8) | header[3] as i16)
+    }
+
+    fn respond(stream: &mut net::TcpStream, id: i16) {
+        stream.write_all(&mock::response(RESULT, id, &mock::void_body())).unwrap();
+    }
+
+    #[test]
+    fn reconnects_after_listener_restarts() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let id = read_frame(&mut stream).unwrap();
+            respond(&mut stream, id);
+            // the node goes down with the second request in flight
+            read_frame(&mut stream).unwrap();
+            drop(listener);
+            drop(stream);
+
+            thread::sleep(Duration::from_millis(150));
+            let listener = net::TcpListener::bind(addr).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut requests = 0;
+            while let Some(id) = read_frame(&mut stream) {
+                requests += 1;
+                respond(&mut stream, id);
+            }
+            requests
+        });
+
+        let options = ReconnectOptions {
+            base: Duration::from_millis(20),
+            max: Duration::from_millis(40),
+            max_attempts: None,
+        };
+        let address = addr.to_string();
+        let connect = move || {
+            TransportTcp::connect(&address)
+                .map_err(error::Error::from)
+                .map(|transport| Session::start(CDRS::new(transport, NoneAuthenticator)))
+                .boxed()
+        };
+
+        let mut core = Core::new().unwrap();
+        let session = core.run(ReconnectingSession::connect(connect, options)).unwrap();
+        let select = || QueryBuilder::new("SELECT * FROM t").finalize();
+        let (session, result) = core.run(session.query(select(), RequestOptions::new()))
+            .unwrap();
+        assert!(result.is_ok());
+
+        let (session, result) = core.run(session.query(select(), RequestOptions::new()))
+            .unwrap();
+        match result {
+            Err(error::Error::ConnectionReset) => {}
+            other => panic!("ConnectionReset expected, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(session.reconnects(), 1);
+
+        let (session, result) = core.run(session.query_idempotent(select())).unwrap();
+        assert!(result.is_ok());
+        assert_eq!(session.reconnects(), 1);
+
+        drop(session);
+        // the request which was in flight was not sent again
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let options = ReconnectOptions {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            max_attempts: Some(3),
+        };
+        let delays: Vec<_> = (0..6).map(|attempt| options.delay(attempt)).collect();
+        assert_eq!(delays,
+                   vec![Duration::from_millis(0),
+                        Duration::from_millis(100),
+                        Duration::from_millis(200),
+                        Duration::from_millis(400),
+                        Duration::from_millis(800),
+                        Duration::from_secs(1)]);
+        assert_eq!(options.delay(100), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,291 @@
+//! Options of a single request.
+
+use std::fmt;
+use std::time::Duration;
+
+use cdrs::consistency::Consistency;
+use cdrs::frame::Flag;
+use cdrs::frame::frame_query::QueryFlags;
+use cdrs::query::{Query, QueryBatch, QueryParams};
+
+/// Overrides whether a request frame is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Override {
+    /// Compress if a session negotiated a codec and a body is big enough.
+    Auto,
+    /// Always compress. It's an error if a session hasn't negotiated a codec.
+    ForceOn,
+    /// Never compress.
+    ForceOff,
+}
+
+impl Default for Override {
+    fn default() -> Override {
+        Override::Auto
+    }
+}
+
+/// Options which apply to one request only.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RequestOptions {
+    tracing: bool,
+    warnings: bool,
+    compression: Override,
+    idempotent: bool,
+    timeout: Option<Duration>,
+}
+
+impl RequestOptions {
+    pub fn new() -> RequestOptions {
+        RequestOptions::default()
+    }
+
+    /// Asks a server to trace the request.
+    pub fn tracing(mut self, tracing: bool) -> RequestOptions {
+        self.tracing = tracing;
+        self
+    }
+
+    /// Asks a server to return warnings along with a response.
+    pub fn warnings(mut self, warnings: bool) -> RequestOptions {
+        self.warnings = warnings;
+        self
+    }
+
+    /// Overrides whether the request is compressed. It only chooses if a frame
+    /// is compressed, responses are decoded according to their own flags anyway.
+    pub fn compression(mut self, compression: Override) -> RequestOptions {
+        self.compression = compression;
+        self
+    }
+
+    pub fn get_compression(&self) -> Override {
+        self.compression
+    }
+
+    /// Marks the request as one which can be applied several times with the same
+    /// effect, so a retry policy may send it again after a write timeout.
+    pub fn idempotent(mut self, idempotent: bool) -> RequestOptions {
+        self.idempotent = idempotent;
+        self
+    }
+
+    pub fn is_idempotent(&self) -> bool {
+        self.idempotent
+    }
+
+    /// Fails the request with `Error::RequestTimeout` if no response comes in
+    /// `timeout`. It overrides a default timeout of a session.
+    pub fn timeout(mut self, timeout: Duration) -> RequestOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Frame flags which correspond to the options.
+    pub fn flags(&self) -> Vec<Flag> {
+        let mut flags = vec![];
+        if self.tracing {
+            flags.push(Flag::Tracing);
+        }
+        if self.warnings {
+            flags.push(Flag::Warning);
+        }
+        flags
+    }
+}
+
+/// A query, query parameters of an execution or a batch which may leave its
+/// consistency to a session, see `Session::set_default_consistency`. Requests
+/// passed as they are keep their own consistency, e.g. one set by `QueryBuilder`.
+/// Serial consistency which is not set falls back to a default of a session anyway.
+#[derive(Debug, Clone)]
+pub struct Statement<R> {
+    request: R,
+    consistency: Option<Consistency>,
+}
+
+impl<R: Consistent> Statement<R> {
+    /// Leaves consistency of `request` to a session.
+    pub fn new(request: R) -> Statement<R> {
+        Statement {
+            request: request,
+            consistency: None,
+        }
+    }
+
+    /// Copies the statement with `copy` of the request, cdrs requests aren't `Clone`.
+    pub fn clone_with<F>(&self, copy: F) -> Statement<R>
+        where F: FnOnce(&R) -> R
+    {
+        Statement {
+            request: copy(&self.request),
+            consistency: self.consistency.clone(),
+        }
+    }
+
+    /// Sets consistency of the request, it wins over a default of a session.
+    pub fn consistency(mut self, consistency: Consistency) -> Statement<R> {
+        self.consistency = Some(consistency);
+        self
+    }
+
+    /// The request with consistency levels which are not set replaced by defaults.
+    /// Levels without defaults are left as the request has them.
+    pub fn resolve(self,
+                   consistency: Option<&Consistency>,
+                   serial_consistency: Option<&Consistency>)
+                   -> R {
+        let mut request = self.request;
+        if let Some(consistency) = self.consistency.as_ref().or(consistency) {
+            request.set_consistency(consistency.clone());
+        }
+        if let Some(serial_consistency) = serial_consistency {
+            if !request.has_serial_consistency() {
+                request.set_serial_consistency(serial_consistency.clone());
+            }
+        }
+        request
+    }
+}
+
+impl<R: Consistent> From<R> for Statement<R> {
+    fn from(request: R) -> Statement<R> {
+        let consistency = request.get_consistency();
+        Statement::new(request).consistency(consistency)
+    }
+}
+
+/// Requests which carry consistency levels, see `Statement`.
+pub trait Consistent {
+    fn get_consistency(&self) -> Consistency;
+
+    fn set_consistency(&mut self, consistency: Consistency);
+
+    fn has_serial_consistency(&self) -> bool;
+
+    fn set_serial_consistency(&mut self, serial_consistency: Consistency);
+}
+
+impl Consistent for Query {
+    fn get_consistency(&self) -> Consistency {
+        self.consistency.clone()
+    }
+
+    fn set_consistency(&mut self, consistency: Consistency) {
+        self.consistency = consistency;
+    }
+
+    fn has_serial_consistency(&self) -> bool {
+        self.serial_consistency.is_some()
+    }
+
+    fn set_serial_consistency(&mut self, serial_consistency: Consistency) {
+        self.serial_consistency = Some(serial_consistency);
+    }
+}
+
+impl Consistent for QueryParams {
+    fn get_consistency(&self) -> Consistency {
+        self.consistency.clone()
+    }
+
+    fn set_consistency(&mut self, consistency: Consistency) {
+        self.consistency = consistency;
+    }
+
+    fn has_serial_consistency(&self) -> bool {
+        self.serial_consistency.is_some()
+    }
+
+    // parameters are serialized according to their flags
+    fn set_serial_consistency(&mut self, serial_consistency: Consistency) {
+        self.serial_consistency = Some(serial_consistency);
+        self.flags.push(QueryFlags::WithSerialConsistency);
+    }
+}
+
+impl Consistent for QueryBatch {
+    fn get_consistency(&self) -> Consistency {
+        self.consistency.clone()
+    }
+
+    fn set_consistency(&mut self, consistency: Consistency) {
+        self.consistency = consistency;
+    }
+
+    fn has_serial_consistency(&self) -> bool {
+        self.serial_consistency.is_some()
+    }
+
+    fn set_serial_consistency(&mut self, serial_consistency: Consistency) {
+        self.serial_consistency = Some(serial_consistency);
+        self.query_flags.push(QueryFlags::WithSerialConsistency);
+    }
+}
+
+/// Formats a query for logs and error context. Bound values may be sensitive,
+/// so only their number is shown unless redaction is turned off.
+pub struct DebugQuery<'a> {
+    query: &'a Query,
+    redact_values: bool,
+}
+
+impl<'a> DebugQuery<'a> {
+    pub fn new(query: &'a Query) -> DebugQuery<'a> {
+        DebugQuery {
+            query: query,
+            redact_values: true,
+        }
+    }
+
+    pub fn redact_values(mut self, redact_values: bool) -> DebugQuery<'a> {
+        self.redact_values = redact_values;
+        self
+    }
+}
+
+impl<'a> fmt::Debug for DebugQuery<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut query = f.debug_struct("Query");
+        query.field("query", &self.query.query)
+            .field("consistency", &self.query.consistency);
+
+        match self.query.values {
+            Some(ref values) if self.redact_values => {
+                query.field("values", &format_args!("<{} redacted>", values.len()))
+            }
+            ref values => query.field("values", values),
+        };
+
+        query.field("page_size", &self.query.page_size)
+            .field("paging_state", &self.query.paging_state.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cdrs::query::QueryBuilder;
+    use cdrs::types::value::Value;
+
+    use super::*;
+
+    #[test]
+    fn redacts_bound_values() {
+        let query = QueryBuilder::new("UPDATE users SET password = ? WHERE id = ?")
+            .values(vec![Value::from("hunter2"), Value::from(1)])
+            .finalize();
+
+        let redacted = format!("{:?}", DebugQuery::new(&query));
+        assert!(redacted.contains("UPDATE users SET password = ?"), "{}", redacted);
+        assert!(redacted.contains("<2 redacted>"), "{}", redacted);
+        assert!(!redacted.contains("body"), "{}", redacted);
+
+        let shown = format!("{:?}", DebugQuery::new(&query).redact_values(false));
+        assert!(shown.contains("body"), "{}", shown);
+    }
+}
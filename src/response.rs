@@ -0,0 +1,41 @@
+//! Responses along with what a server reports about a request besides its result.
+
+use std::sync::Arc;
+
+use cdrs::frame::Frame;
+use uuid::Uuid;
+
+use codec::CustomPayload;
+
+/// A handler of warnings of every response of a session, see `Session::on_warnings`.
+pub type WarningsHandler = Arc<Fn(&[String]) + Send + Sync>;
+
+/// A response with warnings of a server, the id of its trace and its custom payload.
+#[derive(Debug)]
+pub struct QueryResponse {
+    /// Warnings come only if a request asks for them, e.g. with
+    /// `RequestOptions::warnings`. A server sends them along with errors as well.
+    pub warnings: Vec<String>,
+    /// The id of a trace of a traced request, see `Session::get_tracing_info`.
+    pub tracing_id: Option<Uuid>,
+    /// A custom payload of the response, it's empty if a server sent none.
+    pub custom_payload: CustomPayload,
+    pub frame: Frame,
+}
+
+impl QueryResponse {
+    pub fn into_frame(self) -> Frame {
+        self.frame
+    }
+}
+
+impl From<Frame> for QueryResponse {
+    fn from(frame: Frame) -> QueryResponse {
+        QueryResponse {
+            warnings: frame.warnings.clone(),
+            tracing_id: frame.tracing_id,
+            custom_payload: CustomPayload::new(),
+            frame: frame,
+        }
+    }
+}
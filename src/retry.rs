@@ -0,0 +1,244 @@
+//! Retries of requests which failed with transient server errors.
+//!
+//! A server answers with `Read_timeout`, `Write_timeout`, `Unavailable` or
+//! `Overloaded` if it couldn't serve a request at the moment, though the same
+//! request may succeed if it's sent again. A retry policy decides whether it is
+//! and where. A session has a single connection, so it retries on the same node
+//! only, while `Cluster` can move on to the next one.
+
+use cdrs::frame::{Frame, Opcode};
+
+use error::ServerError;
+
+pub use backoff::{OVERLOADED, WRITE_TIMEOUT};
+
+/// Error code of `Unavailable` server error.
+pub const UNAVAILABLE: i32 = 0x1000;
+/// Error code of `Read_timeout` server error.
+pub const READ_TIMEOUT: i32 = 0x1200;
+
+/// What to do with a request which failed with a server error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RetryDecision {
+    /// Send the request to the same node again.
+    Retry,
+    /// Send the request to the next node of a plan. A session which has no
+    /// other nodes returns the error as it is.
+    RetryNextNode,
+    /// Return the error to a caller.
+    Rethrow,
+}
+
+pub trait RetryPolicy {
+    /// Decides what to do with a request which failed with a server error `code`
+    /// after it has been retried `retries` times. A write which timed out may have
+    /// been applied anyway, so it should be retried only if it's `idempotent`.
+    fn on_error(&self, code: i32, retries: usize, idempotent: bool) -> RetryDecision;
+
+    /// Works as `on_error` with details of the error, e.g. how many replicas
+    /// acknowledged a write which timed out. Falls back to `on_error` by default.
+    fn on_server_error(&self,
+                       error: &ServerError,
+                       retries: usize,
+                       idempotent: bool)
+                       -> RetryDecision {
+        self.on_error(error.code(), retries, idempotent)
+    }
+}
+
+/// Retries read timeouts and timeouts of idempotent writes on the same node,
+/// and sends requests which found a node unavailable or overloaded to the next
+/// one. Requests are retried at most `max_retries` times, once by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DefaultRetryPolicy {
+    max_retries: usize,
+}
+
+impl DefaultRetryPolicy {
+    pub fn new(max_retries: usize) -> DefaultRetryPolicy {
+        DefaultRetryPolicy { max_retries: max_retries }
+    }
+}
+
+impl Default for DefaultRetryPolicy {
+    fn default() -> DefaultRetryPolicy {
+        DefaultRetryPolicy::new(1)
+    }
+}
+
+impl RetryPolicy for DefaultRetryPolicy {
+    fn on_error(&self, code: i32, retries: usize, idempotent: bool) -> RetryDecision {
+        if retries >= self.max_retries {
+            return RetryDecision::Rethrow;
+        }
+
+        match code {
+            READ_TIMEOUT => RetryDecision::Retry,
+            WRITE_TIMEOUT if idempotent => RetryDecision::Retry,
+            UNAVAILABLE | OVERLOADED => RetryDecision::RetryNextNode,
+            _ => RetryDecision::Rethrow,
+        }
+    }
+}
+
+/// Never retries, every error is returned to a caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct FallthroughRetryPolicy;
+
+impl RetryPolicy for FallthroughRetryPolicy {
+    fn on_error(&self, _code: i32, _retries: usize, _idempotent: bool) -> RetryDecision {
+        RetryDecision::Rethrow
+    }
+}
+
+/// Error code of a response which is an ERROR frame.
+pub fn error_code(frame: &Frame) -> Option<i32> {
+    if frame.opcode != Opcode::Error || frame.body.len() < 4 {
+        return None;
+    }
+    let body = &frame.body;
+    Some(((body[0] as i32) << 24) | ((body[1] as i32) << 16) | ((body[2] as i32) << 8) |
+         body[3] as i32)
+}
+
+/// Server error of a response which is an ERROR frame.
+pub fn server_error(frame: &Frame) -> Option<ServerError> {
+    if frame.opcode != Opcode::Error {
+        return None;
+    }
+    match ServerError::from_body(&frame.body) {
+        Ok((error, _)) => Some(error),
+        Err(_) => error_code(frame).map(ServerError::Other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use cdrs::query::QueryBuilder;
+
+    use super::*;
+    use mock::{self, MockTransport, ERROR, QUERY, RESULT};
+    use request::RequestOptions;
+
+    /// Sends a query which fails with `code` once and then succeeds. Returns
+    /// the number of sent queries and the opcode of the final response.
+    fn query_failing_with<P>(policy: P, code: i32, idempotent: bool) -> (usize, u8)
+        where P: RetryPolicy + Send + Sync + 'static
+    {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(code, "try again")));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+
+        let mut session = mock::session(transport.clone());
+        session.retry_policy(policy);
+        let options = RequestOptions::new().idempotent(idempotent);
+        let (_, response) = session.query_with(QueryBuilder::new("INSERT INTO t").finalize(),
+                                               options)
+            .wait()
+            .unwrap();
+
+        let queries = mock::opcodes(&transport.written());
+        assert!(queries.iter().all(|&opcode| opcode == QUERY));
+        let opcode = if error_code(&response).is_some() {
+            ERROR
+        } else {
+            RESULT
+        };
+        (queries.len(), opcode)
+    }
+
+    #[test]
+    fn default_policy_retries_transient_errors() {
+        let policy = DefaultRetryPolicy::default();
+        assert_eq!(query_failing_with(policy, READ_TIMEOUT, false), (2, RESULT));
+        assert_eq!(query_failing_with(policy, WRITE_TIMEOUT, true), (2, RESULT));
+        assert_eq!(query_failing_with(policy, WRITE_TIMEOUT, false), (1, ERROR));
+        // a session can't move on to another node
+        assert_eq!(query_failing_with(policy, UNAVAILABLE, true), (1, ERROR));
+        assert_eq!(query_failing_with(policy, OVERLOADED, true), (1, ERROR));
+        assert_eq!(query_failing_with(policy, 0x2200, true), (1, ERROR));
+        assert_eq!(query_failing_with(DefaultRetryPolicy::new(0), READ_TIMEOUT, true),
+                   (1, ERROR));
+    }
+
+    #[test]
+    fn retries_write_timeouts_of_idempotent_queries_only() {
+        let insert = || QueryBuilder::new("INSERT INTO t").finalize();
+        let transport = MockTransport::new();
+        for _ in 0..2 {
+            transport.push_read(mock::response(ERROR, 0, &mock::error_body(WRITE_TIMEOUT, "")));
+            transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        }
+
+        let session = mock::session(transport.clone());
+        let (session, response) = session.query_idempotent(insert()).wait().unwrap();
+        assert_eq!(error_code(&response), None);
+        assert_eq!(mock::opcodes(&transport.written()).len(), 2);
+
+        let (_, response) = session.query(insert(), false, false).wait().unwrap();
+        assert_eq!(error_code(&response), Some(WRITE_TIMEOUT));
+        assert_eq!(mock::opcodes(&transport.written()).len(), 3);
+    }
+
+    /// Retries writes which timed out before any replica acknowledged them.
+    struct UnacknowledgedWrites;
+
+    impl RetryPolicy for UnacknowledgedWrites {
+        fn on_error(&self, _code: i32, _retries: usize, _idempotent: bool) -> RetryDecision {
+            RetryDecision::Rethrow
+        }
+
+        fn on_server_error(&self,
+                           error: &ServerError,
+                           retries: usize,
+                           _idempotent: bool)
+                           -> RetryDecision {
+            match *error {
+                ServerError::WriteTimeout { received: 0, .. } if retries == 0 => {
+                    RetryDecision::Retry
+                }
+                _ => RetryDecision::Rethrow,
+            }
+        }
+    }
+
+    #[test]
+    fn policies_decide_on_details_of_server_errors() {
+        let insert = || QueryBuilder::new("INSERT INTO t").finalize();
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(ERROR, 0, &mock::write_timeout_body(0)));
+        transport.push_read(mock::response(RESULT, 0, &mock::void_body()));
+        transport.push_read(mock::response(ERROR, 0, &mock::write_timeout_body(1)));
+
+        let mut session = mock::session(transport.clone());
+        session.retry_policy(UnacknowledgedWrites);
+        let (session, response) = session.query(insert(), false, false).wait().unwrap();
+        assert_eq!(error_code(&response), None);
+        assert_eq!(mock::opcodes(&transport.written()).len(), 2);
+
+        let (_, response) = session.query(insert(), false, false).wait().unwrap();
+        match server_error(&response) {
+            Some(ServerError::WriteTimeout { received, block_for, ref write_type, .. }) => {
+                assert_eq!((received, block_for, &write_type[..]), (1, 2, "SIMPLE"))
+            }
+            other => panic!("write timeout expected, got {:?}", other),
+        }
+        assert_eq!(mock::opcodes(&transport.written()).len(), 3);
+    }
+
+    #[test]
+    fn default_policy_bounds_retries() {
+        let policy = DefaultRetryPolicy::new(2);
+        assert_eq!(policy.on_error(READ_TIMEOUT, 1, false), RetryDecision::Retry);
+        assert_eq!(policy.on_error(READ_TIMEOUT, 2, false), RetryDecision::Rethrow);
+        assert_eq!(policy.on_error(UNAVAILABLE, 0, false), RetryDecision::RetryNextNode);
+    }
+
+    #[test]
+    fn fallthrough_policy_never_retries() {
+        for &code in &[READ_TIMEOUT, WRITE_TIMEOUT, UNAVAILABLE, OVERLOADED] {
+            assert_eq!(query_failing_with(FallthroughRetryPolicy, code, true), (1, ERROR));
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! Conversion of result rows into Rust types.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use cdrs::error as cdrs_error;
+use cdrs::frame::Frame;
+use cdrs::frame::frame_response::ResponseBody;
+use cdrs::frame::frame_result::{ColSpec, ColType, ResResultBody};
+use cdrs::types::{CBytes, IntoRustByName};
+use cdrs::types::rows::Row;
+use uuid::Uuid;
+
+use paging::Page;
+use error;
+
+/// Types which could be built from a single result row.
+///
+/// `cdrs_future_derive` provides `#[derive(TryFromRow)]` which maps fields
+/// of a struct to columns with the same names.
+pub trait TryFromRow: Sized {
+    fn try_from_row(row: Row) -> error::Result<Self>;
+}
+
+impl TryFromRow for Row {
+    fn try_from_row(row: Row) -> error::Result<Row> {
+        Ok(row)
+    }
+}
+
+/// Types which could be built from a result row by positions of its columns,
+/// e.g. tuples. Unlike `TryFromRow` they are given specs of the columns, so
+/// a conversion error tells a column along with its type.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row, columns: &[ColSpec]) -> error::Result<Self>;
+}
+
+/// Types a single column converts into. Null is an error unless it's read
+/// into an `Option`.
+pub trait FromColumn: Sized {
+    fn from_column(row: &Row, column: &ColSpec, field: &str) -> error::Result<Self>;
+}
+
+macro_rules! from_column {
+    ($type:ty, $name:expr, $($col_type:pat)|+) => {
+        impl FromColumn for $type {
+            fn from_column(row: &Row, column: &ColSpec, field: &str) -> error::Result<$type> {
+                match try!(<Option<$type>>::from_column(row, column, field)) {
+                    Some(value) => Ok(value),
+                    None => Err(conversion_error(column.name.as_str(), field, "null".to_string())),
+                }
+            }
+        }
+
+        impl FromColumn for Option<$type> {
+            fn from_column(row: &Row,
+                           column: &ColSpec,
+                           field: &str)
+                           -> error::Result<Option<$type>> {
+                match column.col_type.id {
+                    $($col_type)|+ => nullable_column(row, column.name.as_str(), field),
+                    ref actual => {
+                        let reason = format!("expected {}, column is {:?}", $name, actual);
+                        Err(conversion_error(column.name.as_str(), field, reason))
+                    }
+                }
+            }
+        }
+    }
+}
+
+from_column!(String, "String", ColType::Ascii | ColType::Varchar);
+from_column!(bool, "bool", ColType::Boolean);
+from_column!(i8, "i8", ColType::Tinyint);
+from_column!(i16, "i16", ColType::Smallint);
+from_column!(i32, "i32", ColType::Int);
+from_column!(i64,
+             "i64",
+             ColType::Bigint | ColType::Counter | ColType::Time | ColType::Timestamp);
+from_column!(f32, "f32", ColType::Float);
+from_column!(f64, "f64", ColType::Double);
+from_column!(IpAddr, "IpAddr", ColType::Inet);
+from_column!(Uuid, "Uuid", ColType::Uuid | ColType::Timeuuid);
+
+macro_rules! tuple_from_row {
+    ($arity:expr; $($name:ident: $index:expr),+) => {
+        impl<$($name: FromColumn),+> FromRow for ($($name,)+) {
+            fn from_row(row: &Row, columns: &[ColSpec]) -> error::Result<Self> {
+                if columns.len() != $arity {
+                    return Err(format!("Row of {} columns cannot be read into a tuple of {}",
+                                       columns.len(),
+                                       $arity)
+                                       .into());
+                }
+                Ok(($(try!($name::from_column(row, &columns[$index], stringify!($index))),)+))
+            }
+        }
+    }
+}
+
+tuple_from_row!(1; A: 0);
+tuple_from_row!(2; A: 0, B: 1);
+tuple_from_row!(3; A: 0, B: 1, C: 2);
+tuple_from_row!(4; A: 0, B: 1, C: 2, D: 3);
+tuple_from_row!(5; A: 0, B: 1, C: 2, D: 3, E: 4);
+tuple_from_row!(6; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+tuple_from_row!(7; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+tuple_from_row!(8; A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
+
+/// Converts rows of a response into `R`. Results without rows give no rows
+/// and a server error is returned as an error.
+pub fn rows_as<R: FromRow>(frame: Frame) -> error::Result<Vec<R>> {
+    match try!(frame.get_body()) {
+        ResponseBody::Result(ResResultBody::Rows(rows_body)) => {
+            let columns = rows_body.metadata.col_specs.clone();
+            Row::from_frame_body(rows_body)
+                .iter()
+                .map(|row| R::from_row(row, &columns))
+                .collect()
+        }
+        body => Page::from_response(&frame, body).map(|_| vec![]),
+    }
+}
+
+/// Reads a value of `column` which is mapped into `field`. Null is an error.
+pub fn column<T>(row: &Row, column: &str, field: &str) -> error::Result<T>
+    where Row: IntoRustByName<T>
+{
+    match row.get_by_name(column) {
+        Some(Ok(value)) => Ok(value),
+        Some(Err(err)) => Err(conversion_error(column, field, err.to_string())),
+        None => Err(conversion_error(column, field, "no such column".to_string())),
+    }
+}
+
+/// Reads a value of `column` which is mapped into `field`. Null is `None`.
+pub fn nullable_column<T>(row: &Row, column: &str, field: &str) -> error::Result<Option<T>>
+    where Row: IntoRustByName<T>
+{
+    match row.get_by_name(column) {
+        Some(Ok(value)) => Ok(Some(value)),
+        Some(Err(ref err)) if is_null(err) => Ok(None),
+        Some(Err(err)) => Err(conversion_error(column, field, err.to_string())),
+        None => Err(conversion_error(column, field, "no such column".to_string())),
+    }
+}
+
+/// A row as bytes of its cells, `None` is null.
+///
+/// Rows of system tables are read this way with the `*_cell` functions below,
+/// so they don't depend on conversions of `cdrs`.
+pub type RawRow = Vec<Option<Vec<u8>>>;
+
+/// Reads rows of a response as bytes of their cells.
+pub fn raw_rows(frame: Frame) -> error::Result<Vec<RawRow>> {
+    match try!(frame.get_body()) {
+        ResponseBody::Result(ResResultBody::Rows(rows)) => {
+            Ok(rows.rows_content
+                   .into_iter()
+                   .map(|row| row.into_iter().map(raw_cell).collect())
+                   .collect())
+        }
+        ResponseBody::Error(_) => Err(error::Error::from_error_body(&frame.body)),
+        _ => Err("Unexpected type of frame. Rows are expected".into()),
+    }
+}
+
+/// Bytes of a cell. `cdrs` reads null as empty bytes, so an empty cell is `None`.
+fn raw_cell(cell: CBytes) -> Option<Vec<u8>> {
+    let bytes = cell.into_plain();
+    if bytes.is_empty() { None } else { Some(bytes) }
+}
+
+/// Reads a `text` cell.
+pub fn text_cell(cell: Option<&Option<Vec<u8>>>) -> error::Result<Option<String>> {
+    match cell {
+        Some(&Some(ref bytes)) => {
+            String::from_utf8(bytes.clone())
+                .map(Some)
+                .map_err(|_| "Text of system tables is not valid UTF-8".into())
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Reads an `inet` cell.
+pub fn inet_cell(cell: Option<&Option<Vec<u8>>>) -> error::Result<Option<IpAddr>> {
+    let bytes = match cell {
+        Some(&Some(ref bytes)) => bytes,
+        _ => return Ok(None),
+    };
+    match bytes.len() {
+        4 => Ok(Some(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])))),
+        16 => {
+            let mut segments = [0u16; 8];
+            for (i, segment) in segments.iter_mut().enumerate() {
+                *segment = ((bytes[2 * i] as u16) << 8) | bytes[2 * i + 1] as u16;
+            }
+            Ok(Some(IpAddr::V6(Ipv6Addr::new(segments[0],
+                                              segments[1],
+                                              segments[2],
+                                              segments[3],
+                                              segments[4],
+                                              segments[5],
+                                              segments[6],
+                                              segments[7]))))
+        }
+        len => Err(format!("Address of {} bytes is neither IPv4 nor IPv6", len).into()),
+    }
+}
+
+/// Reads an `int` cell.
+pub fn int_cell(cell: Option<&Option<Vec<u8>>>) -> error::Result<Option<i32>> {
+    match cell {
+        Some(&Some(ref bytes)) if bytes.len() == 4 => Ok(Some(read_be(bytes) as i32)),
+        Some(&Some(ref bytes)) => Err(format!("Int of {} bytes", bytes.len()).into()),
+        _ => Ok(None),
+    }
+}
+
+/// Reads a `bigint` or a `timestamp` cell.
+pub fn bigint_cell(cell: Option<&Option<Vec<u8>>>) -> error::Result<Option<i64>> {
+    match cell {
+        Some(&Some(ref bytes)) if bytes.len() == 8 => Ok(Some(read_be(bytes) as i64)),
+        Some(&Some(ref bytes)) => Err(format!("Bigint of {} bytes", bytes.len()).into()),
+        _ => Ok(None),
+    }
+}
+
+/// Reads a `map<text, text>` cell, null is an empty map.
+pub fn text_map_cell(cell: Option<&Option<Vec<u8>>>) -> error::Result<HashMap<String, String>> {
+    let bytes = match cell {
+        Some(&Some(ref bytes)) => bytes,
+        _ => return Ok(HashMap::new()),
+    };
+    let read_text = |at: usize| -> error::Result<(String, usize)> {
+        let len = match bytes.get(at..at + 4) {
+            Some(len) => read_be(len) as usize,
+            None => return Err("Map of text is truncated".into()),
+        };
+        match bytes.get(at + 4..at + 4 + len) {
+            Some(text) => {
+                String::from_utf8(text.to_vec())
+                    .map(|text| (text, at + 4 + len))
+                    .map_err(|_| "Text of system tables is not valid UTF-8".into())
+            }
+            None => Err("Map of text is truncated".into()),
+        }
+    };
+
+    let count = match bytes.get(0..4) {
+        Some(count) => read_be(count) as usize,
+        None => return Err("Map of text is truncated".into()),
+    };
+    let mut at = 4;
+    let mut map = HashMap::with_capacity(count);
+    for _ in 0..count {
+        let (key, next) = try!(read_text(at));
+        let (value, next) = try!(read_text(next));
+        map.insert(key, value);
+        at = next;
+    }
+    Ok(map)
+}
+
+fn read_be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, byte| (acc << 8) | *byte as u64)
+}
+
+/// `cdrs` doesn't have a distinct error kind for null values, so an error is
+/// compared with the one `cdrs` reports for a cell without bytes rather than
+/// looked into for words which a conversion error may contain as well.
+fn is_null(err: &cdrs_error::Error) -> bool {
+    match (err, cdrs_error::column_is_empty_err()) {
+        (&cdrs_error::Error::General(ref message),
+         cdrs_error::Error::General(ref null_message)) => message == null_message,
+        _ => false,
+    }
+}
+
+fn conversion_error(column: &str, field: &str, reason: String) -> error::Error {
+    error::Error::Conversion {
+        column: column.to_string(),
+        field: field.to_string(),
+        reason: reason,
+    }
+}
@@ -0,0 +1,377 @@
+//! Full table scans split by token ranges.
+//!
+//! A scan of a whole table is executed as a number of queries each of which reads
+//! a single range of Murmur3 tokens, `token(pk) > start AND token(pk) <= end`.
+//! Ranges which follow a token ring are served by a single replica set each.
+//! `Cluster::scan` reads ranges of its token ring with a bounded number of
+//! queries at once, a session reads them one after another.
+
+use std::collections::VecDeque;
+use std::i64;
+
+use cdrs::query::{Query, QueryBuilder};
+use cdrs::types::value::Value;
+use futures::{Async, Poll, Stream};
+
+/// The lowest Murmur3 token. No key hashes to it, so ranges starting at it cover
+/// the whole beginning of the ring.
+pub const MIN_TOKEN: i64 = i64::MIN;
+/// The highest Murmur3 token.
+pub const MAX_TOKEN: i64 = i64::MAX;
+
+/// Range of tokens `(start, end]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TokenRange {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl TokenRange {
+    pub fn new(start: i64, end: i64) -> TokenRange {
+        TokenRange {
+            start: start,
+            end: end,
+        }
+    }
+}
+
+/// Splits the whole ring into `n` ranges of nearly equal size.
+pub fn split_even(n: usize) -> Vec<TokenRange> {
+    let n = if n == 0 { 1 } else { n as u64 };
+    let step = u64::max_value() / n;
+
+    let mut ranges = Vec::with_capacity(n as usize);
+    let mut start = MIN_TOKEN;
+    for i in 1..n + 1 {
+        let end = if i == n {
+            MAX_TOKEN
+        } else {
+            (MIN_TOKEN as u64).wrapping_add(step * i) as i64
+        };
+        ranges.push(TokenRange::new(start, end));
+        start = end;
+    }
+    ranges
+}
+
+/// Builds ranges between consecutive tokens of a ring. The range which wraps around
+/// from the highest token to the lowest one is split in two, as `start < end`
+/// has to hold for a query to match anything.
+pub fn ring_ranges(tokens: &[i64]) -> Vec<TokenRange> {
+    let mut tokens = tokens.to_vec();
+    tokens.sort();
+    tokens.dedup();
+
+    let (first, last) = match (tokens.first(), tokens.last()) {
+        (Some(&first), Some(&last)) => (first, last),
+        _ => return split_even(1),
+    };
+
+    let mut ranges = vec![];
+    if first != MIN_TOKEN {
+        ranges.push(TokenRange::new(MIN_TOKEN, first));
+    }
+    for pair in tokens.windows(2) {
+        ranges.push(TokenRange::new(pair[0], pair[1]));
+    }
+    if last != MAX_TOKEN {
+        ranges.push(TokenRange::new(last, MAX_TOKEN));
+    }
+    ranges
+}
+
+/// A query which reads a whole table and the columns of its partition key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanQuery {
+    template: String,
+    partition_key: Vec<String>,
+}
+
+impl ScanQuery {
+    /// `template` is a `SELECT` of a table, it may have a `WHERE` clause of its own.
+    /// `partition_key` lists partition key columns in their order.
+    pub fn new(template: &str, partition_key: &[&str]) -> ScanQuery {
+        ScanQuery {
+            template: template.trim().trim_right_matches(';').to_string(),
+            partition_key: partition_key.iter().map(|column| column.to_string()).collect(),
+        }
+    }
+
+    /// CQL of a query which reads a single token range. The range is bound
+    /// as two `bigint` values. Predicates of the range go right after the
+    /// conditions of the template, before its `GROUP BY`, `ORDER BY`, `LIMIT`
+    /// or `ALLOW FILTERING`.
+    pub fn cql(&self) -> String {
+        let token = format!("token({})", self.partition_key.join(", "));
+        let words = words(&self.template);
+        let conjunction = if find_keyword(&words, &["WHERE"]).is_some() {
+            "AND"
+        } else {
+            "WHERE"
+        };
+        let tail = TAIL_CLAUSES.iter()
+            .filter_map(|clause| find_keyword(&words, clause))
+            .min()
+            .unwrap_or(self.template.len());
+
+        let mut cql = format!("{} {} {} > ? AND {} <= ?",
+                              self.template[..tail].trim_end(),
+                              conjunction,
+                              token,
+                              token);
+        if tail < self.template.len() {
+            cql.push(' ');
+            cql.push_str(&self.template[tail..]);
+        }
+        cql
+    }
+
+    /// Query which reads a given range.
+    pub fn query_for(&self, range: &TokenRange) -> Query {
+        QueryBuilder::new(self.cql())
+            .values(vec![Value::from(range.start), Value::from(range.end)])
+            .finalize()
+    }
+}
+
+/// Clauses of a `SELECT` which follow its `WHERE` clause.
+const TAIL_CLAUSES: &'static [&'static [&'static str]] = &[&["GROUP", "BY"],
+                                                           &["ORDER", "BY"],
+                                                           &["PER", "PARTITION", "LIMIT"],
+                                                           &["LIMIT"],
+                                                           &["ALLOW", "FILTERING"]];
+
+/// Words of a statement outside of quotes along with their offsets.
+fn words(cql: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    let mut quote = None;
+    for (i, c) in cql.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c.is_alphanumeric() || c == '_' => {
+                if start.is_none() {
+                    start = Some(i);
+                }
+            }
+            None => {
+                if let Some(start) = start.take() {
+                    words.push((start, &cql[start..i]));
+                }
+                if c == '\'' || c == '"' {
+                    quote = Some(c);
+                }
+            }
+        }
+    }
+    if let (Some(start), None) = (start, quote) {
+        words.push((start, &cql[start..]));
+    }
+    words
+}
+
+/// Offset of the first occurrence of `keyword`, a sequence of words.
+fn find_keyword(words: &[(usize, &str)], keyword: &[&str]) -> Option<usize> {
+    words.windows(keyword.len())
+        .find(|window| {
+                  window.iter()
+                      .zip(keyword)
+                      .all(|(&(_, word), expected)| word.eq_ignore_ascii_case(expected))
+              })
+        .map(|window| window[0].0)
+}
+
+/// Stream which polls up to `limit` streams at once and yields their items
+/// in the order they come. The next stream starts as soon as one ends.
+pub struct BoundedMerge<S> {
+    pending: VecDeque<S>,
+    active: Vec<S>,
+    limit: usize,
+    next: usize,
+}
+
+/// Merges `streams` polling at most `limit` of them at once. Streams have to be
+/// lazy, e.g. not send a request before they are polled, for the limit to hold.
+pub fn merge_bounded<S: Stream>(streams: Vec<S>, limit: usize) -> BoundedMerge<S> {
+    BoundedMerge {
+        pending: streams.into_iter().collect(),
+        active: vec![],
+        limit: if limit == 0 { 1 } else { limit },
+        next: 0,
+    }
+}
+
+impl<S: Stream> Stream for BoundedMerge<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        loop {
+            while self.active.len() < self.limit {
+                match self.pending.pop_front() {
+                    Some(stream) => self.active.push(stream),
+                    None => break,
+                }
+            }
+            if self.active.is_empty() {
+                return Ok(Async::Ready(None));
+            }
+
+            let mut ended = false;
+            for _ in 0..self.active.len() {
+                let i = self.next % self.active.len();
+                match try!(self.active[i].poll()) {
+                    Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                    Async::Ready(None) => {
+                        self.active.remove(i);
+                        ended = true;
+                        break;
+                    }
+                    Async::NotReady => self.next = i + 1,
+                }
+            }
+            if !ended {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::sync::{Arc, Mutex};
+    use futures::{stream, task, Future};
+    use cdrs::types::IntoRustByName;
+    use cdrs::types::value::Value;
+
+    use super::*;
+    use mock::{self, MockTransport, RESULT};
+
+    #[test]
+    fn splits_ring_evenly() {
+        let ranges = split_even(4);
+        assert_eq!(ranges.len(), 4);
+        assert_eq!(ranges[0].start, MIN_TOKEN);
+        assert_eq!(ranges[3].end, MAX_TOKEN);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        assert!(ranges[1].end < 0 && ranges[2].end > 0);
+    }
+
+    #[test]
+    fn splits_wraparound_range() {
+        let ranges = ring_ranges(&[100, -100, 0, 200]);
+        assert_eq!(ranges,
+                   vec![TokenRange::new(MIN_TOKEN, -100),
+                        TokenRange::new(-100, 0),
+                        TokenRange::new(0, 100),
+                        TokenRange::new(100, 200),
+                        TokenRange::new(200, MAX_TOKEN)]);
+
+        let ranges = ring_ranges(&[MIN_TOKEN, 0]);
+        assert_eq!(ranges,
+                   vec![TokenRange::new(MIN_TOKEN, 0), TokenRange::new(0, MAX_TOKEN)]);
+    }
+
+    #[test]
+    fn appends_token_predicates() {
+        let scan = ScanQuery::new("SELECT id, name FROM shop.orders;", &["customer", "day"]);
+        assert_eq!(scan.cql(),
+                   "SELECT id, name FROM shop.orders WHERE token(customer, day) > ? AND \
+                    token(customer, day) <= ?");
+
+        let query = scan.query_for(&TokenRange::new(-100, 0));
+        assert_eq!(mock::encoded(&query.values.unwrap()),
+                   mock::encoded(&[Value::from(-100i64), Value::from(0i64)]));
+
+        let scan = ScanQuery::new("SELECT id FROM shop.orders\nWHERE total > 0 ALLOW FILTERING",
+                                  &["customer"]);
+        assert_eq!(scan.cql(),
+                   "SELECT id FROM shop.orders\nWHERE total > 0 AND token(customer) > ? AND \
+                    token(customer) <= ? ALLOW FILTERING");
+
+        let scan = ScanQuery::new("SELECT id FROM orders WHERE note = 'limit where' LIMIT 10",
+                                  &["customer"]);
+        assert_eq!(scan.cql(),
+                   "SELECT id FROM orders WHERE note = 'limit where' AND token(customer) > ? \
+                    AND token(customer) <= ? LIMIT 10");
+
+        let scan = ScanQuery::new("SELECT id FROM orders PER PARTITION LIMIT 1 ALLOW FILTERING",
+                                  &["customer"]);
+        assert_eq!(scan.cql(),
+                   "SELECT id FROM orders WHERE token(customer) > ? AND token(customer) <= ? \
+                    PER PARTITION LIMIT 1 ALLOW FILTERING");
+
+        let scan = ScanQuery::new("SELECT id FROM wherever", &["customer"]);
+        assert!(scan.cql().starts_with("SELECT id FROM wherever WHERE token(customer) > ?"));
+    }
+
+    #[test]
+    fn merges_a_bounded_number_of_streams() {
+        // (streams which started and didn't end, the most of them at once)
+        let running = Arc::new(Mutex::new((0, 0)));
+        let streams: Vec<_> = (0..5)
+            .map(|i| {
+                let running = running.clone();
+                let mut items = vec![None, Some(i * 10), Some(i * 10 + 1)].into_iter();
+                stream::poll_fn(move || -> Poll<Option<i32>, ()> {
+                    let mut running = running.lock().unwrap();
+                    match items.next() {
+                        // the first poll finds nothing ready, as a request in flight would
+                        Some(None) => {
+                            running.0 += 1;
+                            running.1 = cmp::max(running.0, running.1);
+                            task::current().notify();
+                            Ok(Async::NotReady)
+                        }
+                        Some(item) => Ok(Async::Ready(item)),
+                        None => {
+                            running.0 -= 1;
+                            Ok(Async::Ready(None))
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let mut items = merge_bounded(streams, 2).collect().wait().unwrap();
+        items.sort();
+        assert_eq!(items, vec![0, 1, 10, 11, 20, 21, 30, 31, 40, 41]);
+        assert_eq!(*running.lock().unwrap(), (0, 2));
+    }
+
+    #[test]
+    fn yields_every_row_once() {
+        let transport = MockTransport::new();
+        let page = |ids: &[i32], paging_state: Option<&[u8]>| {
+            let rows: Vec<_> = ids.iter().map(|id| vec![mock::int(*id)]).collect();
+            mock::response(RESULT,
+                           0,
+                           &mock::rows_body(&[("id", mock::INT)], &rows, paging_state))
+        };
+        // a fake ring of 4 tokens makes 5 ranges, the second one has two pages
+        transport.push_read(page(&[1, 2], None));
+        transport.push_read(page(&[3], Some(b"p")));
+        transport.push_read(page(&[4], None));
+        transport.push_read(page(&[], None));
+        transport.push_read(page(&[5, 6], None));
+        transport.push_read(page(&[7], None));
+
+        let session = mock::session(transport);
+        let ranges = ring_ranges(&[-100, 0, 100, 200]);
+        let rows = session
+            .scan_ranges(ScanQuery::new("SELECT id FROM t", &["id"]), ranges)
+            .collect()
+            .wait()
+            .unwrap();
+
+        let mut ids: Vec<i32> = rows.iter()
+            .map(|row| row.get_by_name("id").unwrap().unwrap())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+}
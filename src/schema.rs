@@ -0,0 +1,349 @@
+//! Schema metadata read from `system_schema` keyspace.
+
+use cdrs::types::rows::Row;
+
+use rows::{self, TryFromRow};
+use error;
+
+/// Query which reads columns of a table.
+pub const SELECT_COLUMNS: &'static str = "SELECT keyspace_name, table_name, column_name, kind, \
+                                           position, clustering_order, type FROM \
+                                           system_schema.columns WHERE keyspace_name = ? AND \
+                                           table_name = ?";
+
+/// Query which lists keyspaces.
+pub const SELECT_KEYSPACES: &'static str = "SELECT keyspace_name FROM system_schema.keyspaces";
+
+/// Query which lists tables of a keyspace.
+pub const SELECT_TABLES: &'static str = "SELECT table_name FROM system_schema.tables WHERE \
+                                          keyspace_name = ?";
+
+/// Keyspaces which are created and managed by a server itself.
+pub const SYSTEM_KEYSPACES: &'static [&'static str] = &["system",
+                                                         "system_auth",
+                                                         "system_distributed",
+                                                         "system_schema",
+                                                         "system_traces",
+                                                         "system_views",
+                                                         "system_virtual_schema"];
+
+pub fn is_system_keyspace(keyspace: &str) -> bool {
+    SYSTEM_KEYSPACES.contains(&keyspace)
+}
+
+/// Max length of a keyspace name a server accepts.
+pub const MAX_KEYSPACE_NAME_LEN: usize = 48;
+
+/// Checks that `keyspace` is a name a server accepts: up to 48 letters, digits
+/// and underscores. Quotes, spaces or semicolons are rejected, so a name can't
+/// smuggle anything into a statement it's used in.
+pub fn validate_keyspace_name(keyspace: &str) -> error::Result<()> {
+    let valid = !keyspace.is_empty() && keyspace.len() <= MAX_KEYSPACE_NAME_LEN &&
+                keyspace.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(error::Error::General(format!("Invalid keyspace name {:?}", keyspace)))
+    }
+}
+
+/// Reads names from `column` of each row and sorts them.
+pub fn sorted_names(rows: Vec<Row>, column: &str) -> error::Result<Vec<String>> {
+    let mut names = vec![];
+    for row in rows {
+        names.push(try!(rows::column(&row, column, "name")));
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Role of a column in a table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColumnKind {
+    PartitionKey,
+    Clustering,
+    Regular,
+    Static,
+}
+
+impl ColumnKind {
+    fn parse(kind: &str) -> error::Result<ColumnKind> {
+        match kind {
+            "partition_key" => Ok(ColumnKind::PartitionKey),
+            "clustering" => Ok(ColumnKind::Clustering),
+            "regular" => Ok(ColumnKind::Regular),
+            "static" => Ok(ColumnKind::Static),
+            _ => Err(format!("Unknown column kind {}", kind).into()),
+        }
+    }
+}
+
+/// Order of a clustering column. Other columns have no order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClusteringOrder {
+    Asc,
+    Desc,
+    None,
+}
+
+impl ClusteringOrder {
+    fn parse(order: &str) -> error::Result<ClusteringOrder> {
+        match order {
+            "asc" => Ok(ClusteringOrder::Asc),
+            "desc" => Ok(ClusteringOrder::Desc),
+            "none" => Ok(ClusteringOrder::None),
+            _ => Err(format!("Unknown clustering order {}", order).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnMetadata {
+    pub name: String,
+    /// CQL type as it's written in a schema, e.g. `map<text, int>`.
+    pub cql_type: String,
+    pub kind: ColumnKind,
+    /// Position within a partition or a clustering key, `-1` for other columns.
+    pub position: i32,
+    pub clustering_order: ClusteringOrder,
+}
+
+impl ColumnMetadata {
+    pub fn is_static(&self) -> bool {
+        self.kind == ColumnKind::Static
+    }
+}
+
+/// Row of `system_schema.columns`.
+#[derive(Debug)]
+pub struct SchemaColumn {
+    pub keyspace: String,
+    pub table: String,
+    pub column: ColumnMetadata,
+}
+
+impl TryFromRow for SchemaColumn {
+    fn try_from_row(row: Row) -> error::Result<SchemaColumn> {
+        let kind: String = try!(rows::column(&row, "kind", "kind"));
+        let order: String = try!(rows::column(&row, "clustering_order", "clustering_order"));
+
+        Ok(SchemaColumn {
+               keyspace: try!(rows::column(&row, "keyspace_name", "keyspace")),
+               table: try!(rows::column(&row, "table_name", "table")),
+               column: ColumnMetadata {
+                   name: try!(rows::column(&row, "column_name", "name")),
+                   cql_type: try!(rows::column(&row, "type", "cql_type")),
+                   kind: try!(ColumnKind::parse(kind.as_str())),
+                   position: try!(rows::column(&row, "position", "position")),
+                   clustering_order: try!(ClusteringOrder::parse(order.as_str())),
+               },
+           })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableMetadata {
+    pub keyspace: String,
+    pub name: String,
+    /// Partition key columns ordered by position.
+    pub partition_key: Vec<ColumnMetadata>,
+    /// Clustering columns ordered by position.
+    pub clustering_key: Vec<ColumnMetadata>,
+    /// Regular and static columns ordered by name.
+    pub columns: Vec<ColumnMetadata>,
+}
+
+impl TableMetadata {
+    /// Builds metadata of a table from its rows of `system_schema.columns`.
+    /// A table always has a partition key, so no rows means there is no such table.
+    pub fn from_columns(keyspace: &str,
+                        name: &str,
+                        columns: Vec<SchemaColumn>)
+                        -> error::Result<TableMetadata> {
+        if columns.is_empty() {
+            return Err(error::Error::NotFound(format!("Table {}.{}", keyspace, name)));
+        }
+
+        let mut table = TableMetadata {
+            keyspace: keyspace.to_string(),
+            name: name.to_string(),
+            partition_key: vec![],
+            clustering_key: vec![],
+            columns: vec![],
+        };
+
+        for SchemaColumn { column, .. } in columns {
+            match column.kind {
+                ColumnKind::PartitionKey => table.partition_key.push(column),
+                ColumnKind::Clustering => table.clustering_key.push(column),
+                ColumnKind::Regular | ColumnKind::Static => table.columns.push(column),
+            }
+        }
+
+        table.partition_key.sort_by_key(|column| column.position);
+        table.clustering_key.sort_by_key(|column| column.position);
+        table.columns.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(table)
+    }
+
+    /// Looks a column of any kind up by name.
+    pub fn column(&self, name: &str) -> Option<&ColumnMetadata> {
+        self.partition_key
+            .iter()
+            .chain(&self.clustering_key)
+            .chain(&self.columns)
+            .find(|column| column.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::compression::Compression;
+    use cdrs::query::QueryBuilder;
+
+    use super::*;
+    use client::CDRS;
+    use mock::{self, MockTransport, ERROR, READY, RESULT};
+    use error;
+
+    fn columns_response(columns: &[(&str, &str, i32, &str, &str)]) -> Vec<u8> {
+        let specs = [("keyspace_name", mock::VARCHAR),
+                     ("table_name", mock::VARCHAR),
+                     ("column_name", mock::VARCHAR),
+                     ("kind", mock::VARCHAR),
+                     ("position", mock::INT),
+                     ("clustering_order", mock::VARCHAR),
+                     ("type", mock::VARCHAR)];
+        let rows: Vec<_> = columns.iter()
+            .map(|&(name, kind, position, order, cql_type)| {
+                     vec![mock::text("shop"),
+                          mock::text("orders"),
+                          mock::text(name),
+                          mock::text(kind),
+                          mock::int(position),
+                          mock::text(order),
+                          mock::text(cql_type)]
+                 })
+            .collect();
+        mock::response(RESULT, 0, &mock::rows_body(&specs, &rows, None))
+    }
+
+    fn names(columns: &[ColumnMetadata]) -> Vec<String> {
+        columns.iter().map(|column| column.name.clone()).collect()
+    }
+
+    #[test]
+    fn describes_table() {
+        let transport = MockTransport::new();
+        transport.push_read(columns_response(&[("total", "regular", -1, "none", "decimal"),
+                                               ("placed_at", "clustering", 0, "desc", "timestamp"),
+                                               ("day", "partition_key", 1, "none", "date"),
+                                               ("currency", "static", -1, "none", "text"),
+                                               ("customer", "partition_key", 0, "none", "uuid")]));
+
+        let (_, table) = mock::session(transport)
+            .describe_table("shop", "orders")
+            .wait()
+            .unwrap();
+
+        assert_eq!(table.keyspace, "shop");
+        assert_eq!(table.name, "orders");
+        assert_eq!(names(&table.partition_key), vec!["customer", "day"]);
+        assert_eq!(names(&table.clustering_key), vec!["placed_at"]);
+        assert_eq!(table.clustering_key[0].clustering_order, ClusteringOrder::Desc);
+        assert_eq!(names(&table.columns), vec!["currency", "total"]);
+        assert!(table.column("currency").unwrap().is_static());
+        assert_eq!(table.column("total").unwrap().cql_type, "decimal");
+    }
+
+    fn names_response(column: &str, names: &[&str]) -> Vec<u8> {
+        let rows: Vec<_> = names.iter().map(|name| vec![mock::text(name)]).collect();
+        mock::response(RESULT,
+                       0,
+                       &mock::rows_body(&[(column, mock::VARCHAR)], &rows, None))
+    }
+
+    #[test]
+    fn lists_keyspaces() {
+        let keyspaces = ["system", "shop", "system_schema", "analytics", "system_auth"];
+
+        let transport = MockTransport::new();
+        transport.push_read(names_response("keyspace_name", &keyspaces));
+        let (_, user_keyspaces) = mock::session(transport).keyspaces(false).wait().unwrap();
+        assert_eq!(user_keyspaces, vec!["analytics", "shop"]);
+
+        let transport = MockTransport::new();
+        transport.push_read(names_response("keyspace_name", &keyspaces));
+        let (_, all_keyspaces) = mock::session(transport).keyspaces(true).wait().unwrap();
+        assert_eq!(all_keyspaces,
+                   vec!["analytics", "shop", "system", "system_auth", "system_schema"]);
+    }
+
+    #[test]
+    fn lists_tables() {
+        let transport = MockTransport::new();
+        transport.push_read(names_response("table_name", &["orders", "customers"]));
+        let (_, tables) = mock::session(transport).tables("shop").wait().unwrap();
+        assert_eq!(tables, vec!["customers", "orders"]);
+
+        let transport = MockTransport::new();
+        transport.push_read(names_response("table_name", &[]));
+        let (_, tables) = mock::session(transport).tables("nope").wait().unwrap();
+        assert!(tables.is_empty());
+    }
+
+    #[test]
+    fn uses_keyspace_for_unqualified_names() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::response(READY, 0, &[]));
+        transport.push_read(mock::response(RESULT, 0, &mock::set_keyspace_body("shop")));
+        transport.push_read(names_response("table_name", &["orders"]));
+        transport.push_read(mock::response(RESULT, 0, &mock::set_keyspace_body("Archive")));
+        transport.push_read(mock::response(ERROR, 0, &mock::error_body(0x2200, "no keyspace")));
+
+        let session = CDRS::new(transport.clone(), NoneAuthenticator)
+            .start_with_keyspace(Compression::None, "shop")
+            .wait()
+            .unwrap();
+        assert_eq!(session.current_keyspace(), Some("shop"));
+        assert!(String::from_utf8_lossy(&transport.written()).contains("USE shop"));
+
+        let (session, _) = session.query(QueryBuilder::new("SELECT * FROM orders").finalize(),
+                                         false,
+                                         false)
+            .wait()
+            .unwrap();
+        // a name which is not all lowercase is quoted to keep its case
+        let session = session.use_keyspace("Archive").wait().unwrap();
+        assert_eq!(session.current_keyspace(), Some("Archive"));
+        assert!(String::from_utf8_lossy(&transport.written()).contains("USE \"Archive\""));
+
+        let (mut session, result) = session.try_use_keyspace("missing").wait().unwrap();
+        assert!(result.is_err());
+        assert_eq!(session.current_keyspace(), Some("Archive"));
+
+        // invalid names are never sent
+        let sent = transport.written().len();
+        let long = "k".repeat(MAX_KEYSPACE_NAME_LEN + 1);
+        for name in &["", "shop; DROP TABLE orders", "\"shop\"", long.as_str()] {
+            let (rejected, result) = session.try_use_keyspace(name).wait().unwrap();
+            assert!(result.is_err(), "{:?} is accepted", name);
+            session = rejected;
+        }
+        assert_eq!(transport.written().len(), sent);
+    }
+
+    #[test]
+    fn missing_table_is_not_found() {
+        let transport = MockTransport::new();
+        transport.push_read(columns_response(&[]));
+
+        match mock::session(transport).describe_table("shop", "nope").wait() {
+            Err(error::Error::NotFound(_)) => (),
+            other => panic!("NotFound expected, got {:?}", other.map(|(_, table)| table)),
+        }
+    }
+}
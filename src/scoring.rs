@@ -0,0 +1,188 @@
+//! Per-host statistics of request outcomes and scores derived from them.
+//!
+//! Scores shift traffic away from hosts which fail or time out more often than
+//! others without excluding them completely, so a host whose stats improve
+//! gets its traffic back.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+/// Result of a single request sent to a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Outcome {
+    Success,
+    Error,
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct ScoringOptions {
+    /// Number of the latest outcomes of a host which are taken into account.
+    pub window: usize,
+    /// How much an error rate lowers a score.
+    pub error_weight: f64,
+    /// How much a timeout rate lowers a score.
+    pub timeout_weight: f64,
+    /// The lowest share of traffic relative to a perfect host which a host gets
+    /// whatever its score is.
+    pub min_share: f64,
+}
+
+impl Default for ScoringOptions {
+    fn default() -> ScoringOptions {
+        ScoringOptions {
+            window: 1000,
+            error_weight: 1.0,
+            timeout_weight: 2.0,
+            min_share: 0.05,
+        }
+    }
+}
+
+/// Counts of outcomes within a window.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HostStats {
+    pub successes: usize,
+    pub errors: usize,
+    pub timeouts: usize,
+}
+
+impl HostStats {
+    pub fn total(&self) -> usize {
+        self.successes + self.errors + self.timeouts
+    }
+
+    fn rate(&self, count: usize) -> f64 {
+        if self.total() == 0 {
+            0.0
+        } else {
+            count as f64 / self.total() as f64
+        }
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        self.rate(self.errors)
+    }
+
+    pub fn timeout_rate(&self) -> f64 {
+        self.rate(self.timeouts)
+    }
+}
+
+/// Sliding window of the latest outcomes of a host.
+#[derive(Debug, Default, Clone)]
+struct Window {
+    outcomes: VecDeque<Outcome>,
+    stats: HostStats,
+}
+
+impl Window {
+    fn push(&mut self, outcome: Outcome, len: usize) {
+        self.outcomes.push_back(outcome);
+        *self.count(outcome) += 1;
+
+        while self.outcomes.len() > len {
+            if let Some(oldest) = self.outcomes.pop_front() {
+                *self.count(oldest) -= 1;
+            }
+        }
+    }
+
+    fn count(&mut self, outcome: Outcome) -> &mut usize {
+        match outcome {
+            Outcome::Success => &mut self.stats.successes,
+            Outcome::Error => &mut self.stats.errors,
+            Outcome::Timeout => &mut self.stats.timeouts,
+        }
+    }
+}
+
+/// Outcome statistics and scores of hosts.
+#[derive(Debug, Default, Clone)]
+pub struct HostScores {
+    options: ScoringOptions,
+    windows: HashMap<SocketAddr, Window>,
+}
+
+impl HostScores {
+    pub fn new(options: ScoringOptions) -> HostScores {
+        HostScores {
+            options: options,
+            windows: HashMap::new(),
+        }
+    }
+
+    pub fn options(&self) -> &ScoringOptions {
+        &self.options
+    }
+
+    pub fn record(&mut self, host: SocketAddr, outcome: Outcome) {
+        let len = self.options.window;
+        self.windows
+            .entry(host)
+            .or_default()
+            .push(outcome, len);
+    }
+
+    /// Raw statistics of a host within the current window.
+    pub fn stats(&self, host: &SocketAddr) -> HostStats {
+        self.windows
+            .get(host)
+            .map(|window| window.stats)
+            .unwrap_or_default()
+    }
+
+    /// Statistics of all hosts which have any outcomes recorded.
+    pub fn all_stats(&self) -> HashMap<SocketAddr, HostStats> {
+        self.windows
+            .iter()
+            .map(|(host, window)| (*host, window.stats))
+            .collect()
+    }
+
+    /// Score of a host within `[0, 1]`, where 1 is a host without errors and timeouts.
+    /// Hosts without outcomes have the best score.
+    pub fn score(&self, host: &SocketAddr) -> f64 {
+        let stats = self.stats(host);
+        let penalty = self.options.error_weight * stats.error_rate() +
+                      self.options.timeout_weight * stats.timeout_rate();
+        (1.0 - penalty).max(0.0).min(1.0)
+    }
+
+    /// Relative share of traffic of a host, which is never lower than `min_share`.
+    pub fn weight(&self, host: &SocketAddr) -> f64 {
+        self.score(host).max(self.options.min_share)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_forgets_old_outcomes() {
+        let host = "127.0.0.1:9042".parse().unwrap();
+        let mut scores = HostScores::new(ScoringOptions {
+                                             window: 10,
+                                             ..ScoringOptions::default()
+                                         });
+
+        for _ in 0..5 {
+            scores.record(host, Outcome::Timeout);
+        }
+        assert_eq!(scores.stats(&host).timeouts, 5);
+        assert_eq!(scores.score(&host), 0.0);
+        assert_eq!(scores.weight(&host), 0.05);
+
+        for _ in 0..10 {
+            scores.record(host, Outcome::Success);
+        }
+        assert_eq!(scores.stats(&host),
+                   HostStats {
+                       successes: 10,
+                       errors: 0,
+                       timeouts: 0,
+                   });
+        assert_eq!(scores.score(&host), 1.0);
+    }
+}
@@ -0,0 +1,288 @@
+//! Execution of CQL scripts, e.g. schema migrations.
+//!
+//! A script is split into statements by semicolons which are not inside
+//! string literals, quoted identifiers, `$$` blocks, comments or batches.
+//! Comments are dropped from statements.
+
+use std::time::Duration;
+
+use cdrs::frame::{Frame, Opcode};
+use cdrs::frame::frame_response::ResponseBody;
+use cdrs::frame::frame_result::ResResultBody;
+
+use error;
+
+/// Schema version of a node a session is connected to.
+pub const SELECT_LOCAL_SCHEMA: &'static str = "SELECT schema_version FROM system.local \
+                                               WHERE key = 'local'";
+/// Schema versions of other nodes as the connected node sees them.
+pub const SELECT_PEERS_SCHEMA: &'static str = "SELECT schema_version FROM system.peers";
+
+/// Max number of characters of a statement kept in `StatementOutcome::preview`.
+pub const PREVIEW_LENGTH: usize = 60;
+
+/// What happens to the rest of a script when a statement fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OnError {
+    /// Don't execute the rest of statements.
+    Stop,
+    /// Execute the rest of statements anyway.
+    Continue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ScriptOptions {
+    pub on_error: OnError,
+    /// After a statement which changed schema, wait until every node reports
+    /// the same schema version before the next statement.
+    pub schema_agreement: bool,
+    /// Time nodes have to agree on schema in. Otherwise the statement which
+    /// changed it fails with `Error::SchemaDisagreement`, though it was applied.
+    pub schema_agreement_timeout: Duration,
+}
+
+impl Default for ScriptOptions {
+    fn default() -> ScriptOptions {
+        ScriptOptions {
+            on_error: OnError::Stop,
+            schema_agreement: true,
+            schema_agreement_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Result of a single statement of a script.
+#[derive(Debug)]
+pub struct StatementOutcome {
+    /// Position of the statement in the script, starting from 0.
+    pub index: usize,
+    /// Beginning of the statement text.
+    pub preview: String,
+    pub result: error::Result<Frame>,
+}
+
+impl StatementOutcome {
+    pub fn new(index: usize, statement: &str, result: error::Result<Frame>) -> StatementOutcome {
+        StatementOutcome {
+            index: index,
+            preview: preview(statement),
+            result: result,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+fn preview(statement: &str) -> String {
+    if statement.chars().count() <= PREVIEW_LENGTH {
+        return statement.to_string();
+    }
+
+    let mut preview: String = statement.chars().take(PREVIEW_LENGTH).collect();
+    preview.push_str("...");
+    preview
+}
+
+/// Turns an ERROR response into an error.
+pub fn check_response(frame: Frame) -> error::Result<Frame> {
+    if frame.opcode == Opcode::Error {
+        return Err(error::Error::from_error_body(&frame.body));
+    }
+    Ok(frame)
+}
+
+/// Returns `true` if a statement which got `frame` in response changed schema.
+pub fn is_schema_change(frame: &Frame) -> bool {
+    match frame.get_body() {
+        Ok(ResponseBody::Result(ResResultBody::SchemaChange(_))) => true,
+        _ => false,
+    }
+}
+
+/// Reads schema versions from a response to `SELECT_LOCAL_SCHEMA` or `SELECT_PEERS_SCHEMA`.
+pub fn schema_versions(frame: Frame) -> error::Result<Vec<Vec<u8>>> {
+    match try!(frame.get_body()) {
+        ResponseBody::Result(ResResultBody::Rows(rows)) => {
+            Ok(rows.rows_content
+                   .into_iter()
+                   .filter_map(|row| row.into_iter().next().map(|cell| cell.into_plain()))
+                   // cdrs reads null as empty bytes, a peer without a version is skipped
+                   .filter(|version| !version.is_empty())
+                   .collect())
+        }
+        ResponseBody::Error(_) => Err(error::Error::from_error_body(&frame.body)),
+        _ => Err("Unexpected type of frame. Rows are expected".into()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Code,
+    SingleQuoted,
+    DoubleQuoted,
+    DollarQuoted,
+    LineComment,
+    BlockComment,
+}
+
+/// Splits a script into statements without trailing semicolons.
+/// Statements which are empty or consist of comments only are skipped.
+pub fn split_statements(script: &str) -> Vec<String> {
+    let mut statements = vec![];
+    let mut statement = String::new();
+    let mut state = State::Code;
+    let mut chars = script.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let next = chars.peek().cloned();
+
+        match state {
+            State::Code => {
+                match (c, next) {
+                    (';', _) if is_open_batch(&statement) => (),
+                    (';', _) => {
+                        push_statement(&mut statements, &statement);
+                        statement.clear();
+                        continue;
+                    }
+                    ('-', Some('-')) | ('/', Some('/')) => {
+                        chars.next();
+                        state = State::LineComment;
+                        continue;
+                    }
+                    ('/', Some('*')) => {
+                        chars.next();
+                        // a comment separates tokens like whitespace does
+                        statement.push(' ');
+                        state = State::BlockComment;
+                        continue;
+                    }
+                    ('\'', _) => state = State::SingleQuoted,
+                    ('"', _) => state = State::DoubleQuoted,
+                    ('$', Some('$')) => {
+                        chars.next();
+                        statement.push_str("$$");
+                        state = State::DollarQuoted;
+                        continue;
+                    }
+                    _ => (),
+                }
+                statement.push(c);
+            }
+            State::SingleQuoted | State::DoubleQuoted => {
+                let quote = if state == State::SingleQuoted { '\'' } else { '"' };
+                statement.push(c);
+                if c == quote {
+                    if next == Some(quote) {
+                        // an escaped quote
+                        statement.push(quote);
+                        chars.next();
+                    } else {
+                        state = State::Code;
+                    }
+                }
+            }
+            State::DollarQuoted => {
+                if c == '$' && next == Some('$') {
+                    chars.next();
+                    statement.push_str("$$");
+                    state = State::Code;
+                } else {
+                    statement.push(c);
+                }
+            }
+            State::LineComment => {
+                if c == '\n' {
+                    statement.push('\n');
+                    state = State::Code;
+                }
+            }
+            State::BlockComment => {
+                if c == '*' && next == Some('/') {
+                    chars.next();
+                    state = State::Code;
+                }
+            }
+        }
+    }
+
+    push_statement(&mut statements, &statement);
+    statements
+}
+
+/// Statements of a batch are separated with semicolons as well,
+/// the batch ends with `APPLY BATCH`.
+fn is_open_batch(statement: &str) -> bool {
+    let words: Vec<_> = statement.split_whitespace().map(|word| word.to_uppercase()).collect();
+    words.first().map_or(false, |word| word == "BEGIN") &&
+    !words.ends_with(&["APPLY".to_string(), "BATCH".to_string()])
+}
+
+fn push_statement(statements: &mut Vec<String>, statement: &str) {
+    let statement = statement.trim();
+    if !statement.is_empty() {
+        statements.push(statement.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_simple_script() {
+        let script = "CREATE KEYSPACE ks WITH replication = {'class': 'SimpleStrategy', \
+                      'replication_factor': 1};\n\nUSE ks;  \n  SELECT * FROM t";
+        assert_eq!(split_statements(script),
+                   vec!["CREATE KEYSPACE ks WITH replication = {'class': 'SimpleStrategy', \
+                         'replication_factor': 1}",
+                        "USE ks",
+                        "SELECT * FROM t"]);
+    }
+
+    #[test]
+    fn keeps_semicolons_in_literals() {
+        let script = "INSERT INTO t (id, s) VALUES (1, 'a;b''c;');\n\
+                      SELECT \"we;ird\"\"col\" FROM t;\n\
+                      CREATE FUNCTION f(x int) RETURNS NULL ON NULL INPUT RETURNS int \
+                      LANGUAGE java AS $$ return x; // not a comment\n $$;";
+        assert_eq!(split_statements(script),
+                   vec!["INSERT INTO t (id, s) VALUES (1, 'a;b''c;')",
+                        "SELECT \"we;ird\"\"col\" FROM t",
+                        "CREATE FUNCTION f(x int) RETURNS NULL ON NULL INPUT RETURNS int \
+                         LANGUAGE java AS $$ return x; // not a comment\n $$"]);
+    }
+
+    #[test]
+    fn keeps_batch_together() {
+        let script = "BEGIN UNLOGGED BATCH\n  INSERT INTO t (id) VALUES (1);\n  \
+                      INSERT INTO t (id) VALUES (2);\napply  batch;\nSELECT * FROM t;";
+        assert_eq!(split_statements(script),
+                   vec!["BEGIN UNLOGGED BATCH\n  INSERT INTO t (id) VALUES (1);\n  \
+                         INSERT INTO t (id) VALUES (2);\napply  batch",
+                        "SELECT * FROM t"]);
+    }
+
+    #[test]
+    fn drops_comments() {
+        let script = "-- header; with a semicolon\n\
+                      SELECT * FROM t; // trailing; comment\n\
+                      /* block; comment\n spanning lines; */\n\
+                      SELECT/**/id FROM t WHERE s = '-- not a comment';\n\
+                      /* only a comment; */";
+        assert_eq!(split_statements(script),
+                   vec!["SELECT * FROM t",
+                        "SELECT id FROM t WHERE s = '-- not a comment'"]);
+    }
+
+    #[test]
+    fn truncates_preview() {
+        let statement = "x".repeat(100);
+        let outcome = StatementOutcome::new(3, &statement, Err("failed".into()));
+        assert_eq!(outcome.index, 3);
+        assert_eq!(outcome.preview.len(), PREVIEW_LENGTH + 3);
+        assert!(!outcome.is_ok());
+    }
+}
@@ -0,0 +1,164 @@
+//! ScyllaDB shard awareness.
+//!
+//! Scylla runs one shard per CPU core and advertises its sharding parameters
+//! in SUPPORTED options. A client which opens a connection per shard and sends
+//! a request to the shard that owns the request's token avoids cross-core hops
+//! on the server side.
+//!
+//! `Session::detect_shard` learns the shard of a connection. A pool which is
+//! `Pool::shard_aware` routes requests with a known token to a session of the
+//! owning shard and opens sessions to missing shards with `Pool::fill_shards`,
+//! e.g. with `TransportTcp::new_to_shard` which binds a local port so the
+//! connection lands on a given shard.
+
+use client::CassandraOptions;
+
+pub const SCYLLA_SHARD: &'static str = "SCYLLA_SHARD";
+pub const SCYLLA_NR_SHARDS: &'static str = "SCYLLA_NR_SHARDS";
+pub const SCYLLA_SHARDING_IGNORE_MSB: &'static str = "SCYLLA_SHARDING_IGNORE_MSB";
+pub const SCYLLA_SHARD_AWARE_PORT: &'static str = "SCYLLA_SHARD_AWARE_PORT";
+
+/// Lowest local port used when a connection has to land on a particular shard.
+pub const LOCAL_PORT_LOW: u16 = 49152;
+
+/// Sharding parameters of a Scylla node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardInfo {
+    /// Shard which served the connection the options were received on.
+    pub shard: u32,
+    pub nr_shards: u32,
+    pub msb_ignore: u8,
+    /// Port which routes a connection to the shard `local_port % nr_shards`.
+    pub shard_aware_port: Option<u16>,
+}
+
+impl ShardInfo {
+    /// Reads sharding parameters from SUPPORTED options. Returns `None` if
+    /// the node is not a Scylla one or doesn't advertise them.
+    pub fn from_options(options: &CassandraOptions) -> Option<ShardInfo> {
+        let shard = first_value(options, SCYLLA_SHARD);
+        let nr_shards = first_value(options, SCYLLA_NR_SHARDS);
+
+        match (shard, nr_shards) {
+            (Some(shard), Some(nr_shards)) if nr_shards > 0 && shard < nr_shards => {
+                Some(ShardInfo {
+                         shard: shard,
+                         nr_shards: nr_shards,
+                         msb_ignore: first_value(options, SCYLLA_SHARDING_IGNORE_MSB)
+                             .unwrap_or(0) as u8,
+                         shard_aware_port: first_value(options, SCYLLA_SHARD_AWARE_PORT)
+                             .map(|port| port as u16),
+                     })
+            }
+            _ => None,
+        }
+    }
+
+    /// Shard which owns a given Murmur3 token, following Scylla's algorithm:
+    /// the token is biased to be unsigned, shifted by `msb_ignore` bits
+    /// and mapped onto `[0, nr_shards)` by multiplication.
+    pub fn shard_of(&self, token: i64) -> u32 {
+        let biased = (token as u64).wrapping_add(1 << 63) << self.msb_ignore;
+        mul_high(biased, self.nr_shards as u64) as u32
+    }
+
+    /// Shard a connection from a given local port lands on when it
+    /// connects to the shard-aware port.
+    pub fn shard_of_local_port(&self, port: u16) -> u32 {
+        port as u32 % self.nr_shards
+    }
+
+    /// The first local port not lower than `from` which makes a connection
+    /// to the shard-aware port land on `shard`.
+    pub fn local_port_for(&self, shard: u32, from: u16) -> Option<u16> {
+        let mut port = from as u32;
+        let rem = port % self.nr_shards;
+        if rem <= shard {
+            port += shard - rem;
+        } else {
+            port += self.nr_shards - rem + shard;
+        }
+
+        if port > u16::max_value() as u32 {
+            None
+        } else {
+            Some(port as u16)
+        }
+    }
+}
+
+fn first_value(options: &CassandraOptions, key: &str) -> Option<u32> {
+    options.get(key)
+        .and_then(|values| values.first())
+        .and_then(|value| value.parse().ok())
+}
+
+/// High 64 bits of a 128-bit product.
+fn mul_high(a: u64, b: u64) -> u64 {
+    let (a_hi, a_lo) = (a >> 32, a & 0xFFFF_FFFF);
+    let (b_hi, b_lo) = (b >> 32, b & 0xFFFF_FFFF);
+
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+
+    let middle = (lo_lo >> 32) + (hi_lo & 0xFFFF_FFFF) + (lo_hi & 0xFFFF_FFFF);
+    hi_hi + (hi_lo >> 32) + (lo_hi >> 32) + (middle >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::CassandraOptions;
+
+    fn options(nr_shards: &str) -> CassandraOptions {
+        let mut options = CassandraOptions::new();
+        options.insert(SCYLLA_SHARD.to_string(), vec!["1".to_string()]);
+        options.insert(SCYLLA_NR_SHARDS.to_string(), vec![nr_shards.to_string()]);
+        options.insert(SCYLLA_SHARDING_IGNORE_MSB.to_string(), vec!["12".to_string()]);
+        options.insert(SCYLLA_SHARD_AWARE_PORT.to_string(), vec!["19042".to_string()]);
+        options
+    }
+
+    #[test]
+    fn parses_supported_options() {
+        let info = ShardInfo::from_options(&options("4")).unwrap();
+        assert_eq!(info,
+                   ShardInfo {
+                       shard: 1,
+                       nr_shards: 4,
+                       msb_ignore: 12,
+                       shard_aware_port: Some(19042),
+                   });
+
+        assert_eq!(ShardInfo::from_options(&CassandraOptions::new()), None);
+        assert_eq!(ShardInfo::from_options(&options("0")), None);
+    }
+
+    #[test]
+    fn computes_shard_of_token() {
+        let info = ShardInfo {
+            shard: 0,
+            nr_shards: 4,
+            msb_ignore: 0,
+            shard_aware_port: None,
+        };
+
+        assert_eq!(info.shard_of(i64::min_value()), 0);
+        assert_eq!(info.shard_of(-1), 1);
+        assert_eq!(info.shard_of(0), 2);
+        assert_eq!(info.shard_of(i64::max_value()), 3);
+    }
+
+    #[test]
+    fn picks_local_port_for_shard() {
+        let info = ShardInfo::from_options(&options("4")).unwrap();
+        for shard in 0..4 {
+            let port = info.local_port_for(shard, LOCAL_PORT_LOW).unwrap();
+            assert!((LOCAL_PORT_LOW..LOCAL_PORT_LOW + 4).contains(&port));
+            assert_eq!(info.shard_of_local_port(port), shard);
+        }
+        assert_eq!(info.local_port_for(0, u16::max_value()), None);
+    }
+}
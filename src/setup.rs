@@ -0,0 +1,112 @@
+//! Setup of sessions a pool opens, run after a handshake and before
+//! a session serves requests.
+
+use std::sync::Arc;
+
+use cdrs::authenticators::Authenticator;
+use cdrs::frame::Frame;
+use cdrs::transport::CDRSTransport;
+use cdrs::types::rows::Row;
+use cdrs::types::value::Value;
+use futures::future::{self, Future, Loop};
+
+use client::{CDRSFuture, Session};
+use prepared::TypedPrepared;
+use script;
+use error;
+
+/// Callback which sets a session up and resolves into it along with a result,
+/// so a session which failed setup can be closed.
+pub type SetupCallback<T, X> = Arc<Fn(Session<T, X>)
+                                      -> CDRSFuture<(Session<T, X>, error::Result<()>)>
+                                      + Send + Sync>;
+
+/// A step of session setup.
+pub enum SetupAction<T: Authenticator + 'static, X: CDRSTransport + 'static> {
+    /// Makes a keyspace the default one of a session.
+    UseKeyspace(String),
+    /// Prepares statements, so their first executions don't need to.
+    Prepare(Vec<String>),
+    Custom(SetupCallback<T, X>),
+}
+
+impl<T: Authenticator + 'static, X: CDRSTransport + 'static> SetupAction<T, X> {
+    pub fn use_keyspace<S: Into<String>>(keyspace: S) -> SetupAction<T, X> {
+        SetupAction::UseKeyspace(keyspace.into())
+    }
+
+    pub fn prepare(statements: Vec<String>) -> SetupAction<T, X> {
+        SetupAction::Prepare(statements)
+    }
+
+    pub fn custom<F>(callback: F) -> SetupAction<T, X>
+        where F: Fn(Session<T, X>)
+                    -> CDRSFuture<(Session<T, X>, error::Result<()>)>
+                    + Send + Sync + 'static
+    {
+        SetupAction::Custom(Arc::new(callback))
+    }
+}
+
+/// Runs `actions` in order on a session and stops at the first failure.
+/// The returned future itself never fails.
+pub fn run<T, X>(session: Session<T, X>,
+                 actions: Arc<Vec<SetupAction<T, X>>>)
+                 -> CDRSFuture<(Session<T, X>, error::Result<()>)>
+    where T: Authenticator + Send + 'static,
+          X: CDRSTransport + 'static
+{
+    // position of a next action and of a next statement within it
+    future::loop_fn((session, 0, 0), move |(session, action, statement)| {
+        let (frame, prepared, next) = match actions.get(action) {
+            None => return future::ok(Loop::Break((session, Ok(())))).boxed(),
+            Some(&SetupAction::UseKeyspace(ref keyspace)) => {
+                return session.try_use_keyspace(keyspace)
+                           .map(move |(session, result)| match result {
+                                    Ok(()) => Loop::Continue((session, action + 1, 0)),
+                                    Err(err) => Loop::Break((session, Err(err))),
+                                })
+                           .boxed();
+            }
+            Some(&SetupAction::Prepare(ref statements)) => {
+                match statements.get(statement) {
+                    Some(cql) => {
+                        (Frame::new_req_prepare(cql.clone(), vec![]),
+                         Some(cql.clone()),
+                         (action, statement + 1))
+                    }
+                    None => return future::ok(Loop::Continue((session, action + 1, 0))).boxed(),
+                }
+            }
+            Some(&SetupAction::Custom(ref callback)) => {
+                return callback(session)
+                           .map(move |(session, result)| match result {
+                                    Ok(()) => Loop::Continue((session, action + 1, 0)),
+                                    Err(err) => Loop::Break((session, Err(err))),
+                                })
+                           .boxed();
+            }
+        };
+
+        session.try_request(frame)
+            .map(move |(session, result)| {
+                let result = result.and_then(script::check_response).and_then(|frame| {
+                    match prepared {
+                        Some(cql) => {
+                            TypedPrepared::<Vec<Value>, Row>::from_frame(cql,
+                                                                         frame,
+                                                                         session.prepared_cache())
+                                .map(|_| ())
+                        }
+                        None => Ok(()),
+                    }
+                });
+                match result {
+                    Ok(()) => Loop::Continue((session, next.0, next.1)),
+                    Err(err) => Loop::Break((session, Err(err))),
+                }
+            })
+            .boxed()
+    })
+            .boxed()
+}
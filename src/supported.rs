@@ -0,0 +1,118 @@
+//! Options a server reports in a SUPPORTED response.
+
+use cdrs::compression::Compression;
+
+use client::CassandraOptions;
+
+/// Key of CQL versions in a SUPPORTED response.
+pub const CQL_VERSION: &'static str = "CQL_VERSION";
+/// Key of compression algorithms in a SUPPORTED response.
+pub const COMPRESSION: &'static str = "COMPRESSION";
+/// Key of protocol versions in a SUPPORTED response.
+pub const PROTOCOL_VERSIONS: &'static str = "PROTOCOL_VERSIONS";
+
+/// Options of a SUPPORTED response. Well-known ones are parsed, every option
+/// including unknown ones, e.g. Scylla sharding parameters, is kept in `raw`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SupportedOptions {
+    /// Compression algorithms the crate knows, in the order of the response.
+    /// Other algorithms are only in `raw`.
+    pub compressions: Vec<Compression>,
+    raw: CassandraOptions,
+}
+
+impl SupportedOptions {
+    /// CQL versions, e.g. `3.4.4`.
+    pub fn cql_versions(&self) -> &[String] {
+        self.values(CQL_VERSION)
+    }
+
+    /// Protocol versions, e.g. `4/v4`. Servers before 4.0 don't report them.
+    pub fn protocol_versions(&self) -> &[String] {
+        self.values(PROTOCOL_VERSIONS)
+    }
+
+    /// Every option of the response as it was sent.
+    pub fn raw(&self) -> &CassandraOptions {
+        &self.raw
+    }
+
+    /// Values of an option, empty if the server didn't send it.
+    pub fn values(&self, key: &str) -> &[String] {
+        self.raw.get(key).map(|values| &values[..]).unwrap_or(&[])
+    }
+
+    pub fn supports_compression(&self, compression: Compression) -> bool {
+        compression == Compression::None || self.compressions.contains(&compression)
+    }
+
+    pub fn into_raw(self) -> CassandraOptions {
+        self.raw
+    }
+}
+
+impl From<CassandraOptions> for SupportedOptions {
+    fn from(raw: CassandraOptions) -> SupportedOptions {
+        let compressions = raw.get(COMPRESSION)
+            .into_iter()
+            .flat_map(|names| names.iter().filter_map(|name| parse_compression(name)))
+            .collect();
+
+        SupportedOptions {
+            compressions: compressions,
+            raw: raw,
+        }
+    }
+}
+
+/// Parses a name of a compression algorithm, it's case insensitive.
+pub fn parse_compression(name: &str) -> Option<Compression> {
+    if name.eq_ignore_ascii_case("lz4") {
+        Some(Compression::Lz4)
+    } else if name.eq_ignore_ascii_case("snappy") {
+        Some(Compression::Snappy)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(options: &[(&str, &[&str])]) -> CassandraOptions {
+        options.iter()
+            .map(|&(key, values)| {
+                     (key.to_string(), values.iter().map(|value| value.to_string()).collect())
+                 })
+            .collect()
+    }
+
+    #[test]
+    fn parses_known_options() {
+        let options = SupportedOptions::from(raw(&[(CQL_VERSION, &["3.4.4"]),
+                                                   (COMPRESSION, &["snappy", "LZ4", "zstd"]),
+                                                   (PROTOCOL_VERSIONS,
+                                                    &["3/v3", "4/v4", "5/v5-beta"]),
+                                                   ("SCYLLA_NR_SHARDS", &["4"])]));
+
+        assert_eq!(options.cql_versions(), &["3.4.4"]);
+        assert_eq!(options.compressions, vec![Compression::Snappy, Compression::Lz4]);
+        assert_eq!(options.protocol_versions(), &["3/v3", "4/v4", "5/v5-beta"]);
+        assert_eq!(options.values(COMPRESSION), &["snappy", "LZ4", "zstd"]);
+        assert_eq!(options.values("SCYLLA_NR_SHARDS"), &["4"]);
+        assert_eq!(options.raw().len(), 4);
+        assert!(options.supports_compression(Compression::Lz4));
+        assert!(options.supports_compression(Compression::None));
+    }
+
+    #[test]
+    fn tolerates_missing_options() {
+        let options = SupportedOptions::from(CassandraOptions::new());
+        assert!(options.cql_versions().is_empty());
+        assert!(options.compressions.is_empty());
+        assert!(options.protocol_versions().is_empty());
+        assert!(options.values(CQL_VERSION).is_empty());
+        assert!(!options.supports_compression(Compression::Snappy));
+    }
+}
@@ -0,0 +1,153 @@
+//! Client-side timestamps of requests.
+//!
+//! A timestamp generator of a session, see `Session::timestamp_generator`, gives
+//! timestamps to queries, executions and batches which don't set one themselves.
+//! Writes are ordered by these timestamps instead of by clocks of coordinators.
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cdrs::frame::frame_query::QueryFlags;
+use cdrs::query::{Query, QueryBatch, QueryParams};
+
+/// Source of timestamps in microseconds since the Unix epoch.
+pub trait TimestampGenerator {
+    /// A timestamp of a next request or `None` to leave it to a server.
+    fn next_timestamp(&self) -> Option<i64>;
+}
+
+/// Leaves timestamps to servers. Sessions use it by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NoTimestampGenerator;
+
+impl TimestampGenerator for NoTimestampGenerator {
+    fn next_timestamp(&self) -> Option<i64> {
+        None
+    }
+}
+
+/// Gives timestamps of the system clock which strictly increase. A timestamp
+/// is the previous one plus a microsecond if the clock didn't move forward,
+/// e.g. for calls within the same microsecond or if the clock went backwards.
+#[derive(Debug, Default)]
+pub struct MonotonicTimestampGenerator {
+    last: Mutex<i64>,
+}
+
+impl MonotonicTimestampGenerator {
+    pub fn new() -> MonotonicTimestampGenerator {
+        MonotonicTimestampGenerator::default()
+    }
+
+    fn next_after(&self, now: i64) -> i64 {
+        let mut last = self.last.lock().unwrap();
+        if now <= *last {
+            debug!("Clock is at {}us, but the last timestamp is {}us", now, *last);
+        }
+        *last = if now > *last { now } else { *last + 1 };
+        *last
+    }
+}
+
+impl TimestampGenerator for MonotonicTimestampGenerator {
+    fn next_timestamp(&self) -> Option<i64> {
+        Some(self.next_after(now_micros()))
+    }
+}
+
+fn now_micros() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64 * 1_000_000 + since.subsec_micros() as i64,
+        Err(_) => 0,
+    }
+}
+
+/// Requests which carry a timestamp.
+pub trait Timestamped {
+    fn has_timestamp(&self) -> bool;
+
+    fn set_timestamp(&mut self, timestamp: i64);
+}
+
+impl Timestamped for Query {
+    fn has_timestamp(&self) -> bool {
+        self.timestamp.is_some()
+    }
+
+    fn set_timestamp(&mut self, timestamp: i64) {
+        self.timestamp = Some(timestamp);
+    }
+}
+
+impl Timestamped for QueryParams {
+    fn has_timestamp(&self) -> bool {
+        self.timestamp.is_some()
+    }
+
+    // parameters are serialized according to their flags
+    fn set_timestamp(&mut self, timestamp: i64) {
+        self.timestamp = Some(timestamp);
+        self.flags.push(QueryFlags::WithDefaultTimestamp);
+    }
+}
+
+impl Timestamped for QueryBatch {
+    fn has_timestamp(&self) -> bool {
+        self.timestamp.is_some()
+    }
+
+    fn set_timestamp(&mut self, timestamp: i64) {
+        self.timestamp = Some(timestamp);
+    }
+}
+
+/// Sets a timestamp of `generator` unless `request` has one.
+pub fn apply<R: Timestamped>(generator: &TimestampGenerator, request: &mut R) {
+    if request.has_timestamp() {
+        return;
+    }
+    if let Some(timestamp) = generator.next_timestamp() {
+        request.set_timestamp(timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_strictly_increase() {
+        let generator = MonotonicTimestampGenerator::new();
+        let mut last = generator.next_timestamp().unwrap();
+        assert!(last > 1_500_000_000_000_000);
+        // many of them are generated within the same microsecond
+        for _ in 0..10000 {
+            let next = generator.next_timestamp().unwrap();
+            assert!(next > last, "{} follows {}", next, last);
+            last = next;
+        }
+    }
+
+    #[test]
+    fn timestamps_do_not_follow_clock_backwards() {
+        let generator = MonotonicTimestampGenerator::new();
+        assert_eq!(generator.next_after(1000), 1000);
+        assert_eq!(generator.next_after(1000), 1001);
+        assert_eq!(generator.next_after(900), 1002);
+        assert_eq!(generator.next_after(2000), 2000);
+    }
+
+    #[test]
+    fn keeps_timestamps_of_requests() {
+        use cdrs::query::QueryBuilder;
+
+        let generator = MonotonicTimestampGenerator::new();
+        let mut query = QueryBuilder::new("SELECT * FROM t").finalize();
+        apply(&NoTimestampGenerator, &mut query);
+        assert_eq!(query.timestamp, None);
+        apply(&generator, &mut query);
+        let timestamp = query.timestamp.expect("timestamp is generated");
+        apply(&generator, &mut query);
+        assert_eq!(query.timestamp, Some(timestamp));
+    }
+}
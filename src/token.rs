@@ -0,0 +1,178 @@
+//! Token-aware routing.
+//!
+//! Murmur3Partitioner places a partition on the ring by the Murmur3 hash of its
+//! partition key. Every host owns ranges of the ring which end with its tokens,
+//! hundreds of them with vnodes, and a partition is stored by the host owning
+//! the range of its token and by hosts which follow it on the ring.
+
+use std::collections::HashMap;
+use std::i64;
+use std::net::SocketAddr;
+
+/// Name of the partitioner whose tokens `murmur3_token` computes.
+pub const MURMUR3_PARTITIONER: &'static str = "org.apache.cassandra.dht.Murmur3Partitioner";
+
+const C1: u64 = 0x87c3_7b91_1142_53d5;
+const C2: u64 = 0x4cf5_ad43_2745_937f;
+
+fn fmix(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    k ^= k >> 33;
+    k
+}
+
+fn block(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |block, &byte| (block << 8) | byte as u64)
+}
+
+/// Token of a partition key as Murmur3Partitioner computes it: the first half of
+/// MurmurHash3_x64_128. Like Cassandra, bytes of a tail are sign-extended.
+pub fn murmur3_token(key: &[u8]) -> i64 {
+    let mut h1: u64 = 0;
+    let mut h2: u64 = 0;
+
+    let blocks = key.len() / 16;
+    for i in 0..blocks {
+        let k1 = block(&key[i * 16..i * 16 + 8]);
+        let k2 = block(&key[i * 16 + 8..i * 16 + 16]);
+
+        h1 ^= k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+        h1 = h1.rotate_left(27).wrapping_add(h2).wrapping_mul(5).wrapping_add(0x52dc_e729);
+        h2 ^= k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+        h2 = h2.rotate_left(31).wrapping_add(h1).wrapping_mul(5).wrapping_add(0x3849_5ab5);
+    }
+
+    let tail = &key[blocks * 16..];
+    let mut k1: u64 = 0;
+    let mut k2: u64 = 0;
+    for (i, &byte) in tail.iter().enumerate() {
+        let byte = byte as i8 as i64 as u64;
+        if i < 8 {
+            k1 ^= byte << (i * 8);
+        } else {
+            k2 ^= byte << ((i - 8) * 8);
+        }
+    }
+    if tail.len() > 8 {
+        h2 ^= k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+    }
+    if !tail.is_empty() {
+        h1 ^= k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    }
+
+    h1 ^= key.len() as u64;
+    h2 ^= key.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    h1 = fmix(h1);
+    h2 = fmix(h2);
+    h1 = h1.wrapping_add(h2);
+
+    // the lowest token is reserved for the beginning of the ring
+    match h1 as i64 {
+        i64::MIN => i64::MAX,
+        token => token,
+    }
+}
+
+/// Routing key of a partition key of several columns, as Cassandra serializes
+/// composite keys. A key of a single column is its serialized value as it is.
+pub fn composite_routing_key(components: &[&[u8]]) -> Vec<u8> {
+    if components.len() == 1 {
+        return components[0].to_vec();
+    }
+
+    let mut key = vec![];
+    for component in components {
+        key.extend_from_slice(&[(component.len() >> 8) as u8, component.len() as u8]);
+        key.extend_from_slice(component);
+        key.push(0);
+    }
+    key
+}
+
+/// Tokens of hosts sorted along the ring.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenRing {
+    ring: Vec<(i64, SocketAddr)>,
+}
+
+impl TokenRing {
+    pub fn new(tokens: HashMap<SocketAddr, Vec<i64>>) -> TokenRing {
+        let mut ring: Vec<(i64, SocketAddr)> = tokens.into_iter()
+            .flat_map(|(host, tokens)| tokens.into_iter().map(move |token| (token, host)))
+            .collect();
+        ring.sort();
+        TokenRing { ring: ring }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+
+    /// Tokens of every host in the order of the ring.
+    pub fn tokens(&self) -> Vec<i64> {
+        self.ring.iter().map(|&(token, _)| token).collect()
+    }
+
+    /// Up to `n` distinct hosts which store a partition with `token`: the host
+    /// owning the range of the token first, then hosts which follow it on the ring.
+    pub fn replicas(&self, token: i64, n: usize) -> Vec<SocketAddr> {
+        let mut replicas: Vec<SocketAddr> = vec![];
+        if self.ring.is_empty() {
+            return replicas;
+        }
+
+        let start = match self.ring.binary_search_by(|&(owned, _)| owned.cmp(&token)) {
+            Ok(i) | Err(i) => i % self.ring.len(),
+        };
+        let walk = self.ring[start..].iter().chain(&self.ring[..start]);
+        for &(_, host) in walk {
+            if replicas.len() >= n {
+                break;
+            }
+            if !replicas.contains(&host) {
+                replicas.push(host);
+            }
+        }
+        replicas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_murmur3_tokens() {
+        assert_eq!(murmur3_token(b"123"), -7468325962851647638);
+        assert_eq!(murmur3_token(&b"\x00\xff\x10\xfa\x99".repeat(10)), 5837342703291459765);
+        assert_eq!(murmur3_token(&[0xfe; 8]), -8927430733708461935);
+        assert_eq!(murmur3_token(&[0x10; 8]), 1446172840243228796);
+        assert_eq!(murmur3_token(b"9223372036854775807"), 7162290910810015547);
+    }
+
+    #[test]
+    fn finds_replicas_on_ring_with_vnodes() {
+        let a: SocketAddr = "10.0.0.1:9042".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:9042".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:9042".parse().unwrap();
+        let mut tokens = HashMap::new();
+        tokens.insert(a, vec![-100, 0, 100]);
+        tokens.insert(b, vec![-50, 50]);
+        tokens.insert(c, vec![-40, 150]);
+        let ring = TokenRing::new(tokens);
+
+        assert_eq!(ring.replicas(-100, 1), vec![a]);
+        assert_eq!(ring.replicas(-99, 3), vec![b, c, a]);
+        assert_eq!(ring.replicas(-45, 2), vec![c, a]);
+        assert_eq!(ring.replicas(120, 3), vec![c, a, b]);
+        // tokens past the last one belong to the first host of the ring
+        assert_eq!(ring.replicas(151, 2), vec![a, b]);
+        assert_eq!(ring.replicas(0, 10).len(), 3);
+        assert!(TokenRing::default().replicas(0, 1).is_empty());
+    }
+}
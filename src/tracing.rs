@@ -0,0 +1,207 @@
+//! Traces of requests.
+//!
+//! A request sent with the tracing flag, see `RequestOptions::tracing`, is traced
+//! by nodes which serve it and its response carries the id of the trace. Nodes
+//! write traces into `system_traces` asynchronously, so a trace may be missing or
+//! incomplete right after the response. `Session::get_tracing_info` reads it
+//! again a few times until the coordinator reports the duration of the request.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use cdrs::frame::Frame;
+use cdrs::types::value::{Bytes, Value};
+use uuid::Uuid;
+
+use rows;
+use error;
+
+pub const SELECT_TRACE_SESSION: &'static str = "SELECT coordinator, duration, started_at, \
+                                                parameters, request FROM system_traces.sessions \
+                                                WHERE session_id = ?";
+
+pub const SELECT_TRACE_EVENTS: &'static str = "SELECT activity, source, source_elapsed, thread \
+                                               FROM system_traces.events WHERE session_id = ?";
+
+/// Number of times a trace is read before it's considered missing.
+pub const TRACE_ATTEMPTS: usize = 5;
+
+/// Interval between reads of an incomplete trace.
+pub const TRACE_INTERVAL_MS: u64 = 20;
+
+/// A trace of a request, see `Session::get_tracing_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracingInfo {
+    pub coordinator: Option<IpAddr>,
+    /// Time the coordinator took to serve the request.
+    pub duration: Duration,
+    /// Milliseconds since the Unix epoch.
+    pub started_at: Option<i64>,
+    /// Type of the request, e.g. `Execute CQL3 query`.
+    pub request: Option<String>,
+    /// Parameters of the request, e.g. its query and consistency.
+    pub parameters: HashMap<String, String>,
+    /// Events in the order of their ids, i.e. of time.
+    pub events: Vec<TraceEvent>,
+}
+
+/// A step of a traced request on one of nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub activity: Option<String>,
+    pub source: Option<IpAddr>,
+    /// Time since the node received the request.
+    pub source_elapsed: Option<Duration>,
+    pub thread: Option<String>,
+}
+
+/// Value of a trace id to be bound to `session_id` markers.
+pub fn session_id(tracing_id: &Uuid) -> Value {
+    Value::new_normal(Bytes::new(tracing_id.as_bytes().to_vec()))
+}
+
+/// Reads a response to `SELECT_TRACE_SESSION`. Resolves into `None` if the trace
+/// is not complete yet, events are left empty.
+pub fn trace_session(frame: Frame) -> error::Result<Option<TracingInfo>> {
+    let sessions = try!(rows::raw_rows(frame));
+    let row = match sessions.first() {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let duration = match try!(rows::int_cell(row.get(1))) {
+        Some(micros) => micros_to_duration(micros),
+        None => return Ok(None),
+    };
+    Ok(Some(TracingInfo {
+                coordinator: try!(rows::inet_cell(row.get(0))),
+                duration: duration,
+                started_at: try!(rows::bigint_cell(row.get(2))),
+                parameters: try!(rows::text_map_cell(row.get(3))),
+                request: try!(rows::text_cell(row.get(4))),
+                events: vec![],
+            }))
+}
+
+/// Reads a response to `SELECT_TRACE_EVENTS`.
+pub fn trace_events(frame: Frame) -> error::Result<Vec<TraceEvent>> {
+    let mut events = vec![];
+    for row in try!(rows::raw_rows(frame)) {
+        events.push(TraceEvent {
+                        activity: try!(rows::text_cell(row.get(0))),
+                        source: try!(rows::inet_cell(row.get(1))),
+                        source_elapsed: try!(rows::int_cell(row.get(2))).map(micros_to_duration),
+                        thread: try!(rows::text_cell(row.get(3))),
+                    });
+    }
+    Ok(events)
+}
+
+fn micros_to_duration(micros: i32) -> Duration {
+    Duration::from_micros(if micros < 0 { 0 } else { micros as u64 })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Instant;
+    use tokio_core::reactor::Core;
+    use cdrs::query::QueryBuilder;
+
+    use super::*;
+    use mock::{self, MockTransport, RESULT};
+    use request::RequestOptions;
+
+    const TRACING_ID: [u8; 16] = [0x9c, 0x2f, 0x6f, 0x30, 0x4b, 0x0e, 0x11, 0xe9, 0x8b, 0x62, 0x7b,
+                                  0x1c, 0x2d, 0x3e, 0x4f, 0x50];
+
+    fn session_rows(duration: Option<i32>) -> Vec<u8> {
+        let columns = [("coordinator", mock::INET),
+                       ("duration", mock::INT),
+                       ("started_at", mock::BIGINT),
+                       ("parameters", mock::VARCHAR_MAP),
+                       ("request", mock::VARCHAR)];
+        let row = vec![mock::inet([10, 0, 0, 1]),
+                       duration.and_then(mock::int),
+                       mock::bigint(1546300800000),
+                       mock::text_map(&[("consistency_level", "ONE"),
+                                        ("query", "SELECT * FROM t")]),
+                       mock::text("Execute CQL3 query")];
+        mock::response(RESULT, 0, &mock::rows_body(&columns, &[row], None))
+    }
+
+    fn event_rows() -> Vec<u8> {
+        let columns = [("activity", mock::VARCHAR),
+                       ("source", mock::INET),
+                       ("source_elapsed", mock::INT),
+                       ("thread", mock::VARCHAR)];
+        let rows = [vec![mock::text("Parsing SELECT * FROM t"),
+                         mock::inet([10, 0, 0, 1]),
+                         mock::int(120),
+                         mock::text("Native-Transport-Requests-1")],
+                    vec![mock::text("Read 1 live rows"),
+                         mock::inet([10, 0, 0, 2]),
+                         mock::int(950),
+                         None]];
+        mock::response(RESULT, 0, &mock::rows_body(&columns, &rows, None))
+    }
+
+    #[test]
+    fn reads_trace_once_it_is_complete() {
+        let transport = MockTransport::new();
+        transport.push_read(mock::traced_response(RESULT, 0, TRACING_ID, &mock::void_body()));
+        // the trace is not written yet, then it has no duration
+        transport.push_read(mock::response(RESULT,
+                                           0,
+                                           &mock::rows_body(&[("coordinator", mock::INET)],
+                                                            &[],
+                                                            None)));
+        transport.push_read(session_rows(None));
+        transport.push_read(session_rows(Some(1500)));
+        transport.push_read(event_rows());
+
+        let mut core = Core::new().unwrap();
+        let session = mock::session(transport.clone());
+        let select = QueryBuilder::new("SELECT * FROM t").finalize();
+        let (session, (_, tracing_id)) =
+            core.run(session.query_traced(select, RequestOptions::new())).unwrap();
+        let tracing_id = tracing_id.expect("response is traced");
+        assert_eq!(tracing_id.as_bytes(), &TRACING_ID);
+
+        let started = Instant::now();
+        let (_, info) = core.run(session.get_tracing_info(tracing_id)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(2 * TRACE_INTERVAL_MS));
+        assert_eq!(mock::opcodes(&transport.written()).len(), 5);
+
+        assert_eq!(info.coordinator, Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert_eq!(info.duration, Duration::from_micros(1500));
+        assert_eq!(info.started_at, Some(1546300800000));
+        assert_eq!(info.request, Some("Execute CQL3 query".to_string()));
+        assert_eq!(info.parameters.get("query"), Some(&"SELECT * FROM t".to_string()));
+        assert_eq!(info.events.len(), 2);
+        assert_eq!(info.events[1],
+                   TraceEvent {
+                       activity: Some("Read 1 live rows".to_string()),
+                       source: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))),
+                       source_elapsed: Some(Duration::from_micros(950)),
+                       thread: None,
+                   });
+    }
+
+    #[test]
+    fn missing_trace_is_not_found() {
+        let transport = MockTransport::new();
+        for _ in 0..TRACE_ATTEMPTS {
+            transport.push_read(session_rows(None));
+        }
+
+        let mut core = Core::new().unwrap();
+        let session = mock::session(transport.clone());
+        let tracing_id = Uuid::from_bytes(&TRACING_ID).unwrap();
+        match core.run(session.get_tracing_info(tracing_id)) {
+            Err(error::Error::NotFound(_)) => {}
+            other => panic!("NotFound expected, got {:?}", other.map(|(_, info)| info)),
+        }
+        assert_eq!(mock::opcodes(&transport.written()).len(), TRACE_ATTEMPTS);
+    }
+}
@@ -1,55 +1,952 @@
-use std::net;
-use std::io;
+//! Transports which carry frames.
+//!
+//! Implementors of `CDRSTransport` are expected to be non-blocking: `read` and
+//! `write` return `WouldBlock` instead of waiting. `flush` must push every buffered
+//! byte towards a server, every request path flushes a transport once a frame
+//! is written completely, so buffered and TLS transports work without
+//! any special handling.
+//!
+//! A TCP transport is bound either to a reactor of a given `Handle`, or to
+//! a reactor of a task it's created from, so code which runs on a reactor doesn't
+//! need to pass a `Handle` around. A clone of a transport connects on a reactor
+//! of a task which reads or writes it first.
+//!
+//! A TCP transport collects small writes in a buffer until it's flushed, so
+//! frames which are queued together reach a socket in a single write. A write
+//! which doesn't fit is sent along with buffered bytes as a vectored write.
+//!
+//! A timeout of a transport bounds how long a read or a write may be blocked
+//! without any progress, after that it fails with `TimedOut`. Deadlines are
+//! tracked with a timer of the current reactor.
+//!
+//! `TransportTcp::new_to_shard` connects to a shard-aware port of a Scylla node
+//! from a local port which makes the connection land on a given shard.
+//!
+//! `TransportTls` encrypts a connection with `native-tls`, it's available with
+//! the `tls` feature.
+
+use std::net::{self, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::io::{self, IoSlice, Write};
+use std::sync::Mutex;
+use std::thread;
 use std::time;
 
-use tokio_core::reactor::Handle;
+use futures::{Async, Future};
+use futures::future::{self, Loop};
+use futures::sync::oneshot;
+use net2::TcpBuilder;
+use tokio_core::reactor::{Handle, Remote};
 use tokio_core::net::TcpStream;
+use tokio_executor::{DefaultExecutor, Executor};
+use tokio_timer::Delay;
 use cdrs::transport::CDRSTransport;
+#[cfg(feature = "tls")]
+use native_tls;
+#[cfg(feature = "tls")]
+use tokio_tls::{TlsConnector, TlsStream};
+
+use scylla::{self, ShardInfo};
+
+/// Size of a write buffer of a TCP transport, see `TransportTcp::set_write_buffer_size`.
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Future of a transport which is being connected.
+pub type TransportFuture<T> = Box<Future<Item = T, Error = io::Error> + Send>;
+
+/// Socket options which are set on a transport and its clones.
+#[derive(Clone, Copy, Debug, Default)]
+struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<time::Duration>,
+}
+
+impl SocketOptions {
+    fn apply(&self, tcp: &TcpStream) -> io::Result<()> {
+        try!(tcp.set_nodelay(self.nodelay));
+        tcp.set_keepalive(self.keepalive)
+    }
+}
+
+enum Socket<S> {
+    /// A socket of a clone which is connected by a first read or write. The future
+    /// is never locked, the mutex only makes a transport `Sync` as cdrs requires.
+    Connecting(Mutex<TransportFuture<S>>),
+    Connected(S),
+}
 
-pub struct TransportTcp(TcpStream);
+impl<S> Socket<S> {
+    /// Returns the connected stream. It's `WouldBlock` while a clone connects,
+    /// `on_connect` is called with a clone's stream once it's connected.
+    fn stream<F>(&mut self, on_connect: F) -> io::Result<&mut S>
+        where F: FnOnce(&S) -> io::Result<()>
+    {
+        let connected = match *self {
+            Socket::Connecting(ref mut connecting) => {
+                let connecting = connecting.get_mut().expect("a connecting future is never locked");
+                match try!(connecting.poll()) {
+                    Async::Ready(stream) => Some(stream),
+                    Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+                }
+            }
+            Socket::Connected(_) => None,
+        };
+        if let Some(stream) = connected {
+            try!(on_connect(&stream));
+            *self = Socket::Connected(stream);
+        }
+
+        match *self {
+            Socket::Connected(ref mut stream) => Ok(stream),
+            Socket::Connecting(_) => unreachable!("a socket is connected above"),
+        }
+    }
+
+    fn connected(&self) -> Option<&S> {
+        match *self {
+            Socket::Connected(ref stream) => Some(stream),
+            Socket::Connecting(_) => None,
+        }
+    }
+}
+
+/// Deadlines of blocked reads and writes of a transport.
+#[derive(Default)]
+struct Deadlines {
+    timeout: Option<time::Duration>,
+    /// They are set once a read or a write blocks.
+    read: Option<Delay>,
+    write: Option<Delay>,
+}
+
+impl Deadlines {
+    fn new(timeout: Option<time::Duration>) -> Deadlines {
+        Deadlines { timeout: timeout, ..Deadlines::default() }
+    }
+
+    fn check_read<R>(&mut self, result: io::Result<R>) -> io::Result<R> {
+        check_deadline(result, self.timeout, &mut self.read)
+    }
+
+    fn check_write<R>(&mut self, result: io::Result<R>) -> io::Result<R> {
+        if result.is_ok() {
+            // a response to a new request is awaited from now on
+            self.read = None;
+        }
+        check_deadline(result, self.timeout, &mut self.write)
+    }
+}
+
+pub struct TransportTcp {
+    socket: Socket<TcpStream>,
+    /// Address of the server, a clone connects to it again.
+    peer: SocketAddr,
+    options: SocketOptions,
+    deadlines: Deadlines,
+    /// Bytes which are written once the buffer is full or the transport is flushed.
+    write_buffer: Vec<u8>,
+    write_buffer_size: usize,
+}
 
 impl TransportTcp {
-    // TODO: change to fn new<A: net::ToSocketAddrs>(addr: A) when _TransportTcp::new
-    // to be changed in order to accept net::ToSocketAddrs as arg
-    pub fn new(addr: &str, h: &Handle) -> io::Result<TransportTcp> {
-        net::TcpStream::connect(addr)
-            .and_then(|t| TcpStream::from_stream(t, h))
-            .map(|transport| TransportTcp(transport))
+    /// Connects to `addr` on a reactor of `h`. The connection is driven by
+    /// the reactor, so a slow server doesn't stall it. Addresses `addr` resolves
+    /// into are tried in order until one of them accepts a connection:
+    ///
+    /// ```no_run
+    /// extern crate cdrs;
+    /// extern crate cdrs_future;
+    /// extern crate futures;
+    /// extern crate tokio_core;
+    ///
+    /// use cdrs::authenticators::NoneAuthenticator;
+    /// use cdrs::compression::Compression;
+    /// use cdrs_future::client::CDRS;
+    /// use cdrs_future::error;
+    /// use cdrs_future::transport::TransportTcp;
+    /// use futures::Future;
+    /// use tokio_core::reactor::Core;
+    ///
+    /// fn main() {
+    ///     let mut core = Core::new().unwrap();
+    ///     let session = TransportTcp::new("127.0.0.1:9042", &core.handle())
+    ///         .from_err::<error::Error>()
+    ///         .and_then(|transport| {
+    ///                       CDRS::new(transport, NoneAuthenticator).start(Compression::None)
+    ///                   });
+    ///     let session = core.run(session).unwrap();
+    /// }
+    /// ```
+    pub fn new<A>(addr: A, h: &Handle) -> TransportFuture<TransportTcp>
+        where A: ToSocketAddrs + Send + 'static
+    {
+        let remote = h.remote().clone();
+        Box::new(resolve(addr)
+                     .and_then(move |addrs| connect_any(addrs, Some(remote)))
+                     .map(|(tcp, peer)| TransportTcp::connected(tcp, peer)))
+    }
+
+    /// Connects to `addr` on a reactor of the current task, e.g. one within
+    /// `Core::run`. It fails if it's called outside of a reactor, where `new`
+    /// with an explicit `Handle` has to be used instead.
+    pub fn connect(addr: &str) -> TransportFuture<TransportTcp> {
+        if DefaultExecutor::current().status().is_err() {
+            let err = io::Error::new(io::ErrorKind::Other,
+                                     "TransportTcp::connect is called outside of a reactor, \
+                                      use TransportTcp::new with a Handle instead");
+            return Box::new(future::err(err));
+        }
+
+        Box::new(resolve(addr.to_string())
+                     .and_then(|addrs| connect_any(addrs, None))
+                     .map(|(tcp, peer)| TransportTcp::connected(tcp, peer)))
+    }
+
+    /// Connects to the shard-aware port of a Scylla node at `addr` from a local
+    /// port which makes the connection land on `shard`, ports in use are skipped.
+    /// A node without a shard-aware port is connected as with `new`, so
+    /// the connection lands on any shard. Clones land on any shard as well.
+    pub fn new_to_shard(addr: SocketAddr,
+                        info: &ShardInfo,
+                        shard: u32,
+                        h: &Handle)
+                        -> TransportFuture<TransportTcp> {
+        let peer = match info.shard_aware_port {
+            Some(port) => SocketAddr::new(addr.ip(), port),
+            None => return TransportTcp::new(addr, h),
+        };
+        let tcp = match bind_for_shard(&peer, info, shard) {
+            Ok(tcp) => tcp,
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        Box::new(TcpStream::connect_stream(tcp, &peer, h)
+                     .map(move |tcp| TransportTcp::connected(tcp, peer)))
+    }
+
+    fn connected(tcp: TcpStream, peer: SocketAddr) -> TransportTcp {
+        TransportTcp {
+            socket: Socket::Connected(tcp),
+            peer: peer,
+            options: SocketOptions::default(),
+            deadlines: Deadlines::default(),
+            write_buffer: Vec::new(),
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+        }
+    }
+
+    /// Sets a size of a buffer which collects writes until the transport is
+    /// flushed, zero disables it. Clones of the transport inherit it.
+    pub fn set_write_buffer_size(&mut self, size: usize) {
+        self.write_buffer_size = size;
+    }
+
+    pub fn write_buffer_size(&self) -> usize {
+        self.write_buffer_size
+    }
+
+    /// Sets `TCP_NODELAY` of the socket. Clones of the transport inherit it.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.options.nodelay = nodelay;
+        self.apply_options()
     }
+
+    /// Sets TCP keepalive of the socket. Clones of the transport inherit it.
+    pub fn set_keepalive(&mut self, keepalive: Option<time::Duration>) -> io::Result<()> {
+        self.options.keepalive = keepalive;
+        self.apply_options()
+    }
+
+    /// Address of the server the transport is connected to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn apply_options(&self) -> io::Result<()> {
+        match self.socket.connected() {
+            Some(tcp) => self.options.apply(tcp),
+            // options are set once it's connected
+            None => Ok(()),
+        }
+    }
+
+    fn stream(&mut self) -> io::Result<&mut TcpStream> {
+        let options = self.options;
+        self.socket.stream(|tcp| options.apply(tcp))
+    }
+
+    /// Checks a deadline of a write, which has made progress if a part of
+    /// `buffered` bytes was sent before it blocked.
+    fn check_write<R>(&mut self, result: io::Result<R>, buffered: usize) -> io::Result<R> {
+        if self.write_buffer.len() < buffered {
+            self.deadlines.write = None;
+        }
+        self.deadlines.check_write(result)
+    }
+
+    /// Buffers `buf` if it fits, otherwise writes it after buffered bytes until
+    /// the rest of it fits or the socket blocks. Bytes of `buf` which were sent
+    /// or buffered are reported even if the socket fails after them.
+    fn write_buffered(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let options = self.options;
+        let mut written = 0;
+        loop {
+            let rest = &buf[written..];
+            if self.write_buffer.len() + rest.len() <= self.write_buffer_size {
+                self.write_buffer.extend_from_slice(rest);
+                return Ok(buf.len());
+            }
+
+            let tcp = try!(self.socket.stream(|tcp| options.apply(tcp)));
+            let result = if self.write_buffer.is_empty() {
+                tcp.write(rest)
+            } else {
+                tcp.write_vectored(&[IoSlice::new(&self.write_buffer), IoSlice::new(rest)])
+            };
+            let n = match result {
+                Ok(0) => return Ok(written),
+                Ok(n) => n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) if written > 0 => return Ok(written),
+                Err(err) => return Err(err),
+            };
+
+            let buffered = self.write_buffer.len();
+            if n < buffered {
+                // a part of `buf` may fit now
+                self.write_buffer.drain(..n);
+                continue;
+            }
+            self.write_buffer.clear();
+            written += n - buffered;
+            if written == buf.len() {
+                return Ok(written);
+            }
+        }
+    }
+
+    /// Writes every buffered byte and flushes the socket.
+    fn flush_buffered(&mut self) -> io::Result<()> {
+        let options = self.options;
+        let tcp = try!(self.socket.stream(|tcp| options.apply(tcp)));
+        while !self.write_buffer.is_empty() {
+            match tcp.write(&self.write_buffer) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "socket has not accepted buffered bytes"))
+                }
+                Ok(n) => {
+                    self.write_buffer.drain(..n);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        tcp.flush()
+    }
+}
+
+/// Resolves `addr` on a thread of its own, so a slow DNS doesn't stall a reactor.
+fn resolve<A>(addr: A) -> TransportFuture<Vec<SocketAddr>>
+    where A: ToSocketAddrs + Send + 'static
+{
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+                      let addrs = addr.to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>());
+                      let _ = sender.send(addrs);
+                  });
+
+    Box::new(receiver.then(|result| match result {
+                               Ok(Ok(ref addrs)) if addrs.is_empty() => {
+                                   Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                      "address doesn't resolve to anything"))
+                               }
+                               Ok(result) => result,
+                               Err(_) => {
+                                   Err(io::Error::new(io::ErrorKind::Other,
+                                                      "address resolution panicked"))
+                               }
+                           }))
+}
+
+/// Connects to `addrs` in order until a connection succeeds, on a reactor of
+/// `remote` if it's run on one. It fails with an error of the last address.
+fn connect_any(addrs: Vec<SocketAddr>,
+               remote: Option<Remote>)
+               -> TransportFuture<(TcpStream, SocketAddr)> {
+    let attempts = future::loop_fn((addrs.into_iter(), None), move |(mut addrs, last_err)| {
+        let addr = match addrs.next() {
+            Some(addr) => addr,
+            None => return future::err(last_err.expect("addresses are resolved")).boxed(),
+        };
+        let connecting = match remote.as_ref().and_then(Remote::handle) {
+            Some(handle) => TcpStream::connect(&addr, &handle),
+            None => TcpStream::connect2(&addr),
+        };
+
+        connecting.then(move |result| match result {
+                            Ok(tcp) => Ok(Loop::Break((tcp, addr))),
+                            Err(err) => Ok(Loop::Continue((addrs, Some(err)))),
+                        })
+            .boxed()
+    });
+    Box::new(attempts)
+}
+
+/// Binds a socket to the first free local port from which a connection to
+/// `peer` lands on `shard`.
+fn bind_for_shard(peer: &SocketAddr, info: &ShardInfo, shard: u32) -> io::Result<net::TcpStream> {
+    let mut from = scylla::LOCAL_PORT_LOW;
+    while let Some(port) = info.local_port_for(shard, from) {
+        let (builder, local) = match *peer {
+            SocketAddr::V4(_) => {
+                (try!(TcpBuilder::new_v4()), SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+            }
+            SocketAddr::V6(_) => {
+                (try!(TcpBuilder::new_v6()), SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))
+            }
+        };
+        match builder.bind(local) {
+            Ok(_) => return builder.to_tcp_stream(),
+            Err(ref err) if err.kind() == io::ErrorKind::AddrInUse => (),
+            Err(err) => return Err(err),
+        }
+        from = match port.checked_add(1) {
+            Some(from) => from,
+            None => break,
+        };
+    }
+
+    Err(io::Error::new(io::ErrorKind::AddrInUse,
+                       format!("no free local port for shard {}", shard)))
+}
+
+/// Turns `WouldBlock` of an operation into `TimedOut` once the operation has been
+/// blocked for `timeout`. The deadline is set when it blocks first, and it's reset
+/// as soon as the operation makes progress.
+fn check_deadline<R>(result: io::Result<R>,
+                     timeout: Option<time::Duration>,
+                     deadline: &mut Option<Delay>)
+                     -> io::Result<R> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return result,
+    };
+    match result {
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+        _ => {
+            *deadline = None;
+            return result;
+        }
+    }
+
+    let fired = {
+        let delay = deadline.get_or_insert_with(|| Delay::new(time::Instant::now() + timeout));
+        match delay.poll() {
+            Ok(Async::Ready(())) => true,
+            Ok(Async::NotReady) => false,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+        }
+    };
+    if fired {
+        *deadline = None;
+        return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                  format!("no progress of a transport in {:?}", timeout)));
+    }
+    result
 }
 
 impl io::Read for TransportTcp {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        let result = self.stream().and_then(|tcp| tcp.read(buf));
+        self.deadlines.check_read(result)
     }
 }
 
 impl io::Write for TransportTcp {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        let buffered = self.write_buffer.len();
+        let result = self.write_buffered(buf);
+        self.check_write(result, buffered)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.0.flush()
+        let buffered = self.write_buffer.len();
+        let result = self.flush_buffered();
+        self.check_write(result, buffered)
     }
 }
 
 impl CDRSTransport for TransportTcp {
+    /// Opens another connection to the same server with the same socket options.
+    /// The clone can be used right away, its reads and writes are `WouldBlock`
+    /// until the connection is established.
     fn try_clone(&self) -> io::Result<TransportTcp> {
-        // let addr = try!(self.tcp.peer_addr());
-        // TcpStream::connect(addr).map(|socket| TransportTcp { tcp: socket })
-        Err(io::Error::new(io::ErrorKind::Other, "not implemented"))
+        Ok(TransportTcp {
+               socket: Socket::Connecting(Mutex::new(Box::new(TcpStream::connect2(&self.peer)))),
+               peer: self.peer,
+               options: self.options,
+               deadlines: Deadlines::new(self.deadlines.timeout),
+               write_buffer: Vec::new(),
+               write_buffer_size: self.write_buffer_size,
+           })
+    }
+
+    /// Sends buffered bytes before the socket is shut down for writes.
+    fn close(&mut self, close: net::Shutdown) -> io::Result<()> {
+        if close != net::Shutdown::Read && !self.write_buffer.is_empty() {
+            let buffered = self.write_buffer.len();
+            let result = self.flush_buffered();
+            try!(self.check_write(result, buffered));
+        }
+        match self.socket.connected() {
+            Some(tcp) => tcp.shutdown(close),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// Sets how long a read or a write may be blocked before it fails with
+    /// `TimedOut`. `None` clears the timeout.
+    fn set_timeout(&mut self, dur: Option<time::Duration>) -> io::Result<()> {
+        self.deadlines = Deadlines::new(dur);
+        Ok(())
     }
+}
+
+/// A transport which encrypts a TCP connection with TLS. A server certificate
+/// is verified by a given connector, e.g. one which trusts a CA of a cluster:
+///
+/// ```ignore
+///   let ca = native_tls::Certificate::from_pem(&pem)?;
+///   let connector = native_tls::TlsConnector::builder().add_root_certificate(ca).build()?;
+///   let transport = TransportTls::new("10.0.0.1:9142", "node1.example.com", connector, &handle);
+/// ```
+#[cfg(feature = "tls")]
+pub struct TransportTls {
+    socket: Socket<TlsStream<TcpStream>>,
+    peer: SocketAddr,
+    /// Name of the server its certificate is verified against.
+    domain: String,
+    connector: TlsConnector,
+    options: SocketOptions,
+    deadlines: Deadlines,
+}
+
+#[cfg(feature = "tls")]
+impl TransportTls {
+    /// Connects to `addr` on a reactor of `h` and performs a TLS handshake,
+    /// checking that a certificate of the server is valid for `domain`.
+    pub fn new<A>(addr: A,
+                  domain: &str,
+                  connector: native_tls::TlsConnector,
+                  h: &Handle)
+                  -> TransportFuture<TransportTls>
+        where A: ToSocketAddrs + Send + 'static
+    {
+        let remote = h.remote().clone();
+        let connector = TlsConnector::from(connector);
+        let domain = domain.to_string();
+
+        let connecting = resolve(addr).and_then(move |addrs| connect_any(addrs, Some(remote)));
+        Box::new(connecting.and_then(move |(tcp, peer)| {
+            tls_handshake(future::ok(tcp),
+                          connector.clone(),
+                          domain.clone(),
+                          SocketOptions::default())
+                .map(move |tls| {
+                         TransportTls {
+                             socket: Socket::Connected(tls),
+                             peer: peer,
+                             domain: domain,
+                             connector: connector,
+                             options: SocketOptions::default(),
+                             deadlines: Deadlines::default(),
+                         }
+                     })
+        }))
+    }
+
+    /// Sets `TCP_NODELAY` of the socket. Clones of the transport inherit it.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
+        self.options.nodelay = nodelay;
+        self.apply_options()
+    }
+
+    /// Sets TCP keepalive of the socket. Clones of the transport inherit it.
+    pub fn set_keepalive(&mut self, keepalive: Option<time::Duration>) -> io::Result<()> {
+        self.options.keepalive = keepalive;
+        self.apply_options()
+    }
+
+    /// Address of the server the transport is connected to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    fn apply_options(&self) -> io::Result<()> {
+        match self.socket.connected() {
+            Some(tls) => self.options.apply(tls.get_ref().get_ref()),
+            // options are set once it's connected
+            None => Ok(()),
+        }
+    }
+}
+
+/// Performs a TLS handshake on a connection `tcp` resolves into.
+#[cfg(feature = "tls")]
+fn tls_handshake<F>(tcp: F,
+                    connector: TlsConnector,
+                    domain: String,
+                    options: SocketOptions)
+                    -> TransportFuture<TlsStream<TcpStream>>
+    where F: Future<Item = TcpStream, Error = io::Error> + Send + 'static
+{
+    Box::new(tcp.and_then(move |tcp| {
+                              try!(options.apply(&tcp));
+                              Ok(tcp)
+                          })
+                 .and_then(move |tcp| {
+                               connector.connect(&domain, tcp)
+                                   .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+                           }))
+}
+
+#[cfg(feature = "tls")]
+impl io::Read for TransportTls {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.socket.stream(|_| Ok(())).and_then(|tls| tls.read(buf));
+        self.deadlines.check_read(result)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl io::Write for TransportTls {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.socket.stream(|_| Ok(())).and_then(|tls| tls.write(buf));
+        self.deadlines.check_write(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.socket.stream(|_| Ok(())).and_then(|tls| tls.flush());
+        self.deadlines.check_write(result)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl CDRSTransport for TransportTls {
+    /// Opens another encrypted connection to the same server with the same
+    /// socket options. Its reads and writes are `WouldBlock` until the TLS
+    /// handshake is done.
+    fn try_clone(&self) -> io::Result<TransportTls> {
+        let handshake = tls_handshake(TcpStream::connect2(&self.peer),
+                                      self.connector.clone(),
+                                      self.domain.clone(),
+                                      self.options);
+        Ok(TransportTls {
+               socket: Socket::Connecting(Mutex::new(handshake)),
+               peer: self.peer,
+               domain: self.domain.clone(),
+               connector: self.connector.clone(),
+               options: self.options,
+               deadlines: Deadlines::new(self.deadlines.timeout),
+           })
+    }
+
+    /// Sends TLS `close_notify` before the TCP connection is shut down.
+    fn close(&mut self, close: net::Shutdown) -> io::Result<()> {
+        let tls = match self.socket {
+            Socket::Connected(ref mut tls) => tls,
+            Socket::Connecting(_) => return Err(io::ErrorKind::NotConnected.into()),
+        };
+        match tls.get_mut().shutdown() {
+            Ok(()) => (),
+            // the connection is shut down anyway if the notification doesn't fit
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            Err(err) => return Err(err),
+        }
+        tls.get_ref().get_ref().shutdown(close)
+    }
+
+    /// Sets how long a read or a write may be blocked before it fails with
+    /// `TimedOut`. `None` clears the timeout.
+    fn set_timeout(&mut self, dur: Option<time::Duration>) -> io::Result<()> {
+        self.deadlines = Deadlines::new(dur);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use futures::future::{self, Future};
+    use tokio_core::reactor::{Core, Timeout};
+    use cdrs::authenticators::NoneAuthenticator;
+    use cdrs::compression::Compression;
+
+    use super::*;
+    use client::CDRS;
+    use error;
+    use mock::{self, SUPPORTED};
+
+    /// Answers an OPTIONS frame on each of `connections` accepted connections.
+    fn serve_options(listener: net::TcpListener, connections: usize) -> thread::JoinHandle<()> {
+        thread::spawn(move || for _ in 0..connections {
+                          let (mut socket, _) = listener.accept().unwrap();
+                          let mut header = [0; 9];
+                          socket.read_exact(&mut header).unwrap();
+                          let stream = ((header[2] as i16) << 8) | header[3] as i16;
+                          let body = mock::supported_body(&[("COMPRESSION", &["lz4"])]);
+                          socket.write_all(&mock::response(SUPPORTED, stream, &body)).unwrap();
+                      })
+    }
+
+    #[test]
+    fn connects_on_current_reactor() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let mut core = Core::new().unwrap();
+        let transport = core.run(future::lazy(move || TransportTcp::connect(&addr)));
+        assert!(transport.is_ok());
+    }
+
+    #[test]
+    fn dropped_session_closes_connection() {
+        use client::Session;
+
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut core = Core::new().unwrap();
+        let transport = core.run(TransportTcp::new(addr, &core.handle())).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        drop(Session::start(CDRS::new(transport, NoneAuthenticator)));
+        let mut buf = [0; 16];
+        assert_eq!(server.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn clones_connect_to_the_same_server() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve_options(listener, 2);
+
+        let mut core = Core::new().unwrap();
+        let mut transport = core.run(TransportTcp::new(addr, &core.handle())).unwrap();
+        transport.set_nodelay(true).unwrap();
+        transport.set_keepalive(Some(Duration::from_secs(30))).unwrap();
+        let clone = transport.try_clone().unwrap();
+        assert_eq!(clone.peer_addr(), addr);
+        assert!(clone.options.nodelay);
+        assert_eq!(clone.options.keepalive, Some(Duration::from_secs(30)));
+
+        let options = CDRS::new(transport, NoneAuthenticator)
+            .supported()
+            .join(CDRS::new(clone, NoneAuthenticator).supported());
+        let ((_, original), (_, cloned)) = core.run(options).unwrap();
+        assert_eq!(original.compressions, vec![Compression::Lz4]);
+        assert_eq!(cloned, original);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connects_to_shard_from_local_port() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let info = ShardInfo {
+            shard: 0,
+            nr_shards: 4,
+            msb_ignore: 0,
+            shard_aware_port: Some(port),
+        };
+
+        let mut core = Core::new().unwrap();
+        let addr = "127.0.0.1:9042".parse().unwrap();
+        let transport = core.run(TransportTcp::new_to_shard(addr, &info, 3, &core.handle()))
+            .unwrap();
+        assert_eq!(transport.peer_addr().port(), port);
+
+        let (_, client) = listener.accept().unwrap();
+        assert!(client.port() >= scylla::LOCAL_PORT_LOW);
+        assert_eq!(info.shard_of_local_port(client.port()), 3);
+    }
+
+    #[test]
+    fn buffers_writes_until_flush() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut core = Core::new().unwrap();
+        let mut transport = core.run(TransportTcp::new(addr, &core.handle())).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        transport.set_write_buffer_size(16);
+        assert_eq!(transport.try_clone().unwrap().write_buffer_size(), 16);
+
+        let big: Vec<u8> = (0..40).collect();
+        let written = core.run(future::lazy(|| -> io::Result<()> {
+            assert_eq!(try!(transport.write(&[1, 2, 3])), 3);
+            assert_eq!(try!(transport.write(&[4, 5])), 2);
+            assert_eq!(transport.write_buffer, vec![1, 2, 3, 4, 5]);
+
+            // buffered bytes go first, then `big` is written as a whole
+            assert_eq!(try!(transport.write(&big)), big.len());
+            assert!(transport.write_buffer.is_empty());
+            try!(transport.flush());
+            assert!(transport.write_buffer.is_empty());
+            Ok(())
+        }));
+        written.unwrap();
+
+        let mut received = vec![0; 45];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..5], &[1, 2, 3, 4, 5]);
+        assert_eq!(&received[5..], &big[..]);
+    }
+
+    #[test]
+    fn sends_buffered_bytes_on_close() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut core = Core::new().unwrap();
+        let mut transport = core.run(TransportTcp::new(addr, &core.handle())).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let closed = core.run(future::lazy(|| -> io::Result<()> {
+            assert_eq!(try!(transport.write(&[1, 2, 3])), 3);
+            transport.close(net::Shutdown::Both)
+        }));
+        closed.unwrap();
+
+        let mut received = vec![];
+        server.read_to_end(&mut received).unwrap();
+        assert_eq!(received, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn times_out_reads_of_a_silent_server() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+                                       let (socket, _) = listener.accept().unwrap();
+                                       thread::sleep(Duration::from_millis(500));
+                                       drop(socket);
+                                   });
+
+        let mut core = Core::new().unwrap();
+        let mut transport = core.run(TransportTcp::new(addr, &core.handle())).unwrap();
+        transport.set_timeout(Some(Duration::from_millis(200))).unwrap();
+        transport.set_timeout(None).unwrap();
+        transport.set_timeout(Some(Duration::from_millis(50))).unwrap();
+
+        let started = Instant::now();
+        match core.run(CDRS::new(transport, NoneAuthenticator).supported()) {
+            Err(error::Error::Io(ref err)) if err.kind() == io::ErrorKind::TimedOut => (),
+            other => panic!("TimedOut expected, got {:?}", other.map(|_| ())),
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(200),
+                "{:?}",
+                elapsed);
+        server.join().unwrap();
+    }
+
+    /// Exchanges OPTIONS with a TLS node at `CDRS_TLS_NODE`, e.g. `10.0.0.1:9142`,
+    /// whose certificate is issued for `CDRS_TLS_DOMAIN` by a CA in a PEM file
+    /// at `CDRS_TLS_CA`. It's skipped if they're not set.
+    #[cfg(feature = "tls")]
+    #[test]
+    fn talks_to_tls_node() {
+        use std::env;
+        use std::fs::File;
+
+        let (node, domain, ca) = match (env::var("CDRS_TLS_NODE"),
+                                        env::var("CDRS_TLS_DOMAIN"),
+                                        env::var("CDRS_TLS_CA")) {
+            (Ok(node), Ok(domain), Ok(ca)) => (node, domain, ca),
+            _ => return,
+        };
+        let mut pem = vec![];
+        File::open(ca).unwrap().read_to_end(&mut pem).unwrap();
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(native_tls::Certificate::from_pem(&pem).unwrap())
+            .build()
+            .unwrap();
+
+        let mut core = Core::new().unwrap();
+        let transport = core.run(TransportTls::new(node, &domain, connector, &core.handle()))
+            .unwrap();
+        let clone = transport.try_clone().unwrap();
+        let options = CDRS::new(transport, NoneAuthenticator)
+            .supported()
+            .join(CDRS::new(clone, NoneAuthenticator).supported());
+        let ((_, original), (_, cloned)) = core.run(options).unwrap();
+        assert!(!original.cql_versions().is_empty());
+        assert_eq!(cloned, original);
+    }
+
+    /// Addresses a hostname resolves into.
+    struct Resolved(Vec<net::SocketAddr>);
+
+    impl net::ToSocketAddrs for Resolved {
+        type Iter = ::std::vec::IntoIter<net::SocketAddr>;
+
+        fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+            Ok(self.0.clone().into_iter())
+        }
+    }
+
+    /// An address nobody listens on.
+    fn closed_addr() -> net::SocketAddr {
+        net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn tries_resolved_addresses_in_order() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let reachable = listener.local_addr().unwrap();
+
+        let mut core = Core::new().unwrap();
+        let addrs = Resolved(vec![closed_addr(), reachable]);
+        let transport = core.run(TransportTcp::new(addrs, &core.handle())).unwrap();
+        assert_eq!(transport.peer_addr(), reachable);
+
+        let addrs = Resolved(vec![closed_addr(), closed_addr()]);
+        match core.run(TransportTcp::new(addrs, &core.handle())) {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused),
+            Ok(_) => panic!("ConnectionRefused expected"),
+        }
+        match core.run(TransportTcp::new(Resolved(vec![]), &core.handle())) {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("InvalidInput expected"),
+        }
+    }
+
+    #[test]
+    fn connects_without_blocking_the_reactor() {
+        let mut core = Core::new().unwrap();
+        let started = Instant::now();
+        // a non-routable address, a connection to it hangs or fails
+        let connecting = TransportTcp::new("10.255.255.1:9042", &core.handle());
+        assert!(started.elapsed() < Duration::from_millis(50));
 
-    fn close(&mut self, _close: net::Shutdown) -> io::Result<()> {
-        // self.tcp.shutdown(close)
-        Err(io::Error::new(io::ErrorKind::Other, "not implemented"))
+        let timeout = Timeout::new(Duration::from_millis(50), &core.handle()).unwrap();
+        let _ = core.run(connecting.select2(timeout));
+        assert!(started.elapsed() < Duration::from_secs(1));
     }
 
-    fn set_timeout(&mut self, _dur: Option<time::Duration>) -> io::Result<()> {
-        // self.tcp
-        //     .set_read_timeout(dur)
-        //     .and_then(|_| self.tcp.set_write_timeout(dur))
-        Err(io::Error::new(io::ErrorKind::Other, "not implemented"))
+    #[test]
+    fn fails_outside_of_reactor() {
+        let result = thread::spawn(|| TransportTcp::connect("127.0.0.1:9042").wait().map(|_| ()))
+            .join()
+            .unwrap();
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("outside of a reactor"), "{}", err);
     }
 }
@@ -0,0 +1,287 @@
+//! Client side validation of bound values against bind markers of a prepared statement.
+//!
+//! Values are checked in their serialized form: a value is compatible with a marker
+//! if a server would be able to decode its bytes as the marker's type. Elements of
+//! collections and fields of UDTs are checked one level deep.
+
+use std::str;
+
+use cdrs::frame::frame_result::{ColType, ColTypeOption, ColTypeOptionValue};
+use cdrs::types::value::{Value, ValueType};
+
+use error;
+
+/// Whether bound values are validated before a request is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Validation {
+    /// Check number and types of values, fail locally on a mismatch.
+    Strict,
+    /// Send values as they are and let a server decide.
+    Off,
+}
+
+impl Default for Validation {
+    fn default() -> Validation {
+        Validation::Off
+    }
+}
+
+/// Checks that `values` match `markers` in number and types.
+pub fn validate(markers: &[&ColTypeOption], values: &[Value]) -> error::Result<()> {
+    if markers.len() != values.len() {
+        return Err(error::Error::BindArity {
+                       markers: markers.len(),
+                       values: values.len(),
+                   });
+    }
+
+    for (index, (marker, value)) in markers.iter().zip(values).enumerate() {
+        match value.value_type {
+            ValueType::Normal(_) => (),
+            // nulls and unset values fit any marker
+            _ => continue,
+        }
+
+        try!(check_value(marker, &value.body, true).map_err(|mismatch| {
+            error::Error::BoundValue {
+                index: index,
+                expected: mismatch.expected,
+                provided: mismatch.provided,
+            }
+        }));
+    }
+
+    Ok(())
+}
+
+struct Mismatch {
+    expected: String,
+    provided: String,
+}
+
+fn check_value(col_type: &ColTypeOption, bytes: &[u8], deep: bool) -> Result<(), Mismatch> {
+    let fits = match col_type.id {
+        ColType::Boolean | ColType::Tinyint => bytes.len() == 1,
+        ColType::Smallint => bytes.len() == 2,
+        ColType::Int | ColType::Float | ColType::Date => bytes.len() == 4,
+        ColType::Bigint | ColType::Counter | ColType::Double | ColType::Timestamp |
+        ColType::Time => bytes.len() == 8,
+        ColType::Uuid | ColType::Timeuuid => bytes.len() == 16,
+        ColType::Inet => bytes.len() == 4 || bytes.len() == 16,
+        ColType::Varchar => str::from_utf8(bytes).is_ok(),
+        ColType::Ascii => bytes.iter().all(|b| *b < 0x80),
+        ColType::List | ColType::Set | ColType::Map | ColType::Udt if deep => {
+            return check_composite(col_type, bytes)
+        }
+        _ => true,
+    };
+
+    if fits {
+        Ok(())
+    } else {
+        Err(Mismatch {
+                expected: type_name(col_type),
+                provided: describe(bytes),
+            })
+    }
+}
+
+fn check_composite(col_type: &ColTypeOption, bytes: &[u8]) -> Result<(), Mismatch> {
+    let malformed = || {
+        Mismatch {
+            expected: type_name(col_type),
+            provided: describe(bytes),
+        }
+    };
+    let in_element = |element: &str, mismatch: Mismatch| {
+        Mismatch {
+            expected: format!("{} of {}", mismatch.expected, element),
+            provided: mismatch.provided,
+        }
+    };
+
+    match col_type.value {
+        Some(ColTypeOptionValue::CList(ref element)) |
+        Some(ColTypeOptionValue::CSet(ref element)) => {
+            let elements = try!(split_elements(bytes, 1).ok_or_else(&malformed));
+            for (i, element_bytes) in elements.iter().enumerate() {
+                try!(check_value(element, element_bytes, false)
+                         .map_err(|m| in_element(&format!("element {}", i), m)));
+            }
+            Ok(())
+        }
+        Some(ColTypeOptionValue::CMap((ref key, ref value))) => {
+            let elements = try!(split_elements(bytes, 2).ok_or_else(&malformed));
+            for (i, pair) in elements.chunks(2).enumerate() {
+                try!(check_value(key, pair[0], false)
+                         .map_err(|m| in_element(&format!("key {}", i), m)));
+                try!(check_value(value, pair[1], false)
+                         .map_err(|m| in_element(&format!("value {}", i), m)));
+            }
+            Ok(())
+        }
+        Some(ColTypeOptionValue::UdtType(ref udt)) => {
+            let fields = try!(split_fields(bytes).ok_or_else(&malformed));
+            if fields.len() > udt.descriptions.len() {
+                return Err(malformed());
+            }
+            for (field, &(ref name, ref field_type)) in fields.iter().zip(&udt.descriptions) {
+                if let Some(field_bytes) = *field {
+                    try!(check_value(field_type, field_bytes, false)
+                             .map_err(|m| in_element(&format!("field {}", name.as_str()), m)));
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Splits a serialized list, set (`per_entry` is 1) or map (`per_entry` is 2)
+/// into elements. Returns `None` if bytes are malformed.
+pub fn split_elements(bytes: &[u8], per_entry: usize) -> Option<Vec<&[u8]>> {
+    let (count, mut rest) = match read_int(bytes) {
+        Some((count, rest)) if count >= 0 => (count as usize * per_entry, rest),
+        _ => return None,
+    };
+
+    let mut elements = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (element, tail) = match read_bytes(rest) {
+            Some((Some(element), tail)) => (element, tail),
+            _ => return None,
+        };
+        elements.push(element);
+        rest = tail;
+    }
+
+    if rest.is_empty() { Some(elements) } else { None }
+}
+
+/// Splits a serialized UDT into fields, `None` fields are nulls.
+fn split_fields(mut bytes: &[u8]) -> Option<Vec<Option<&[u8]>>> {
+    let mut fields = vec![];
+    while !bytes.is_empty() {
+        let (field, rest) = match read_bytes(bytes) {
+            Some(field) => field,
+            None => return None,
+        };
+        fields.push(field);
+        bytes = rest;
+    }
+    Some(fields)
+}
+
+fn read_int(bytes: &[u8]) -> Option<(i32, &[u8])> {
+    if bytes.len() < 4 {
+        return None;
+    }
+
+    let n = (bytes[0] as i32) << 24 | (bytes[1] as i32) << 16 | (bytes[2] as i32) << 8 |
+            bytes[3] as i32;
+    Some((n, &bytes[4..]))
+}
+
+fn read_bytes(bytes: &[u8]) -> Option<(Option<&[u8]>, &[u8])> {
+    match read_int(bytes) {
+        Some((len, rest)) if len < 0 => Some((None, rest)),
+        Some((len, rest)) if rest.len() >= len as usize => {
+            Some((Some(&rest[..len as usize]), &rest[len as usize..]))
+        }
+        _ => None,
+    }
+}
+
+fn type_name(col_type: &ColTypeOption) -> String {
+    format!("{:?}", col_type.id).to_lowercase()
+}
+
+/// Describes a value which doesn't fit a marker, its type is unknown after
+/// serialization, so only its shape is reported.
+fn describe(bytes: &[u8]) -> String {
+    match str::from_utf8(bytes) {
+        Ok(text) if !bytes.is_empty() && text.chars().all(|c| !c.is_control()) => {
+            format!("{}-byte value {:?}", bytes.len(), text)
+        }
+        _ => format!("{}-byte value", bytes.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cdrs::frame::frame_result::{ColType, ColTypeOption, ColTypeOptionValue};
+    use cdrs::types::value::{Value, ValueType};
+
+    use super::*;
+    use error;
+
+    fn simple(id: ColType) -> ColTypeOption {
+        ColTypeOption {
+            id: id,
+            value: None,
+        }
+    }
+
+    fn int(i: i32) -> Vec<u8> {
+        vec![(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]
+    }
+
+    fn element(bytes: &[u8]) -> Vec<u8> {
+        let mut element = int(bytes.len() as i32);
+        element.extend_from_slice(bytes);
+        element
+    }
+
+    #[test]
+    fn count_mismatch() {
+        let int_marker = simple(ColType::Int);
+        match validate(&[&int_marker, &int_marker], &[Value::from(1)]) {
+            Err(error::Error::BindArity { markers: 2, values: 1 }) => (),
+            other => panic!("BindArity expected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn simple_type_mismatch() {
+        let markers = [simple(ColType::Varchar), simple(ColType::Int)];
+        let markers: Vec<_> = markers.iter().collect();
+        assert!(validate(&markers, &[Value::from("id".to_string()), Value::from(1)]).is_ok());
+
+        match validate(&markers, &[Value::from("id".to_string()), Value::from("one".to_string())]) {
+            Err(error::Error::BoundValue { index, expected, provided }) => {
+                assert_eq!(index, 1);
+                assert_eq!(expected, "int");
+                assert_eq!(provided, "3-byte value \"one\"");
+            }
+            other => panic!("BoundValue expected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn map_value_type_mismatch() {
+        let map = ColTypeOption {
+            id: ColType::Map,
+            value: Some(ColTypeOptionValue::CMap((Box::new(simple(ColType::Varchar)),
+                                                  Box::new(simple(ColType::Int))))),
+        };
+
+        let mut body = int(2);
+        body.extend(element(b"a"));
+        body.extend(element(&int(1)));
+        body.extend(element(b"b"));
+        body.extend(element(b"two"));
+        let value = Value {
+            value_type: ValueType::Normal(body.len() as i32),
+            body: body,
+        };
+
+        match validate(&[&map], &[value]) {
+            Err(error::Error::BoundValue { index, expected, provided }) => {
+                assert_eq!(index, 0);
+                assert_eq!(expected, "int of value 1");
+                assert_eq!(provided, "3-byte value \"two\"");
+            }
+            other => panic!("BoundValue expected, got {:?}", other),
+        }
+    }
+}
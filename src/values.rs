@@ -0,0 +1,491 @@
+//! Conversion of Rust types into bound values of a query.
+//!
+//! `query_values!` binds values of any types which implement `IntoValue`,
+//! either by position or by name. Types which convert only `Into<Value>`, like
+//! blobs of cdrs, are bound wrapped into `AsValue`:
+//!
+//! ```
+//! # #[macro_use] extern crate cdrs_future;
+//! # fn main() {
+//! let by_position = query_values!(1, "alice", None::<i32>);
+//! let by_name = query_values!{"id" => 1, "name" => "alice"};
+//! assert!(!by_position.with_names() && by_name.with_names());
+//! # }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cdrs::IntoBytes;
+use cdrs::frame::{Frame, Opcode};
+use cdrs::types::value::{Bytes, Value};
+use uuid::Uuid;
+
+use error;
+
+/// Flag of query parameters which says that values are preceded by their names.
+pub const WITH_NAMES_FLAG: u8 = 0x40;
+
+/// Builds `QueryValues` of values which implement `IntoValue`. Values are bound
+/// by position, `query_values!(a, b)`, or by name, `query_values!{"a" => a}`.
+#[macro_export]
+macro_rules! query_values {
+    ($($name:expr => $value:expr),+ $(,)*) => {
+        $crate::values::QueryValues::Named(
+            vec![$(($name.to_string(), $crate::values::IntoValue::into_value($value))),+])
+    };
+    ($($value:expr),* $(,)*) => {
+        $crate::values::QueryValues::Simple(
+            vec![$($crate::values::IntoValue::into_value($value)),*])
+    };
+}
+
+/// Types of fields which could be bound to a marker. `None` is bound as null.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+/// Binds a value of a type which converts only `Into<Value>` where `IntoValue`
+/// is expected, the same way `#[derive(IntoQueryValues)]` binds its fields.
+#[derive(Debug, Clone)]
+pub struct AsValue<T>(pub T);
+
+/// Values of markers of a query, see `query_values!`.
+#[derive(Debug, Clone)]
+pub enum QueryValues {
+    /// Values bound in order of markers.
+    Simple(Vec<Value>),
+    /// Values bound to markers with the same names, in any order.
+    Named(Vec<(String, Value)>),
+}
+
+impl QueryValues {
+    /// Values of `values` bound by position.
+    pub fn simple<V: IntoQueryValues>(values: V) -> QueryValues {
+        QueryValues::Simple(values.into_query_values())
+    }
+
+    /// Whether `with_names` should be set on query parameters of the values.
+    pub fn with_names(&self) -> bool {
+        match *self {
+            QueryValues::Simple(_) => false,
+            QueryValues::Named(_) => true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            QueryValues::Simple(ref values) => values.len(),
+            QueryValues::Named(ref values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Splits the values into values of query parameters and their names,
+    /// which are `None` for values bound by position.
+    pub fn into_parts(self) -> (Vec<Value>, Option<Vec<String>>) {
+        match self {
+            QueryValues::Simple(values) => (values, None),
+            QueryValues::Named(values) => {
+                let (names, values) = values.into_iter().unzip();
+                (values, Some(names))
+            }
+        }
+    }
+}
+
+/// Types which could be bound to markers of a query.
+///
+/// `cdrs_future_derive` provides `#[derive(IntoQueryValues)]` which binds fields
+/// of a struct in order of their declaration.
+pub trait IntoQueryValues {
+    /// Number of values produced by this type or `None` if it's known only
+    /// for an instance, like for `Vec<Value>`.
+    fn arity() -> Option<usize>;
+
+    fn into_query_values(self) -> Vec<Value>;
+}
+
+/// Types which know columns their values are bound to, in order of `into_query_values`.
+///
+/// `cdrs_future_derive` provides `#[derive(Columns)]` which takes column names
+/// of struct fields the same way `#[derive(IntoQueryValues)]` binds them.
+pub trait Columns {
+    fn columns() -> &'static [&'static str];
+}
+
+/// Orders named `values` as `markers` of a statement. Every marker needs a value
+/// and every value needs a marker.
+pub fn bind_by_name(markers: &[String], values: Vec<(String, Value)>) -> error::Result<Vec<Value>> {
+    let mut values: Vec<_> = values.into_iter().map(Some).collect();
+    let mut bound = Vec::with_capacity(markers.len());
+    for marker in markers {
+        let position = values.iter()
+            .position(|value| value.as_ref().map_or(false, |&(ref name, _)| name == marker));
+        match position.and_then(|position| values[position].take()) {
+            Some((_, value)) => bound.push(value),
+            None => return Err(format!("No value is bound to marker `{}`", marker).into()),
+        }
+    }
+
+    match values.into_iter().flatten().next() {
+        Some((name, _)) => Err(format!("Statement has no marker `{}`", name).into()),
+        None => Ok(bound),
+    }
+}
+
+/// Converts a field into a bound value.
+pub fn value<T: Into<Value>>(value: T) -> Value {
+    value.into()
+}
+
+/// Converts an optional field into a bound value, `None` is bound as null.
+pub fn nullable_value<T: Into<Value>>(value: Option<T>) -> Value {
+    match value {
+        Some(value) => value.into(),
+        None => Value::new_null(),
+    }
+}
+
+/// Writes `names` before values of a QUERY or EXECUTE request and sets
+/// `WITH_NAMES_FLAG`, as the protocol expects a `[string]` name before every
+/// `[value]` then. The request has to carry as many values as there are names.
+pub fn set_value_names(frame: &mut Frame, names: &[String]) -> error::Result<()> {
+    let mut position = match frame.opcode {
+        Opcode::Query => 4 + try!(read_len(&frame.body, 0, 4)),
+        Opcode::Execute => 2 + try!(read_len(&frame.body, 0, 2)),
+        _ => return Err("Only QUERY and EXECUTE requests bind values by name".into()),
+    };
+    // consistency, then flags and the number of values
+    position += 2;
+    if frame.body.len() < position + 3 {
+        return Err("Request has no values to name".into());
+    }
+    let count = try!(read_len(&frame.body, position + 1, 2));
+    if count != names.len() {
+        return Err(format!("Request has {} values, but {} names", count, names.len()).into());
+    }
+    frame.body[position] |= WITH_NAMES_FLAG;
+    position += 3;
+
+    let mut body = frame.body[..position].to_vec();
+    for name in names {
+        body.extend_from_slice(&[(name.len() >> 8) as u8, name.len() as u8]);
+        body.extend_from_slice(name.as_bytes());
+        // null and unset values are negative lengths without bytes
+        let len = try!(read_len(&frame.body, position, 4)) as i32;
+        let end = position + 4 + if len < 0 { 0 } else { len as usize };
+        if frame.body.len() < end {
+            return Err("Value of a request is shorter than its length".into());
+        }
+        body.extend_from_slice(&frame.body[position..end]);
+        position = end;
+    }
+    body.extend_from_slice(&frame.body[position..]);
+    frame.body = body;
+    Ok(())
+}
+
+fn read_len(body: &[u8], position: usize, size: usize) -> error::Result<usize> {
+    if body.len() < position + size {
+        return Err("Request body is shorter than expected".into());
+    }
+    Ok(body[position..position + size].iter().fold(0, |len, &byte| (len << 8) | byte as usize))
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl<T: Into<Value>> IntoValue for AsValue<T> {
+    fn into_value(self) -> Value {
+        self.0.into()
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        match self {
+            Some(value) => value.into_value(),
+            None => Value::new_null(),
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::new_normal(Bytes::new(self.into_bytes()))
+    }
+}
+
+impl<'a> IntoValue for &'a str {
+    fn into_value(self) -> Value {
+        Value::new_normal(Bytes::new(self.as_bytes().to_vec()))
+    }
+}
+
+impl IntoValue for Vec<u8> {
+    fn into_value(self) -> Value {
+        Value::new_normal(Bytes::new(self))
+    }
+}
+
+/// Bound as a `list`, `Vec<u8>` is bound as a `blob` though.
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        let len = self.len();
+        collection_value(len, self.into_iter().map(IntoValue::into_value))
+    }
+}
+
+/// Bound as a `set`.
+impl<T: IntoValue + Eq + Hash> IntoValue for HashSet<T> {
+    fn into_value(self) -> Value {
+        let len = self.len();
+        collection_value(len, self.into_iter().map(IntoValue::into_value))
+    }
+}
+
+/// Bound as a `map`.
+impl<K: IntoValue + Eq + Hash, V: IntoValue> IntoValue for HashMap<K, V> {
+    fn into_value(self) -> Value {
+        let len = self.len();
+        let entries = self.into_iter()
+            .flat_map(|(key, value)| vec![key.into_value(), value.into_value()]);
+        collection_value(len, entries)
+    }
+}
+
+/// Encodes `len` elements, or entries of a map, followed by their values.
+fn collection_value<I: Iterator<Item = Value>>(len: usize, values: I) -> Value {
+    let mut bytes: Vec<u8> = (0..4).rev().map(|i| (len >> (8 * i)) as u8).collect();
+    for value in values {
+        bytes.extend(value.into_cbytes());
+    }
+    Value::new_normal(Bytes::new(bytes))
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::new_normal(Bytes::new(vec![self as u8]))
+    }
+}
+
+macro_rules! int_into_value {
+    ($($int:ty: $size:expr),+) => {
+        $(impl IntoValue for $int {
+            fn into_value(self) -> Value {
+                let bytes: Vec<u8> = (0..$size)
+                    .rev()
+                    .map(|i| (self as i64 >> (8 * i)) as u8)
+                    .collect();
+                Value::new_normal(Bytes::new(bytes))
+            }
+        })+
+    }
+}
+
+int_into_value!(i8: 1, i16: 2, i32: 4, i64: 8);
+
+impl IntoValue for f32 {
+    fn into_value(self) -> Value {
+        (self.to_bits() as i32).into_value()
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        (self.to_bits() as i64).into_value()
+    }
+}
+
+impl IntoValue for Uuid {
+    fn into_value(self) -> Value {
+        Value::new_normal(Bytes::new(self.as_bytes().to_vec()))
+    }
+}
+
+impl IntoValue for IpAddr {
+    fn into_value(self) -> Value {
+        match self {
+            IpAddr::V4(ip) => Value::new_normal(Bytes::new(ip.octets().to_vec())),
+            IpAddr::V6(ip) => Value::new_normal(Bytes::new(ip.octets().to_vec())),
+        }
+    }
+}
+
+/// Bound as a `timestamp`, i.e. milliseconds since the Unix epoch.
+impl IntoValue for SystemTime {
+    fn into_value(self) -> Value {
+        let millis = |since: ::std::time::Duration| {
+            since.as_secs() as i64 * 1000 + since.subsec_millis() as i64
+        };
+        match self.duration_since(UNIX_EPOCH) {
+            Ok(since) => millis(since).into_value(),
+            Err(err) => (-millis(err.duration())).into_value(),
+        }
+    }
+}
+
+impl IntoQueryValues for () {
+    fn arity() -> Option<usize> {
+        Some(0)
+    }
+
+    fn into_query_values(self) -> Vec<Value> {
+        vec![]
+    }
+}
+
+impl IntoQueryValues for Vec<Value> {
+    fn arity() -> Option<usize> {
+        None
+    }
+
+    fn into_query_values(self) -> Vec<Value> {
+        self
+    }
+}
+
+macro_rules! tuple_into_query_values {
+    ($arity:expr; $($name:ident),+) => {
+        impl<$($name: IntoValue),+> IntoQueryValues for ($($name,)+) {
+            fn arity() -> Option<usize> {
+                Some($arity)
+            }
+
+            #[allow(non_snake_case)]
+            fn into_query_values(self) -> Vec<Value> {
+                let ($($name,)+) = self;
+                vec![$($name.into_value()),+]
+            }
+        }
+    }
+}
+
+tuple_into_query_values!(1; A);
+tuple_into_query_values!(2; A, B);
+tuple_into_query_values!(3; A, B, C);
+tuple_into_query_values!(4; A, B, C, D);
+tuple_into_query_values!(5; A, B, C, D, E);
+tuple_into_query_values!(6; A, B, C, D, E, F);
+tuple_into_query_values!(7; A, B, C, D, E, F, G);
+tuple_into_query_values!(8; A, B, C, D, E, F, G, H);
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use cdrs::IntoBytes;
+    use cdrs::query::QueryBuilder;
+
+    use super::*;
+    use client;
+
+    fn encoded<V: IntoValue>(value: V) -> Vec<u8> {
+        value.into_value().into_cbytes()
+    }
+
+    #[test]
+    fn encodes_values() {
+        assert_eq!(encoded(258), vec![0, 0, 0, 4, 0, 0, 1, 2]);
+        assert_eq!(encoded(-2i64),
+                   vec![0, 0, 0, 8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(encoded("bob"), vec![0, 0, 0, 3, b'b', b'o', b'b']);
+        assert_eq!(encoded("bob".to_string()), encoded("bob"));
+        assert_eq!(encoded(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))), vec![0, 0, 0, 4, 10, 0, 0, 1]);
+
+        let uuid = [0x9c, 0x2f, 0x6f, 0x30, 0x4b, 0x0e, 0x11, 0xe9, 0x8b, 0x62, 0x7b, 0x1c, 0x2d,
+                    0x3e, 0x4f, 0x50];
+        let mut expected = vec![0, 0, 0, 16];
+        expected.extend_from_slice(&uuid);
+        assert_eq!(encoded(Uuid::from_bytes(&uuid).unwrap()), expected);
+
+        let timestamp = UNIX_EPOCH + Duration::from_millis(1546300800000);
+        assert_eq!(encoded(timestamp),
+                   vec![0, 0, 0, 8, 0, 0, 0x01, 0x68, 0x06, 0xb5, 0xbc, 0x00]);
+        assert_eq!(encoded(UNIX_EPOCH - Duration::from_millis(1)), encoded(-1i64));
+
+        assert_eq!(encoded(None::<i32>), vec![0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(encoded(Some(258)), encoded(258));
+    }
+
+    #[test]
+    fn encodes_collections() {
+        assert_eq!(encoded(vec![1, 258]),
+                   vec![0, 0, 0, 20, 0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 1, 2]);
+        assert_eq!(encoded(vec![1u8, 2]), vec![0, 0, 0, 2, 1, 2]);
+
+        let mut map = HashMap::new();
+        map.insert("a", true);
+        assert_eq!(encoded(map), vec![0, 0, 0, 14, 0, 0, 0, 1, 0, 0, 0, 1, b'a', 0, 0, 0, 1, 1]);
+        let set: HashSet<_> = vec![7i8].into_iter().collect();
+        assert_eq!(encoded(set), vec![0, 0, 0, 9, 0, 0, 0, 1, 0, 0, 0, 1, 7]);
+    }
+
+    #[test]
+    fn binds_into_value_types_as_they_convert() {
+        let values = (AsValue(258), AsValue("bob".to_string())).into_query_values();
+        assert_eq!(values[0].clone().into_cbytes(), encoded(258));
+        assert_eq!(values[1].clone().into_cbytes(), encoded("bob"));
+        assert_eq!(query_values!(AsValue(Value::new_null())).len(), 1);
+    }
+
+    #[test]
+    fn binds_tuples_in_order() {
+        let values = (1, None::<String>, "alice").into_query_values();
+        let encoded: Vec<_> = values.into_iter().map(|value| value.into_cbytes()).collect();
+        assert_eq!(encoded,
+                   vec![vec![0, 0, 0, 4, 0, 0, 0, 1],
+                        vec![0xff, 0xff, 0xff, 0xff],
+                        vec![0, 0, 0, 5, b'a', b'l', b'i', b'c', b'e']]);
+        assert_eq!(<(i32, Option<String>, &str)>::arity(), Some(3));
+    }
+
+    #[test]
+    fn builds_values_by_position_or_by_name() {
+        let by_position = query_values!(1, "alice");
+        assert!(!by_position.with_names());
+        let (values, names) = by_position.into_parts();
+        assert_eq!(values.len(), 2);
+        assert_eq!(names, None);
+
+        let by_name = query_values!{"id" => 1, "name" => None::<String>,};
+        assert!(by_name.with_names());
+        assert_eq!(by_name.len(), 2);
+        let (values, names) = by_name.into_parts();
+        assert_eq!(values[1].clone().into_cbytes(), vec![0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(names, Some(vec!["id".to_string(), "name".to_string()]));
+
+        assert!(query_values!().is_empty());
+    }
+
+    #[test]
+    fn names_values_of_queries() {
+        let cql = "SELECT * FROM users WHERE id = :id AND age = :age";
+        let (values, names) = query_values!{"id" => 1, "age" => None::<i32>}.into_parts();
+        let mut query = QueryBuilder::new(cql).finalize();
+        query.values = Some(values);
+        query.with_names = Some(true);
+        let mut frame = client::query_frame(query, vec![]);
+        set_value_names(&mut frame, &names.unwrap()).unwrap();
+
+        let mut expected = vec![0, 0, 0, cql.len() as u8];
+        expected.extend_from_slice(cql.as_bytes());
+        // ONE, flags of values with names and two values
+        expected.extend_from_slice(&[0, 1, 0x41, 0, 2]);
+        expected.extend_from_slice(&[0, 2, b'i', b'd', 0, 0, 0, 4, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0, 3, b'a', b'g', b'e', 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(frame.body, expected);
+
+        let mut frame = client::query_frame(QueryBuilder::new(cql).finalize(), vec![]);
+        assert!(set_value_names(&mut frame, &["id".to_string()]).is_err());
+    }
+}